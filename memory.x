@@ -1,8 +1,12 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 64K 
-  RAM : ORIGIN = 0x20000000, LENGTH = 32K
+  /* Sectors 0-5 for the firmware, 6 and 7 (128K each) for the settings
+     store, see `InternalFlash` in src/hw/stm32f446.rs */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 256K
+  STORAGE : ORIGIN = 0x08040000, LENGTH = 256K
+  /* SRAM1 and SRAM2, contiguous */
+  RAM : ORIGIN = 0x20000000, LENGTH = 128K
 }
 
 /* This is where the call stack will be allocated. */
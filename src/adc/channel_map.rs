@@ -0,0 +1,246 @@
+use crate::hw::SampleTime;
+
+// Length of the ADC regular sequence
+pub const MAX_CHANNELS: usize = 16;
+
+// Cycles spent converting after sampling, at 12 bit resolution
+const CONVERSION_CYCLES: u32 = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Port {
+	A,
+	B,
+	C,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pin {
+	pub port: Port,
+	pub pin: u8,
+}
+
+/// The pin an ADC123 external channel is bonded to.
+///
+/// Channels 0-7 are PA0-PA7, 8-9 PB0-PB1 and 10-15 PC0-PC5. The same on the
+/// F411 and F446 in the 64 pin packages.
+pub fn pin(channel: u8) -> Option<Pin> {
+	match channel {
+		0..=7 => Some(Pin { port: Port::A, pin: channel }),
+		8..=9 => Some(Pin { port: Port::B, pin: channel - 8 }),
+		10..=15 => Some(Pin { port: Port::C, pin: channel - 10 }),
+		_ => None,
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+	Empty,
+	TooManyChannels,
+	// Not an external channel
+	InvalidChannel(u8),
+}
+
+/// Register values for a regular sequence.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Registers {
+	pub sqr1: u32,
+	pub sqr2: u32,
+	pub sqr3: u32,
+	pub smpr1: u32,
+	pub smpr2: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelMap {
+	channels: [u8; MAX_CHANNELS],
+	len: usize,
+	// Indexed by ADC channel
+	sample_times: [SampleTime; MAX_CHANNELS],
+}
+
+impl ChannelMap {
+	/// `channels` in conversion order, a channel may appear more than once.
+	pub fn new(channels: &[u8], sample_time: SampleTime) -> Result<ChannelMap, Error> {
+		if channels.is_empty() {
+			return Err(Error::Empty);
+		}
+		if channels.len() > MAX_CHANNELS {
+			return Err(Error::TooManyChannels);
+		}
+
+		let mut map = ChannelMap {
+			channels: [0; MAX_CHANNELS],
+			len: channels.len(),
+			sample_times: [sample_time; MAX_CHANNELS],
+		};
+		for (i, &channel) in channels.iter().enumerate() {
+			if pin(channel).is_none() {
+				return Err(Error::InvalidChannel(channel));
+			}
+			map.channels[i] = channel;
+		}
+
+		Ok(map)
+	}
+
+	// Override the sample time of one ADC channel
+	pub fn sample_time(mut self, channel: u8, sample_time: SampleTime) -> ChannelMap {
+		assert!(pin(channel).is_some(), "not an external channel");
+		self.sample_times[channel as usize] = sample_time;
+		self
+	}
+
+	/// Sample time of one ADC channel
+	pub fn channel_sample_time(&self, channel: u8) -> SampleTime {
+		self.sample_times[channel as usize]
+	}
+
+	/// Same sample times, different sequence
+	pub fn with_sequence(&self, channels: &[u8]) -> Result<ChannelMap, Error> {
+		let mut map = ChannelMap::new(channels, SampleTime::Cycles3)?;
+		map.sample_times = self.sample_times;
+		Ok(map)
+	}
+
+	pub fn channels(&self) -> &[u8] {
+		&self.channels[..self.len]
+	}
+
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	/// DMA buffer length holding `frames` complete frames
+	pub fn buffer_len(&self, frames: usize) -> usize {
+		self.len * frames
+	}
+
+	/// ADC clock cycles needed to convert the whole sequence
+	pub fn sequence_cycles(&self) -> u32 {
+		self.channels()
+			.iter()
+			.map(|&channel| self.sample_times[channel as usize].cycles() + CONVERSION_CYCLES)
+			.sum()
+	}
+
+	/// Whether the sequence finishes within one sample period
+	pub fn fits(&self, adc_clock: u32, sample_rate: u32) -> bool {
+		self.sequence_cycles() as u64 * sample_rate as u64 <= adc_clock as u64
+	}
+
+	/// SQR1-3 and SMPR1-2 for this sequence.
+	///
+	/// SQR3 holds conversion 1-6, SQR2 7-12 and SQR1 13-16 plus the length
+	/// (L = conversions - 1), 5 bits per conversion. SMPR2 holds the sample
+	/// time of channel 0-9 and SMPR1 channel 10-18, 3 bits per channel.
+	pub fn registers(&self) -> Registers {
+		let mut regs = Registers::default();
+
+		for (i, &channel) in self.channels().iter().enumerate() {
+			let channel = channel as u32;
+			match i {
+				0..=5 => regs.sqr3 |= channel << (i * 5),
+				6..=11 => regs.sqr2 |= channel << ((i - 6) * 5),
+				_ => regs.sqr1 |= channel << ((i - 12) * 5),
+			}
+
+			let smp = self.sample_times[channel as usize] as u32;
+			if channel < 10 {
+				regs.smpr2 |= smp << (channel * 3);
+			} else {
+				regs.smpr1 |= smp << ((channel - 10) * 3);
+			}
+		}
+		regs.sqr1 |= (self.len as u32 - 1) << 20;
+
+		regs
+	}
+
+	/// GPIO MODER bits putting the pins of `port` used by the map in analog mode
+	pub fn analog_moder(&self, port: Port) -> u32 {
+		self.channels()
+			.iter()
+			.filter_map(|&channel| pin(channel))
+			.filter(|pin| pin.port == port)
+			.fold(0, |moder, pin| moder | 0b11 << (pin.pin * 2))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn full_sequence_registers() {
+		let channels: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+		let map = ChannelMap::new(&channels, SampleTime::Cycles28).unwrap();
+		let regs = map.registers();
+
+		assert_eq!(regs.sqr3, 5 << 25 | 4 << 20 | 3 << 15 | 2 << 10 | 1 << 5);
+		assert_eq!(regs.sqr2, 11 << 25 | 10 << 20 | 9 << 15 | 8 << 10 | 7 << 5 | 6);
+		assert_eq!(regs.sqr1, 15 << 20 | 15 << 15 | 14 << 10 | 13 << 5 | 12);
+		// 0b010 for every channel
+		assert_eq!(regs.smpr2, 0x1249_2492);
+		assert_eq!(regs.smpr1, 0x0001_2492);
+	}
+
+	#[test]
+	fn today_two_channel_layout() {
+		let map = ChannelMap::new(&[0, 1], SampleTime::Cycles56).unwrap();
+		let regs = map.registers();
+
+		assert_eq!(regs.sqr1, 1 << 20);
+		assert_eq!(regs.sqr3, 1 << 5);
+		assert_eq!(regs.smpr2, 0b011_011);
+		assert_eq!(map.analog_moder(Port::A), 0b1111);
+		assert_eq!(map.analog_moder(Port::B), 0);
+	}
+
+	#[test]
+	fn per_channel_sample_time() {
+		let map = ChannelMap::new(&[12, 3], SampleTime::Cycles15)
+			.unwrap()
+			.sample_time(12, SampleTime::Cycles480);
+		let regs = map.registers();
+
+		assert_eq!(regs.smpr1, 0b111 << 6);
+		assert_eq!(regs.smpr2, 0b001 << 9);
+		assert_eq!(map.sequence_cycles(), 480 + 12 + 15 + 12);
+	}
+
+	#[test]
+	fn gpio_across_ports() {
+		let map = ChannelMap::new(&[9, 0, 15, 8, 10], SampleTime::Cycles15).unwrap();
+
+		assert_eq!(map.analog_moder(Port::A), 0b11);
+		assert_eq!(map.analog_moder(Port::B), 0b1111);
+		assert_eq!(map.analog_moder(Port::C), 0b11 << 10 | 0b11);
+		assert_eq!(pin(15), Some(Pin { port: Port::C, pin: 5 }));
+	}
+
+	#[test]
+	fn sequence_timing() {
+		let channels: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+		// 16 * (28 + 12) = 640 cycles, 28.4us at 22.5 MHz
+		let map = ChannelMap::new(&channels, SampleTime::Cycles28).unwrap();
+		assert!(map.fits(22_500_000, 16_000));
+		assert!(!map.fits(22_500_000, 44_100));
+
+		let map = ChannelMap::new(&channels, SampleTime::Cycles56).unwrap();
+		assert!(!map.fits(22_500_000, 32_000));
+		assert_eq!(map.buffer_len(4), 64);
+	}
+
+	#[test]
+	fn rejects_invalid_maps() {
+		assert_eq!(ChannelMap::new(&[], SampleTime::Cycles3), Err(Error::Empty));
+		assert_eq!(ChannelMap::new(&[0; 17], SampleTime::Cycles3), Err(Error::TooManyChannels));
+		// 16-18 are the internal temperature sensor, VREFINT and VBAT
+		assert_eq!(ChannelMap::new(&[0, 16], SampleTime::Cycles3), Err(Error::InvalidChannel(16)));
+	}
+}
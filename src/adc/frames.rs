@@ -0,0 +1,86 @@
+use core::iter::{Copied, Skip, StepBy};
+use core::slice::{ChunksExact, Iter};
+
+/// A buffer of interleaved frames, `width` samples per frame.
+///
+/// Raw ADC codes by default, or any other sample type such as the
+/// decimated input frames.
+#[derive(Clone, Copy, Debug)]
+pub struct Frames<'a, T = u16> {
+	buffer: &'a [T],
+	width: usize,
+}
+
+impl<'a, T: Copy> Frames<'a, T> {
+	pub fn new(buffer: &'a [T], width: usize) -> Frames<'a, T> {
+		assert!(width > 0 && buffer.len().is_multiple_of(width), "buffer must hold whole frames");
+		Frames { buffer, width }
+	}
+
+	// Number of frames
+	pub fn len(&self) -> usize {
+		self.buffer.len() / self.width
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.buffer.is_empty()
+	}
+
+	pub fn width(&self) -> usize {
+		self.width
+	}
+
+	pub fn frame(&self, index: usize) -> &'a [T] {
+		&self.buffer[index * self.width..(index + 1) * self.width]
+	}
+
+	pub fn iter(&self) -> ChunksExact<'a, T> {
+		self.buffer.chunks_exact(self.width)
+	}
+
+	/// The samples of one position in the frame, oldest first
+	pub fn channel(&self, index: usize) -> Copied<StepBy<Skip<Iter<'a, T>>>> {
+		assert!(index < self.width);
+		self.buffer.iter().skip(index).step_by(self.width).copied()
+	}
+
+	/// Split into one stream per frame position.
+	///
+	/// `out[i]` receives channel `i`, each output must hold `len()` samples.
+	pub fn deinterleave(&self, out: &mut [&mut [T]]) {
+		assert!(out.len() <= self.width);
+
+		for (i, frame) in self.iter().enumerate() {
+			for (channel, stream) in out.iter_mut().enumerate() {
+				stream[i] = frame[channel];
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn splits_frames() {
+		let buffer = [10, 20, 30, 11, 21, 31];
+		let frames = Frames::new(&buffer, 3);
+
+		assert_eq!(frames.len(), 2);
+		assert_eq!(frames.frame(1), &[11, 21, 31]);
+		assert_eq!(frames.channel(1).collect::<Vec<_>>(), vec![20, 21]);
+
+		let mut a = [0; 2];
+		let mut b = [0; 2];
+		let mut c = [0; 2];
+		frames.deinterleave(&mut [&mut a, &mut b, &mut c]);
+		assert_eq!((a, b, c), ([10, 11], [20, 21], [30, 31]));
+	}
+
+	#[test]
+	#[should_panic]
+	fn partial_frame_rejected() {
+		Frames::new(&[1, 2, 3], 2);
+	}
+}
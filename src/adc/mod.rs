@@ -0,0 +1,13 @@
+//! ADC channel layout and sample frames.
+//!
+//! A `ChannelMap` is the list of ADC channels converted on every sample clock
+//! tick, in conversion order. The DMA buffers hold whole frames, one sample
+//! per entry in the map, which `Frames` splits back into per channel streams.
+
+mod channel_map;
+mod frames;
+pub mod multi;
+
+pub use self::channel_map::{pin, ChannelMap, Error, Pin, Port, Registers, MAX_CHANNELS};
+pub use self::frames::Frames;
+pub use self::multi::{Allocation, Mode};
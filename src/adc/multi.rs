@@ -0,0 +1,344 @@
+//! ADC1-ADC3 in triple mode.
+//!
+//! In triple regular simultaneous mode every trigger converts rank 1 on all
+//! three ADCs at once, then rank 2 and so on. The results are read from the
+//! common data register (CDR) in DMA mode 2, one 32 bit word holding two
+//! results:
+//!
+//! ```text
+//! 1st request: ADC2 << 16 | ADC1
+//! 2nd request: ADC1 << 16 | ADC3
+//! 3rd request: ADC3 << 16 | ADC2
+//! ```
+//!
+//! Stored little endian, the half words in memory come out as ADC1, ADC2,
+//! ADC3, ADC1, ... so a frame holds rank 1 of all three ADCs, then rank 2.
+//! `Allocation` spreads the channel map across the ADCs and knows where each
+//! input ends up in that frame.
+//!
+//! Triple interleaved mode converts one channel on all three ADCs, staggered
+//! by the common delay, giving three samples of that input per trigger.
+
+use super::{ChannelMap, MAX_CHANNELS};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+	Independent,
+	TripleSimultaneous,
+	TripleInterleaved,
+}
+
+impl Mode {
+	// ADC_CCR MULTI bits
+	pub fn multi(self) -> u8 {
+		match self {
+			Mode::Independent => 0b00000,
+			Mode::TripleSimultaneous => 0b10110,
+			Mode::TripleInterleaved => 0b10111,
+		}
+	}
+
+	// Number of ADCs converting
+	pub fn adcs(self) -> usize {
+		match self {
+			Mode::Independent => 1,
+			_ => 3,
+		}
+	}
+}
+
+/// Whether ADC3 can convert an external channel.
+///
+/// On the 64 pin packages ADC3 only reaches IN0-IN3 (PA0-PA3) and IN10-IN13
+/// (PC0-PC3), its IN4-IN9 and IN14-IN15 are on port F.
+pub fn adc3_channel(channel: u8) -> bool {
+	matches!(channel, 0..=3 | 10..=13)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+	// Interleaved mode samples exactly one channel
+	InterleavedChannels,
+	// The channel can not be converted by ADC3
+	NotOnAdc3(u8),
+	// A channel would be converted by two ADCs at the same time
+	SameChannel(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Allocation {
+	mode: Mode,
+	// Regular sequence of every ADC, all of length `len`
+	sequences: [ChannelMap; 3],
+	len: usize,
+	// Frame position of every input of the channel map
+	positions: [u8; MAX_CHANNELS],
+	inputs: usize,
+}
+
+impl Allocation {
+	pub fn new(map: &ChannelMap, mode: Mode) -> Result<Allocation, Error> {
+		let inputs = map.len();
+		let mut positions = [0u8; MAX_CHANNELS];
+
+		match mode {
+			Mode::Independent => {
+				for (i, position) in positions.iter_mut().enumerate().take(inputs) {
+					*position = i as u8;
+				}
+				Ok(Allocation { mode, sequences: [*map; 3], len: inputs, positions, inputs })
+			}
+
+			Mode::TripleInterleaved => {
+				if inputs != 1 {
+					return Err(Error::InterleavedChannels);
+				}
+				let channel = map.channels()[0];
+				if !adc3_channel(channel) {
+					return Err(Error::NotOnAdc3(channel));
+				}
+				Ok(Allocation { mode, sequences: [*map; 3], len: 1, positions, inputs })
+			}
+
+			Mode::TripleSimultaneous => Allocation::simultaneous(map),
+		}
+	}
+
+	fn simultaneous(map: &ChannelMap) -> Result<Allocation, Error> {
+		let channels = map.channels();
+		let inputs = channels.len();
+
+		// ADC3 takes its share from the channels it can reach, ADC1 and ADC2
+		// split the rest.
+		let share = inputs.div_ceil(3);
+		let on_adc3 = channels.iter().filter(|&&c| adc3_channel(c)).count().min(share);
+		let len = share.max((inputs - on_adc3).div_ceil(2));
+
+		let mut sequences = [[0u8; MAX_CHANNELS]; 3];
+		let mut lens = [0usize; 3];
+		let mut positions = [0u8; MAX_CHANNELS];
+
+		for (i, &channel) in channels.iter().enumerate() {
+			let adc = if adc3_channel(channel) && lens[2] < on_adc3 {
+				2
+			} else if lens[0] <= lens[1] {
+				0
+			} else {
+				1
+			};
+
+			let rank = lens[adc];
+			sequences[adc][rank] = channel;
+			positions[i] = (rank * 3 + adc) as u8;
+			lens[adc] += 1;
+		}
+
+		// Pad the shorter sequences, the padding results are never read
+		for adc in 0..3 {
+			for rank in lens[adc]..len {
+				sequences[adc][rank] = (0..16u8)
+					.find(|&c| {
+						(adc != 2 || adc3_channel(c))
+							&& (0..3).all(|other| other == adc || sequences[other][rank] != c)
+							&& !channels.contains(&c)
+					})
+					.or_else(|| sequences[adc][..rank].first().copied())
+					.unwrap_or(0);
+			}
+		}
+
+		// No channel may be sampled by two ADCs at once
+		let ranks = sequences[0][..len].iter().zip(&sequences[1][..len]).zip(&sequences[2][..len]);
+		for ((&a, &b), &c) in ranks {
+			if a == b || a == c {
+				return Err(Error::SameChannel(a));
+			}
+			if b == c {
+				return Err(Error::SameChannel(b));
+			}
+		}
+
+		let sequence = |adc: usize| map.with_sequence(&sequences[adc][..len]).unwrap();
+		Ok(Allocation {
+			mode: Mode::TripleSimultaneous,
+			sequences: [sequence(0), sequence(1), sequence(2)],
+			len,
+			positions,
+			inputs,
+		})
+	}
+
+	pub fn mode(&self) -> Mode {
+		self.mode
+	}
+
+	/// Regular sequence of ADC1 (0), ADC2 (1) or ADC3 (2)
+	pub fn sequence(&self, adc: usize) -> &ChannelMap {
+		&self.sequences[adc]
+	}
+
+	/// Half words per trigger
+	pub fn frame_width(&self) -> usize {
+		self.len * self.mode.adcs()
+	}
+
+	/// Number of inputs of the channel map
+	pub fn inputs(&self) -> usize {
+		self.inputs
+	}
+
+	/// Frame position of input `input` of the channel map
+	pub fn position(&self, input: usize) -> usize {
+		self.positions[input] as usize
+	}
+
+	/// The channel converted into each frame position, in frame order
+	pub fn frame_channels(&self) -> impl Iterator<Item = u8> + '_ {
+		let adcs = self.mode.adcs();
+		(0..self.frame_width()).map(move |p| self.sequences[p % adcs].channels()[p / adcs])
+	}
+
+	/// ADC clock cycles from trigger until the last result is available
+	pub fn sequence_cycles(&self) -> u32 {
+		self.sequences[..self.mode.adcs()]
+			.iter()
+			.map(|sequence| sequence.sequence_cycles())
+			.max()
+			.unwrap_or(0)
+	}
+
+	pub fn fits(&self, adc_clock: u32, sample_rate: u32) -> bool {
+		self.sequence_cycles() as u64 * sample_rate as u64 <= adc_clock as u64
+	}
+
+	/// Split a buffer of frames into one stream per input.
+	///
+	/// In interleaved mode the single input gets three samples per frame,
+	/// otherwise `out[i]` receives one sample per frame for input `i`.
+	pub fn deinterleave(&self, buffer: &[u16], out: &mut [&mut [u16]]) {
+		let width = self.frame_width();
+		assert!(buffer.len().is_multiple_of(width), "buffer must hold whole frames");
+
+		for (f, frame) in buffer.chunks_exact(width).enumerate() {
+			if self.mode == Mode::TripleInterleaved {
+				out[0][f * 3..f * 3 + 3].copy_from_slice(frame);
+				continue;
+			}
+			for (input, stream) in out.iter_mut().enumerate().take(self.inputs) {
+				stream[f] = frame[self.positions[input] as usize];
+			}
+		}
+	}
+}
+
+/// The two results packed in one CDR word, in the order they are stored.
+pub fn unpack_cdr(word: u32) -> [u16; 2] {
+	[word as u16, (word >> 16) as u16]
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::hw::SampleTime;
+
+	const ALL: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+	fn map(channels: &[u8]) -> ChannelMap {
+		ChannelMap::new(channels, SampleTime::Cycles56).unwrap()
+	}
+
+	#[test]
+	fn sixteen_inputs_over_three_adcs() {
+		let allocation = Allocation::new(&map(&ALL), Mode::TripleSimultaneous).unwrap();
+
+		assert_eq!(allocation.frame_width(), 18);
+		assert_eq!(allocation.sequence(2).channels(), &[0, 1, 2, 3, 10, 11]);
+		for adc in 0..3 {
+			assert_eq!(allocation.sequence(adc).len(), 6);
+		}
+
+		// Every input has its own frame position, ADC3 only on its own channels
+		let channels: Vec<u8> = allocation.frame_channels().collect();
+		let mut positions: Vec<usize> = (0..16).map(|input| allocation.position(input)).collect();
+		for (input, &channel) in ALL.iter().enumerate() {
+			assert_eq!(channels[positions[input]], channel);
+		}
+		positions.sort();
+		positions.dedup();
+		assert_eq!(positions.len(), 16);
+		for rank in 0..6 {
+			assert!(adc3_channel(channels[rank * 3 + 2]));
+		}
+
+		// 6 * (56 + 12) cycles instead of 16 * (56 + 12)
+		assert_eq!(allocation.sequence_cycles(), 408);
+		assert!(allocation.fits(22_500_000, 48_000));
+	}
+
+	#[test]
+	fn uneven_inputs_are_padded() {
+		let allocation = Allocation::new(&map(&[4, 5, 6, 7]), Mode::TripleSimultaneous).unwrap();
+
+		// No ADC3 channels, ADC1 and ADC2 take two each
+		assert_eq!(allocation.frame_width(), 6);
+		assert_eq!(allocation.sequence(0).channels(), &[4, 6]);
+		assert_eq!(allocation.sequence(1).channels(), &[5, 7]);
+		for &channel in allocation.sequence(2).channels() {
+			assert!(adc3_channel(channel));
+		}
+	}
+
+	#[test]
+	fn deinterleaves_packed_cdr_words() {
+		let allocation = Allocation::new(&map(&[0, 4, 5, 1, 6, 7]), Mode::TripleSimultaneous).unwrap();
+		let channels: Vec<u8> = allocation.frame_channels().collect();
+
+		// Two frames, each result is channel * 100 + frame
+		let mut results = Vec::new();
+		for frame in 0..2u16 {
+			for &channel in channels.iter() {
+				results.push(channel as u16 * 100 + frame);
+			}
+		}
+
+		// What DMA mode 2 reads from CDR, then stores little endian
+		let words: Vec<u32> = results
+			.chunks(2)
+			.map(|pair| (pair[1] as u32) << 16 | pair[0] as u32)
+			.collect();
+		let buffer: Vec<u16> = words.iter().flat_map(|&w| unpack_cdr(w).to_vec()).collect();
+		assert_eq!(buffer, results);
+
+		let mut streams = [[0u16; 2]; 6];
+		{
+			let mut out: Vec<&mut [u16]> = streams.iter_mut().map(|s| &mut s[..]).collect();
+			allocation.deinterleave(&buffer, &mut out);
+		}
+		assert_eq!(streams, [[0, 1], [400, 401], [500, 501], [100, 101], [600, 601], [700, 701]]);
+	}
+
+	#[test]
+	fn interleaved_single_channel() {
+		let allocation = Allocation::new(&map(&[10]), Mode::TripleInterleaved).unwrap();
+		assert_eq!(allocation.frame_width(), 3);
+
+		let mut stream = [0u16; 6];
+		allocation.deinterleave(&[1, 2, 3, 4, 5, 6], &mut [&mut stream[..]]);
+		assert_eq!(stream, [1, 2, 3, 4, 5, 6]);
+
+		assert_eq!(Allocation::new(&map(&[0, 1]), Mode::TripleInterleaved), Err(Error::InterleavedChannels));
+		assert_eq!(Allocation::new(&map(&[5]), Mode::TripleInterleaved), Err(Error::NotOnAdc3(5)));
+	}
+
+	#[test]
+	fn independent_is_identity() {
+		let allocation = Allocation::new(&map(&[3, 1]), Mode::Independent).unwrap();
+		assert_eq!(allocation.frame_width(), 2);
+		assert_eq!(allocation.frame_channels().collect::<Vec<_>>(), vec![3, 1]);
+	}
+
+	#[test]
+	fn same_channel_twice_rejected() {
+		assert_eq!(Allocation::new(&map(&[4, 4]), Mode::TripleSimultaneous), Err(Error::SameChannel(4)));
+	}
+}
@@ -0,0 +1,319 @@
+//! ADC sample to voltage, per input channel.
+//!
+//! A table holds up to `MAX_POINTS` measured points (code, millivolts),
+//! sorted by code. Codes are 16 bit, the offset binary form of the
+//! decimated `Sample` (`Sample::to_fine_code`), 16 times the 12 bit ADC
+//! code, so oversampled inputs are calibrated below one ADC code. Codes
+//! between two points are interpolated linearly, codes outside the table
+//! follow the first or last segment. Two points give a plain offset and
+//! gain correction.
+//!
+//! Each segment keeps its slope in microvolts per code as Q16.16, so a
+//! conversion is one multiply and shift, no division per sample.
+
+use crate::adc::MAX_CHANNELS;
+use crate::fixed::{div_round, Sample, Volts};
+
+use super::{average, Error, MAX_POINTS};
+
+/// 16 bit code of the highest 12 bit ADC code
+pub const FULL_SCALE: u16 = 0x0FFF << 4;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Point {
+	pub code: u16,
+	pub millivolts: i32,
+}
+
+impl Point {
+	pub fn new(code: u16, millivolts: i32) -> Point {
+		Point { code, millivolts }
+	}
+}
+
+/// Samples read while a known reference voltage was applied to the jack.
+#[derive(Clone, Copy, Debug)]
+pub struct Measurement<'a> {
+	pub millivolts: i32,
+	pub samples: &'a [Sample],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputCalibration {
+	points: [Point; MAX_POINTS],
+	len: usize,
+	// Microvolts per code, Q16.16, of the segment starting at each point
+	slopes: [i64; MAX_POINTS],
+}
+
+impl InputCalibration {
+	/// Table through `points`, in any order.
+	pub fn new(points: &[Point]) -> Result<InputCalibration, Error> {
+		if points.len() < 2 {
+			return Err(Error::TooFewPoints);
+		}
+		if points.len() > MAX_POINTS {
+			return Err(Error::TooManyPoints);
+		}
+
+		let mut sorted = [Point::default(); MAX_POINTS];
+		sorted[..points.len()].copy_from_slice(points);
+		let sorted_points = &mut sorted[..points.len()];
+		sorted_points.sort_unstable_by_key(|point| point.code);
+
+		for pair in sorted_points.windows(2) {
+			if pair[0].code == pair[1].code {
+				return Err(Error::DuplicateCode(pair[0].code));
+			}
+		}
+
+		// Inverting front ends fall, but the direction must never change
+		let rising = sorted_points[1].millivolts > sorted_points[0].millivolts;
+		for pair in sorted_points.windows(2) {
+			let step = pair[1].millivolts - pair[0].millivolts;
+			if step == 0 || (step > 0) != rising {
+				return Err(Error::NotMonotonic);
+			}
+		}
+
+		let mut calibration = InputCalibration { points: sorted, len: points.len(), slopes: [0; MAX_POINTS] };
+		for i in 0..calibration.len - 1 {
+			let (a, b) = (calibration.points[i], calibration.points[i + 1]);
+			let microvolts = (b.millivolts - a.millivolts) as i64 * 1000;
+			let codes = (b.code - a.code) as i64;
+			calibration.slopes[i] = div_round(microvolts << 16, codes);
+		}
+		// Past the last point the last segment continues
+		calibration.slopes[calibration.len - 1] = calibration.slopes[calibration.len - 2];
+
+		Ok(calibration)
+	}
+
+	/// Table through the averaged samples of each reference voltage.
+	pub fn from_measurements(measurements: &[Measurement]) -> Result<InputCalibration, Error> {
+		if measurements.len() > MAX_POINTS {
+			return Err(Error::TooManyPoints);
+		}
+
+		let mut points = [Point::default(); MAX_POINTS];
+		for (point, measurement) in points.iter_mut().zip(measurements) {
+			let code = average(measurement.samples).ok_or(Error::NoSamples)?;
+			*point = Point::new(code, measurement.millivolts);
+		}
+		InputCalibration::new(&points[..measurements.len()])
+	}
+
+	/// Offset and gain only, from two reference voltages.
+	pub fn two_point(low: Point, high: Point) -> Result<InputCalibration, Error> {
+		InputCalibration::new(&[low, high])
+	}
+
+	/// The front end as designed, ADC code 0 reading `at_zero` and code
+	/// 4095 reading `at_full_scale` (millivolts).
+	pub fn nominal(at_zero: i32, at_full_scale: i32) -> InputCalibration {
+		InputCalibration::new(&[Point::new(0, at_zero), Point::new(FULL_SCALE, at_full_scale)]).unwrap()
+	}
+
+	pub fn points(&self) -> &[Point] {
+		&self.points[..self.len]
+	}
+
+	// Index of the segment converting `code`
+	fn segment(&self, code: u16) -> usize {
+		let points = self.points();
+		// First segment also covers codes below the table
+		let mut i = 0;
+		while i + 2 < points.len() && code >= points[i + 1].code {
+			i += 1;
+		}
+		i
+	}
+
+	/// Input voltage in microvolts
+	pub fn microvolts(&self, sample: Sample) -> i64 {
+		let code = sample.to_fine_code();
+		let i = self.segment(code);
+		let start = self.points[i];
+		let offset = code as i64 - start.code as i64;
+		start.millivolts as i64 * 1000 + ((offset * self.slopes[i] + (1 << 15)) >> 16)
+	}
+
+	pub fn millivolts(&self, sample: Sample) -> i32 {
+		div_round(self.microvolts(sample), 1000) as i32
+	}
+
+	pub fn volts(&self, sample: Sample) -> Volts {
+		Volts::from_bits(div_round(self.microvolts(sample) << 16, 1_000_000) as i32)
+	}
+
+	/// The calibrated input voltage as a sample of the +-10 V range
+	pub fn sample(&self, sample: Sample) -> Sample {
+		Sample::from_volts(self.volts(sample))
+	}
+
+	/// Convert a stream of samples to millivolts
+	pub fn convert(&self, samples: &[Sample], millivolts: &mut [i32]) {
+		for (sample, out) in samples.iter().zip(millivolts.iter_mut()) {
+			*out = self.millivolts(*sample);
+		}
+	}
+}
+
+/// One calibration per ADC input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputCalibrations {
+	channels: [InputCalibration; MAX_CHANNELS],
+}
+
+impl InputCalibrations {
+	/// Every channel at the same (nominal) calibration
+	pub fn new(calibration: InputCalibration) -> InputCalibrations {
+		InputCalibrations { channels: [calibration; MAX_CHANNELS] }
+	}
+
+	pub fn get(&self, channel: usize) -> &InputCalibration {
+		&self.channels[channel]
+	}
+
+	pub fn set(&mut self, channel: usize, calibration: InputCalibration) {
+		self.channels[channel] = calibration;
+	}
+
+	pub fn millivolts(&self, channel: usize, sample: Sample) -> i32 {
+		self.channels[channel].millivolts(sample)
+	}
+
+	pub fn volts(&self, channel: usize, sample: Sample) -> Volts {
+		self.channels[channel].volts(sample)
+	}
+
+	pub fn sample(&self, channel: usize, sample: Sample) -> Sample {
+		self.channels[channel].sample(sample)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn fine(code: u16) -> Sample {
+		Sample::from_fine_code(code)
+	}
+
+	// Inverting +-10 V front end, 0 V a little off centre
+	fn measured() -> InputCalibration {
+		InputCalibration::two_point(Point::new(62240, -9000), Point::new(4000, 9000)).unwrap()
+	}
+
+	#[test]
+	fn two_points_correct_offset_and_gain() {
+		let calibration = measured();
+		assert_eq!(calibration.millivolts(fine(62240)), -9000);
+		assert_eq!(calibration.millivolts(fine(4000)), 9000);
+		// Half way is 0 V
+		assert_eq!(calibration.millivolts(fine(33120)), 0);
+		// Extrapolated towards the rails
+		assert_eq!(calibration.millivolts(fine(0)), 10236);
+		assert_eq!(calibration.millivolts(fine(FULL_SCALE)), -10014);
+	}
+
+	#[test]
+	fn fixed_point_volts() {
+		let calibration = InputCalibration::nominal(-10_000, 10_000);
+		assert_eq!(calibration.volts(fine(0)), Volts::from_int(-10));
+		assert_eq!(calibration.volts(fine(FULL_SCALE)), Volts::from_int(10));
+		// 1 V in from the bottom rail
+		let sample = fine(FULL_SCALE / 20 * 9);
+		let volts = calibration.volts(sample).to_bits() as f64 / 65536.0;
+		assert!((volts - calibration.millivolts(sample) as f64 / 1000.0).abs() < 0.001);
+
+		assert_eq!(calibration.sample(fine(0)), Sample::MIN);
+		assert_eq!(calibration.sample(fine(FULL_SCALE)), Sample::MAX);
+		assert_eq!(calibration.sample(sample), Sample::from_volts(calibration.volts(sample)));
+	}
+
+	#[test]
+	fn resolves_fractions_of_an_adc_code() {
+		// A 12 bit code is 16 codes of the table
+		let calibration = InputCalibration::nominal(-2048, 2047);
+		assert_eq!(calibration.microvolts(Sample::from_code(2048)), 0);
+		assert_eq!(calibration.microvolts(fine(0x8004)), 250);
+		assert_eq!(calibration.microvolts(fine(0x7FF8)), -500);
+	}
+
+	#[test]
+	fn piecewise_linear_tables() {
+		// Compressed near the top rail
+		let calibration = InputCalibration::new(&[
+			Point::new(64000, 9500),
+			Point::new(0, -10_000),
+			Point::new(32000, 0),
+			Point::new(57600, 8000),
+		]).unwrap();
+		assert_eq!(calibration.points()[0], Point::new(0, -10_000));
+
+		for point in calibration.points() {
+			assert_eq!(calibration.millivolts(fine(point.code)), point.millivolts);
+		}
+		assert_eq!(calibration.millivolts(fine(16000)), -5000);
+		assert_eq!(calibration.millivolts(fine(44800)), 4000);
+		assert_eq!(calibration.millivolts(fine(60800)), 8750);
+		// Last segment continues
+		assert_eq!(calibration.millivolts(fine(FULL_SCALE)), 9856);
+
+		let mut out = [0; 3];
+		calibration.convert(&[fine(0), fine(32000), fine(57600)], &mut out);
+		assert_eq!(out, [-10_000, 0, 8000]);
+	}
+
+	#[test]
+	fn monotonic_over_the_whole_range() {
+		let calibration = InputCalibration::new(&[
+			Point::new(1600, 9800),
+			Point::new(32768, 0),
+			Point::new(48000, -4900),
+			Point::new(63840, -9850),
+		]).unwrap();
+
+		let mut last = calibration.microvolts(fine(0));
+		for code in 1..=u16::MAX {
+			let microvolts = calibration.microvolts(fine(code));
+			assert!(microvolts < last);
+			last = microvolts;
+		}
+	}
+
+	#[test]
+	fn rejects_bad_tables() {
+		let p = Point::new;
+		assert_eq!(InputCalibration::new(&[p(0, 0)]), Err(Error::TooFewPoints));
+		assert_eq!(InputCalibration::new(&[p(0, 0); 9]), Err(Error::TooManyPoints));
+		assert_eq!(InputCalibration::new(&[p(10, 0), p(10, 5)]), Err(Error::DuplicateCode(10)));
+		assert_eq!(InputCalibration::new(&[p(0, 0), p(10, 5), p(20, 4)]), Err(Error::NotMonotonic));
+		assert_eq!(InputCalibration::new(&[p(0, 0), p(10, 0)]), Err(Error::NotMonotonic));
+	}
+
+	#[test]
+	fn tables_from_noisy_references() {
+		// Noisy 12 bit codes, averaged below one code
+		let samples = |codes: &[u16]| -> Vec<Sample> { codes.iter().map(|&code| Sample::from_code(code)).collect() };
+		let calibration = InputCalibration::from_measurements(&[
+			Measurement { millivolts: 5000, samples: &samples(&[1023, 1025, 1024, 1025]) },
+			Measurement { millivolts: -5000, samples: &samples(&[3071, 3073]) },
+			Measurement { millivolts: 0, samples: &samples(&[2047, 2049, 2048]) },
+		]).unwrap();
+		assert_eq!(calibration.points(), &[Point::new(16388, 5000), Point::new(32768, 0), Point::new(49152, -5000)]);
+
+		let empty = Measurement { millivolts: 0, samples: &[] };
+		assert_eq!(InputCalibration::from_measurements(&[empty, empty]), Err(Error::NoSamples));
+	}
+
+	#[test]
+	fn per_channel_tables() {
+		let mut calibrations = InputCalibrations::new(InputCalibration::nominal(10_000, -10_000));
+		calibrations.set(3, measured());
+		assert_eq!(calibrations.millivolts(0, fine(0)), 10_000);
+		assert_eq!(calibrations.millivolts(3, fine(4000)), 9000);
+		assert_eq!(calibrations.get(3), &measured());
+	}
+}
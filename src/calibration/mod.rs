@@ -0,0 +1,74 @@
+//! Calibration of the analog front ends.
+//!
+//! Every jack has its own offset and gain error, and the input buffers are
+//! not perfectly linear towards the rails. `input` maps the decimated ADC
+//! samples of one channel to a voltage through a piecewise linear table computed from
+//! reference voltages measured on that jack, `output` maps a voltage to the
+//! PWM code that produces it on an output jack. `session` walks the user
+//! through measuring both over the serial console.
+
+pub mod input;
+pub mod output;
+pub mod session;
+
+pub use self::input::{InputCalibration, InputCalibrations, Measurement, Point};
+pub use self::output::{OutputCalibration, OutputCalibrations};
+pub use self::session::{Action, Limits, Plan, Session};
+
+use crate::fixed::Sample;
+
+// Most points a table can hold
+pub const MAX_POINTS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+	// A table needs at least two points
+	TooFewPoints,
+	TooManyPoints,
+	// Two points measured at the same code
+	DuplicateCode(u16),
+	// Voltage does not rise (or fall) steadily with the code
+	NotMonotonic,
+	// A measurement without any samples
+	NoSamples,
+}
+
+/// Calibration of every jack, what is kept in persistent storage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Calibration {
+	pub inputs: InputCalibrations,
+	pub outputs: OutputCalibrations,
+}
+
+/// Persistent storage for the calibration.
+pub trait Store {
+	type Error;
+
+	/// Replace the stored calibration, which must survive a reset from here on
+	fn commit(&mut self, calibration: &Calibration) -> Result<(), Self::Error>;
+}
+
+/// Mean of input samples as a 16 bit code, rounded, for measuring one
+/// reference voltage.
+pub fn average(samples: &[Sample]) -> Option<u16> {
+	if samples.is_empty() {
+		return None;
+	}
+
+	let sum: u32 = samples.iter().map(|sample| sample.to_fine_code() as u32).sum();
+	let len = samples.len() as u32;
+	Some(((sum + len / 2) / len) as u16)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn averages_round_to_nearest() {
+		let samples = |codes: &[u16]| -> Vec<Sample> { codes.iter().map(|&code| Sample::from_fine_code(code)).collect() };
+		assert_eq!(average(&[]), None);
+		assert_eq!(average(&samples(&[1, 2])), Some(2));
+		assert_eq!(average(&samples(&[32767, 32768, 32768, 32767, 32767])), Some(32767));
+	}
+}
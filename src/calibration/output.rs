@@ -0,0 +1,243 @@
+//! Output voltage to PWM code, per output channel.
+//!
+//! The table holds points (fine code, measured microvolts) taken with a
+//! meter on the output jack, sorted by voltage. A voltage between two points
+//! is interpolated linearly, outside the table the first or last segment
+//! continues. Two points give offset and gain, more points take out the
+//! bow of the filter and output stage.
+//!
+//! Codes are 16 bit fine codes so the calibration is not limited to 12
+//! bits: one cent at 1V/oct is 833 uV, less than a 12 bit step over a 10 V
+//! span. How much of that a timer resolves depends on its period. TIM1 and
+//! TIM8 at 180 MHz and a 20 kHz carrier have 9000 steps, 1.1 mV over 10 V,
+//! which keeps every note within half a count (a little over half a cent)
+//! of its target. The 4500 steps of the APB1 timers do not, without dither.
+//! Take the calibration points at `Carrier::fine_code` of whole compare
+//! counts so the table does not pick up the rounding to counts.
+
+use crate::fixed::Volts;
+use crate::pwm::{MAX_FINE_CODE, MAX_OUTPUTS};
+
+use super::{Error, MAX_POINTS};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Point {
+	// 16 bit fine code written to the output
+	pub code: u16,
+	// Voltage measured on the jack
+	pub microvolts: i32,
+}
+
+impl Point {
+	pub fn new(code: u16, microvolts: i32) -> Point {
+		Point { code, microvolts }
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputCalibration {
+	points: [Point; MAX_POINTS],
+	len: usize,
+	// Fine codes per microvolt, Q32.32, of the segment starting at each point
+	slopes: [i64; MAX_POINTS],
+}
+
+impl OutputCalibration {
+	/// Table through `points`, in any order.
+	pub fn new(points: &[Point]) -> Result<OutputCalibration, Error> {
+		if points.len() < 2 {
+			return Err(Error::TooFewPoints);
+		}
+		if points.len() > MAX_POINTS {
+			return Err(Error::TooManyPoints);
+		}
+
+		let mut sorted = [Point::default(); MAX_POINTS];
+		sorted[..points.len()].copy_from_slice(points);
+		let sorted_points = &mut sorted[..points.len()];
+
+		sorted_points.sort_unstable_by_key(|point| point.code);
+		for pair in sorted_points.windows(2) {
+			if pair[0].code == pair[1].code {
+				return Err(Error::DuplicateCode(pair[0].code));
+			}
+		}
+
+		// Rising or falling with the code, never both
+		sorted_points.sort_unstable_by_key(|point| point.microvolts);
+		let rising = sorted_points[1].code > sorted_points[0].code;
+		for pair in sorted_points.windows(2) {
+			if pair[0].microvolts == pair[1].microvolts || (pair[1].code > pair[0].code) != rising {
+				return Err(Error::NotMonotonic);
+			}
+		}
+
+		let mut calibration = OutputCalibration { points: sorted, len: points.len(), slopes: [0; MAX_POINTS] };
+		for i in 0..calibration.len - 1 {
+			let (a, b) = (calibration.points[i], calibration.points[i + 1]);
+			let codes = b.code as i64 - a.code as i64;
+			let microvolts = b.microvolts as i64 - a.microvolts as i64;
+			calibration.slopes[i] = ((codes << 32) + microvolts / 2) / microvolts;
+		}
+		calibration.slopes[calibration.len - 1] = calibration.slopes[calibration.len - 2];
+
+		Ok(calibration)
+	}
+
+	/// The output stage as designed, code 0 giving `at_zero` and the top
+	/// code giving `at_full_scale` (microvolts).
+	pub fn nominal(at_zero: i32, at_full_scale: i32) -> OutputCalibration {
+		OutputCalibration::new(&[Point::new(0, at_zero), Point::new(MAX_FINE_CODE, at_full_scale)]).unwrap()
+	}
+
+	pub fn points(&self) -> &[Point] {
+		&self.points[..self.len]
+	}
+
+	/// Fine code producing `volts`, clipped to the code range
+	pub fn code(&self, volts: Volts) -> u16 {
+		let microvolts = volts.to_microvolts();
+		let points = self.points();
+		let mut i = 0;
+		while i + 2 < points.len() && microvolts >= points[i + 1].microvolts {
+			i += 1;
+		}
+
+		let start = points[i];
+		// A steep segment's slope nears 2^48, far outside it the product
+		// needs more than 64 bits
+		let offset = microvolts as i128 - start.microvolts as i128;
+		let code = start.code as i128 + ((offset * self.slopes[i] as i128 + (1 << 31)) >> 32);
+		code.clamp(0, MAX_FINE_CODE as i128) as u16
+	}
+}
+
+/// One calibration per output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputCalibrations {
+	channels: [OutputCalibration; MAX_OUTPUTS],
+}
+
+impl OutputCalibrations {
+	pub fn new(calibration: OutputCalibration) -> OutputCalibrations {
+		OutputCalibrations { channels: [calibration; MAX_OUTPUTS] }
+	}
+
+	pub fn get(&self, channel: usize) -> &OutputCalibration {
+		&self.channels[channel]
+	}
+
+	pub fn set(&mut self, channel: usize, calibration: OutputCalibration) {
+		self.channels[channel] = calibration;
+	}
+
+	pub fn code(&self, channel: usize, volts: Volts) -> u16 {
+		self.channels[channel].code(volts)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::pwm::Carrier;
+
+	// An output stage with offset, gain error and a bow in the middle.
+	// `fraction` is the PWM duty.
+	fn stage(fraction: f64, offset: f64, span: f64) -> f64 {
+		offset + fraction * span + 0.004 * 4.0 * fraction * (1.0 - fraction)
+	}
+
+	// What the jack reads for a fine code
+	fn jack(carrier: &Carrier, code: u16, offset: f64, span: f64) -> f64 {
+		stage(carrier.compare_fine(code) as f64 / carrier.steps() as f64, offset, span)
+	}
+
+	// Points spread evenly over the period, on whole compare counts
+	fn calibrate(carrier: &Carrier, offset: f64, span: f64) -> OutputCalibration {
+		let points: Vec<Point> = (0..MAX_POINTS as u32)
+			.map(|i| carrier.fine_code(i * carrier.steps() / (MAX_POINTS as u32 - 1)))
+			.map(|code| Point::new(code, (jack(carrier, code, offset, span) * 1e6).round() as i32))
+			.collect();
+		OutputCalibration::new(&points).unwrap()
+	}
+
+	// Worst note error in cents over `notes`, one volt per octave
+	fn worst_cents(carrier: &Carrier, calibration: &OutputCalibration, notes: core::ops::RangeInclusive<i32>, offset: f64, span: f64) -> f64 {
+		notes
+			.map(|note| {
+				let target = note as f64 / 12.0;
+				let code = calibration.code(Volts::from_microvolts((target * 1e6).round() as i32));
+				(jack(carrier, code, offset, span) - target).abs() * 1200.0
+			})
+			.fold(0.0, f64::max)
+	}
+
+	#[test]
+	fn notes_within_a_cent_bipolar() {
+		let carrier = Carrier::new(20_000, 180_000_000).unwrap();
+		// Nominal +-5 V, reading -5.07 V to 5.13 V
+		let (offset, span) = (-5.07, 10.2);
+
+		let calibration = calibrate(&carrier, offset, span);
+		assert!(worst_cents(&carrier, &calibration, -60..=60, offset, span) < 1.0);
+
+		// Uncalibrated the same notes are off by far more
+		let nominal = OutputCalibration::nominal(-5_000_000, 5_000_000);
+		assert!(worst_cents(&carrier, &nominal, -60..=60, offset, span) > 50.0);
+	}
+
+	#[test]
+	fn notes_within_a_cent_unipolar() {
+		let carrier = Carrier::new(20_000, 180_000_000).unwrap();
+		let (offset, span) = (-0.012, 10.05);
+
+		let calibration = calibrate(&carrier, offset, span);
+		assert!(worst_cents(&carrier, &calibration, 0..=119, offset, span) < 1.0);
+	}
+
+	#[test]
+	fn two_points_are_offset_and_gain() {
+		// Inverting stage
+		let calibration = OutputCalibration::new(&[Point::new(65535, -5_000_000), Point::new(0, 5_000_000)]).unwrap();
+		assert_eq!(calibration.code(Volts::from_int(5)), 0);
+		assert_eq!(calibration.code(Volts::from_int(-5)), 65535);
+		assert_eq!(calibration.code(Volts::ZERO), 32768);
+		assert_eq!(calibration.code(Volts::from_millivolts(2500)), 16384);
+
+		// Out of range voltages clip
+		assert_eq!(calibration.code(Volts::from_int(6)), 0);
+		assert_eq!(calibration.code(Volts::from_int(-6)), 65535);
+	}
+
+	#[test]
+	fn clips_far_outside_a_steep_table() {
+		// Every code within a microvolt
+		let calibration = OutputCalibration::new(&[Point::new(0, 0), Point::new(65535, 1)]).unwrap();
+		assert_eq!(calibration.code(Volts::MAX), 65535);
+		assert_eq!(calibration.code(Volts::MIN), 0);
+		assert_eq!(calibration.code(Volts::from_int(-2000)), 0);
+	}
+
+	#[test]
+	fn table_points_are_exact() {
+		let points = [
+			Point::new(0, -5_100_000),
+			Point::new(20_000, -2_000_000),
+			Point::new(40_000, 1_150_000),
+			Point::new(65_535, 5_080_000),
+		];
+		let calibration = OutputCalibration::new(&points).unwrap();
+		for point in points.iter() {
+			assert_eq!(calibration.code(Volts::from_microvolts(point.microvolts)), point.code);
+		}
+	}
+
+	#[test]
+	fn rejects_bad_tables() {
+		let p = Point::new;
+		assert_eq!(OutputCalibration::new(&[p(0, 0)]), Err(Error::TooFewPoints));
+		assert_eq!(OutputCalibration::new(&[p(5, 0), p(5, 10)]), Err(Error::DuplicateCode(5)));
+		assert_eq!(OutputCalibration::new(&[p(0, 0), p(10, 10), p(20, 5)]), Err(Error::NotMonotonic));
+		assert_eq!(OutputCalibration::new(&[p(0, 7), p(10, 7)]), Err(Error::NotMonotonic));
+	}
+}
@@ -0,0 +1,654 @@
+//! Interactive calibration of every jack, one console line at a time.
+//!
+//! The session asks for each reference voltage in turn on each input and
+//! averages the samples read while it is applied. Each output is then set to
+//! a series of codes and the user types the voltage a meter reads on the
+//! jack. A finished table is checked against the nominal front end before
+//! it replaces the channel's calibration, a bad one is offered for retry.
+//! Nothing is written to the store until the user confirms at the end.
+//!
+//! The session does not touch any hardware. What it needs from the
+//! application comes back as an `Action`: sample an input, set an output.
+//!
+//! Commands: return measures, a number is a voltage in millivolts, `s`
+//! skips the channel (it keeps its previous calibration), `r` retries a
+//! rejected channel, `q` quits without saving, `y`/`n` answer the final
+//! question.
+
+use core::fmt;
+
+use crate::fixed::Sample;
+use crate::pwm::MAX_FINE_CODE;
+
+use super::input::{self, InputCalibration};
+use super::output::{self, OutputCalibration};
+use super::{Calibration, Error, Store, MAX_POINTS};
+
+/// How far a measured point may be from the nominal front end, microvolts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+	pub input: i32,
+	pub output: i32,
+}
+
+/// What to calibrate and how.
+#[derive(Clone, Copy, Debug)]
+pub struct Plan<'a> {
+	pub inputs: &'a [usize],
+	// Reference voltages applied to every input, millivolts
+	pub references: &'a [i32],
+	// Samples averaged for each reference
+	pub samples: usize,
+	pub outputs: &'a [usize],
+	// Fine codes measured on every output
+	pub codes: &'a [u16],
+	pub input_nominal: InputCalibration,
+	// Microvolts at fine code 0 and at MAX_FINE_CODE
+	pub output_nominal: (i32, i32),
+	pub limits: Limits,
+}
+
+/// What the application must do for the session to go on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+	None,
+	// Pass the samples read on this input to `samples`
+	Sample { input: usize },
+	// Hold this output at the fine code
+	SetOutput { output: usize, code: u16 },
+	// The calibration was saved
+	Done,
+	// Quit, nothing was saved
+	Aborted,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Jack {
+	Input,
+	Output,
+}
+
+/// Why a channel's table was not accepted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reason {
+	Table(Error),
+	// Point (in the order taken) too far from nominal, by `error` microvolts
+	OutOfRange { point: usize, error: i32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+	// `index` into the plan's inputs, `point` into its references
+	Input { index: usize, point: usize, sampling: bool },
+	// `index` into the plan's outputs, `point` into its codes
+	Output { index: usize, point: usize },
+	Rejected { jack: Jack, index: usize, reason: Reason },
+	// All channels done, save?
+	Confirm,
+	StoreFailed,
+	Saved,
+	Aborted,
+}
+
+pub struct Session<'a> {
+	plan: Plan<'a>,
+	calibration: Calibration,
+	step: Step,
+	// Last line was not understood
+	unknown: bool,
+	inputs: [input::Point; MAX_POINTS],
+	outputs: [output::Point; MAX_POINTS],
+	// Sum of the 16 bit codes so far for the current reference
+	sum: u64,
+	count: usize,
+}
+
+impl<'a> Session<'a> {
+	/// Start calibrating, channels skipped keep their entry in `calibration`.
+	pub fn new(plan: Plan<'a>, calibration: Calibration) -> Session<'a> {
+		assert!((2..=MAX_POINTS).contains(&plan.references.len()), "2 to MAX_POINTS references");
+		assert!((2..=MAX_POINTS).contains(&plan.codes.len()), "2 to MAX_POINTS output codes");
+		assert!(plan.samples > 0);
+
+		let mut session = Session {
+			plan,
+			calibration,
+			step: Step::Confirm,
+			unknown: false,
+			inputs: [input::Point::default(); MAX_POINTS],
+			outputs: [output::Point::default(); MAX_POINTS],
+			sum: 0,
+			count: 0,
+		};
+		session.start_input(0);
+		session
+	}
+
+	pub fn step(&self) -> Step {
+		self.step
+	}
+
+	/// Calibration so far, with every accepted channel
+	pub fn calibration(&self) -> &Calibration {
+		&self.calibration
+	}
+
+	/// What the current step needs from the application
+	pub fn action(&self) -> Action {
+		match self.step {
+			Step::Input { index, sampling: true, .. } => Action::Sample { input: self.plan.inputs[index] },
+			Step::Output { index, point } => Action::SetOutput {
+				output: self.plan.outputs[index],
+				code: self.plan.codes[point],
+			},
+			Step::Saved => Action::Done,
+			Step::Aborted => Action::Aborted,
+			_ => Action::None,
+		}
+	}
+
+	/// Text asking for the next line
+	pub fn prompt(&self) -> Prompt<'_> {
+		Prompt(self)
+	}
+
+	/// Handle one line typed by the user.
+	pub fn line<S: Store>(&mut self, line: &str, store: &mut S) -> Action {
+		let line = line.trim();
+		self.unknown = false;
+
+		match (self.step, line) {
+			(Step::Saved, _) | (Step::Aborted, _) => {}
+			(_, "q") => self.step = Step::Aborted,
+
+			(Step::Input { index, point, sampling: false }, "") => {
+				self.sum = 0;
+				self.count = 0;
+				self.step = Step::Input { index, point, sampling: true };
+			}
+			(Step::Input { index, sampling: false, .. }, "s") => self.start_input(index + 1),
+			(Step::Output { index, .. }, "s") => self.start_output(index + 1),
+			(Step::Output { index, point }, _) => match parse_millivolts(line) {
+				Some(microvolts) => {
+					self.outputs[point] = output::Point::new(self.plan.codes[point], microvolts);
+					if point + 1 < self.plan.codes.len() {
+						self.step = Step::Output { index, point: point + 1 };
+					} else {
+						self.finish_output(index);
+					}
+				}
+				None => self.unknown = true,
+			},
+
+			(Step::Rejected { jack: Jack::Input, index, .. }, "r") => self.start_input(index),
+			(Step::Rejected { jack: Jack::Input, index, .. }, "s") => self.start_input(index + 1),
+			(Step::Rejected { jack: Jack::Output, index, .. }, "r") => self.start_output(index),
+			(Step::Rejected { jack: Jack::Output, index, .. }, "s") => self.start_output(index + 1),
+
+			(Step::Confirm, "y") | (Step::StoreFailed, "r") => {
+				self.step = match store.commit(&self.calibration) {
+					Ok(()) => Step::Saved,
+					Err(_) => Step::StoreFailed,
+				};
+			}
+			(Step::Confirm, "n") => self.step = Step::Aborted,
+
+			_ => self.unknown = true,
+		}
+
+		self.action()
+	}
+
+	/// Unfiltered samples read on the input asked for by `Action::Sample`.
+	///
+	/// They average to a point of the table in 16 bit codes.
+	pub fn samples(&mut self, samples: &[Sample]) -> Action {
+		if let Step::Input { index, point, sampling: true } = self.step {
+			let wanted = (self.plan.samples - self.count).min(samples.len());
+			self.sum += samples[..wanted].iter().map(|sample| sample.to_fine_code() as u64).sum::<u64>();
+			self.count += wanted;
+
+			if self.count == self.plan.samples {
+				let count = self.count as u64;
+				let code = ((self.sum + count / 2) / count) as u16;
+				self.inputs[point] = input::Point::new(code, self.plan.references[point]);
+
+				if point + 1 < self.plan.references.len() {
+					self.step = Step::Input { index, point: point + 1, sampling: false };
+				} else {
+					self.finish_input(index);
+				}
+			}
+		}
+
+		self.action()
+	}
+
+	fn start_input(&mut self, index: usize) {
+		if index < self.plan.inputs.len() {
+			self.step = Step::Input { index, point: 0, sampling: false };
+		} else {
+			self.start_output(0);
+		}
+	}
+
+	fn start_output(&mut self, index: usize) {
+		self.step = if index < self.plan.outputs.len() {
+			Step::Output { index, point: 0 }
+		} else {
+			Step::Confirm
+		};
+	}
+
+	fn finish_input(&mut self, index: usize) {
+		let points = &self.inputs[..self.plan.references.len()];
+		let nominal = &self.plan.input_nominal;
+		let error = |p: &input::Point| p.millivolts as i64 * 1000 - nominal.microvolts(Sample::from_fine_code(p.code));
+		let checked = check(points.iter().map(error), self.plan.limits.input)
+			.and_then(|_| InputCalibration::new(points).map_err(Reason::Table));
+
+		match checked {
+			Ok(calibration) => {
+				self.calibration.inputs.set(self.plan.inputs[index], calibration);
+				self.start_input(index + 1);
+			}
+			Err(reason) => self.step = Step::Rejected { jack: Jack::Input, index, reason },
+		}
+	}
+
+	fn finish_output(&mut self, index: usize) {
+		let points = &self.outputs[..self.plan.codes.len()];
+		let (at_zero, at_full) = self.plan.output_nominal;
+		let nominal = |code: u16| at_zero as i64 + (at_full as i64 - at_zero as i64) * code as i64 / MAX_FINE_CODE as i64;
+		let checked = check(points.iter().map(|p| p.microvolts as i64 - nominal(p.code)), self.plan.limits.output)
+			.and_then(|_| OutputCalibration::new(points).map_err(Reason::Table));
+
+		match checked {
+			Ok(calibration) => {
+				self.calibration.outputs.set(self.plan.outputs[index], calibration);
+				self.start_output(index + 1);
+			}
+			Err(reason) => self.step = Step::Rejected { jack: Jack::Output, index, reason },
+		}
+	}
+}
+
+// First deviation (microvolts) beyond `limit`
+fn check(deviations: impl Iterator<Item = i64>, limit: i32) -> Result<(), Reason> {
+	for (point, error) in deviations.enumerate() {
+		if error.abs() > limit as i64 {
+			let error = error.clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+			return Err(Reason::OutOfRange { point, error });
+		}
+	}
+	Ok(())
+}
+
+/// Parse a voltage typed in millivolts with up to three decimals, to
+/// microvolts.
+pub fn parse_millivolts(text: &str) -> Option<i32> {
+	let (negative, text) = match text.strip_prefix('-') {
+		Some(rest) => (true, rest),
+		None => (false, text.strip_prefix('+').unwrap_or(text)),
+	};
+	let (whole, fraction) = match text.find('.') {
+		Some(dot) => (&text[..dot], &text[dot + 1..]),
+		None => (text, ""),
+	};
+	if (whole.is_empty() && fraction.is_empty()) || fraction.len() > 3 {
+		return None;
+	}
+
+	let mut microvolts: i32 = 0;
+	for (i, byte) in whole.bytes().chain(fraction.bytes()).chain(b"000".iter().copied()).enumerate() {
+		if i == whole.len() + 3 {
+			break;
+		}
+		if !byte.is_ascii_digit() {
+			return None;
+		}
+		microvolts = microvolts.checked_mul(10)?.checked_add((byte - b'0') as i32)?;
+	}
+	Some(if negative { -microvolts } else { microvolts })
+}
+
+/// Text for the current step, see `Session::prompt`.
+pub struct Prompt<'a>(&'a Session<'a>);
+
+impl<'a> fmt::Display for Prompt<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let session = self.0;
+		let plan = &session.plan;
+		if session.unknown {
+			write!(f, "? ")?;
+		}
+
+		match session.step {
+			Step::Input { index, point, sampling: false } => write!(
+				f,
+				"Input {}: apply {} mV, return to measure, s to skip, q to quit",
+				plan.inputs[index], plan.references[point],
+			),
+			Step::Input { index, .. } => write!(f, "Input {}: measuring", plan.inputs[index]),
+			Step::Output { index, point } => write!(
+				f,
+				"Output {} at code {}: type the measured mV, s to skip, q to quit",
+				plan.outputs[index], plan.codes[point],
+			),
+			Step::Rejected { jack, index, reason } => {
+				match jack {
+					Jack::Input => write!(f, "Input {} rejected: ", plan.inputs[index])?,
+					Jack::Output => write!(f, "Output {} rejected: ", plan.outputs[index])?,
+				}
+				match reason {
+					Reason::Table(error) => write!(f, "{:?}", error)?,
+					Reason::OutOfRange { point, error } => write!(f, "point {} is {} uV off nominal", point + 1, error)?,
+				}
+				write!(f, ", r to retry, s to skip, q to quit")
+			}
+			Step::Confirm => write!(f, "Save calibration? y/n"),
+			Step::StoreFailed => write!(f, "Saving failed, r to retry, q to quit"),
+			Step::Saved => write!(f, "Calibration saved"),
+			Step::Aborted => write!(f, "Calibration aborted, nothing saved"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use core::fmt::Write;
+
+	use super::*;
+	use crate::calibration::{InputCalibrations, OutputCalibrations};
+	use crate::console::Console;
+	use crate::fixed::Volts;
+	use crate::hw::mock::Board;
+
+	const INPUTS: [usize; 3] = [0, 5, 15];
+	const REFERENCES: [i32; 3] = [-8000, 0, 8000];
+	const OUTPUTS: [usize; 2] = [1, 9];
+	const CODES: [u16; 5] = [3000, 17000, 32768, 48000, 62000];
+
+	fn plan() -> Plan<'static> {
+		Plan {
+			inputs: &INPUTS,
+			references: &REFERENCES,
+			samples: 16,
+			outputs: &OUTPUTS,
+			codes: &CODES,
+			// Inverting +-10 V inputs, +-5 V outputs
+			input_nominal: InputCalibration::nominal(10_000, -10_000),
+			output_nominal: (-5_000_000, 5_000_000),
+			limits: Limits { input: 300_000, output: 200_000 },
+		}
+	}
+
+	fn nominal() -> Calibration {
+		Calibration {
+			inputs: InputCalibrations::new(plan().input_nominal),
+			outputs: OutputCalibrations::new(OutputCalibration::nominal(-5_000_000, 5_000_000)),
+		}
+	}
+
+	// Front end of each input, a little off in offset and gain
+	fn input_code(input: usize, millivolts: i32, noise: i32) -> u16 {
+		let offset = input as f64 * 7.0 - 40.0;
+		let gain = 1.0 + input as f64 * 0.001;
+		let code = 2047.5 - (millivolts as f64 * gain + offset) * 4095.0 / 20_000.0;
+		(code.round() as i32 + noise).clamp(0, 4095) as u16
+	}
+
+	// Output stage, offset, gain and a slight bow, microvolts
+	fn output_microvolts(output: usize, code: u16) -> i32 {
+		let x = code as f64 / MAX_FINE_CODE as f64;
+		let volts = -5.03 + output as f64 * 0.004 + 10.08 * x - 0.02 * x * (1.0 - x);
+		(volts * 1e6).round() as i32
+	}
+
+	#[derive(Default)]
+	struct MemoryStore {
+		saved: Option<Calibration>,
+		// Commits to fail before one succeeds
+		failures: usize,
+	}
+
+	impl Store for MemoryStore {
+		type Error = ();
+
+		fn commit(&mut self, calibration: &Calibration) -> Result<(), ()> {
+			if self.failures > 0 {
+				self.failures -= 1;
+				return Err(());
+			}
+			self.saved = Some(*calibration);
+			Ok(())
+		}
+	}
+
+	// Follows the prompts: applies the references, reads the meter, says yes
+	struct FakeUser {
+		board: Board,
+		console: Console<crate::hw::mock::MockSerial>,
+		// Reference actually applied to an input, when not the one asked for
+		wrong_reference: Option<(usize, i32)>,
+		output: Option<(usize, u16)>,
+		transcript: String,
+	}
+
+	impl FakeUser {
+		fn new() -> FakeUser {
+			let board = Board::new(0);
+			FakeUser {
+				console: Console::new(board.serial()),
+				board,
+				wrong_reference: None,
+				output: None,
+				transcript: String::new(),
+			}
+		}
+
+		fn type_line(&mut self, session: &mut Session, store: &mut MemoryStore, line: &str) -> Action {
+			self.board.type_text(line);
+			self.board.type_text("\r\n");
+			let line = String::from(self.console.poll().unwrap());
+			let action = session.line(&line, store);
+			self.follow(session, action)
+		}
+
+		// Carry out what the session asks of the application
+		fn follow(&mut self, session: &mut Session, mut action: Action) -> Action {
+			let mut tick = 0;
+			while let Action::Sample { input } = action {
+				let point = match session.step() {
+					Step::Input { point, .. } => point,
+					step => unreachable!("{:?}", step),
+				};
+				let mut millivolts = REFERENCES[point];
+				if let Some((wrong, applied)) = self.wrong_reference {
+					if wrong == input {
+						millivolts = applied;
+					}
+				}
+				// Two codes of noise per frame, four frames per buffer
+				let samples: Vec<Sample> = (0..4)
+					.map(|i| Sample::from_code(input_code(input, millivolts, [-1, 1, 0, 0][(tick + i) % 4])))
+					.collect();
+				tick += 1;
+				action = session.samples(&samples);
+			}
+			if let Action::SetOutput { output, code } = action {
+				self.output = Some((output, code));
+			}
+
+			writeln!(self.console, "{}", session.prompt()).unwrap();
+			self.transcript += &self.board.take_serial_output();
+			action
+		}
+
+		// Meter on the output being set
+		fn meter(&self) -> String {
+			let (output, code) = self.output.unwrap();
+			let microvolts = output_microvolts(output, code);
+			let sign = if microvolts < 0 { "-" } else { "" };
+			format!("{}{}.{:03}", sign, microvolts.abs() / 1000, microvolts.abs() % 1000)
+		}
+
+		fn run(&mut self, session: &mut Session, store: &mut MemoryStore) -> Action {
+			let mut action = session.action();
+			for _ in 0..100 {
+				action = match session.step() {
+					Step::Input { .. } => self.type_line(session, store, ""),
+					Step::Output { .. } => {
+						let reading = self.meter();
+						self.type_line(session, store, &reading)
+					}
+					Step::Confirm | Step::StoreFailed | Step::Rejected { .. } => return action,
+					Step::Saved | Step::Aborted => return action,
+				};
+			}
+			panic!("session did not finish");
+		}
+	}
+
+	#[test]
+	fn walks_through_every_jack() {
+		let mut user = FakeUser::new();
+		let mut store = MemoryStore::default();
+		let mut session = Session::new(plan(), nominal());
+
+		user.run(&mut session, &mut store);
+		assert_eq!(session.step(), Step::Confirm);
+		assert!(store.saved.is_none());
+		assert_eq!(user.type_line(&mut session, &mut store, "y"), Action::Done);
+
+		let saved = store.saved.unwrap();
+		assert!(user.transcript.contains("Input 5: apply -8000 mV"));
+		assert!(user.transcript.contains("Output 9 at code 62000"));
+		assert!(user.transcript.ends_with("Calibration saved\r\n"));
+
+		// Calibrated inputs read within a code of the reference
+		for &input in &INPUTS {
+			for millivolts in (-9000..=9000).step_by(500) {
+				let read = saved.inputs.millivolts(input, Sample::from_code(input_code(input, millivolts, 0)));
+				assert!((read - millivolts).abs() <= 5, "input {} {} mV read {}", input, millivolts, read);
+			}
+		}
+		// Untouched channels stay nominal
+		assert_eq!(saved.inputs.get(1), nominal().inputs.get(1));
+		assert_eq!(saved.outputs.get(0), nominal().outputs.get(0));
+
+		// Calibrated outputs land within a millivolt, left to the bow between points
+		for &output in &OUTPUTS {
+			for millivolts in (-4500..=4500).step_by(250) {
+				let code = saved.outputs.code(output, Volts::from_millivolts(millivolts));
+				let error = output_microvolts(output, code) - millivolts * 1000;
+				assert!(error.abs() < 1000, "output {} {} mV off by {} uV", output, millivolts, error);
+			}
+		}
+	}
+
+	#[test]
+	fn bad_tables_are_retried_or_skipped() {
+		let mut user = FakeUser::new();
+		let mut store = MemoryStore::default();
+		let mut session = Session::new(plan(), nominal());
+
+		// Patched into the wrong jack, input 5 sees 0 V throughout
+		user.wrong_reference = Some((5, 0));
+		user.run(&mut session, &mut store);
+		assert_eq!(
+			session.step(),
+			Step::Rejected { jack: Jack::Input, index: 1, reason: Reason::OutOfRange { point: 0, error: -7_992_674 } },
+		);
+		assert!(user.transcript.ends_with("Input 5 rejected: point 1 is -7992674 uV off nominal, r to retry, s to skip, q to quit\r\n"));
+
+		// Fixed and retried
+		user.wrong_reference = None;
+		user.type_line(&mut session, &mut store, "r");
+		assert_eq!(session.step(), Step::Input { index: 1, point: 0, sampling: false });
+		user.run(&mut session, &mut store);
+		assert_eq!(session.step(), Step::Confirm);
+		assert_ne!(session.calibration().inputs.get(5), nominal().inputs.get(5));
+
+		// Typing error on an output, then skipping it
+		let mut session = Session::new(plan(), nominal());
+		for _ in 0..3 {
+			user.type_line(&mut session, &mut store, "s");
+		}
+		let action = user.type_line(&mut session, &mut store, "4.2V");
+		assert_eq!(action, Action::SetOutput { output: 1, code: 3000 });
+		assert!(user.transcript.ends_with("? Output 1 at code 3000: type the measured mV, s to skip, q to quit\r\n"));
+		for millivolts in ["100", "0", "-100", "200", "300"] {
+			user.type_line(&mut session, &mut store, millivolts);
+		}
+		assert_eq!(
+			session.step(),
+			Step::Rejected { jack: Jack::Output, index: 0, reason: Reason::OutOfRange { point: 0, error: 4_642_230 } },
+		);
+		user.type_line(&mut session, &mut store, "s");
+		assert_eq!(session.step(), Step::Output { index: 1, point: 0 });
+	}
+
+	#[test]
+	fn non_monotonic_outputs_are_rejected() {
+		let mut store = MemoryStore::default();
+		let mut session = Session::new(Plan { limits: Limits { input: 0, output: 10_000_000 }, ..plan() }, nominal());
+
+		for _ in 0..3 {
+			session.line("s", &mut store);
+		}
+		for millivolts in ["-4000", "-2000", "-3000", "2000", "4000"] {
+			session.line(millivolts, &mut store);
+		}
+		assert_eq!(
+			session.step(),
+			Step::Rejected { jack: Jack::Output, index: 0, reason: Reason::Table(Error::NotMonotonic) },
+		);
+	}
+
+	#[test]
+	fn quitting_saves_nothing() {
+		let mut store = MemoryStore::default();
+		let mut session = Session::new(plan(), nominal());
+		session.line("", &mut store);
+		assert_eq!(session.line("q", &mut store), Action::Aborted);
+		assert_eq!(session.line("y", &mut store), Action::Aborted);
+		assert!(store.saved.is_none());
+
+		// Declined at the end
+		let mut session = Session::new(plan(), nominal());
+		for _ in 0..5 {
+			session.line("s", &mut store);
+		}
+		assert_eq!(session.step(), Step::Confirm);
+		assert_eq!(session.line("n", &mut store), Action::Aborted);
+		assert!(store.saved.is_none());
+	}
+
+	#[test]
+	fn failed_commits_are_retried() {
+		let mut store = MemoryStore { failures: 1, ..MemoryStore::default() };
+		let mut session = Session::new(plan(), nominal());
+		for _ in 0..5 {
+			session.line("s", &mut store);
+		}
+		assert_eq!(session.line("y", &mut store), Action::None);
+		assert_eq!(session.step(), Step::StoreFailed);
+		assert_eq!(session.line("r", &mut store), Action::Done);
+		assert_eq!(store.saved, Some(nominal()));
+	}
+
+	#[test]
+	fn millivolts_are_parsed() {
+		assert_eq!(parse_millivolts("1234"), Some(1_234_000));
+		assert_eq!(parse_millivolts("-4998.7"), Some(-4_998_700));
+		assert_eq!(parse_millivolts("+.5"), Some(500));
+		assert_eq!(parse_millivolts("-0.001"), Some(-1));
+		assert_eq!(parse_millivolts("12."), Some(12_000));
+		assert_eq!(parse_millivolts("1.2345"), None);
+		assert_eq!(parse_millivolts("."), None);
+		assert_eq!(parse_millivolts("4.2V"), None);
+		assert_eq!(parse_millivolts("99999999"), None);
+		assert_eq!(parse_millivolts(""), None);
+	}
+}
@@ -0,0 +1,352 @@
+//! Tempo and phase of an external clock.
+//!
+//! The follower takes the rising edges of one gate input and keeps a
+//! position in clock pulses, Q32.32: the whole part counts pulses, the
+//! fraction is the phase within the current pulse. Clock dividers and
+//! multipliers lock to it, dividing the whole part or multiplying the
+//! fraction.
+//!
+//! The tempo is the interval between edges, smoothed over a few edges.
+//! An interval more than 25% off the estimate is an outlier and does not
+//! move it: a short one is a glitch and the edge is ignored, a long one is
+//! taken as pulses missing in between. Two outliers in a row that agree
+//! with each other are a new tempo, which is then taken at once.
+//!
+//! The position runs on at the estimated tempo between edges. Every edge
+//! pulls it halfway towards the pulse that edge stands for, like the phase
+//! detector of a PLL, so a jittery clock gives a smooth position. The
+//! position never goes backwards and never runs past the next expected
+//! pulse: a clock that slows down or stops leaves it waiting at the end of
+//! the pulse. Without edges for four periods the clock counts as stopped,
+//! the next edge restarts it in sync, at the previous tempo.
+
+use crate::gate::{Edge, Event};
+
+/// One clock pulse in `position` units
+pub const PULSE: u64 = 1 << 32;
+
+// Share of the phase error corrected per edge, as a shift
+const PHASE_GAIN: u32 = 1;
+// Share of the interval error taken into the period per edge, as a shift
+const PERIOD_GAIN: u32 = 2;
+// Periods without an edge before the clock counts as stopped
+const STOP_PERIODS: u64 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+	// No edges yet, or none for a while
+	Stopped,
+	// One edge seen, the tempo comes with the next
+	Starting,
+	Running,
+}
+
+/// Follows the clock on one input.
+#[derive(Clone, Copy, Debug)]
+pub struct ClockFollower {
+	input: usize,
+	sample_rate: u32,
+	ppqn: u32,
+	// Ticks per pulse, Q16, 0 until known
+	period: u64,
+	// Position units per tick
+	increment: u64,
+	// Tick of the last accepted edge and of the last edge at all
+	last: Option<u64>,
+	last_raw: u64,
+	// Interval of the last outlier, Q16
+	outlier: Option<u64>,
+	// Pulse the last accepted edge stood for
+	pulse: u64,
+	// Position at tick `anchor`
+	anchor: u64,
+	anchor_position: u64,
+}
+
+impl ClockFollower {
+	/// Clock on `input`, `ppqn` pulses per quarter note
+	pub fn new(input: usize, sample_rate: u32, ppqn: u32) -> ClockFollower {
+		ClockFollower {
+			input,
+			sample_rate,
+			ppqn: ppqn.max(1),
+			period: 0,
+			increment: 0,
+			last: None,
+			last_raw: 0,
+			outlier: None,
+			pulse: 0,
+			anchor: 0,
+			anchor_position: 0,
+		}
+	}
+
+	pub fn input(&self) -> usize {
+		self.input
+	}
+
+	/// Rising edges on the clock input, anything else is ignored
+	pub fn on_event(&mut self, event: &Event) {
+		if event.input == self.input && event.edge == Edge::Rising {
+			self.on_edge(event.at);
+		}
+	}
+
+	/// Rising edge at tick `at`
+	pub fn on_edge(&mut self, at: u64) {
+		let raw = (at - self.last_raw.min(at)) << 16;
+		self.last_raw = at;
+
+		let last = match self.last {
+			Some(last) if self.period == 0 || at - last <= STOP_PERIODS * self.period_ticks() => last,
+			// First edge or back from a stop, in sync right away
+			_ => return self.restart(at),
+		};
+		let interval = (at - last) << 16;
+
+		if self.period == 0 {
+			// The tempo comes with the second edge, which is pulse 1 exactly
+			self.set_period(interval);
+			self.pulse += 1;
+			self.last = Some(at);
+			self.anchor = at;
+			self.anchor_position = self.pulse * PULSE;
+			return;
+		}
+
+		let pulses = if self.outlier.is_some_and(|outlier| close(raw, outlier)) {
+			// A new tempo, also when twice as fast
+			self.outlier = None;
+			self.set_period(raw);
+			self.pulses(interval)
+		} else if close(interval, self.period) {
+			self.outlier = None;
+			self.set_period(self.period - (self.period >> PERIOD_GAIN) + (interval >> PERIOD_GAIN));
+			1
+		} else {
+			self.outlier = Some(raw);
+			if interval < self.period {
+				// A glitch
+				return;
+			}
+			// Pulses missing in between
+			self.pulses(interval)
+		};
+		self.accept(at, pulses);
+	}
+
+	fn restart(&mut self, at: u64) {
+		// The next whole pulse, or pulse 0 on the very first edge
+		let pulse = match self.last {
+			Some(_) => self.position(at).div_ceil(PULSE),
+			None => 0,
+		};
+		self.last = Some(at);
+		self.outlier = None;
+		self.pulse = pulse;
+		self.anchor = at;
+		self.anchor_position = pulse * PULSE;
+	}
+
+	// Edge at `at` is `pulses` after the last accepted one
+	fn accept(&mut self, at: u64, pulses: u64) {
+		let predicted = self.anchor_position + (at - self.anchor) * self.increment;
+		let shown = self.position(at);
+		self.pulse += pulses;
+		let target = self.pulse * PULSE;
+
+		// Halfway to the target, but never back from what was shown
+		let corrected = if target >= predicted {
+			predicted + ((target - predicted) >> PHASE_GAIN)
+		} else {
+			predicted - ((predicted - target) >> PHASE_GAIN)
+		};
+		self.anchor = at;
+		self.anchor_position = corrected.max(shown);
+		self.last = Some(at);
+	}
+
+	// Whole pulses in `interval`, at least one
+	fn pulses(&self, interval: u64) -> u64 {
+		((interval + self.period / 2) / self.period).max(1)
+	}
+
+	fn set_period(&mut self, period: u64) {
+		self.period = period.max(1 << 16);
+		self.increment = (PULSE << 16) / self.period;
+	}
+
+	// Whole ticks per pulse
+	fn period_ticks(&self) -> u64 {
+		(self.period + (1 << 15)) >> 16
+	}
+
+	/// Pulses since the clock first started, Q32.32
+	pub fn position(&self, now: u64) -> u64 {
+		if self.last.is_none() {
+			return 0;
+		}
+		let elapsed = now.saturating_sub(self.anchor).min(STOP_PERIODS * self.period_ticks());
+		let linear = self.anchor_position + elapsed * self.increment;
+		// Waits at the end of the pulse for the next edge
+		let next = (self.pulse + 1) * PULSE - 1;
+		linear.min(next).max(self.anchor_position)
+	}
+
+	/// Phase within the current pulse, a whole turn is 2^32
+	pub fn phase(&self, now: u64) -> u32 {
+		self.position(now) as u32
+	}
+
+	pub fn state(&self, now: u64) -> State {
+		match self.last {
+			None => State::Stopped,
+			Some(_) if self.period == 0 => State::Starting,
+			Some(last) if now.saturating_sub(last) > STOP_PERIODS * self.period_ticks() => State::Stopped,
+			Some(_) => State::Running,
+		}
+	}
+
+	/// Ticks per pulse, once known
+	pub fn period(&self) -> Option<u32> {
+		if self.period == 0 { None } else { Some(self.period_ticks() as u32) }
+	}
+
+	/// Quarter notes per minute
+	pub fn bpm(&self) -> Option<f32> {
+		if self.period == 0 {
+			return None;
+		}
+		let pulses_per_second = self.sample_rate as f64 * 65536.0 / self.period as f64;
+		Some((pulses_per_second * 60.0 / self.ppqn as f64) as f32)
+	}
+}
+
+// Within 25% of `reference`
+fn close(interval: u64, reference: u64) -> bool {
+	interval.abs_diff(reference) <= reference / 4
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	const RATE: u32 = 16_000;
+	// 120 BPM at one pulse per quarter note
+	const PERIOD: u64 = 8000;
+
+	// Follow `edges`, checking the position never goes back
+	fn follow(follower: &mut ClockFollower, edges: &[u64]) {
+		let mut shown = 0;
+		let mut now = 0;
+		for &edge in edges {
+			while now < edge {
+				let position = follower.position(now);
+				assert!(position >= shown, "back from {} to {} at {}", shown, position, now);
+				shown = position;
+				now += 100;
+			}
+			follower.on_edge(edge);
+			assert!(follower.position(edge) >= shown);
+		}
+	}
+
+	// Position in pulses
+	fn pulses(follower: &ClockFollower, at: u64) -> f64 {
+		follower.position(at) as f64 / PULSE as f64
+	}
+
+	// Distance of the position from a whole pulse, in pulses
+	fn phase_error(follower: &ClockFollower, at: u64) -> f64 {
+		let phase = follower.phase(at) as i32;
+		phase as f64 / PULSE as f64
+	}
+
+	#[test]
+	fn locks_to_a_jittery_clock() {
+		let mut follower = ClockFollower::new(0, RATE, 1);
+		assert_eq!(follower.state(0), State::Stopped);
+		assert_eq!(follower.bpm(), None);
+
+		// +-40 ticks of jitter, 2.5 ms
+		let jitter = [0, 40, -25, 10, -40, 33, -5, 20, -30, 0, 15, -12];
+		let edges: Vec<u64> = (0..48).map(|i| (1000 + i * PERIOD as i64 + jitter[i as usize % 12]) as u64).collect();
+		follow(&mut follower, &edges[..1]);
+		assert_eq!(follower.state(edges[0]), State::Starting);
+		follow(&mut follower, &edges[1..]);
+
+		let now = edges[47];
+		assert_eq!(follower.state(now), State::Running);
+		let bpm = follower.bpm().unwrap();
+		assert!((bpm - 120.0).abs() < 0.5, "{}", bpm);
+		assert!((pulses(&follower, now) - 47.0).abs() < 0.01);
+		// Half a pulse on
+		let phase = follower.phase(now + PERIOD / 2) as f64 / PULSE as f64;
+		assert!((phase - 0.5).abs() < 0.02, "{}", phase);
+		assert!(phase_error(&follower, now).abs() < 0.01);
+	}
+
+	#[test]
+	fn ignores_glitches_and_missing_pulses() {
+		let mut follower = ClockFollower::new(0, RATE, 4);
+		let mut edges: Vec<u64> = (0..10).map(|i| i * PERIOD).collect();
+		// A spike just after a pulse, then two pulses lost
+		edges.insert(5, 4 * PERIOD + 300);
+		edges.retain(|&edge| edge != 7 * PERIOD && edge != 8 * PERIOD);
+		edges.extend((10..14).map(|i| i * PERIOD));
+		follow(&mut follower, &edges);
+
+		// Still 120 BPM at 4 PPQN, that is 30 quarter notes a minute
+		assert!((follower.bpm().unwrap() - 30.0).abs() < 0.1);
+		assert_eq!(follower.period(), Some(8000));
+		assert!((pulses(&follower, 13 * PERIOD) - 13.0).abs() < 0.001);
+	}
+
+	#[test]
+	fn follows_a_new_tempo() {
+		let mut follower = ClockFollower::new(0, RATE, 1);
+		// 120 BPM, then 90, then twice as fast
+		let mut edges: Vec<u64> = (0..8).map(|i| i * PERIOD).collect();
+		let slow = PERIOD * 4 / 3;
+		edges.extend((1..8).map(|i| 7 * PERIOD + i * slow));
+		let fast = PERIOD * 2 / 3;
+		let end = 7 * PERIOD + 7 * slow;
+		edges.extend((1..12).map(|i| end + i * fast));
+		follow(&mut follower, &edges[..12]);
+		assert!((follower.bpm().unwrap() - 90.0).abs() < 1.0, "{:?}", follower.bpm());
+
+		follow(&mut follower, &edges[12..]);
+		assert!((follower.bpm().unwrap() - 180.0).abs() < 2.0, "{:?}", follower.bpm());
+		assert!(phase_error(&follower, *edges.last().unwrap()).abs() < 0.01);
+	}
+
+	#[test]
+	fn stops_and_restarts_in_sync() {
+		let mut follower = ClockFollower::new(0, RATE, 1);
+		follow(&mut follower, &[0, PERIOD, 2 * PERIOD]);
+		assert_eq!(follower.state(3 * PERIOD), State::Running);
+
+		// Waits at the end of the pulse, stopped after four periods
+		assert_eq!(follower.position(3 * PERIOD + 100), 3 * PULSE - 1);
+		assert_eq!(follower.state(6 * PERIOD), State::Running);
+		assert_eq!(follower.state(6 * PERIOD + 1), State::Stopped);
+		assert_eq!(follower.position(100 * PERIOD), 3 * PULSE - 1);
+
+		// Back at the old tempo, on the next pulse
+		follower.on_edge(50 * PERIOD);
+		assert_eq!(follower.state(50 * PERIOD), State::Running);
+		assert_eq!(follower.position(50 * PERIOD), 3 * PULSE);
+		assert!((pulses(&follower, 50 * PERIOD + PERIOD / 4) - 3.25).abs() < 0.001);
+	}
+
+	#[test]
+	fn only_rising_edges_of_its_input() {
+		let mut follower = ClockFollower::new(2, RATE, 1);
+		assert_eq!(follower.input(), 2);
+		follower.on_event(&Event { input: 1, edge: Edge::Rising, at: 0 });
+		follower.on_event(&Event { input: 2, edge: Edge::Falling, at: 0 });
+		assert_eq!(follower.state(0), State::Stopped);
+		follower.on_event(&Event { input: 2, edge: Edge::Rising, at: 0 });
+		assert_eq!(follower.state(0), State::Starting);
+	}
+}
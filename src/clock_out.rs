@@ -0,0 +1,317 @@
+//! Clock divider and multiplier outputs.
+//!
+//! An output in clock mode plays gate pulses at `multiply / divide` times
+//! the rate of the followed clock. Nothing is counted on the output side:
+//! the output position is the follower's position scaled by the ratio, so
+//! every output is locked to the clock's pulse 0 and two outputs with the
+//! same ratio always agree. Odd and fractional ratios come out of the same
+//! scaling, a 3/2 output fires on every other input pulse and halfway in
+//! between.
+//!
+//! Swing delays every second output pulse to `swing` percent of the pair,
+//! 50 is straight. Each pulse is high for `width` percent of its slot, the
+//! time until the next pulse starts, so the delayed pulse is shorter and
+//! there is always a gap before the next.
+//!
+//! The outputs play only while the clock runs, from its second edge on,
+//! and are low while it is stopped. A pulse already under way when the
+//! clock starts is skipped rather than cut short.
+
+use crate::calibration::OutputCalibrations;
+use crate::clock_follower::{ClockFollower, State, PULSE};
+use crate::fixed::Volts;
+use crate::pwm::MAX_OUTPUTS;
+
+/// Largest multiplier and divider
+pub const MAX_RATIO: u32 = 64;
+
+// Gate levels
+const HIGH: Volts = Volts::from_bits(5 << 16);
+const LOW: Volts = Volts::ZERO;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+	// Multiplier or divider of zero or above MAX_RATIO
+	Ratio,
+	// Outside 50-75%
+	Swing,
+	// Outside 1-99%
+	Width,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClockOutConfig {
+	pub multiply: u32,
+	pub divide: u32,
+	// Start of every second pulse, percent of the pair
+	pub swing: u32,
+	// Percent of the slot a pulse is high
+	pub width: u32,
+}
+
+impl Default for ClockOutConfig {
+	/// The clock itself, straight, half duty
+	fn default() -> ClockOutConfig {
+		ClockOutConfig { multiply: 1, divide: 1, swing: 50, width: 50 }
+	}
+}
+
+/// One output in clock mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClockOut {
+	config: ClockOutConfig,
+	// Within a pair of pulses, Q0.32: the start of the second pulse and
+	// where each pulse ends
+	second: u64,
+	first_end: u64,
+	second_end: u64,
+	// Output position at which the clock started running
+	from: Option<u128>,
+}
+
+impl ClockOut {
+	pub fn new(config: ClockOutConfig) -> Result<ClockOut, Error> {
+		let ratio = 1..=MAX_RATIO;
+		if !ratio.contains(&config.multiply) || !ratio.contains(&config.divide) {
+			return Err(Error::Ratio);
+		}
+		if !(50..=75).contains(&config.swing) {
+			return Err(Error::Swing);
+		}
+		if !(1..=99).contains(&config.width) {
+			return Err(Error::Width);
+		}
+
+		let second = PULSE * config.swing as u64 / 100;
+		Ok(ClockOut {
+			config,
+			second,
+			first_end: second * config.width as u64 / 100,
+			second_end: second + (PULSE - second) * config.width as u64 / 100,
+			from: None,
+		})
+	}
+
+	pub fn config(&self) -> ClockOutConfig {
+		self.config
+	}
+
+	/// Gate at the clock's `position`, Q32.32 pulses, `None` while the
+	/// clock is not running
+	pub fn gate(&mut self, position: Option<u64>) -> bool {
+		let position = match position {
+			Some(position) => position,
+			None => {
+				self.from = None;
+				return false;
+			}
+		};
+		let scaled = position as u128 * self.config.multiply as u128 / self.config.divide as u128;
+		let from = *self.from.get_or_insert(scaled);
+
+		// Where within the current pair of output pulses
+		let pair_start = scaled - scaled % (2 * PULSE as u128);
+		let pair = ((scaled - pair_start) / 2) as u64;
+		let (start, high) = if pair < self.second {
+			(pair_start, pair < self.first_end)
+		} else {
+			(pair_start + 2 * self.second as u128, pair < self.second_end)
+		};
+		high && start >= from
+	}
+}
+
+/// The outputs in clock mode.
+pub struct ClockOuts {
+	outputs: [Option<ClockOut>; MAX_OUTPUTS],
+	// Fine codes of the gate levels per output
+	high: [u16; MAX_OUTPUTS],
+	low: [u16; MAX_OUTPUTS],
+}
+
+impl ClockOuts {
+	/// Every output in CV mode
+	pub fn new(calibrations: OutputCalibrations) -> ClockOuts {
+		let mut outs = ClockOuts { outputs: [None; MAX_OUTPUTS], high: [0; MAX_OUTPUTS], low: [0; MAX_OUTPUTS] };
+		outs.set_calibrations(calibrations);
+		outs
+	}
+
+	/// Clock mode for an output, or back to CV with `None`
+	pub fn set(&mut self, output: usize, config: Option<ClockOutConfig>) -> Result<(), Error> {
+		self.outputs[output] = config.map(ClockOut::new).transpose()?;
+		Ok(())
+	}
+
+	pub fn get(&self, output: usize) -> Option<&ClockOut> {
+		self.outputs[output].as_ref()
+	}
+
+	/// Gate levels are calibrated voltages
+	pub fn set_calibrations(&mut self, calibrations: OutputCalibrations) {
+		for output in 0..MAX_OUTPUTS {
+			self.high[output] = calibrations.code(output, HIGH);
+			self.low[output] = calibrations.code(output, LOW);
+		}
+	}
+
+	/// Fill the clock outputs of `frames`, one fine code per output, the
+	/// first frame at sample clock tick `tick`. Other outputs are left alone.
+	pub fn render(&mut self, clock: Option<&ClockFollower>, tick: u64, frames: &mut [u16]) {
+		for (now, frame) in (tick..).zip(frames.chunks_exact_mut(MAX_OUTPUTS)) {
+			let position = clock.filter(|clock| clock.state(now) == State::Running).map(|clock| clock.position(now));
+			for (output, out) in self.outputs.iter_mut().enumerate() {
+				if let Some(out) = out {
+					frame[output] = if out.gate(position) { self.high[output] } else { self.low[output] };
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::calibration::OutputCalibration;
+
+	const RATE: u32 = 16_000;
+
+	// 24 pulses of a 2000 tick clock, 120 BPM at 4 PPQN, with a few ticks
+	// of jitter
+	const JITTERY: [u64; 24] = [
+		0, 2003, 3998, 6001, 7999, 10_002, 11_997, 14_000, 16_004, 17_998, 19_999, 22_003, 23_996, 26_001, 28_000,
+		30_002, 31_997, 34_001, 35_999, 38_003, 39_998, 42_000, 44_002, 45_999,
+	];
+
+	// 5 V and 0 V on a nominal +-5 V output
+	const HIGH: u16 = 65535;
+	const LOW: u16 = 32767;
+
+	fn calibrations() -> OutputCalibrations {
+		OutputCalibrations::new(OutputCalibration::nominal(-5_000_000, 5_000_000))
+	}
+
+	// Ticks at which `out` goes high and low following `edges`
+	fn play(mut out: ClockOut, edges: &[u64], until: u64) -> (Vec<u64>, Vec<u64>) {
+		let mut follower = ClockFollower::new(0, RATE, 4);
+		let (mut rising, mut falling) = (Vec::new(), Vec::new());
+		let mut edges = edges.iter().peekable();
+		let mut was_high = false;
+		for now in 0..until {
+			while edges.next_if(|&&edge| edge == now).is_some() {
+				follower.on_edge(now);
+			}
+			let running = follower.state(now) == State::Running;
+			let high = out.gate(if running { Some(follower.position(now)) } else { None });
+			match (was_high, high) {
+				(false, true) => rising.push(now),
+				(true, false) => falling.push(now),
+				_ => {}
+			}
+			was_high = high;
+		}
+		(rising, falling)
+	}
+
+	fn out(multiply: u32, divide: u32) -> ClockOut {
+		ClockOut::new(ClockOutConfig { multiply, divide, ..ClockOutConfig::default() }).unwrap()
+	}
+
+	// Within a few ticks of `expected`
+	fn near(ticks: &[u64], expected: &[u64]) -> bool {
+		ticks.len() == expected.len() && ticks.iter().zip(expected).all(|(&t, &e)| t.abs_diff(e) <= 8)
+	}
+
+	#[test]
+	fn divides_on_the_clock_pulses() {
+		// From the second edge on, on every third pulse counted from the first
+		let (rising, falling) = play(out(1, 3), &JITTERY, 46_000);
+		assert!(near(&rising, &[6000, 12_000, 18_000, 24_000, 30_000, 36_000, 42_000]), "{:?}", rising);
+		// Half of three pulses later
+		assert!(near(&falling, &[9000, 15_000, 21_000, 27_000, 33_000, 39_000, 45_000]), "{:?}", falling);
+
+		let (rising, _) = play(out(1, 2), &JITTERY, 46_000);
+		assert!(near(&rising, &(1..12).map(|i| i * 4000).collect::<Vec<_>>()), "{:?}", rising);
+	}
+
+	#[test]
+	fn multiplies_between_the_pulses() {
+		let (rising, falling) = play(out(3, 1), &JITTERY, 10_000);
+		// The first pulse of a 667 tick clock, then locked to the input
+		let expected: Vec<u64> = (3..15).map(|i| i * 2000 / 3).collect();
+		assert!(near(&rising, &expected), "{:?}", rising);
+		assert!(near(&falling, &expected.iter().map(|t| t + 333).collect::<Vec<_>>()), "{:?}", falling);
+
+		// Every other input pulse and halfway in between
+		let (rising, _) = play(out(3, 2), &JITTERY, 14_500);
+		assert!(near(&rising, &[2667, 4000, 5333, 6667, 8000, 9333, 10_667, 12_000, 13_333]), "{:?}", rising);
+	}
+
+	#[test]
+	fn swing_and_width() {
+		let config = ClockOutConfig { multiply: 2, swing: 75, width: 20, ..ClockOutConfig::default() };
+		let (rising, falling) = play(ClockOut::new(config).unwrap(), &JITTERY, 10_000);
+		// Pulses of a pair at 0 and 75%, each 20% of its slot
+		assert!(near(&rising, &[2003, 3500, 3998, 5500, 6001, 7500, 7999, 9500]), "{:?}", rising);
+		assert!(near(&falling, &[2300, 3600, 4300, 5600, 6300, 7600, 8300, 9600]), "{:?}", falling);
+
+		// Always a gap before the next pulse
+		let config = ClockOutConfig { width: 99, ..ClockOutConfig::default() };
+		let (rising, falling) = play(ClockOut::new(config).unwrap(), &JITTERY, 25_000);
+		assert_eq!((rising.len(), falling.len()), (12, 11));
+		assert!(falling.iter().zip(&rising[1..]).all(|(fall, rise)| fall < rise));
+	}
+
+	#[test]
+	fn low_while_stopped() {
+		let out = out(1, 2);
+		let follower = ClockFollower::new(0, RATE, 4);
+		let mut outs = ClockOuts::new(calibrations());
+		outs.set(3, Some(out.config())).unwrap();
+
+		let mut frames = [7; 2 * MAX_OUTPUTS];
+		outs.render(Some(&follower), 0, &mut frames);
+		assert_eq!(frames[3], LOW);
+		outs.render(None, 0, &mut frames);
+		assert_eq!(frames[MAX_OUTPUTS + 3], LOW);
+		// Other outputs untouched
+		assert!(frames.iter().enumerate().filter(|&(i, _)| i % MAX_OUTPUTS != 3).all(|(_, &code)| code == 7));
+
+		// A /2 output stopped halfway through its pulse
+		let (rising, falling) = play(out, &JITTERY[..4], 30_000);
+		assert_eq!((rising.len(), falling.len()), (1, 1));
+	}
+
+	#[test]
+	fn renders_gate_levels() {
+		let mut follower = ClockFollower::new(0, RATE, 4);
+		follower.on_edge(0);
+		follower.on_edge(2000);
+		let mut outs = ClockOuts::new(calibrations());
+		outs.set(0, Some(ClockOutConfig::default())).unwrap();
+		outs.set(1, Some(ClockOutConfig { multiply: 2, ..ClockOutConfig::default() })).unwrap();
+
+		// Both start a pulse on the edge
+		let mut frames = [0; 3 * MAX_OUTPUTS];
+		outs.render(Some(&follower), 2000, &mut frames);
+		assert!(frames.chunks_exact(MAX_OUTPUTS).all(|frame| frame[..2] == [HIGH, HIGH]));
+		// Past the middle of the doubled pulse
+		outs.render(Some(&follower), 2600, &mut frames);
+		assert!(frames.chunks_exact(MAX_OUTPUTS).all(|frame| frame[..2] == [HIGH, LOW]));
+	}
+
+	#[test]
+	fn rejects_bad_configs() {
+		let config = ClockOutConfig::default();
+		assert_eq!(ClockOut::new(ClockOutConfig { divide: 0, ..config }).err(), Some(Error::Ratio));
+		assert_eq!(ClockOut::new(ClockOutConfig { multiply: MAX_RATIO + 1, ..config }).err(), Some(Error::Ratio));
+		assert_eq!(ClockOut::new(ClockOutConfig { swing: 80, ..config }).err(), Some(Error::Swing));
+		assert_eq!(ClockOut::new(ClockOutConfig { width: 0, ..config }).err(), Some(Error::Width));
+
+		let mut outs = ClockOuts::new(calibrations());
+		assert_eq!(outs.set(2, Some(ClockOutConfig { width: 100, ..config })), Err(Error::Width));
+		assert!(outs.get(2).is_none());
+		outs.set(2, Some(config)).unwrap();
+		assert_eq!(outs.get(2).map(ClockOut::config), Some(config));
+	}
+}
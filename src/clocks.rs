@@ -0,0 +1,538 @@
+//! System clock tree.
+//!
+//! `ClockConfig` describes the wanted clocks, `solve` picks the oscillator,
+//! PLL (M, N, P, Q), bus prescalers and flash wait states within the limits
+//! of the reference manual and returns them as a `Setup`. The device code
+//! writes the `Setup` to RCC/FLASH and reports the resulting `Clocks`, decoded
+//! back from the registers.
+
+const HSI: u32 = 16_000_000;
+const MHZ: u32 = 1_000_000;
+
+// Reference manual limits for one part
+pub struct Limits {
+	pub sysclk_max: u32,
+	pub pclk1_max: u32,
+	pub pclk2_max: u32,
+	// SYSCLK above this needs the over-drive regulator
+	pub overdrive_above: Option<u32>,
+	// HCLK upper bound for each flash wait state, 2.7V to 3.6V
+	pub flash_wait_states: &'static [u32],
+	pub adc_max: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mcu {
+	Stm32f446,
+	Stm32f411,
+}
+
+impl Mcu {
+	pub fn limits(self) -> &'static Limits {
+		match self {
+			// RM0390
+			Mcu::Stm32f446 => &Limits {
+				sysclk_max: 180 * MHZ,
+				pclk1_max: 45 * MHZ,
+				pclk2_max: 90 * MHZ,
+				overdrive_above: Some(168 * MHZ),
+				flash_wait_states: &[30 * MHZ, 60 * MHZ, 90 * MHZ, 120 * MHZ, 150 * MHZ, 180 * MHZ],
+				adc_max: 36 * MHZ,
+			},
+			// RM0383
+			Mcu::Stm32f411 => &Limits {
+				sysclk_max: 100 * MHZ,
+				pclk1_max: 50 * MHZ,
+				pclk2_max: 100 * MHZ,
+				overdrive_above: None,
+				flash_wait_states: &[30 * MHZ, 64 * MHZ, 90 * MHZ, 100 * MHZ],
+				adc_max: 36 * MHZ,
+			},
+		}
+	}
+}
+
+// PLL limits shared by both parts
+const HSE_MIN: u32 = 4 * MHZ;
+const HSE_MAX: u32 = 26 * MHZ;
+const VCO_IN_MIN: u32 = MHZ;
+const VCO_IN_MAX: u32 = 2 * MHZ;
+const VCO_OUT_MIN: u64 = 100 * MHZ as u64;
+const VCO_OUT_MAX: u64 = 432 * MHZ as u64;
+const PLLM: (u32, u32) = (2, 63);
+const PLLN: (u32, u32) = (50, 432);
+const PLLP: [u32; 4] = [2, 4, 6, 8];
+const PLLQ: (u32, u32) = (2, 15);
+const PLL48: u32 = 48 * MHZ;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+	Hsi,
+	Hse(u32),
+}
+
+impl Source {
+	pub fn frequency(self) -> u32 {
+		match self {
+			Source::Hsi => HSI,
+			Source::Hse(hz) => hz,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+	// HSE outside 4 to 26 MHz
+	InvalidHse,
+	SysclkTooHigh,
+	// No PLL setting within the VCO limits reaches the requested clocks
+	NoPllSolution,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pll {
+	pub m: u32,
+	pub n: u32,
+	pub p: u32,
+	pub q: u32,
+}
+
+/// The clocks the rest of the firmware derives its timing from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Clocks {
+	pub sysclk: u32,
+	pub hclk: u32,
+	pub pclk1: u32,
+	pub pclk2: u32,
+	// PLL48CLK (USB, SDIO), zero when the PLL is off
+	pub pll48: u32,
+	ppre1: u32,
+	ppre2: u32,
+}
+
+impl Clocks {
+	/// Clock of the timers on APB1 (TIM2-TIM5)
+	pub fn timclk1(&self) -> u32 {
+		if self.ppre1 == 1 { self.pclk1 } else { self.pclk1 * 2 }
+	}
+
+	/// Clock of the timers on APB2 (TIM1, TIM8)
+	pub fn timclk2(&self) -> u32 {
+		if self.ppre2 == 1 { self.pclk2 } else { self.pclk2 * 2 }
+	}
+
+	/// Smallest PCLK2 divider keeping the ADC clock within limits.
+	///
+	/// Returns the `ADC_COMMON.ccr.adcpre` bits and the resulting ADC clock.
+	pub fn adc_prescaler(&self, mcu: Mcu) -> (u8, u32) {
+		let max = mcu.limits().adc_max;
+		for (bits, div) in [2, 4, 6, 8].iter().enumerate() {
+			if self.pclk2 / div <= max {
+				return (bits as u8, self.pclk2 / div);
+			}
+		}
+		(0b11, self.pclk2 / 8)
+	}
+
+	/// Work out the clocks from the RCC register contents.
+	///
+	/// `pllcfgr` and `cfgr` are the raw RCC_PLLCFGR and RCC_CFGR values,
+	/// the system clock is taken from the SWS status bits.
+	pub fn decode(hse: Option<u32>, pllcfgr: u32, cfgr: u32) -> Clocks {
+		let pll = Pll {
+			m: pllcfgr & 0x3F,
+			n: (pllcfgr >> 6) & 0x1FF,
+			p: (((pllcfgr >> 16) & 0b11) + 1) * 2,
+			q: (pllcfgr >> 24) & 0xF,
+		};
+		let pll_input = if pllcfgr & (1 << 22) != 0 { hse.unwrap_or(0) } else { HSI };
+
+		let (sysclk, pll48) = match (cfgr >> 2) & 0b11 {
+			0b01 => (hse.unwrap_or(0), 0),
+			0b10 => pll_output(pll_input, &pll),
+			_ => (HSI, 0),
+		};
+
+		let hpre = match (cfgr >> 4) & 0xF {
+			0b1000 => 2,
+			0b1001 => 4,
+			0b1010 => 8,
+			0b1011 => 16,
+			0b1100 => 64,
+			0b1101 => 128,
+			0b1110 => 256,
+			0b1111 => 512,
+			_ => 1,
+		};
+
+		let ppre = |bits: u32| match bits & 0b111 {
+			0b100 => 2,
+			0b101 => 4,
+			0b110 => 8,
+			0b111 => 16,
+			_ => 1,
+		};
+		let ppre1 = ppre(cfgr >> 10);
+		let ppre2 = ppre(cfgr >> 13);
+
+		let hclk = sysclk / hpre;
+		Clocks {
+			sysclk,
+			hclk,
+			pclk1: hclk / ppre1,
+			pclk2: hclk / ppre2,
+			pll48,
+			ppre1,
+			ppre2,
+		}
+	}
+}
+
+/// Register values for a clock configuration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Setup {
+	pub mcu: Mcu,
+	pub source: Source,
+	pub pll: Option<Pll>,
+	pub ppre1: u32,
+	pub ppre2: u32,
+	pub flash_latency: u8,
+	pub overdrive: bool,
+	pub clocks: Clocks,
+}
+
+impl Setup {
+	/// RCC_PLLCFGR value, PLLR left at its reset value
+	pub fn pllcfgr(&self) -> u32 {
+		let pll = match self.pll {
+			Some(pll) => pll,
+			None => return 0x2400_3010, // reset value
+		};
+
+		let src = match self.source {
+			Source::Hse(_) => 1 << 22,
+			Source::Hsi => 0,
+		};
+		let r = match self.mcu {
+			Mcu::Stm32f446 => 2 << 28,
+			Mcu::Stm32f411 => 0,
+		};
+
+		r | pll.q << 24 | src | (pll.p / 2 - 1) << 16 | pll.n << 6 | pll.m
+	}
+
+	/// RCC_CFGR value, with SW selecting the system clock
+	pub fn cfgr(&self) -> u32 {
+		let ppre = |div: u32| match div {
+			2 => 0b100,
+			4 => 0b101,
+			8 => 0b110,
+			16 => 0b111,
+			_ => 0b000,
+		};
+
+		let sw = match (self.pll, self.source) {
+			(Some(_), _) => 0b10,
+			(None, Source::Hse(_)) => 0b01,
+			(None, Source::Hsi) => 0b00,
+		};
+
+		ppre(self.ppre2) << 13 | ppre(self.ppre1) << 10 | sw
+	}
+
+	/// RCC_CFGR as it reads back once the switch has happened (SWS = SW)
+	pub fn cfgr_switched(&self) -> u32 {
+		let cfgr = self.cfgr();
+		cfgr | (cfgr & 0b11) << 2
+	}
+
+	pub fn hse(&self) -> Option<u32> {
+		match self.source {
+			Source::Hse(hz) => Some(hz),
+			Source::Hsi => None,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ClockConfig {
+	mcu: Mcu,
+	source: Source,
+	sysclk: Option<u32>,
+	pll48: bool,
+}
+
+impl ClockConfig {
+	/// Defaults to the HSI at the highest SYSCLK the part allows
+	pub fn new(mcu: Mcu) -> ClockConfig {
+		ClockConfig { mcu, source: Source::Hsi, sysclk: None, pll48: false }
+	}
+
+	pub fn hse(mut self, hz: u32) -> ClockConfig {
+		self.source = Source::Hse(hz);
+		self
+	}
+
+	pub fn sysclk(mut self, hz: u32) -> ClockConfig {
+		self.sysclk = Some(hz);
+		self
+	}
+
+	// Require exactly 48 MHz on PLL48CLK (USB)
+	pub fn require_pll48(mut self) -> ClockConfig {
+		self.pll48 = true;
+		self
+	}
+
+	pub fn solve(&self) -> Result<Setup, Error> {
+		let limits = self.mcu.limits();
+		let input = self.source.frequency();
+
+		if let Source::Hse(hz) = self.source {
+			if !(HSE_MIN..=HSE_MAX).contains(&hz) {
+				return Err(Error::InvalidHse);
+			}
+		}
+
+		let target = self.sysclk.unwrap_or(limits.sysclk_max);
+		if target > limits.sysclk_max {
+			return Err(Error::SysclkTooHigh);
+		}
+
+		let (sysclk, pll, pll48) = if target == input && !self.pll48 {
+			(input, None, 0)
+		} else {
+			let pll = solve_pll(input, target, self.pll48, limits.sysclk_max)?;
+			let (sysclk, pll48) = pll_output(input, &pll);
+			(sysclk, Some(pll), pll48)
+		};
+
+		// AHB runs at SYSCLK, the APB buses as fast as allowed
+		let hclk = sysclk;
+		let ppre1 = bus_prescaler(hclk, limits.pclk1_max);
+		let ppre2 = bus_prescaler(hclk, limits.pclk2_max);
+
+		let flash_latency = limits.flash_wait_states
+			.iter()
+			.position(|&max| hclk <= max)
+			.unwrap_or(limits.flash_wait_states.len() - 1) as u8;
+
+		let overdrive = match limits.overdrive_above {
+			Some(above) => sysclk > above,
+			None => false,
+		};
+
+		Ok(Setup {
+			mcu: self.mcu,
+			source: self.source,
+			pll,
+			ppre1,
+			ppre2,
+			flash_latency,
+			overdrive,
+			clocks: Clocks {
+				sysclk,
+				hclk,
+				pclk1: hclk / ppre1,
+				pclk2: hclk / ppre2,
+				pll48,
+				ppre1,
+				ppre2,
+			},
+		})
+	}
+}
+
+// (SYSCLK, PLL48CLK) produced by a PLL setting
+fn pll_output(input: u32, pll: &Pll) -> (u32, u32) {
+	if pll.m == 0 || pll.p == 0 || pll.q == 0 {
+		return (0, 0);
+	}
+	let vco = input as u64 * pll.n as u64 / pll.m as u64;
+	((vco / pll.p as u64) as u32, (vco / pll.q as u64) as u32)
+}
+
+fn bus_prescaler(hclk: u32, max: u32) -> u32 {
+	let mut div = 1;
+	while hclk / div > max && div < 16 {
+		div *= 2;
+	}
+	div
+}
+
+// Closest SYSCLK not above `max`, preferring the highest VCO input (lowest jitter)
+fn solve_pll(input: u32, target: u32, pll48: bool, max: u32) -> Result<Pll, Error> {
+	let mut best: Option<(u32, Pll)> = None;
+
+	for m in PLLM.0..=PLLM.1 {
+		if input < m * VCO_IN_MIN || input > m * VCO_IN_MAX {
+			continue;
+		}
+
+		for &p in PLLP.iter() {
+			let n = if pll48 {
+				// The VCO has to be a multiple of 48 MHz, take the one closest to target
+				let vco = target as u64 * p as u64;
+				let q = ((vco + PLL48 as u64 / 2) / PLL48 as u64).clamp(PLLQ.0 as u64, PLLQ.1 as u64);
+				let ratio = PLL48 as u64 * q * m as u64;
+				if !ratio.is_multiple_of(input as u64) {
+					continue;
+				}
+				(ratio / input as u64) as u32
+			} else {
+				// N giving the closest SYSCLK
+				((target as u64 * p as u64 * m as u64 + input as u64 / 2) / input as u64) as u32
+			};
+			if !(PLLN.0..=PLLN.1).contains(&n) {
+				continue;
+			}
+
+			let vco = input as u64 * n as u64 / m as u64;
+			if !(VCO_OUT_MIN..=VCO_OUT_MAX).contains(&vco) {
+				continue;
+			}
+
+			let sysclk = (vco / p as u64) as u32;
+			if sysclk > max {
+				continue;
+			}
+
+			// PLL48CLK must not exceed 48 MHz even when unused
+			let q = (vco.div_ceil(PLL48 as u64) as u32).max(PLLQ.0);
+			if q > PLLQ.1 {
+				continue;
+			}
+
+			let diff = (sysclk as i64 - target as i64).unsigned_abs() as u32;
+			let better = match best {
+				Some((best_diff, _)) => diff < best_diff,
+				None => true,
+			};
+			if better {
+				best = Some((diff, Pll { m, n, p, q }));
+			}
+		}
+	}
+
+	best.map(|(_, pll)| pll).ok_or(Error::NoPllSolution)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	// Check a setup against the reference manual limits
+	fn check_limits(setup: &Setup) {
+		let limits = setup.mcu.limits();
+		let clocks = &setup.clocks;
+
+		if let Some(pll) = setup.pll {
+			let input = setup.source.frequency();
+			assert!(input >= pll.m * VCO_IN_MIN && input <= pll.m * VCO_IN_MAX);
+			assert!(pll.n >= PLLN.0 && pll.n <= PLLN.1);
+			let vco = input as u64 * pll.n as u64 / pll.m as u64;
+			assert!((VCO_OUT_MIN..=VCO_OUT_MAX).contains(&vco));
+			assert!(PLLP.contains(&pll.p));
+			assert!(pll.q >= PLLQ.0 && pll.q <= PLLQ.1);
+			assert!(clocks.pll48 <= PLL48);
+		}
+
+		assert!(clocks.sysclk <= limits.sysclk_max);
+		assert!(clocks.pclk1 <= limits.pclk1_max);
+		assert!(clocks.pclk2 <= limits.pclk2_max);
+		assert!(clocks.hclk <= limits.flash_wait_states[setup.flash_latency as usize]);
+		if setup.flash_latency > 0 {
+			assert!(clocks.hclk > limits.flash_wait_states[setup.flash_latency as usize - 1]);
+		}
+
+		// What the registers would decode to matches the solver
+		assert_eq!(Clocks::decode(setup.hse(), setup.pllcfgr(), setup.cfgr_switched()), *clocks);
+	}
+
+	#[test]
+	fn f446_from_hsi_at_180mhz() {
+		let setup = ClockConfig::new(Mcu::Stm32f446).solve().unwrap();
+		check_limits(&setup);
+
+		assert_eq!(setup.pll, Some(Pll { m: 8, n: 180, p: 2, q: 8 }));
+		assert_eq!(setup.clocks.sysclk, 180_000_000);
+		assert_eq!(setup.clocks.pclk1, 45_000_000);
+		assert_eq!(setup.clocks.pclk2, 90_000_000);
+		assert_eq!(setup.clocks.timclk1(), 90_000_000);
+		assert_eq!(setup.clocks.timclk2(), 180_000_000);
+		assert_eq!(setup.flash_latency, 5);
+		assert!(setup.overdrive);
+		assert_eq!(setup.clocks.adc_prescaler(Mcu::Stm32f446), (0b01, 22_500_000));
+	}
+
+	#[test]
+	fn f446_from_hse_with_usb_clock() {
+		let setup = ClockConfig::new(Mcu::Stm32f446)
+			.hse(8_000_000)
+			.sysclk(168_000_000)
+			.require_pll48()
+			.solve()
+			.unwrap();
+		check_limits(&setup);
+
+		assert_eq!(setup.pll, Some(Pll { m: 4, n: 168, p: 2, q: 7 }));
+		assert_eq!(setup.clocks.pll48, 48_000_000);
+		assert!(!setup.overdrive);
+	}
+
+	#[test]
+	fn f411_from_25mhz_hse() {
+		let setup = ClockConfig::new(Mcu::Stm32f411).hse(25_000_000).solve().unwrap();
+		check_limits(&setup);
+
+		assert_eq!(setup.clocks.sysclk, 100_000_000);
+		assert_eq!(setup.clocks.pclk1, 50_000_000);
+		assert_eq!(setup.clocks.pclk2, 100_000_000);
+		assert_eq!(setup.clocks.timclk1(), 100_000_000);
+		assert_eq!(setup.flash_latency, 3);
+
+		// 100 MHz and exactly 48 MHz can not share a VCO, 96 MHz can
+		let setup = ClockConfig::new(Mcu::Stm32f411).hse(25_000_000).require_pll48().solve().unwrap();
+		check_limits(&setup);
+		assert_eq!(setup.clocks.sysclk, 96_000_000);
+		assert_eq!(setup.clocks.pll48, 48_000_000);
+	}
+
+	#[test]
+	fn no_pll_when_running_from_oscillator() {
+		let setup = ClockConfig::new(Mcu::Stm32f446).sysclk(16_000_000).solve().unwrap();
+		check_limits(&setup);
+
+		assert_eq!(setup.pll, None);
+		assert_eq!(setup.flash_latency, 0);
+		assert_eq!(setup.clocks.timclk1(), 16_000_000);
+		assert_eq!(setup.cfgr(), 0);
+	}
+
+	#[test]
+	fn every_sysclk_within_limits() {
+		for &mcu in [Mcu::Stm32f446, Mcu::Stm32f411].iter() {
+			for &hse in [None, Some(8_000_000), Some(12_000_000), Some(25_000_000)].iter() {
+				let mut sysclk = 24 * MHZ;
+				while sysclk <= mcu.limits().sysclk_max {
+					let mut config = ClockConfig::new(mcu).sysclk(sysclk);
+					if let Some(hz) = hse {
+						config = config.hse(hz);
+					}
+					let setup = config.solve().unwrap();
+					check_limits(&setup);
+					assert!(setup.clocks.sysclk <= sysclk + MHZ && setup.clocks.sysclk + MHZ >= sysclk);
+					sysclk += 4 * MHZ;
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn rejects_invalid_configs() {
+		assert_eq!(ClockConfig::new(Mcu::Stm32f446).sysclk(200_000_000).solve(), Err(Error::SysclkTooHigh));
+		assert_eq!(ClockConfig::new(Mcu::Stm32f411).sysclk(180_000_000).solve(), Err(Error::SysclkTooHigh));
+		assert_eq!(ClockConfig::new(Mcu::Stm32f446).hse(30_000_000).solve(), Err(Error::InvalidHse));
+		assert_eq!(ClockConfig::new(Mcu::Stm32f446).hse(2_000_000).solve(), Err(Error::InvalidHse));
+	}
+}
@@ -0,0 +1,472 @@
+//! Settings kept across power cycles, in a versioned binary format.
+//!
+//! The first byte is the format version, the rest little endian fields in
+//! a fixed order (see `wire`). `encode` always writes the current `VERSION`.
+//! `decode` reads every version ever released and migrates older ones to
+//! the current `Config`, so a firmware update keeps the calibration. Values
+//! the firmware could not run with are rejected, the caller then falls back
+//! to its defaults.
+//!
+//! Versions:
+//! 1. sample rate, ADC mode, channel map, calibration
+//! 2. adds the oversampling ratio, input calibration codes are 16 bit;
+//!    older settings get a ratio of 1 and their 12 bit codes times 16
+//! 3. adds the modes set on the console: each output's mode and dither,
+//!    each input's gate and filter, the clock input; older settings start
+//!    with everything plain CV
+//!
+//! A new version bumps `VERSION`, gets a module decoding the one it
+//! replaces (as `v1` and `v2` do) and a round trip test through `decode`.
+//! Released layouts never change.
+
+mod modes;
+mod v1;
+mod v2;
+mod wire;
+
+pub use self::modes::{ClockInput, InputSettings, Modes, OutputMode, OutputSettings, QuantizerConfig, ScaleSource};
+
+use crate::adc::{self, ChannelMap, Mode};
+use crate::calibration::{self, Calibration, Store};
+use crate::clock_out::ClockOut;
+use crate::hw::Flash;
+use crate::storage::{self, Storage};
+
+use self::wire::{Reader, Writer, CALIBRATION_SIZE, CHANNEL_MAP_SIZE, MODES_SIZE};
+
+pub const VERSION: u8 = 3;
+
+// Storage key of the configuration
+pub const KEY: u16 = 1;
+
+// Longest encoding, every calibration table full and every mode set
+pub const MAX_SIZE: usize = 1 + 4 + 1 + 1 + CHANNEL_MAP_SIZE + CALIBRATION_SIZE + MODES_SIZE;
+
+// Sample rates the firmware runs at, in Hz
+pub const MIN_SAMPLE_RATE: u32 = 1_000;
+pub const MAX_SAMPLE_RATE: u32 = 48_000;
+
+/// Highest oversampling ratio, what the firmware's buffers have room for
+pub const MAX_OVERSAMPLING: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+	Truncated,
+	TrailingBytes,
+	// From a newer firmware, or not a configuration at all
+	UnknownVersion(u8),
+	// A field holds a value no version ever wrote, or one the firmware
+	// can not run with
+	Invalid,
+	Channels(adc::Error),
+	Calibration(calibration::Error),
+	BufferTooSmall,
+	Storage(storage::Error),
+}
+
+impl From<storage::Error> for Error {
+	fn from(error: storage::Error) -> Error {
+		Error::Storage(error)
+	}
+}
+
+fn mode_tag(mode: Mode) -> u8 {
+	match mode {
+		Mode::Independent => 0,
+		Mode::TripleSimultaneous => 1,
+		Mode::TripleInterleaved => 2,
+	}
+}
+
+fn mode_from_tag(tag: u8) -> Result<Mode, Error> {
+	match tag {
+		0 => Ok(Mode::Independent),
+		1 => Ok(Mode::TripleSimultaneous),
+		2 => Ok(Mode::TripleInterleaved),
+		_ => Err(Error::Invalid),
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+	// Of the inputs after decimation, and of the outputs
+	pub sample_rate: u32,
+	pub adc_mode: Mode,
+	// Sample clock ticks per input sample, 1 without oversampling
+	pub oversampling: usize,
+	pub channels: ChannelMap,
+	pub calibration: Calibration,
+	pub modes: Modes,
+}
+
+/// Write `config` in the current format, returning the length.
+pub fn encode(config: &Config, buffer: &mut [u8]) -> Result<usize, Error> {
+	let mut w = Writer::new(buffer);
+	w.u8(VERSION);
+	w.u32(config.sample_rate);
+	w.u8(mode_tag(config.adc_mode));
+	w.u8(config.oversampling as u8);
+	wire::write_channel_map(&mut w, &config.channels);
+	wire::write_calibration(&mut w, &config.calibration);
+	wire::write_modes(&mut w, &config.modes);
+	w.finish()
+}
+
+/// Read a configuration of any version.
+pub fn decode(bytes: &[u8]) -> Result<Config, Error> {
+	let config = match version(bytes)? {
+		1 => v2::migrate(v1::migrate(v1::decode(bytes)?)),
+		2 => v2::migrate(v2::decode(bytes)?),
+		3 => decode_v3(bytes)?,
+		version => return Err(Error::UnknownVersion(version)),
+	};
+	validate(&config)?;
+	Ok(config)
+}
+
+// The board runs the three ADCs together, its sample timer and filters are
+// designed for the range of rates, its buffers for the oversampling. Modes
+// are those the console accepts, a quantizer's trigger output has no other
+fn validate(config: &Config) -> Result<(), Error> {
+	let rate = MIN_SAMPLE_RATE..=MAX_SAMPLE_RATE;
+	if config.adc_mode == Mode::Independent
+		|| !rate.contains(&config.sample_rate)
+		|| !(1..=MAX_OVERSAMPLING).contains(&config.oversampling)
+	{
+		return Err(Error::Invalid);
+	}
+
+	let modes = &config.modes;
+	let valid = modes.outputs.iter().all(|settings| match settings.mode {
+		OutputMode::Off => true,
+		OutputMode::Clock(clock) => ClockOut::new(clock).is_ok(),
+		OutputMode::Quantize(quantizer) => {
+			let scale = match quantizer.scale {
+				ScaleSource::Mask(mask) => (1..=0x0FFF).contains(&mask),
+				ScaleSource::Tuning => true,
+			};
+			scale && quantizer.trigger.is_none_or(|trigger| modes.outputs[trigger].mode == OutputMode::Off)
+		}
+		OutputMode::Hold(_) => true,
+	});
+	if !valid {
+		return Err(Error::Invalid);
+	}
+	Ok(())
+}
+
+/// Format version of an encoded configuration
+pub fn version(bytes: &[u8]) -> Result<u8, Error> {
+	bytes.first().copied().ok_or(Error::Truncated)
+}
+
+fn decode_v3(bytes: &[u8]) -> Result<Config, Error> {
+	let mut r = Reader::new(&bytes[1..]);
+	let sample_rate = r.u32()?;
+	let adc_mode = mode_from_tag(r.u8()?)?;
+	let oversampling = r.u8()? as usize;
+	let channels = wire::read_channel_map(&mut r)?;
+	let calibration = wire::read_calibration(&mut r)?;
+	let modes = wire::read_modes(&mut r)?;
+	r.finish()?;
+
+	Ok(Config { sample_rate, adc_mode, oversampling, channels, calibration, modes })
+}
+
+/// Read the configuration from the store, `None` if there is none.
+///
+/// An older version is migrated and written back in the current format.
+pub fn load<F: Flash>(storage: &mut Storage<F>) -> Result<Option<Config>, Error> {
+	let mut buffer = [0; MAX_SIZE];
+	let len = match storage.read(KEY, &mut buffer)? {
+		Some(len) => len,
+		None => return Ok(None),
+	};
+
+	let config = decode(&buffer[..len])?;
+	if version(&buffer[..len])? != VERSION {
+		save(storage, &config)?;
+	}
+	Ok(Some(config))
+}
+
+pub fn save<F: Flash>(storage: &mut Storage<F>, config: &Config) -> Result<(), Error> {
+	let mut buffer = [0; MAX_SIZE];
+	let len = encode(config, &mut buffer)?;
+	storage.write(KEY, &buffer[..len])?;
+	Ok(())
+}
+
+/// The configuration and the store it is saved in.
+///
+/// Calibrations committed by a calibration session are saved with the rest
+/// of the configuration.
+pub struct ConfigStore<F> {
+	storage: Storage<F>,
+	config: Config,
+}
+
+impl<F: Flash> ConfigStore<F> {
+	/// The stored configuration, `defaults` when there is none or it is
+	/// unreadable. The error, if any, comes back alongside.
+	pub fn open(mut storage: Storage<F>, defaults: Config) -> (ConfigStore<F>, Result<(), Error>) {
+		let (config, result) = match load(&mut storage) {
+			Ok(Some(config)) => (config, Ok(())),
+			Ok(None) => (defaults, Ok(())),
+			Err(error) => (defaults, Err(error)),
+		};
+		(ConfigStore { storage, config }, result)
+	}
+
+	pub fn config(&self) -> &Config {
+		&self.config
+	}
+
+	pub fn save(&mut self, config: Config) -> Result<(), Error> {
+		save(&mut self.storage, &config)?;
+		self.config = config;
+		Ok(())
+	}
+
+	pub fn storage(&self) -> &Storage<F> {
+		&self.storage
+	}
+
+	/// For the records kept next to the configuration, such as the tuning
+	pub fn storage_mut(&mut self) -> &mut Storage<F> {
+		&mut self.storage
+	}
+}
+
+impl<F: Flash> Store for ConfigStore<F> {
+	type Error = Error;
+
+	fn commit(&mut self, calibration: &Calibration) -> Result<(), Error> {
+		self.save(Config { calibration: *calibration, ..self.config })
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::calibration::{input, output, InputCalibration, InputCalibrations, OutputCalibration, OutputCalibrations};
+	use crate::clock_out::ClockOutConfig;
+	use crate::filter::Kind;
+	use crate::fixed::Volts;
+	use crate::gate::GateConfig;
+	use crate::hw::mock::MockFlash;
+	use crate::hw::SampleTime;
+	use crate::pwm::{Shaping, MAX_OUTPUTS};
+	use crate::sample_hold::{self, HoldConfig, Source};
+
+	pub fn config() -> Config {
+		let mut inputs = InputCalibrations::new(InputCalibration::nominal(10_000, -10_000));
+		inputs.set(3, InputCalibration::new(&[
+			input::Point::new(1600, 9800),
+			input::Point::new(32768, 0),
+			input::Point::new(63840, -9850),
+		]).unwrap());
+		let mut outputs = OutputCalibrations::new(OutputCalibration::nominal(-5_000_000, 5_000_000));
+		outputs.set(15, OutputCalibration::new(&[
+			output::Point::new(0, -5_070_000),
+			output::Point::new(32768, 31_000),
+			output::Point::new(65535, 5_130_000),
+		]).unwrap());
+
+		let mut modes = Modes::default();
+		let quantizer = QuantizerConfig { input: 2, scale: ScaleSource::Mask(0xAB5), transpose: -3, trigger: Some(1) };
+		modes.set_output(0, OutputMode::Quantize(quantizer));
+		modes.outputs[0].dither = Shaping::SecondOrder;
+		modes.set_output(4, OutputMode::Clock(ClockOutConfig { multiply: 3, divide: 2, swing: 60, width: 25 }));
+		modes.set_output(9, OutputMode::Hold(HoldConfig {
+			source: Source::Noise,
+			trigger: 5,
+			mode: sample_hold::Mode::TrackHold,
+			gate: GateConfig::default(),
+		}));
+		modes.outputs[9].dither = Shaping::Tpdf;
+		let quantizer = QuantizerConfig { input: 7, scale: ScaleSource::Tuning, transpose: 0, trigger: None };
+		modes.set_output(12, OutputMode::Quantize(quantizer));
+		modes.inputs[3] = InputSettings { gate: None, filter: Kind::Butterworth, cutoff: 250.5 };
+		modes.inputs[5].gate = Some(GateConfig { threshold: Volts::from_millivolts(-500), ..GateConfig::default() });
+		modes.clock = Some(ClockInput { input: 5, ppqn: 24 });
+
+		Config {
+			sample_rate: 16_000,
+			adc_mode: Mode::TripleSimultaneous,
+			oversampling: 4,
+			channels: ChannelMap::new(&[0, 1, 2, 3, 10, 11], SampleTime::Cycles56)
+				.unwrap()
+				.sample_time(10, SampleTime::Cycles144),
+			calibration: Calibration { inputs, outputs },
+			modes,
+		}
+	}
+
+	#[test]
+	fn current_version_round_trips() {
+		let config = config();
+		let mut buffer = [0; MAX_SIZE];
+		let len = encode(&config, &mut buffer).unwrap();
+		assert_eq!(buffer[0], VERSION);
+		assert_eq!(decode(&buffer[..len]), Ok(config));
+
+		// A calibration full of 8 point tables is the longest
+		let mut full = config;
+		let points: Vec<_> = (0..8).map(|i| input::Point::new(i * 8000, 10_000 - i as i32 * 2500)).collect();
+		full.calibration.inputs = InputCalibrations::new(InputCalibration::new(&points).unwrap());
+		let points: Vec<_> = (0..8).map(|i| output::Point::new(i * 9000, i as i32 * 1000)).collect();
+		full.calibration.outputs = OutputCalibrations::new(OutputCalibration::new(&points).unwrap());
+		// As are holds on every output, gates and filters on every input
+		for output in 0..MAX_OUTPUTS {
+			let hold = HoldConfig {
+				source: Source::Input(output),
+				trigger: 0,
+				mode: sample_hold::Mode::SampleHold,
+				gate: GateConfig::default(),
+			};
+			full.modes.set_output(output, OutputMode::Hold(hold));
+		}
+		for settings in full.modes.inputs.iter_mut() {
+			*settings = InputSettings { gate: Some(GateConfig::default()), filter: Kind::OnePole, cutoff: 10.0 };
+		}
+		assert_eq!(encode(&full, &mut buffer), Ok(MAX_SIZE));
+		assert_eq!(decode(&buffer), Ok(full));
+	}
+
+	#[test]
+	fn rejects_damaged_data() {
+		let mut buffer = [0; MAX_SIZE];
+		let len = encode(&config(), &mut buffer).unwrap();
+
+		assert_eq!(decode(&[]), Err(Error::Truncated));
+		assert_eq!(decode(&buffer[..len - 1]), Err(Error::Truncated));
+		assert_eq!(decode(&buffer[..len + 1]), Err(Error::TrailingBytes));
+		assert_eq!(encode(&config(), &mut buffer[..len - 1]), Err(Error::BufferTooSmall));
+
+		buffer[0] = VERSION + 1;
+		assert_eq!(decode(&buffer[..len]), Err(Error::UnknownVersion(VERSION + 1)));
+		buffer[0] = VERSION;
+
+		// ADC mode
+		buffer[5] = 7;
+		assert_eq!(decode(&buffer[..len]), Err(Error::Invalid));
+		buffer[5] = 1;
+		// First channel
+		buffer[8] = 20;
+		assert_eq!(decode(&buffer[..len]), Err(Error::Channels(adc::Error::InvalidChannel(20))));
+		buffer[8] = 0;
+		assert_eq!(decode(&buffer[..len]), Ok(config()));
+	}
+
+	#[test]
+	fn rejects_modes_the_console_would_not_set() {
+		let mut buffer = [0; MAX_SIZE];
+		let encoded = |modes: Modes, buffer: &mut [u8]| encode(&Config { modes, ..config() }, buffer).unwrap();
+
+		let mut modes = config().modes;
+		modes.outputs[4].mode = OutputMode::Clock(ClockOutConfig { multiply: 0, ..ClockOutConfig::default() });
+		let len = encoded(modes, &mut buffer);
+		assert_eq!(decode(&buffer[..len]), Err(Error::Invalid));
+
+		let mut modes = config().modes;
+		let quantizer = QuantizerConfig { input: 7, scale: ScaleSource::Mask(0), transpose: 0, trigger: None };
+		modes.outputs[12].mode = OutputMode::Quantize(quantizer);
+		let len = encoded(modes, &mut buffer);
+		assert_eq!(decode(&buffer[..len]), Err(Error::Invalid));
+
+		// Output 1 is output 0's trigger
+		let mut modes = config().modes;
+		modes.outputs[1].mode = OutputMode::Clock(ClockOutConfig::default());
+		let len = encoded(modes, &mut buffer);
+		assert_eq!(decode(&buffer[..len]), Err(Error::Invalid));
+
+		// The section starts with output 0's dither
+		let len = encoded(config().modes, &mut buffer);
+		let mut section = [0; MODES_SIZE];
+		let mut w = Writer::new(&mut section);
+		wire::write_modes(&mut w, &config().modes);
+		let start = len - w.finish().unwrap();
+		assert_eq!(buffer[start], 3);
+		buffer[start] = 4;
+		assert_eq!(decode(&buffer[..len]), Err(Error::Invalid));
+		buffer[start] = 3;
+		assert_eq!(decode(&buffer[..len]), Ok(config()));
+	}
+
+	#[test]
+	fn rejects_settings_the_firmware_can_not_run() {
+		let mut buffer = [0; MAX_SIZE];
+		let encoded = |config: Config, buffer: &mut [u8]| encode(&config, buffer).unwrap();
+
+		// A single ADC
+		let len = encoded(Config { adc_mode: Mode::Independent, ..config() }, &mut buffer);
+		assert_eq!(decode(&buffer[..len]), Err(Error::Invalid));
+
+		for &sample_rate in &[0, MIN_SAMPLE_RATE - 1, MAX_SAMPLE_RATE + 1] {
+			let len = encoded(Config { sample_rate, ..config() }, &mut buffer);
+			assert_eq!(decode(&buffer[..len]), Err(Error::Invalid), "{} Hz", sample_rate);
+		}
+		let len = encoded(Config { sample_rate: MAX_SAMPLE_RATE, ..config() }, &mut buffer);
+		assert!(decode(&buffer[..len]).is_ok());
+
+		// More than the buffers hold
+		for &oversampling in &[0, MAX_OVERSAMPLING + 1] {
+			let len = encoded(Config { oversampling, ..config() }, &mut buffer);
+			assert_eq!(decode(&buffer[..len]), Err(Error::Invalid), "ratio {}", oversampling);
+		}
+
+		// Stored by a broken firmware, the defaults take over
+		let flash = MockFlash::new(2, 16 * 1024);
+		let mut storage = Storage::mount(flash).unwrap();
+		let len = encoded(Config { sample_rate: 0, ..config() }, &mut buffer);
+		storage.write(KEY, &buffer[..len]).unwrap();
+		let (store, result) = ConfigStore::open(storage, config());
+		assert_eq!(result, Err(Error::Invalid));
+		assert_eq!(store.config(), &config());
+	}
+
+	#[test]
+	fn saved_in_flash() {
+		let flash = MockFlash::new(2, 16 * 1024);
+		let defaults = config();
+
+		let (mut store, result) = ConfigStore::open(Storage::mount(flash.clone()).unwrap(), defaults);
+		assert_eq!(result, Ok(()));
+		assert_eq!(store.config(), &defaults);
+
+		// A calibration session commits
+		let mut calibration = defaults.calibration;
+		calibration.inputs.set(0, InputCalibration::nominal(9_990, -10_020));
+		store.commit(&calibration).unwrap();
+
+		let (store, result) = ConfigStore::open(Storage::mount(flash.clone()).unwrap(), defaults);
+		assert_eq!(result, Ok(()));
+		assert_eq!(store.config().calibration, calibration);
+		assert_eq!(store.config().sample_rate, defaults.sample_rate);
+
+		// Unreadable, back to the defaults
+		let mut storage = Storage::mount(flash.clone()).unwrap();
+		storage.write(KEY, &[VERSION, 1, 2]).unwrap();
+		let (store, result) = ConfigStore::open(storage, defaults);
+		assert_eq!(result, Err(Error::Truncated));
+		assert_eq!(store.config(), &defaults);
+	}
+
+	#[test]
+	fn older_versions_are_migrated_on_load() {
+		let flash = MockFlash::new(2, 16 * 1024);
+		let mut storage = Storage::mount(flash.clone()).unwrap();
+		let mut buffer = [0; MAX_SIZE];
+		let len = v1::test::encode(&config(), &mut buffer);
+		storage.write(KEY, &buffer[..len]).unwrap();
+
+		let loaded = load(&mut storage).unwrap().unwrap();
+		assert_eq!(loaded, Config { oversampling: 1, modes: Modes::default(), ..config() });
+
+		// Written back as the current version
+		let len = storage.read(KEY, &mut buffer).unwrap().unwrap();
+		assert_eq!(buffer[0], VERSION);
+		assert_eq!(decode(&buffer[..len]), Ok(loaded));
+	}
+}
@@ -0,0 +1,128 @@
+//! What each output and input is set to on the console, kept across restarts.
+
+use crate::adc::MAX_CHANNELS;
+use crate::clock_out::ClockOutConfig;
+use crate::filter::Kind;
+use crate::gate::GateConfig;
+use crate::pwm::{Shaping, MAX_OUTPUTS};
+use crate::sample_hold::HoldConfig;
+
+/// Where a quantizer's scale comes from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScaleSource {
+	// Twelve tone equal temperament, bit `i` for `i` semitones above the root
+	Mask(u16),
+	// The scale of the stored tuning
+	Tuning,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuantizerConfig {
+	pub input: usize,
+	pub scale: ScaleSource,
+	// In scale degrees
+	pub transpose: i32,
+	// Output pulsing on every note change
+	pub trigger: Option<usize>,
+}
+
+/// What an output plays, one at a time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputMode {
+	// Nothing kept, also the trigger output of a quantizer
+	Off,
+	Clock(ClockOutConfig),
+	Quantize(QuantizerConfig),
+	Hold(HoldConfig),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputSettings {
+	pub mode: OutputMode,
+	pub dither: Shaping,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InputSettings {
+	pub gate: Option<GateConfig>,
+	pub filter: Kind,
+	// In Hz, ignored by `Kind::Off`
+	pub cutoff: f32,
+}
+
+/// The input the clock is followed on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClockInput {
+	pub input: usize,
+	pub ppqn: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Modes {
+	pub outputs: [OutputSettings; MAX_OUTPUTS],
+	pub inputs: [InputSettings; MAX_CHANNELS],
+	pub clock: Option<ClockInput>,
+}
+
+impl Default for Modes {
+	/// Every output and input plain CV, without dither or filters
+	fn default() -> Modes {
+		Modes {
+			outputs: [OutputSettings { mode: OutputMode::Off, dither: Shaping::Off }; MAX_OUTPUTS],
+			inputs: [InputSettings { gate: None, filter: Kind::Off, cutoff: 0.0 }; MAX_CHANNELS],
+			clock: None,
+		}
+	}
+}
+
+impl Modes {
+	/// Put `output` in `mode`. As on the board it stops being a quantizer's
+	/// trigger, and a quantizer's trigger output is turned off.
+	pub fn set_output(&mut self, output: usize, mode: OutputMode) {
+		for settings in self.outputs.iter_mut() {
+			if let OutputMode::Quantize(quantizer) = &mut settings.mode {
+				if quantizer.trigger == Some(output) {
+					quantizer.trigger = None;
+				}
+			}
+		}
+		if let OutputMode::Quantize(QuantizerConfig { trigger: Some(trigger), .. }) = mode {
+			self.set_output(trigger, OutputMode::Off);
+		}
+		self.outputs[output].mode = mode;
+	}
+
+	/// Whether a quantizer pulses `output`
+	pub fn is_trigger(&self, output: usize) -> bool {
+		self.outputs.iter().any(|settings| match settings.mode {
+			OutputMode::Quantize(quantizer) => quantizer.trigger == Some(output),
+			_ => false,
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn quantize(input: usize, trigger: Option<usize>) -> OutputMode {
+		OutputMode::Quantize(QuantizerConfig { input, scale: ScaleSource::Mask(0xAB5), transpose: 0, trigger })
+	}
+
+	#[test]
+	fn an_output_plays_one_mode() {
+		let mut modes = Modes::default();
+		modes.set_output(2, OutputMode::Clock(ClockOutConfig::default()));
+		modes.set_output(0, quantize(1, Some(2)));
+		assert_eq!(modes.outputs[2].mode, OutputMode::Off);
+		assert!(modes.is_trigger(2));
+
+		// Taken over, the quantizer goes on without its trigger
+		modes.set_output(2, OutputMode::Clock(ClockOutConfig::default()));
+		assert_eq!(modes.outputs[0].mode, quantize(1, None));
+		assert!(!modes.is_trigger(2));
+
+		modes.set_output(0, OutputMode::Off);
+		assert_eq!(modes.outputs[2].mode, OutputMode::Clock(ClockOutConfig::default()));
+	}
+}
@@ -0,0 +1,112 @@
+//! Version 1, without oversampling and with 12 bit input calibration codes.
+
+use crate::adc::{ChannelMap, Mode, MAX_CHANNELS};
+use crate::calibration::input::{InputCalibration, Point};
+use crate::calibration::{Calibration, MAX_POINTS};
+
+use super::v2::V2;
+use super::wire::{self, Reader};
+use super::{mode_from_tag, Error};
+
+// Highest code of the 12 bit tables
+const MAX_CODE: u16 = 0x0FFF;
+
+pub struct V1 {
+	sample_rate: u32,
+	adc_mode: Mode,
+	channels: ChannelMap,
+	calibration: Calibration,
+}
+
+pub fn decode(bytes: &[u8]) -> Result<V1, Error> {
+	let mut r = Reader::new(&bytes[1..]);
+	let sample_rate = r.u32()?;
+	let adc_mode = mode_from_tag(r.u8()?)?;
+	let channels = wire::read_channel_map(&mut r)?;
+	let calibration = wire::read_calibration(&mut r)?;
+	r.finish()?;
+
+	let mut codes = (0..MAX_CHANNELS).flat_map(|channel| calibration.inputs.get(channel).points()).map(|p| p.code);
+	if codes.any(|code| code > MAX_CODE) {
+		return Err(Error::Invalid);
+	}
+	Ok(V1 { sample_rate, adc_mode, channels, calibration })
+}
+
+/// Inputs were read without oversampling, their tables in 12 bit codes
+pub fn migrate(v1: V1) -> V2 {
+	let mut calibration = v1.calibration;
+	for channel in 0..MAX_CHANNELS {
+		calibration.inputs.set(channel, widen(calibration.inputs.get(channel)));
+	}
+	V2 { sample_rate: v1.sample_rate, adc_mode: v1.adc_mode, oversampling: 1, channels: v1.channels, calibration }
+}
+
+// The same table in the 16 bit codes of the decimated samples
+fn widen(table: &InputCalibration) -> InputCalibration {
+	let mut points = [Point::default(); MAX_POINTS];
+	for (wide, point) in points.iter_mut().zip(table.points()) {
+		*wide = Point::new(point.code << 4, point.millivolts);
+	}
+	// Scaling the codes keeps their order and spacing
+	InputCalibration::new(&points[..table.points().len()]).unwrap()
+}
+
+#[cfg(test)]
+pub mod test {
+	use super::super::modes::Modes;
+	use super::super::wire::Writer;
+	use super::super::{decode, mode_tag, Config};
+	use super::*;
+
+	// As the version 1 firmware wrote it, `config`'s input codes back to 12 bits
+	pub fn encode(config: &Config, buffer: &mut [u8]) -> usize {
+		let mut calibration = config.calibration;
+		for channel in 0..MAX_CHANNELS {
+			let points: Vec<_> = calibration
+				.inputs
+				.get(channel)
+				.points()
+				.iter()
+				.map(|point| Point::new(point.code >> 4, point.millivolts))
+				.collect();
+			calibration.inputs.set(channel, InputCalibration::new(&points).unwrap());
+		}
+
+		let mut w = Writer::new(buffer);
+		w.u8(1);
+		w.u32(config.sample_rate);
+		w.u8(mode_tag(config.adc_mode));
+		wire::write_channel_map(&mut w, &config.channels);
+		wire::write_calibration(&mut w, &calibration);
+		w.finish().unwrap()
+	}
+
+	#[test]
+	fn round_trips_to_the_current_config() {
+		let config = super::super::test::config();
+		let mut buffer = [0; super::super::MAX_SIZE];
+		let len = encode(&config, &mut buffer);
+		assert_eq!(&buffer[..6], &[1, 0x80, 0x3E, 0, 0, 1]);
+
+		let migrated = decode(&buffer[..len]).unwrap();
+		assert_eq!(migrated, Config { oversampling: 1, modes: Modes::default(), ..config });
+		assert_eq!(migrated.calibration.inputs.get(3).points()[1], Point::new(32768, 0));
+		assert_eq!(decode(&buffer[..len - 1]), Err(Error::Truncated));
+	}
+
+	#[test]
+	fn rejects_codes_beyond_12_bits() {
+		let mut config = super::super::test::config();
+		config.calibration.inputs.set(0, InputCalibration::new(&[Point::new(0, 0), Point::new(65520, 10)]).unwrap());
+		let mut buffer = [0; super::super::MAX_SIZE];
+		let len = encode(&config, &mut buffer);
+		assert!(decode(&buffer[..len]).is_ok());
+
+		// Input 0's second point, the table length and the first point before it
+		let code = 6 + super::super::wire::CHANNEL_MAP_SIZE + 1 + 6;
+		assert_eq!(&buffer[code..code + 2], &[0xFF, 0x0F]);
+		buffer[code + 1] = 0x10;
+		assert_eq!(decode(&buffer[..len]), Err(Error::Invalid));
+	}
+}
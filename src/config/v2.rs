@@ -0,0 +1,72 @@
+//! Version 2, without the modes of the outputs and inputs.
+
+use crate::adc::{ChannelMap, Mode};
+use crate::calibration::Calibration;
+
+use super::modes::Modes;
+use super::wire::{self, Reader};
+use super::{mode_from_tag, Config, Error};
+
+pub struct V2 {
+	pub sample_rate: u32,
+	pub adc_mode: Mode,
+	pub oversampling: usize,
+	pub channels: ChannelMap,
+	pub calibration: Calibration,
+}
+
+pub fn decode(bytes: &[u8]) -> Result<V2, Error> {
+	let mut r = Reader::new(&bytes[1..]);
+	let sample_rate = r.u32()?;
+	let adc_mode = mode_from_tag(r.u8()?)?;
+	let oversampling = r.u8()? as usize;
+	let channels = wire::read_channel_map(&mut r)?;
+	let calibration = wire::read_calibration(&mut r)?;
+	r.finish()?;
+
+	Ok(V2 { sample_rate, adc_mode, oversampling, channels, calibration })
+}
+
+/// Modes were not kept, every output and input starts as plain CV
+pub fn migrate(v2: V2) -> Config {
+	Config {
+		sample_rate: v2.sample_rate,
+		adc_mode: v2.adc_mode,
+		oversampling: v2.oversampling,
+		channels: v2.channels,
+		calibration: v2.calibration,
+		modes: Modes::default(),
+	}
+}
+
+#[cfg(test)]
+pub mod test {
+	use super::super::wire::Writer;
+	use super::super::{decode, mode_tag, MAX_SIZE};
+	use super::*;
+
+	// As the version 2 firmware wrote it, without `config`'s modes
+	pub fn encode(config: &Config, buffer: &mut [u8]) -> usize {
+		let mut w = Writer::new(buffer);
+		w.u8(2);
+		w.u32(config.sample_rate);
+		w.u8(mode_tag(config.adc_mode));
+		w.u8(config.oversampling as u8);
+		wire::write_channel_map(&mut w, &config.channels);
+		wire::write_calibration(&mut w, &config.calibration);
+		w.finish().unwrap()
+	}
+
+	#[test]
+	fn round_trips_to_the_current_config() {
+		let config = super::super::test::config();
+		let mut buffer = [0; MAX_SIZE];
+		let len = encode(&config, &mut buffer);
+		assert_eq!(&buffer[..7], &[2, 0x80, 0x3E, 0, 0, 1, 4]);
+
+		let migrated = decode(&buffer[..len]).unwrap();
+		assert_eq!(migrated, Config { modes: Modes::default(), ..config });
+		assert_eq!(decode(&buffer[..len - 1]), Err(Error::Truncated));
+		assert_eq!(decode(&buffer[..len + 1]), Err(Error::TrailingBytes));
+	}
+}
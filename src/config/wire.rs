@@ -0,0 +1,409 @@
+//! Little endian encoding of the configuration sections.
+//!
+//! Each section is written the same way by every version that has not
+//! changed it. A version changing a section gets its own copy of the old
+//! reader in its module.
+
+use core::convert::TryInto;
+
+use crate::adc::{self, ChannelMap, MAX_CHANNELS};
+use crate::calibration::{
+	input, output, Calibration, InputCalibration, InputCalibrations, OutputCalibration, OutputCalibrations, MAX_POINTS,
+};
+use crate::clock_out::ClockOutConfig;
+use crate::filter::Kind;
+use crate::fixed::Volts;
+use crate::gate::GateConfig;
+use crate::hw::SampleTime;
+use crate::pwm::{Shaping, MAX_OUTPUTS};
+use crate::sample_hold::{self, HoldConfig, Source};
+
+use super::modes::{ClockInput, InputSettings, Modes, OutputMode, OutputSettings, QuantizerConfig, ScaleSource};
+use super::Error;
+
+// Indexed by the SMPx encoding
+const SAMPLE_TIMES: [SampleTime; 8] = [
+	SampleTime::Cycles3,
+	SampleTime::Cycles15,
+	SampleTime::Cycles28,
+	SampleTime::Cycles56,
+	SampleTime::Cycles84,
+	SampleTime::Cycles112,
+	SampleTime::Cycles144,
+	SampleTime::Cycles480,
+];
+
+// Channel count, channels, sample time of every ADC channel
+pub const CHANNEL_MAP_SIZE: usize = 1 + 2 * MAX_CHANNELS;
+// Point count and the points of one table
+const TABLE_SIZE: usize = 1 + MAX_POINTS * 6;
+pub const CALIBRATION_SIZE: usize = (MAX_CHANNELS + MAX_OUTPUTS) * TABLE_SIZE;
+// Threshold, hysteresis, minimum width
+const GATE_SIZE: usize = 4 + 4 + 4;
+// Source, trigger and mode of a hold, the longest output mode
+const HOLD_SIZE: usize = 3 + GATE_SIZE;
+// Dither, mode tag and the longest mode of every output, gate and filter of
+// every input, the clock input
+pub const MODES_SIZE: usize = MAX_OUTPUTS * (2 + HOLD_SIZE) + MAX_CHANNELS * (1 + GATE_SIZE + 1 + 4) + 1 + 4;
+
+// Stands for no input or output
+const NONE: u8 = 0xFF;
+
+pub struct Writer<'a> {
+	buffer: &'a mut [u8],
+	len: usize,
+	overflow: bool,
+}
+
+impl<'a> Writer<'a> {
+	pub fn new(buffer: &'a mut [u8]) -> Writer<'a> {
+		Writer { buffer, len: 0, overflow: false }
+	}
+
+	pub fn bytes(&mut self, bytes: &[u8]) {
+		match self.buffer.get_mut(self.len..self.len + bytes.len()) {
+			Some(space) => {
+				space.copy_from_slice(bytes);
+				self.len += bytes.len();
+			}
+			None => self.overflow = true,
+		}
+	}
+
+	pub fn u8(&mut self, value: u8) {
+		self.bytes(&[value]);
+	}
+
+	pub fn u16(&mut self, value: u16) {
+		self.bytes(&value.to_le_bytes());
+	}
+
+	pub fn u32(&mut self, value: u32) {
+		self.bytes(&value.to_le_bytes());
+	}
+
+	pub fn i32(&mut self, value: i32) {
+		self.bytes(&value.to_le_bytes());
+	}
+
+	/// Bytes written, an error if they did not all fit
+	pub fn finish(self) -> Result<usize, Error> {
+		if self.overflow { Err(Error::BufferTooSmall) } else { Ok(self.len) }
+	}
+}
+
+pub struct Reader<'a> {
+	data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+	pub fn new(data: &'a [u8]) -> Reader<'a> {
+		Reader { data }
+	}
+
+	fn take<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+		if self.data.len() < N {
+			return Err(Error::Truncated);
+		}
+		let (bytes, rest) = self.data.split_at(N);
+		self.data = rest;
+		Ok(bytes.try_into().unwrap())
+	}
+
+	pub fn u8(&mut self) -> Result<u8, Error> {
+		Ok(self.take::<1>()?[0])
+	}
+
+	pub fn u16(&mut self) -> Result<u16, Error> {
+		self.take().map(u16::from_le_bytes)
+	}
+
+	pub fn u32(&mut self) -> Result<u32, Error> {
+		self.take().map(u32::from_le_bytes)
+	}
+
+	pub fn i32(&mut self) -> Result<i32, Error> {
+		self.take().map(i32::from_le_bytes)
+	}
+
+	/// Everything must have been read
+	pub fn finish(self) -> Result<(), Error> {
+		if self.data.is_empty() { Ok(()) } else { Err(Error::TrailingBytes) }
+	}
+}
+
+pub fn write_channel_map(w: &mut Writer, map: &ChannelMap) {
+	w.u8(map.len() as u8);
+	for i in 0..MAX_CHANNELS {
+		w.u8(map.channels().get(i).copied().unwrap_or(0));
+	}
+	for channel in 0..MAX_CHANNELS as u8 {
+		w.u8(map.channel_sample_time(channel) as u8);
+	}
+}
+
+pub fn read_channel_map(r: &mut Reader) -> Result<ChannelMap, Error> {
+	let len = r.u8()? as usize;
+	let channels: [u8; MAX_CHANNELS] = r.take()?;
+	if len > MAX_CHANNELS {
+		return Err(Error::Channels(adc::Error::TooManyChannels));
+	}
+
+	let mut map = ChannelMap::new(&channels[..len], SampleTime::Cycles3).map_err(Error::Channels)?;
+	for channel in 0..MAX_CHANNELS as u8 {
+		let bits = r.u8()?;
+		let sample_time = *SAMPLE_TIMES.get(bits as usize).ok_or(Error::Invalid)?;
+		map = map.sample_time(channel, sample_time);
+	}
+	Ok(map)
+}
+
+pub fn write_calibration(w: &mut Writer, calibration: &Calibration) {
+	for channel in 0..MAX_CHANNELS {
+		let points = calibration.inputs.get(channel).points();
+		w.u8(points.len() as u8);
+		for point in points {
+			w.u16(point.code);
+			w.i32(point.millivolts);
+		}
+	}
+	for channel in 0..MAX_OUTPUTS {
+		let points = calibration.outputs.get(channel).points();
+		w.u8(points.len() as u8);
+		for point in points {
+			w.u16(point.code);
+			w.i32(point.microvolts);
+		}
+	}
+}
+
+// Point count of a table, checked against the space for it
+fn table_len(r: &mut Reader) -> Result<usize, Error> {
+	let len = r.u8()? as usize;
+	if len > MAX_POINTS {
+		return Err(Error::Invalid);
+	}
+	Ok(len)
+}
+
+fn read_input(r: &mut Reader) -> Result<InputCalibration, Error> {
+	let mut points = [input::Point::default(); MAX_POINTS];
+	let len = table_len(r)?;
+	for point in &mut points[..len] {
+		*point = input::Point::new(r.u16()?, r.i32()?);
+	}
+	InputCalibration::new(&points[..len]).map_err(Error::Calibration)
+}
+
+fn read_output(r: &mut Reader) -> Result<OutputCalibration, Error> {
+	let mut points = [output::Point::default(); MAX_POINTS];
+	let len = table_len(r)?;
+	for point in &mut points[..len] {
+		*point = output::Point::new(r.u16()?, r.i32()?);
+	}
+	OutputCalibration::new(&points[..len]).map_err(Error::Calibration)
+}
+
+pub fn read_calibration(r: &mut Reader) -> Result<Calibration, Error> {
+	let mut inputs = InputCalibrations::new(read_input(r)?);
+	for channel in 1..MAX_CHANNELS {
+		inputs.set(channel, read_input(r)?);
+	}
+	let mut outputs = OutputCalibrations::new(read_output(r)?);
+	for channel in 1..MAX_OUTPUTS {
+		outputs.set(channel, read_output(r)?);
+	}
+	Ok(Calibration { inputs, outputs })
+}
+
+// Tags are part of the format, never reuse one
+fn shaping_tag(shaping: Shaping) -> u8 {
+	match shaping {
+		Shaping::Off => 0,
+		Shaping::Tpdf => 1,
+		Shaping::FirstOrder => 2,
+		Shaping::SecondOrder => 3,
+	}
+}
+
+fn shaping_from_tag(tag: u8) -> Result<Shaping, Error> {
+	match tag {
+		0 => Ok(Shaping::Off),
+		1 => Ok(Shaping::Tpdf),
+		2 => Ok(Shaping::FirstOrder),
+		3 => Ok(Shaping::SecondOrder),
+		_ => Err(Error::Invalid),
+	}
+}
+
+fn kind_tag(kind: Kind) -> u8 {
+	match kind {
+		Kind::Off => 0,
+		Kind::OnePole => 1,
+		Kind::Butterworth => 2,
+		Kind::Bessel => 3,
+		Kind::MovingAverage => 4,
+	}
+}
+
+fn kind_from_tag(tag: u8) -> Result<Kind, Error> {
+	match tag {
+		0 => Ok(Kind::Off),
+		1 => Ok(Kind::OnePole),
+		2 => Ok(Kind::Butterworth),
+		3 => Ok(Kind::Bessel),
+		4 => Ok(Kind::MovingAverage),
+		_ => Err(Error::Invalid),
+	}
+}
+
+// An input or output number, checked against `count`
+fn index(r: &mut Reader, count: usize) -> Result<usize, Error> {
+	let index = r.u8()? as usize;
+	if index >= count {
+		return Err(Error::Invalid);
+	}
+	Ok(index)
+}
+
+fn optional_index(r: &mut Reader, count: usize) -> Result<Option<usize>, Error> {
+	match r.u8()? {
+		NONE => Ok(None),
+		index if (index as usize) < count => Ok(Some(index as usize)),
+		_ => Err(Error::Invalid),
+	}
+}
+
+fn write_gate(w: &mut Writer, gate: &GateConfig) {
+	w.i32(gate.threshold.to_bits());
+	w.i32(gate.hysteresis.to_bits());
+	w.u32(gate.min_width);
+}
+
+fn read_gate(r: &mut Reader) -> Result<GateConfig, Error> {
+	Ok(GateConfig {
+		threshold: Volts::from_bits(r.i32()?),
+		hysteresis: Volts::from_bits(r.i32()?),
+		min_width: r.u32()?,
+	})
+}
+
+// Ratios, swing and width all fit a byte when valid
+fn write_output_mode(w: &mut Writer, mode: &OutputMode) {
+	match mode {
+		OutputMode::Off => w.u8(0),
+		OutputMode::Clock(clock) => {
+			w.u8(1);
+			w.bytes(&[clock.multiply as u8, clock.divide as u8, clock.swing as u8, clock.width as u8]);
+		}
+		OutputMode::Quantize(quantizer) => {
+			w.u8(2);
+			w.u8(quantizer.input as u8);
+			match quantizer.scale {
+				ScaleSource::Mask(mask) => {
+					w.u8(0);
+					w.u16(mask);
+				}
+				ScaleSource::Tuning => w.u8(1),
+			}
+			w.i32(quantizer.transpose);
+			w.u8(quantizer.trigger.map_or(NONE, |trigger| trigger as u8));
+		}
+		OutputMode::Hold(hold) => {
+			w.u8(3);
+			w.u8(match hold.source {
+				Source::Input(input) => input as u8,
+				Source::Noise => NONE,
+			});
+			w.u8(hold.trigger as u8);
+			w.u8(match hold.mode {
+				sample_hold::Mode::SampleHold => 0,
+				sample_hold::Mode::TrackHold => 1,
+			});
+			write_gate(w, &hold.gate);
+		}
+	}
+}
+
+fn read_output_mode(r: &mut Reader) -> Result<OutputMode, Error> {
+	Ok(match r.u8()? {
+		0 => OutputMode::Off,
+		1 => {
+			let [multiply, divide, swing, width] = r.take()?;
+			OutputMode::Clock(ClockOutConfig {
+				multiply: multiply as u32,
+				divide: divide as u32,
+				swing: swing as u32,
+				width: width as u32,
+			})
+		}
+		2 => {
+			let input = index(r, MAX_CHANNELS)?;
+			let scale = match r.u8()? {
+				0 => ScaleSource::Mask(r.u16()?),
+				1 => ScaleSource::Tuning,
+				_ => return Err(Error::Invalid),
+			};
+			let transpose = r.i32()?;
+			let trigger = optional_index(r, MAX_OUTPUTS)?;
+			OutputMode::Quantize(QuantizerConfig { input, scale, transpose, trigger })
+		}
+		3 => {
+			let source = optional_index(r, MAX_CHANNELS)?.map_or(Source::Noise, Source::Input);
+			let trigger = index(r, MAX_CHANNELS)?;
+			let mode = match r.u8()? {
+				0 => sample_hold::Mode::SampleHold,
+				1 => sample_hold::Mode::TrackHold,
+				_ => return Err(Error::Invalid),
+			};
+			OutputMode::Hold(HoldConfig { source, trigger, mode, gate: read_gate(r)? })
+		}
+		_ => return Err(Error::Invalid),
+	})
+}
+
+pub fn write_modes(w: &mut Writer, modes: &Modes) {
+	for settings in &modes.outputs {
+		w.u8(shaping_tag(settings.dither));
+		write_output_mode(w, &settings.mode);
+	}
+	for settings in &modes.inputs {
+		match &settings.gate {
+			Some(gate) => {
+				w.u8(1);
+				write_gate(w, gate);
+			}
+			None => w.u8(0),
+		}
+		w.u8(kind_tag(settings.filter));
+		w.u32(settings.cutoff.to_bits());
+	}
+	match modes.clock {
+		Some(clock) => {
+			w.u8(clock.input as u8);
+			w.u32(clock.ppqn);
+		}
+		None => w.u8(NONE),
+	}
+}
+
+pub fn read_modes(r: &mut Reader) -> Result<Modes, Error> {
+	let mut modes = Modes::default();
+	for settings in modes.outputs.iter_mut() {
+		let dither = shaping_from_tag(r.u8()?)?;
+		*settings = OutputSettings { mode: read_output_mode(r)?, dither };
+	}
+	for settings in modes.inputs.iter_mut() {
+		let gate = match r.u8()? {
+			0 => None,
+			1 => Some(read_gate(r)?),
+			_ => return Err(Error::Invalid),
+		};
+		let filter = kind_from_tag(r.u8()?)?;
+		*settings = InputSettings { gate, filter, cutoff: f32::from_bits(r.u32()?) };
+	}
+	if let Some(input) = optional_index(r, MAX_CHANNELS)? {
+		modes.clock = Some(ClockInput { input, ppqn: r.u32()? });
+	}
+	Ok(modes)
+}
@@ -0,0 +1,139 @@
+//! Line oriented text console on a serial port.
+//!
+//! Typed characters are echoed back and collected until return, backspace
+//! removes the last one. Output goes through `core::fmt::Write`, newlines
+//! are sent as CR LF for plain terminal programs.
+
+use core::fmt;
+use core::str;
+
+use crate::hw::Serial;
+
+// Longest line kept, further characters are dropped
+pub const LINE_LEN: usize = 64;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+
+pub struct Console<S> {
+	serial: S,
+	line: [u8; LINE_LEN],
+	len: usize,
+	// A complete line is waiting in `line`
+	complete: bool,
+	// Last line ended with CR, a LF right after it is part of the same end
+	after_cr: bool,
+}
+
+impl<S: Serial> Console<S> {
+	pub fn new(serial: S) -> Console<S> {
+		Console { serial, line: [0; LINE_LEN], len: 0, complete: false, after_cr: false }
+	}
+
+	/// Read what arrived, a complete line once return was pressed.
+	///
+	/// The line is trimmed and stays valid until the next `poll`.
+	pub fn poll(&mut self) -> Option<&str> {
+		if self.complete {
+			self.complete = false;
+			self.len = 0;
+		}
+
+		while let Some(byte) = self.serial.read() {
+			let after_cr = self.after_cr;
+			self.after_cr = byte == b'\r';
+			match byte {
+				b'\n' if after_cr => {}
+				b'\r' | b'\n' => {
+					self.send(b"\r\n");
+					self.complete = true;
+					break;
+				}
+				BACKSPACE | DELETE if self.len > 0 => {
+					self.len -= 1;
+					self.send(b"\x08 \x08");
+				}
+				// Printable ASCII only
+				0x20..=0x7E if self.len < LINE_LEN => {
+					self.line[self.len] = byte;
+					self.len += 1;
+					self.send(&[byte]);
+				}
+				_ => {}
+			}
+		}
+
+		if !self.complete {
+			return None;
+		}
+		// Only ASCII is stored
+		str::from_utf8(&self.line[..self.len]).ok().map(str::trim)
+	}
+
+	fn send(&mut self, bytes: &[u8]) {
+		for &byte in bytes {
+			while !self.serial.write(byte) {}
+		}
+	}
+
+	pub fn serial(&mut self) -> &mut S {
+		&mut self.serial
+	}
+}
+
+impl<S: Serial> fmt::Write for Console<S> {
+	fn write_str(&mut self, text: &str) -> fmt::Result {
+		for (i, part) in text.split('\n').enumerate() {
+			if i > 0 {
+				self.send(b"\r\n");
+			}
+			self.send(part.as_bytes());
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use core::fmt::Write;
+
+	use super::*;
+	use crate::hw::mock::Board;
+
+	#[test]
+	fn lines_are_echoed_and_edited() {
+		let board = Board::new(0);
+		let mut console = Console::new(board.serial());
+
+		board.type_text("cak");
+		assert_eq!(console.poll(), None);
+		board.type_text("\x08l \r\n");
+		assert_eq!(console.poll(), Some("cal"));
+		assert_eq!(board.take_serial_output(), "cak\x08 \x08l \r\n");
+
+		// The LF after CR is not an empty line
+		assert_eq!(console.poll(), None);
+		// LF alone ends a line too
+		board.type_text("\n");
+		assert_eq!(console.poll(), Some(""));
+	}
+
+	#[test]
+	fn long_lines_are_cut() {
+		let board = Board::new(0);
+		let mut console = Console::new(board.serial());
+
+		board.type_text(&"x".repeat(LINE_LEN + 10));
+		board.type_text("\r");
+		assert_eq!(console.poll().map(str::len), Some(LINE_LEN));
+	}
+
+	#[test]
+	fn newlines_are_crlf() {
+		let board = Board::new(0);
+		let mut console = Console::new(board.serial());
+
+		write!(console, "a\nb\n").unwrap();
+		assert_eq!(board.take_serial_output(), "a\r\nb\r\n");
+	}
+}
@@ -0,0 +1,330 @@
+//! Ownership of the two DMA sample buffers.
+//!
+//! The DMA writes one half while the application reads the other. On every
+//! transfer complete interrupt the stream has switched target (CT bit) and
+//! the half it just left is complete. `swap` records that, `take` lends the
+//! completed half out until the returned `Half` is dropped.
+//!
+//! If the application still holds a half when the DMA switches back to it,
+//! the DMA is writing into data being read. That is counted as an overrun.
+//!
+//! Output streams run the other way round, the DMA reads from the buffers.
+//! `take_mut` lends the half the DMA just finished reading so the next
+//! frames can be written into it, and an overrun means the DMA started
+//! reading a half that was still being written.
+
+use core::ops::{Deref, DerefMut};
+use core::slice;
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+use crate::hw::Target;
+
+const NONE: u8 = 0;
+const M0: u8 = 1;
+const M1: u8 = 2;
+
+fn encode(target: Target) -> u8 {
+	match target {
+		Target::M0 => M0,
+		Target::M1 => M1,
+	}
+}
+
+fn index(target: Target) -> usize {
+	match target {
+		Target::M0 => 0,
+		Target::M1 => 1,
+	}
+}
+
+pub struct DoubleBuffer {
+	buffers: [*mut u16; 2],
+	len: usize,
+	// Completed half not yet taken
+	ready: AtomicU8,
+	// Half lent to the application
+	lent: AtomicU8,
+	// Times the DMA started writing each half
+	entered: [AtomicU32; 2],
+	swaps: AtomicU32,
+	overruns: AtomicU32,
+}
+
+// Shared between the DMA interrupt and the application, all state is atomic
+unsafe impl Sync for DoubleBuffer {}
+
+impl DoubleBuffer {
+	pub fn new(m0: &'static mut [u16], m1: &'static mut [u16]) -> DoubleBuffer {
+		assert!(m0.len() == m1.len(), "both halves must be the same length");
+
+		DoubleBuffer {
+			len: m0.len(),
+			buffers: [m0.as_mut_ptr(), m1.as_mut_ptr()],
+			ready: AtomicU8::new(NONE),
+			lent: AtomicU8::new(NONE),
+			entered: [AtomicU32::new(0), AtomicU32::new(0)],
+			swaps: AtomicU32::new(0),
+			overruns: AtomicU32::new(0),
+		}
+	}
+
+	// Memory addresses for the DMA stream (M0AR, M1AR)
+	pub fn m0(&self) -> *mut u16 {
+		self.buffers[0]
+	}
+
+	pub fn m1(&self) -> *mut u16 {
+		self.buffers[1]
+	}
+
+	// Length of each half
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	/// Transfer complete. `current` is the half the DMA now writes to.
+	pub fn swap(&self, current: Target) {
+		self.swaps.fetch_add(1, Ordering::Relaxed);
+		self.enter(current);
+		self.ready.store(encode(current.other()), Ordering::Release);
+	}
+
+	/// The DMA was re-initialised and starts over at the beginning of M0.
+	///
+	/// Whatever it had written into the half it was filling is dropped, as
+	/// is a completed M0 not yet taken.
+	pub fn restart(&self) {
+		self.enter(Target::M0);
+		let _ = self.ready.compare_exchange(M0, NONE, Ordering::AcqRel, Ordering::Acquire);
+	}
+
+	fn enter(&self, target: Target) {
+		self.entered[index(target)].fetch_add(1, Ordering::AcqRel);
+
+		if self.lent.load(Ordering::Acquire) == encode(target) {
+			self.overruns.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+
+	/// Borrow the most recently completed half.
+	///
+	/// `None` if nothing completed since the last take, or the previous
+	/// half is still borrowed.
+	pub fn take(&self) -> Option<Half<'_>> {
+		if self.lent.load(Ordering::Acquire) != NONE {
+			return None;
+		}
+
+		let target = match self.ready.swap(NONE, Ordering::AcqRel) {
+			M0 => Target::M0,
+			M1 => Target::M1,
+			_ => return None,
+		};
+
+		self.lent.store(encode(target), Ordering::Release);
+		let i = index(target);
+		Some(Half {
+			owner: self,
+			target,
+			taken_at: self.entered[i].load(Ordering::Acquire),
+		})
+	}
+
+	/// Borrow the most recently completed half for writing, see `take`.
+	pub fn take_mut(&self) -> Option<HalfMut<'_>> {
+		self.take().map(HalfMut)
+	}
+
+	/// Number of times the DMA switched into a half still borrowed
+	pub fn overruns(&self) -> u32 {
+		self.overruns.load(Ordering::Relaxed)
+	}
+
+	pub fn swaps(&self) -> u32 {
+		self.swaps.load(Ordering::Relaxed)
+	}
+}
+
+/// A completed half, returned to the `DoubleBuffer` on drop.
+pub struct Half<'a> {
+	owner: &'a DoubleBuffer,
+	target: Target,
+	taken_at: u32,
+}
+
+impl<'a> Half<'a> {
+	pub fn target(&self) -> Target {
+		self.target
+	}
+
+	/// False once the DMA has moved back into this half
+	pub fn intact(&self) -> bool {
+		self.owner.entered[index(self.target)].load(Ordering::Acquire) == self.taken_at
+	}
+}
+
+impl<'a> Deref for Half<'a> {
+	type Target = [u16];
+
+	fn deref(&self) -> &[u16] {
+		let owner = self.owner;
+		unsafe { slice::from_raw_parts(owner.buffers[index(self.target)], owner.len) }
+	}
+}
+
+impl<'a> Drop for Half<'a> {
+	fn drop(&mut self) {
+		self.owner.lent.store(NONE, Ordering::Release);
+	}
+}
+
+/// A completed half lent out for writing, returned on drop.
+pub struct HalfMut<'a>(Half<'a>);
+
+impl<'a> HalfMut<'a> {
+	pub fn target(&self) -> Target {
+		self.0.target()
+	}
+
+	/// False once the DMA has moved back into this half
+	pub fn intact(&self) -> bool {
+		self.0.intact()
+	}
+}
+
+impl<'a> Deref for HalfMut<'a> {
+	type Target = [u16];
+
+	fn deref(&self) -> &[u16] {
+		&self.0
+	}
+}
+
+impl<'a> DerefMut for HalfMut<'a> {
+	// The half is lent to only one borrower at a time
+	fn deref_mut(&mut self) -> &mut [u16] {
+		let owner = self.0.owner;
+		unsafe { slice::from_raw_parts_mut(owner.buffers[index(self.0.target)], owner.len) }
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn buffers(len: usize) -> DoubleBuffer {
+		DoubleBuffer::new(
+			Box::leak(vec![0u16; len].into_boxed_slice()),
+			Box::leak(vec![1u16; len].into_boxed_slice()),
+		)
+	}
+
+	#[test]
+	fn lends_completed_half() {
+		let buffers = buffers(4);
+		assert!(buffers.take().is_none());
+
+		// DMA filled M0 and moved on to M1
+		buffers.swap(Target::M1);
+		let half = buffers.take().unwrap();
+		assert_eq!(half.target(), Target::M0);
+		assert_eq!(&*half, &[0, 0, 0, 0]);
+		assert!(half.intact());
+		drop(half);
+
+		// Taken once per swap
+		assert!(buffers.take().is_none());
+
+		buffers.swap(Target::M0);
+		let half = buffers.take().unwrap();
+		assert_eq!(half.target(), Target::M1);
+		assert_eq!(&*half, &[1, 1, 1, 1]);
+		assert_eq!(buffers.overruns(), 0);
+	}
+
+	#[test]
+	fn holding_across_swap_is_an_overrun() {
+		let buffers = buffers(2);
+
+		buffers.swap(Target::M1);
+		let half = buffers.take().unwrap();
+
+		// DMA completes M1 and switches back into the half being read
+		buffers.swap(Target::M0);
+		assert_eq!(buffers.overruns(), 1);
+		assert!(!half.intact());
+
+		// The newer half is not handed out while the old one is held
+		assert!(buffers.take().is_none());
+		drop(half);
+
+		let half = buffers.take().unwrap();
+		assert_eq!(half.target(), Target::M1);
+		drop(half);
+
+		buffers.swap(Target::M1);
+		assert_eq!(buffers.overruns(), 1);
+		assert_eq!(buffers.swaps(), 3);
+	}
+
+	#[test]
+	fn restart_drops_m0() {
+		let buffers = buffers(2);
+
+		// M1 is held while the DMA restarts into M0
+		buffers.swap(Target::M1);
+		buffers.swap(Target::M0);
+		let half = buffers.take().unwrap();
+		assert_eq!(half.target(), Target::M1);
+		buffers.restart();
+		assert!(half.intact());
+		assert_eq!(buffers.overruns(), 0);
+		drop(half);
+
+		// A completed M0 not yet taken is gone after the restart
+		buffers.swap(Target::M1);
+		buffers.restart();
+		assert!(buffers.take().is_none());
+
+		// A held M0 is overwritten
+		buffers.swap(Target::M1);
+		let half = buffers.take().unwrap();
+		buffers.restart();
+		assert!(!half.intact());
+		assert_eq!(buffers.overruns(), 1);
+	}
+
+	#[test]
+	fn output_halves_are_written() {
+		let buffers = buffers(2);
+
+		// DMA read M0 and moved on to M1, M0 gets the next frames
+		buffers.swap(Target::M1);
+		{
+			let mut half = buffers.take_mut().unwrap();
+			assert_eq!(half.target(), Target::M0);
+			half.copy_from_slice(&[5, 6]);
+			assert!(buffers.take().is_none());
+		}
+
+		buffers.swap(Target::M0);
+		buffers.swap(Target::M1);
+		assert_eq!(&*buffers.take().unwrap(), &[5, 6]);
+	}
+
+	#[test]
+	fn released_before_swap_is_fine() {
+		let buffers = buffers(2);
+
+		for i in 0..10 {
+			buffers.swap(if i % 2 == 0 { Target::M1 } else { Target::M0 });
+			let half = buffers.take().unwrap();
+			assert!(half.intact());
+		}
+		assert_eq!(buffers.overruns(), 0);
+	}
+}
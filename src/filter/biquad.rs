@@ -0,0 +1,151 @@
+//! Second order low pass sections, direct form I.
+//!
+//! Coefficients follow the RBJ audio EQ cookbook low pass and are stored in
+//! Q30, the accumulator is 64 bit. The quantised numerator is derived from
+//! the quantised denominator so that `b0 + b1 + b2 = 1 + a1 + a2` holds
+//! exactly and the gain at DC is one. The part of every output shifted out
+//! is fed back into the next accumulation, a constant input settles to
+//! exactly itself instead of to a limit cycle around it.
+
+use core::f64::consts::PI;
+
+use super::design::{cos, round, sin};
+use super::{Error, FRACTION};
+
+const ONE: i64 = 1 << 30;
+
+// Q of the analog prototypes
+const BUTTERWORTH_Q: f64 = core::f64::consts::FRAC_1_SQRT_2;
+const BESSEL_Q: f64 = 0.577_350_269_189_625_8;
+// Bessel prototype frequency with -3 dB at 1 rad/s
+const BESSEL_SCALE: f64 = 1.272_019_649_514_069;
+
+// Smallest b0 + b1 + b2 kept, below it the poles are too coarse to trust
+const MIN_GAIN: i64 = 64;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Biquad {
+	// Q30, b0 b1 b2 and a1 a2 of 1 + a1 z^-1 + a2 z^-2
+	b: [i64; 3],
+	a: [i64; 2],
+	// Last two inputs and outputs, FRACTION extra bits
+	x: [i32; 2],
+	y: [i32; 2],
+	error: i64,
+}
+
+impl Biquad {
+	/// Maximally flat pass band, 4 % overshoot on steps
+	pub fn butterworth(cutoff: f64) -> Result<Biquad, Error> {
+		Biquad::low_pass(cutoff, BUTTERWORTH_Q)
+	}
+
+	/// Maximally flat group delay, steps settle with next to no overshoot
+	pub fn bessel(cutoff: f64) -> Result<Biquad, Error> {
+		Biquad::low_pass(cutoff * BESSEL_SCALE, BESSEL_Q)
+	}
+
+	// `cutoff` relative to the sample rate
+	fn low_pass(cutoff: f64, q: f64) -> Result<Biquad, Error> {
+		if cutoff <= 0.0 {
+			return Err(Error::CutoffTooLow);
+		}
+		if cutoff >= 0.5 {
+			return Err(Error::CutoffTooHigh);
+		}
+
+		let w0 = 2.0 * PI * cutoff;
+		let alpha = sin(w0) / (2.0 * q);
+		let a0 = 1.0 + alpha;
+		let a1 = round(-2.0 * cos(w0) / a0 * ONE as f64);
+		let a2 = round((1.0 - alpha) / a0 * ONE as f64);
+
+		let gain = ONE + a1 + a2;
+		if gain < MIN_GAIN {
+			return Err(Error::CutoffTooLow);
+		}
+		let b0 = (gain + 2) / 4;
+		let b1 = gain - 2 * b0;
+
+		Ok(Biquad { b: [b0, b1, b0], a: [a1, a2], x: [0; 2], y: [0; 2], error: 0 })
+	}
+
+	pub fn process(&mut self, x: i32) -> i32 {
+		let x = x << FRACTION;
+		let acc = self.b[0] * x as i64 + self.b[1] * self.x[0] as i64 + self.b[2] * self.x[1] as i64
+			- self.a[0] * self.y[0] as i64
+			- self.a[1] * self.y[1] as i64
+			+ self.error;
+		let y = acc >> 30;
+		self.error = acc - (y << 30);
+
+		self.x = [x, self.x[0]];
+		self.y = [y as i32, self.y[0]];
+		(y as i32 + (1 << (FRACTION - 1))) >> FRACTION
+	}
+
+	/// Settle at `x`
+	pub fn reset(&mut self, x: i32) {
+		let x = x << FRACTION;
+		self.x = [x; 2];
+		self.y = [x; 2];
+		self.error = 0;
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::filter::test::gain_db;
+
+	#[test]
+	fn half_power_at_the_cutoff() {
+		for &cutoff in &[0.001, 0.01, 0.1, 0.3] {
+			let mut filter = Biquad::butterworth(cutoff).unwrap();
+			let gain = gain_db(|x| filter.process(x), cutoff);
+			assert!((gain + 3.0).abs() < 0.1, "Butterworth {} at {}", gain, cutoff);
+		}
+		for &cutoff in &[0.001, 0.01, 0.05] {
+			let mut filter = Biquad::bessel(cutoff).unwrap();
+			let gain = gain_db(|x| filter.process(x), cutoff);
+			assert!((gain + 3.0).abs() < 0.3, "Bessel {} at {}", gain, cutoff);
+		}
+
+		// 12 dB per octave
+		let mut filter = Biquad::butterworth(0.01).unwrap();
+		assert!(gain_db(|x| filter.process(x), 0.08) < -35.0);
+	}
+
+	#[test]
+	fn unity_at_dc() {
+		for &cutoff in &[1e-4, 0.001, 0.1, 0.45] {
+			let mut filter = Biquad::butterworth(cutoff).unwrap();
+			let mut y = 0;
+			for _ in 0..500_000 {
+				y = filter.process(4095);
+			}
+			assert_eq!(y, 4095, "at {}", cutoff);
+			for _ in 0..500_000 {
+				y = filter.process(1);
+			}
+			assert_eq!(y, 1, "at {}", cutoff);
+		}
+	}
+
+	#[test]
+	fn bessel_step_barely_overshoots() {
+		let peak = |mut filter: Biquad| (0..1000).map(|_| filter.process(4000)).max().unwrap();
+
+		assert!(peak(Biquad::butterworth(0.01).unwrap()) > 4150);
+		assert!(peak(Biquad::bessel(0.01).unwrap()) < 4040);
+	}
+
+	#[test]
+	fn rejects_bad_cutoffs() {
+		assert_eq!(Biquad::butterworth(0.0).err(), Some(Error::CutoffTooLow));
+		assert_eq!(Biquad::butterworth(1e-6).err(), Some(Error::CutoffTooLow));
+		assert_eq!(Biquad::butterworth(0.5).err(), Some(Error::CutoffTooHigh));
+		// Scaled past Nyquist
+		assert_eq!(Biquad::bessel(0.4).err(), Some(Error::CutoffTooHigh));
+	}
+}
@@ -0,0 +1,292 @@
+//! Oversampling, decimation of the raw ADC codes to 16 bit samples.
+//!
+//! The sample clock runs `ratio` times faster than the rate wanted for the
+//! inputs, `Allocation::fits` with the faster rate tells whether the ADCs
+//! keep up. In interleaved mode all three codes of a tick count, the one
+//! input decimates by three times the ratio. Every input's stream then goes
+//! through a third order CIC decimator: three integrators at the ADC rate,
+//! one sample in `ratio` kept, three combs at the output rate. The
+//! integrators wrap, which the combs undo as long as the register holds
+//! `12 + 3 log2(ratio)` bits.
+//!
+//! The result is scaled to 16 bits, code `c` held steady gives `16 c`, the
+//! offset binary form of `Sample::from_code(c)`. With a little noise on the
+//! input (half an LSB or more, the ADC's own is plenty) averaging gains half
+//! a bit per doubling of the ratio, 14 bits at a ratio of 16 and close to 15
+//! at 64. The CIC droops towards the output Nyquist frequency, which a CV
+//! input rarely reaches.
+
+use crate::adc::{Allocation, Mode, MAX_CHANNELS};
+use crate::fixed::Sample;
+
+use super::Error;
+
+/// Highest oversampling ratio, 30 bit registers
+pub const MAX_RATIO: usize = 64;
+
+const ORDER: usize = 3;
+
+// ADC codes are 12 bit, 0 V sits at mid scale
+const MAX_CODE: u16 = 0x0FFF;
+const MID_CODE: u16 = 0x0800;
+
+// Of the output scaling
+const SHIFT: u32 = 28;
+
+// Codes to 16 bit samples
+const GAIN: u64 = 16;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Cic {
+	integrators: [u32; ORDER],
+	// Previous comb inputs
+	delays: [u32; ORDER],
+	ratio: usize,
+	phase: usize,
+	// Q28 of 16 / ratio^3
+	scale: u64,
+}
+
+impl Cic {
+	pub fn new(ratio: usize) -> Result<Cic, Error> {
+		if ratio == 0 || ratio > MAX_RATIO {
+			return Err(Error::Ratio);
+		}
+		let gain = (ratio as u64).pow(ORDER as u32);
+		Ok(Cic {
+			integrators: [0; ORDER],
+			delays: [0; ORDER],
+			ratio,
+			phase: 0,
+			scale: ((GAIN << SHIFT) + gain / 2) / gain,
+		})
+	}
+
+	pub fn ratio(&self) -> usize {
+		self.ratio
+	}
+
+	/// Feed one code, a 16 bit sample every `ratio` codes
+	pub fn push(&mut self, code: u16) -> Option<u16> {
+		let mut x = code.min(MAX_CODE) as u32;
+		for integrator in &mut self.integrators {
+			*integrator = integrator.wrapping_add(x);
+			x = *integrator;
+		}
+
+		self.phase += 1;
+		if self.phase < self.ratio {
+			return None;
+		}
+		self.phase = 0;
+
+		for delay in &mut self.delays {
+			let y = x.wrapping_sub(*delay);
+			*delay = x;
+			x = y;
+		}
+		Some(((x as u64 * self.scale + (1 << (SHIFT - 1))) >> SHIFT) as u16)
+	}
+
+	/// Settle at `code`, the next outputs are `16 code`
+	pub fn reset(&mut self, code: u16) {
+		self.integrators = [0; ORDER];
+		self.delays = [0; ORDER];
+		self.phase = 0;
+		// The impulse response is ORDER (ratio - 1) + 1 codes long
+		for _ in 0..ORDER * self.ratio {
+			self.push(code);
+		}
+	}
+}
+
+/// A CIC decimator per input of the channel map.
+pub struct Decimator {
+	cics: [Cic; MAX_CHANNELS],
+	// Sample clock ticks per output frame
+	ratio: usize,
+	mode: Mode,
+}
+
+impl Decimator {
+	/// One output frame every `ratio` sample clock ticks of an ADC in
+	/// `mode`. Every input starts settled at mid scale, 0 V.
+	pub fn new(ratio: usize, mode: Mode) -> Result<Decimator, Error> {
+		let codes = if mode == Mode::TripleInterleaved { 3 * ratio } else { ratio };
+		let mut cic = Cic::new(codes)?;
+		cic.reset(MID_CODE);
+		Ok(Decimator { cics: [cic; MAX_CHANNELS], ratio, mode })
+	}
+
+	/// Sample clock ticks per output frame
+	pub fn ratio(&self) -> usize {
+		self.ratio
+	}
+
+	/// Decimate a completed buffer of frames.
+	///
+	/// `out` receives frames of one sample per input, in channel map order,
+	/// returns the number of frames written. A buffer of `n` ticks gives
+	/// `n / ratio` frames when `n` is a multiple of the ratio.
+	pub fn process(&mut self, buffer: &[u16], allocation: &Allocation, out: &mut [Sample]) -> usize {
+		let width = allocation.frame_width();
+		assert!(buffer.len().is_multiple_of(width), "buffer must hold whole frames");
+		assert!(allocation.mode() == self.mode, "decimator built for another ADC mode");
+
+		// The single input fills the whole frame
+		if self.mode == Mode::TripleInterleaved {
+			let cic = &mut self.cics[0];
+			let mut written = 0;
+			for &code in buffer {
+				if let Some(sample) = cic.push(code) {
+					out[written] = Sample::from_fine_code(sample);
+					written += 1;
+				}
+			}
+			return written;
+		}
+
+		let inputs = allocation.inputs();
+		let mut written = 0;
+		for frame in buffer.chunks_exact(width) {
+			// All inputs started together and output on the same frame
+			let mut complete = false;
+			for (input, cic) in self.cics.iter_mut().enumerate().take(inputs) {
+				if let Some(sample) = cic.push(frame[allocation.position(input)]) {
+					out[written * inputs + input] = Sample::from_fine_code(sample);
+					complete = true;
+				}
+			}
+			if complete {
+				written += 1;
+			}
+		}
+		written
+	}
+
+	/// Settle input `input` at `code`
+	pub fn reset(&mut self, input: usize, code: u16) {
+		self.cics[input].reset(code);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::adc::ChannelMap;
+	use crate::hw::SampleTime;
+
+	// xorshift32, noise with a standard deviation of `sigma` LSB
+	struct Noise(u32);
+
+	impl Noise {
+		fn uniform(&mut self) -> f64 {
+			self.0 ^= self.0 << 13;
+			self.0 ^= self.0 >> 17;
+			self.0 ^= self.0 << 5;
+			self.0 as f64 / u32::MAX as f64
+		}
+
+		// Sum of 12 uniforms, close enough to normal
+		fn gaussian(&mut self, sigma: f64) -> f64 {
+			((0..12).map(|_| self.uniform()).sum::<f64>() - 6.0) * sigma
+		}
+	}
+
+	// `level` in codes, with noise, quantised like the ADC does
+	fn convert(level: f64, noise: &mut Noise) -> u16 {
+		(level + noise.gaussian(1.0)).round().clamp(0.0, 4095.0) as u16
+	}
+
+	// Mean and RMS error of the settled 16 bit samples of a steady `level`
+	fn measure(ratio: usize, level: f64) -> (f64, f64) {
+		let mut noise = Noise(0x1234_5678);
+		let mut cic = Cic::new(ratio).unwrap();
+		let samples: Vec<f64> = (0..4000 * ratio)
+			.filter_map(|_| cic.push(convert(level, &mut noise)))
+			.skip(ORDER)
+			.map(|sample| sample as f64 / 16.0)
+			.collect();
+
+		let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+		let rms = (samples.iter().map(|s| (s - level) * (s - level)).sum::<f64>() / samples.len() as f64).sqrt();
+		(mean, rms)
+	}
+
+	#[test]
+	fn scales_to_16_bits() {
+		for &ratio in &[1, 3, 16, MAX_RATIO] {
+			let mut cic = Cic::new(ratio).unwrap();
+			let out: Vec<_> = (0..4 * ratio).filter_map(|_| cic.push(4095)).collect();
+			assert_eq!(out[ORDER..], [65520], "ratio {}", ratio);
+
+			cic.reset(1);
+			assert_eq!((0..ratio).filter_map(|_| cic.push(1)).collect::<Vec<_>>(), [16]);
+			cic.reset(0);
+			assert_eq!((0..ratio).filter_map(|_| cic.push(0)).collect::<Vec<_>>(), [0]);
+		}
+
+		assert_eq!(Cic::new(0).err(), Some(Error::Ratio));
+		assert_eq!(Cic::new(MAX_RATIO + 1).err(), Some(Error::Ratio));
+	}
+
+	#[test]
+	fn averages_out_noise() {
+		let (_, raw) = measure(1, 2047.3);
+		assert!(raw > 0.95 && raw < 1.1, "raw {}", raw);
+
+		// White noise falls with the square root of the ratio, times the
+		// sum of squares of the CIC impulse response
+		let (_, x16) = measure(16, 2047.3);
+		assert!(x16 < raw * 0.2, "ratio 16 {}", x16);
+		let (_, x64) = measure(64, 2047.3);
+		assert!(x64 < raw * 0.1, "ratio 64 {}", x64);
+	}
+
+	#[test]
+	fn resolves_fractions_of_a_code() {
+		// Quarter code steps, 14 bits
+		let levels = [1000.0, 1000.25, 1000.5, 1000.75, 1001.0];
+		let means: Vec<f64> = levels.iter().map(|&level| measure(16, level).0).collect();
+		for (mean, level) in means.iter().zip(&levels) {
+			assert!((mean - level).abs() < 0.03, "{} for {}", mean, level);
+		}
+		assert!(means.windows(2).all(|pair| pair[1] > pair[0]));
+	}
+
+	#[test]
+	fn decimates_each_input() {
+		let fine = |codes: &[u16]| -> Vec<Sample> { codes.iter().map(|&code| Sample::from_fine_code(code)).collect() };
+		let map = ChannelMap::new(&[0, 4, 8], SampleTime::Cycles3).unwrap();
+		let allocation = Allocation::new(&map, Mode::Independent).unwrap();
+
+		// Settled at 0 V from the start
+		let mut decimator = Decimator::new(4, Mode::Independent).unwrap();
+		let mut out = [Sample::MAX; 3];
+		assert_eq!(decimator.process(&[2048; 12], &allocation, &mut out), 1);
+		assert_eq!(out, [Sample::ZERO; 3]);
+
+		let mut decimator = Decimator::new(2, Mode::Independent).unwrap();
+		assert_eq!(decimator.ratio(), 2);
+		decimator.reset(0, 100);
+		decimator.reset(1, 4095);
+		decimator.reset(2, 10);
+
+		let buffer = [100, 4095, 10, 100, 4095, 12, 100, 4095, 12, 100, 4095, 12];
+		let mut out = [Sample::ZERO; 6];
+		assert_eq!(decimator.process(&buffer, &allocation, &mut out), 2);
+		// The step on the third input is 4 codes long at ratio 2, weighted 1 3 3 1
+		assert_eq!(out[..], fine(&[1600, 65520, 164, 1600, 65520, 188])[..]);
+
+		// Three codes a tick in interleaved mode, one frame a tick
+		let map = ChannelMap::new(&[2], SampleTime::Cycles3).unwrap();
+		let allocation = Allocation::new(&map, Mode::TripleInterleaved).unwrap();
+		let mut decimator = Decimator::new(1, Mode::TripleInterleaved).unwrap();
+		decimator.reset(0, 7);
+		let mut out = [Sample::ZERO; 2];
+		assert_eq!(decimator.process(&[7; 6], &allocation, &mut out), 2);
+		assert_eq!(out[..], fine(&[112, 112])[..]);
+
+		assert_eq!(Decimator::new(22, Mode::TripleInterleaved).err(), Some(Error::Ratio));
+	}
+}
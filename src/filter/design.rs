@@ -0,0 +1,73 @@
+//! Just enough floating point maths to design the filters, `core` has no
+//! trigonometric or exponential functions without `std`.
+
+use core::f64::consts::PI;
+
+/// Sine, for |x| <= pi
+pub fn sin(x: f64) -> f64 {
+	debug_assert!(x.abs() <= PI + 1e-9);
+
+	let mut term = x;
+	let mut sum = x;
+	let mut n = 1.0;
+	while term.abs() > 1e-17 {
+		term *= -x * x / ((n + 1.0) * (n + 2.0));
+		sum += term;
+		n += 2.0;
+	}
+	sum
+}
+
+/// Cosine, for |x| <= pi
+pub fn cos(x: f64) -> f64 {
+	debug_assert!(x.abs() <= PI + 1e-9);
+
+	let mut term: f64 = 1.0;
+	let mut sum = 1.0;
+	let mut n = 0.0;
+	while term.abs() > 1e-17 {
+		term *= -x * x / ((n + 1.0) * (n + 2.0));
+		sum += term;
+		n += 2.0;
+	}
+	sum
+}
+
+/// e^x, for |x| <= pi
+pub fn exp(x: f64) -> f64 {
+	debug_assert!(x.abs() <= PI + 1e-9);
+
+	// The series of |x| has no cancellation
+	let y = x.abs();
+	let mut term = 1.0;
+	let mut sum = 1.0;
+	let mut n = 1.0;
+	while term > 1e-17 * sum {
+		term *= y / n;
+		sum += term;
+		n += 1.0;
+	}
+	if x < 0.0 { 1.0 / sum } else { sum }
+}
+
+/// Nearest integer, halves away from zero
+pub fn round(x: f64) -> i64 {
+	if x < 0.0 { (x - 0.5) as i64 } else { (x + 0.5) as i64 }
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn matches_std() {
+		for i in -100..=100 {
+			let x = PI * i as f64 / 100.0;
+			assert!((sin(x) - x.sin()).abs() < 1e-14, "sin {}", x);
+			assert!((cos(x) - x.cos()).abs() < 1e-14, "cos {}", x);
+			assert!((exp(x) / x.exp() - 1.0).abs() < 1e-14, "exp {}", x);
+		}
+		assert_eq!(round(-2.5), -3);
+		assert_eq!(round(2.4), 2);
+	}
+}
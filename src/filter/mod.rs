@@ -0,0 +1,285 @@
+//! Input conditioning, a low pass filter per input.
+//!
+//! Every input can run through one of a one-pole, a second order
+//! Butterworth or Bessel section, or a moving average. `Decimator` first
+//! turns each DMA half buffer of raw ADC codes into one `Sample` per input
+//! and sample rate tick, averaging away the oversampling. The filters then
+//! work on those samples in place, before anything reads them. All of them
+//! run in integer arithmetic with `FRACTION` bits below the sample LSB kept
+//! between samples.
+//!
+//! Cutoffs are given in hertz and designed against the sample rate,
+//! changing it redesigns every filter.
+
+mod biquad;
+mod decimator;
+mod design;
+mod moving_average;
+mod one_pole;
+
+pub use self::biquad::Biquad;
+pub use self::decimator::{Cic, Decimator, MAX_RATIO};
+pub use self::moving_average::{MovingAverage, MAX_WINDOW};
+pub use self::one_pole::OnePole;
+
+use crate::adc::MAX_CHANNELS;
+use crate::fixed::Sample;
+
+// Extra bits kept by the recursive filters
+const FRACTION: u32 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+	// At or above Nyquist, or a window of a single sample
+	CutoffTooHigh,
+	// Zero, negative, or beyond what the coefficients or window can resolve
+	CutoffTooLow,
+	// Not an input of the channel map
+	NoSuchInput,
+	// Oversampling ratio of zero or above MAX_RATIO
+	Ratio,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+	Off,
+	OnePole,
+	Butterworth,
+	Bessel,
+	MovingAverage,
+}
+
+impl Kind {
+	/// Console name of the kind
+	pub fn name(self) -> &'static str {
+		match self {
+			Kind::Off => "off",
+			Kind::OnePole => "onepole",
+			Kind::Butterworth => "butterworth",
+			Kind::Bessel => "bessel",
+			Kind::MovingAverage => "average",
+		}
+	}
+
+	pub fn from_name(name: &str) -> Option<Kind> {
+		[Kind::Off, Kind::OnePole, Kind::Butterworth, Kind::Bessel, Kind::MovingAverage]
+			.iter()
+			.copied()
+			.find(|kind| kind.name() == name)
+	}
+}
+
+// No heap to box the window in, every input has room for the largest filter
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Copy, Debug)]
+pub enum Filter {
+	Off,
+	OnePole(OnePole),
+	Biquad(Biquad),
+	MovingAverage(MovingAverage),
+}
+
+impl Filter {
+	/// `cutoff` relative to the sample rate, ignored by `Kind::Off`
+	pub fn new(kind: Kind, cutoff: f64) -> Result<Filter, Error> {
+		Ok(match kind {
+			Kind::Off => Filter::Off,
+			Kind::OnePole => Filter::OnePole(OnePole::new(cutoff)?),
+			Kind::Butterworth => Filter::Biquad(Biquad::butterworth(cutoff)?),
+			Kind::Bessel => Filter::Biquad(Biquad::bessel(cutoff)?),
+			Kind::MovingAverage => Filter::MovingAverage(MovingAverage::new(cutoff)?),
+		})
+	}
+
+	pub fn process(&mut self, x: i32) -> i32 {
+		match self {
+			Filter::Off => x,
+			Filter::OnePole(filter) => filter.process(x),
+			Filter::Biquad(filter) => filter.process(x),
+			Filter::MovingAverage(filter) => filter.process(x),
+		}
+	}
+
+	/// Settle at `x`
+	pub fn reset(&mut self, x: i32) {
+		match self {
+			Filter::Off => {}
+			Filter::OnePole(filter) => filter.reset(x),
+			Filter::Biquad(filter) => filter.reset(x),
+			Filter::MovingAverage(filter) => filter.reset(x),
+		}
+	}
+
+	/// Filter samples in place, results clipped to the sample range
+	pub fn process_samples<'a>(&mut self, samples: impl Iterator<Item = &'a mut Sample>) {
+		if let Filter::Off = self {
+			return;
+		}
+		for sample in samples {
+			let y = self.process(sample.to_bits() as i32);
+			*sample = Sample::from_bits(y.clamp(i16::MIN as i32, i16::MAX as i32) as i16);
+		}
+	}
+}
+
+/// One filter per input of the channel map.
+pub struct FilterBank {
+	filters: [Filter; MAX_CHANNELS],
+	// Kind and cutoff in Hz of every input, to redesign on rate changes
+	settings: [(Kind, f32); MAX_CHANNELS],
+	sample_rate: u32,
+}
+
+impl FilterBank {
+	/// All filters off, `sample_rate` as for `set_sample_rate`
+	pub fn new(sample_rate: u32) -> FilterBank {
+		FilterBank {
+			filters: [Filter::Off; MAX_CHANNELS],
+			settings: [(Kind::Off, 0.0); MAX_CHANNELS],
+			sample_rate,
+		}
+	}
+
+	/// Samples per second of each input, after decimation.
+	///
+	/// Inputs whose cutoff no longer fits are turned off and the first such
+	/// error returned.
+	pub fn set_sample_rate(&mut self, sample_rate: u32) -> Result<(), Error> {
+		self.sample_rate = sample_rate;
+
+		let mut result = Ok(());
+		for input in 0..MAX_CHANNELS {
+			let (kind, cutoff) = self.settings[input];
+			if let Err(error) = self.set(input, kind, cutoff) {
+				self.settings[input] = (Kind::Off, 0.0);
+				self.filters[input] = Filter::Off;
+				result = result.and(Err(error));
+			}
+		}
+		result
+	}
+
+	pub fn sample_rate(&self) -> u32 {
+		self.sample_rate
+	}
+
+	/// Filter input `input` with a `cutoff` in Hz, the input keeps its
+	/// previous filter on errors
+	pub fn set(&mut self, input: usize, kind: Kind, cutoff: f32) -> Result<(), Error> {
+		if input >= MAX_CHANNELS {
+			return Err(Error::NoSuchInput);
+		}
+		self.filters[input] = Filter::new(kind, cutoff as f64 / self.sample_rate as f64)?;
+		self.settings[input] = (kind, cutoff);
+		Ok(())
+	}
+
+	/// Kind and cutoff in Hz of input `input`
+	pub fn get(&self, input: usize) -> (Kind, f32) {
+		self.settings[input]
+	}
+
+	pub fn filter(&mut self, input: usize) -> &mut Filter {
+		&mut self.filters[input]
+	}
+
+	/// Filter decimated frames of one sample per input in place
+	pub fn process(&mut self, frames: &mut [Sample], inputs: usize) {
+		assert!(inputs > 0 && frames.len().is_multiple_of(inputs), "buffer must hold whole frames");
+
+		for (input, filter) in self.filters.iter_mut().enumerate().take(inputs) {
+			filter.process_samples(frames.iter_mut().skip(input).step_by(inputs));
+		}
+	}
+}
+
+#[cfg(test)]
+pub mod test {
+	use super::*;
+	use std::f64::consts::PI;
+
+	const AMPLITUDE: f64 = 8000.0;
+
+	/// Gain in dB of a filter at `frequency` relative to the sample rate
+	pub fn gain_db(mut process: impl FnMut(i32) -> i32, frequency: f64) -> f64 {
+		let sample = |n: usize| AMPLITUDE * (2.0 * PI * frequency * n as f64).sin();
+
+		// Settled once the filter has seen a couple of thousand samples
+		// and several periods
+		let settle = 4000 + (10.0 / frequency) as usize;
+		for n in 0..settle {
+			process(sample(n).round() as i32);
+		}
+
+		// Correlate with both phases over many periods
+		let len = 20_000 + (20.0 / frequency) as usize;
+		let (mut i, mut q) = (0.0, 0.0);
+		for n in settle..settle + len {
+			let y = process(sample(n).round() as i32) as f64;
+			let phase = 2.0 * PI * frequency * n as f64;
+			i += y * phase.sin();
+			q += y * phase.cos();
+		}
+		let amplitude = 2.0 * (i * i + q * q).sqrt() / len as f64;
+		20.0 * (amplitude / AMPLITUDE).log10()
+	}
+
+	#[test]
+	fn designs_by_kind() {
+		assert_eq!(Kind::from_name("bessel"), Some(Kind::Bessel));
+		assert_eq!(Kind::from_name("average").map(Kind::name), Some("average"));
+		assert_eq!(Kind::from_name("fir"), None);
+
+		assert!(matches!(Filter::new(Kind::Off, 0.0), Ok(Filter::Off)));
+		assert!(matches!(Filter::new(Kind::OnePole, 0.01), Ok(Filter::OnePole(_))));
+		assert!(matches!(Filter::new(Kind::Bessel, 0.01), Ok(Filter::Biquad(_))));
+		assert!(matches!(Filter::new(Kind::MovingAverage, 0.01), Ok(Filter::MovingAverage(_))));
+
+		let mut filter = Filter::new(Kind::Butterworth, 0.3).unwrap();
+		filter.reset(i16::MAX as i32);
+		let mut samples = [Sample::MAX, Sample::MAX, Sample::MIN, Sample::MIN];
+		filter.process_samples(samples.iter_mut());
+		// The undershoot clips to the sample range
+		assert_eq!(samples[..2], [Sample::MAX, Sample::MAX]);
+		assert_eq!(samples[3], Sample::MIN);
+	}
+
+	#[test]
+	fn filters_each_input_in_place() {
+		let samples = |bits: &[i16]| -> Vec<Sample> { bits.iter().map(|&bits| Sample::from_bits(bits)).collect() };
+
+		let mut bank = FilterBank::new(1000);
+		bank.set(1, Kind::MovingAverage, 110.0).unwrap();
+		assert_eq!(bank.get(1), (Kind::MovingAverage, 110.0));
+
+		// Three inputs
+		let mut buffer = samples(&[100, 400, -7, 100, 0, -7, 100, 0, -7, 100, 0, -7]);
+		bank.process(&mut buffer, 3);
+		assert_eq!(buffer, samples(&[100, 100, -7, 100, 100, -7, 100, 100, -7, 100, 100, -7]));
+
+		let mut buffer = samples(&[0, 0, 0, 0, 0, 0]);
+		bank.process(&mut buffer, 3);
+		assert_eq!(buffer, samples(&[0, 0, 0, 0, 0, 0]));
+	}
+
+	#[test]
+	fn follows_the_sample_rate() {
+		let mut bank = FilterBank::new(16_000);
+		assert_eq!(bank.set(16, Kind::OnePole, 100.0), Err(Error::NoSuchInput));
+		assert_eq!(bank.set(0, Kind::Butterworth, 8000.0), Err(Error::CutoffTooHigh));
+		assert_eq!(bank.set(0, Kind::MovingAverage, 20.0), Err(Error::CutoffTooLow));
+		assert_eq!(bank.get(0), (Kind::Off, 0.0));
+
+		bank.set(0, Kind::MovingAverage, 100.0).unwrap();
+		bank.set(1, Kind::Butterworth, 3000.0).unwrap();
+		assert!(matches!(bank.filter(0), Filter::MovingAverage(f) if f.window() == 71));
+
+		// Four times the window for the same cutoff, the Butterworth is
+		// past Nyquist
+		assert_eq!(bank.set_sample_rate(4000), Err(Error::CutoffTooHigh));
+		assert_eq!(bank.sample_rate(), 4000);
+		assert!(matches!(bank.filter(0), Filter::MovingAverage(f) if f.window() == 18));
+		assert_eq!(bank.get(1), (Kind::Off, 0.0));
+		assert!(matches!(bank.filter(1), Filter::Off));
+	}
+}
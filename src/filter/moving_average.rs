@@ -0,0 +1,115 @@
+//! Boxcar average of the last `N` samples.
+//!
+//! A running sum over a ring of the samples in the window, scaled by a Q24
+//! reciprocal. The response has nulls at multiples of `fs / N`, useful to
+//! remove a known interference frequency, and `N = 0.443 fs / fc` puts the
+//! -3 dB point at `fc`.
+
+use super::design::round;
+use super::Error;
+
+/// Longest window
+pub const MAX_WINDOW: usize = 128;
+
+// -3 dB point of a long boxcar, relative to fs / N
+const HALF_POWER: f64 = 0.442_946_470_689_452_3;
+
+#[derive(Clone, Copy, Debug)]
+pub struct MovingAverage {
+	window: [i16; MAX_WINDOW],
+	len: usize,
+	next: usize,
+	sum: i32,
+	// Q24 of 1 / len
+	reciprocal: i64,
+}
+
+impl MovingAverage {
+	/// `cutoff` relative to the sample rate
+	pub fn new(cutoff: f64) -> Result<MovingAverage, Error> {
+		if cutoff <= 0.0 || HALF_POWER / cutoff > MAX_WINDOW as f64 + 0.5 {
+			return Err(Error::CutoffTooLow);
+		}
+		MovingAverage::with_window(round(HALF_POWER / cutoff) as usize)
+	}
+
+	/// Average of exactly `len` samples
+	pub fn with_window(len: usize) -> Result<MovingAverage, Error> {
+		if len < 2 {
+			return Err(Error::CutoffTooHigh);
+		}
+		if len > MAX_WINDOW {
+			return Err(Error::CutoffTooLow);
+		}
+
+		Ok(MovingAverage {
+			window: [0; MAX_WINDOW],
+			len,
+			next: 0,
+			sum: 0,
+			reciprocal: ((1 << 24) + len as i64 / 2) / len as i64,
+		})
+	}
+
+	pub fn window(&self) -> usize {
+		self.len
+	}
+
+	/// Samples are expected within the `i16` range
+	pub fn process(&mut self, x: i32) -> i32 {
+		let x = x as i16;
+		self.sum += x as i32 - self.window[self.next] as i32;
+		self.window[self.next] = x;
+		self.next = if self.next + 1 == self.len { 0 } else { self.next + 1 };
+
+		((self.sum as i64 * self.reciprocal + (1 << 23)) >> 24) as i32
+	}
+
+	/// Settle at `x`
+	pub fn reset(&mut self, x: i32) {
+		self.window = [x as i16; MAX_WINDOW];
+		self.sum = x * self.len as i32;
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::filter::test::gain_db;
+
+	#[test]
+	fn averages_the_window() {
+		let mut filter = MovingAverage::with_window(4).unwrap();
+		let out: Vec<_> = [4, 8, 12, 16, 16, 16, 16, 0].iter().map(|&x| filter.process(x)).collect();
+		assert_eq!(out, vec![1, 3, 6, 10, 13, 15, 16, 12]);
+
+		let mut filter = MovingAverage::with_window(MAX_WINDOW).unwrap();
+		filter.reset(4095);
+		assert_eq!(filter.process(4095), 4095);
+		let mut filter = MovingAverage::with_window(3).unwrap();
+		for _ in 0..3 {
+			filter.process(-4095);
+		}
+		assert_eq!(filter.process(-4095), -4095);
+	}
+
+	#[test]
+	fn response() {
+		let mut filter = MovingAverage::new(0.01).unwrap();
+		assert_eq!(filter.window(), 44);
+		let gain = gain_db(|x| filter.process(x), 0.01);
+		assert!((gain + 3.0).abs() < 0.2, "{}", gain);
+
+		// Null at fs / N
+		let mut filter = MovingAverage::with_window(20).unwrap();
+		assert!(gain_db(|x| filter.process(x), 0.05) < -40.0);
+	}
+
+	#[test]
+	fn rejects_bad_cutoffs() {
+		assert_eq!(MovingAverage::new(0.0).err(), Some(Error::CutoffTooLow));
+		assert_eq!(MovingAverage::new(0.001).err(), Some(Error::CutoffTooLow));
+		assert_eq!(MovingAverage::new(0.3).err(), Some(Error::CutoffTooHigh));
+		assert!(MovingAverage::new(0.0035).is_ok());
+	}
+}
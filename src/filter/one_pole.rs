@@ -0,0 +1,94 @@
+//! First order low pass, `y += a * (x - y)`.
+//!
+//! `a = 1 - e^(-2 pi fc / fs)` matches the impulse response of the analog
+//! RC filter. The coefficient is Q31 and the output keeps `FRACTION` extra
+//! bits. What is shifted out of every update is carried into the next one,
+//! so even at a cutoff of a fraction of a hertz the output creeps all the
+//! way to a constant input instead of stalling short of it.
+
+use core::f64::consts::PI;
+
+use super::design::{exp, round};
+use super::{Error, FRACTION};
+
+const ONE: i64 = 1 << 31;
+
+#[derive(Clone, Copy, Debug)]
+pub struct OnePole {
+	// Q31
+	a: i64,
+	// Output with FRACTION extra bits
+	y: i32,
+	// Remainder of the last update, Q31 of an output step
+	error: i64,
+}
+
+impl OnePole {
+	/// `cutoff` relative to the sample rate
+	pub fn new(cutoff: f64) -> Result<OnePole, Error> {
+		if cutoff <= 0.0 {
+			return Err(Error::CutoffTooLow);
+		}
+		if cutoff >= 0.5 {
+			return Err(Error::CutoffTooHigh);
+		}
+
+		let a = round((1.0 - exp(-2.0 * PI * cutoff)) * ONE as f64);
+		if a == 0 {
+			return Err(Error::CutoffTooLow);
+		}
+		Ok(OnePole { a, y: 0, error: 0 })
+	}
+
+	pub fn process(&mut self, x: i32) -> i32 {
+		let step = (((x << FRACTION) - self.y) as i64) * self.a + self.error;
+		let delta = step >> 31;
+		self.error = step - (delta << 31);
+		self.y += delta as i32;
+		(self.y + (1 << (FRACTION - 1))) >> FRACTION
+	}
+
+	/// Settle at `x`
+	pub fn reset(&mut self, x: i32) {
+		self.y = x << FRACTION;
+		self.error = 0;
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::filter::test::gain_db;
+
+	#[test]
+	fn half_power_at_the_cutoff() {
+		for &cutoff in &[0.001, 0.01, 0.05] {
+			let mut filter = OnePole::new(cutoff).unwrap();
+			let gain = gain_db(|x| filter.process(x), cutoff);
+			assert!((gain + 3.0).abs() < 0.3, "{} at {}", gain, cutoff);
+		}
+		let mut filter = OnePole::new(0.01).unwrap();
+		assert!(gain_db(|x| filter.process(x), 0.1) < -19.0);
+	}
+
+	#[test]
+	fn reaches_the_input() {
+		// 0.5 Hz at 16 kHz
+		let mut filter = OnePole::new(0.5 / 16_000.0).unwrap();
+		let mut y = 0;
+		for _ in 0..200_000 {
+			y = filter.process(2047);
+		}
+		assert_eq!(y, 2047);
+
+		filter.reset(-100);
+		assert_eq!(filter.process(-100), -100);
+	}
+
+	#[test]
+	fn rejects_bad_cutoffs() {
+		assert_eq!(OnePole::new(0.0).err(), Some(Error::CutoffTooLow));
+		assert_eq!(OnePole::new(1e-12).err(), Some(Error::CutoffTooLow));
+		assert_eq!(OnePole::new(0.5).err(), Some(Error::CutoffTooHigh));
+	}
+}
@@ -0,0 +1,324 @@
+//! Fixed point numbers of the signal path.
+//!
+//! `Sample` is Q1.15, the Eurorack range mapped onto `[-1, 1)`: full scale
+//! is +-10 V, one LSB 305 uV. `Volts` is Q16.16, a voltage with 15 uV
+//! resolution and room far beyond any jack. Arithmetic on both saturates
+//! instead of wrapping, a control voltage clips at the rail like the
+//! hardware does.
+//!
+//! The input path carries `Sample`s: the decimator turns ADC codes into
+//! them, the filters work on them in place and the input calibration reads
+//! them as `Volts`. Everything past that, the gates, quantizers, holds and
+//! the output calibration, works in `Volts`.
+//!
+//! Raw ADC codes and PWM fine codes are offset binary, the lowest code is
+//! the negative end of the range. `from_code` and `to_duty` map them
+//! straight across without calibration, the calibration tables turn codes
+//! into `Volts` and back.
+
+use core::ops::{Add, Mul, Neg, Sub};
+
+/// Voltage at `Sample::MAX`, nearly
+pub const FULL_SCALE_VOLTS: i32 = 10;
+
+// Volts bits per sample bit, 10 V over 2^15 in Q16.16
+const VOLTS_PER_SAMPLE: i32 = (FULL_SCALE_VOLTS << 16) >> 15;
+
+/// Q1.15 sample, -1 to 1 for -10 V to 10 V.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Sample(i16);
+
+impl Sample {
+	pub const ZERO: Sample = Sample(0);
+	pub const MAX: Sample = Sample(i16::MAX);
+	pub const MIN: Sample = Sample(i16::MIN);
+
+	pub const fn from_bits(bits: i16) -> Sample {
+		Sample(bits)
+	}
+
+	pub const fn to_bits(self) -> i16 {
+		self.0
+	}
+
+	/// 12 bit offset binary ADC code, code 2048 is zero
+	pub fn from_code(code: u16) -> Sample {
+		Sample(((code.min(0x0FFF) as i32 - 0x800) << 4) as i16)
+	}
+
+	/// 16 bit offset binary, as the oversampled inputs and fine codes
+	pub fn from_fine_code(code: u16) -> Sample {
+		Sample((code ^ 0x8000) as i16)
+	}
+
+	/// 16 bit offset binary PWM fine code
+	pub fn to_fine_code(self) -> u16 {
+		self.0 as u16 ^ 0x8000
+	}
+
+	/// Compare value for a PWM period of `steps` counts, `MIN` at 0
+	pub fn to_duty(self, steps: u32) -> u32 {
+		((self.to_fine_code() as u64 * steps as u64 + (1 << 15)) >> 16) as u32
+	}
+
+	/// Nearest sample, saturating outside +-10 V
+	pub fn from_volts(volts: Volts) -> Sample {
+		let bits = div_round(volts.0 as i64, VOLTS_PER_SAMPLE as i64);
+		Sample(bits.clamp(i16::MIN as i64, i16::MAX as i64) as i16)
+	}
+
+	pub fn to_volts(self) -> Volts {
+		Volts(self.0 as i32 * VOLTS_PER_SAMPLE)
+	}
+
+	pub fn saturating_add(self, other: Sample) -> Sample {
+		Sample(self.0.saturating_add(other.0))
+	}
+
+	pub fn saturating_sub(self, other: Sample) -> Sample {
+		Sample(self.0.saturating_sub(other.0))
+	}
+
+	/// Product rounded to nearest, -1 times -1 saturates
+	pub fn saturating_mul(self, other: Sample) -> Sample {
+		let product = (self.0 as i32 * other.0 as i32 + (1 << 14)) >> 15;
+		Sample(product.clamp(i16::MIN as i32, i16::MAX as i32) as i16)
+	}
+}
+
+impl Add for Sample {
+	type Output = Sample;
+
+	fn add(self, other: Sample) -> Sample {
+		self.saturating_add(other)
+	}
+}
+
+impl Sub for Sample {
+	type Output = Sample;
+
+	fn sub(self, other: Sample) -> Sample {
+		self.saturating_sub(other)
+	}
+}
+
+impl Mul for Sample {
+	type Output = Sample;
+
+	fn mul(self, other: Sample) -> Sample {
+		self.saturating_mul(other)
+	}
+}
+
+impl Neg for Sample {
+	type Output = Sample;
+
+	fn neg(self) -> Sample {
+		Sample(self.0.saturating_neg())
+	}
+}
+
+/// Q16.16 voltage.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Volts(i32);
+
+impl Volts {
+	pub const ZERO: Volts = Volts(0);
+	pub const MAX: Volts = Volts(i32::MAX);
+	pub const MIN: Volts = Volts(i32::MIN);
+	pub const ONE: Volts = Volts(1 << 16);
+
+	pub const fn from_bits(bits: i32) -> Volts {
+		Volts(bits)
+	}
+
+	pub const fn to_bits(self) -> i32 {
+		self.0
+	}
+
+	/// Whole volts, saturating
+	pub fn from_int(volts: i32) -> Volts {
+		Volts(volts.clamp(i16::MIN as i32, i16::MAX as i32) << 16)
+	}
+
+	pub fn from_millivolts(millivolts: i32) -> Volts {
+		Volts::from_micro(millivolts as i64 * 1000)
+	}
+
+	pub fn from_microvolts(microvolts: i32) -> Volts {
+		Volts::from_micro(microvolts as i64)
+	}
+
+	// Rounded to nearest, saturating
+	fn from_micro(microvolts: i64) -> Volts {
+		let bits = div_round(microvolts << 16, 1_000_000);
+		Volts(bits.clamp(i32::MIN as i64, i32::MAX as i64) as i32)
+	}
+
+	pub fn to_millivolts(self) -> i32 {
+		div_round(self.0 as i64 * 1000, 1 << 16) as i32
+	}
+
+	/// Saturating beyond +-2147 V
+	pub fn to_microvolts(self) -> i32 {
+		let microvolts = div_round(self.0 as i64 * 1_000_000, 1 << 16);
+		microvolts.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+	}
+
+	/// Rounded towards negative infinity
+	pub fn floor(self) -> i32 {
+		self.0 >> 16
+	}
+
+	/// The part above `floor`, in `[0, 1)`
+	pub fn fract(self) -> Volts {
+		Volts(self.0 & 0xFFFF)
+	}
+
+	pub fn saturating_add(self, other: Volts) -> Volts {
+		Volts(self.0.saturating_add(other.0))
+	}
+
+	pub fn saturating_sub(self, other: Volts) -> Volts {
+		Volts(self.0.saturating_sub(other.0))
+	}
+
+	/// Product rounded to nearest, saturating
+	pub fn saturating_mul(self, other: Volts) -> Volts {
+		let product = (self.0 as i64 * other.0 as i64 + (1 << 15)) >> 16;
+		Volts(product.clamp(i32::MIN as i64, i32::MAX as i64) as i32)
+	}
+
+	/// Scaled by a gain of `-1` to `1`, saturating
+	pub fn scale(self, gain: Sample) -> Volts {
+		let product = (self.0 as i64 * gain.0 as i64 + (1 << 14)) >> 15;
+		Volts(product.clamp(i32::MIN as i64, i32::MAX as i64) as i32)
+	}
+}
+
+impl Add for Volts {
+	type Output = Volts;
+
+	fn add(self, other: Volts) -> Volts {
+		self.saturating_add(other)
+	}
+}
+
+impl Sub for Volts {
+	type Output = Volts;
+
+	fn sub(self, other: Volts) -> Volts {
+		self.saturating_sub(other)
+	}
+}
+
+impl Mul for Volts {
+	type Output = Volts;
+
+	fn mul(self, other: Volts) -> Volts {
+		self.saturating_mul(other)
+	}
+}
+
+impl Neg for Volts {
+	type Output = Volts;
+
+	fn neg(self) -> Volts {
+		Volts(self.0.saturating_neg())
+	}
+}
+
+impl From<Sample> for Volts {
+	fn from(sample: Sample) -> Volts {
+		sample.to_volts()
+	}
+}
+
+/// Signed division rounding half away from zero
+pub(crate) fn div_round(n: i64, d: i64) -> i64 {
+	if (n < 0) == (d < 0) {
+		(n + d / 2) / d
+	} else {
+		(n - d / 2) / d
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn eurorack_range() {
+		assert_eq!(Sample::MIN.to_volts(), Volts::from_int(-10));
+		assert_eq!(Sample::MAX.to_volts().to_microvolts(), 9_999_695);
+		assert_eq!(Sample::from_volts(Volts::from_int(5)), Sample::from_bits(0x4000));
+		assert_eq!(Sample::from_volts(Volts::from_millivolts(-2500)), Sample::from_bits(-0x2000));
+
+		// Beyond the jacks
+		assert_eq!(Sample::from_volts(Volts::from_int(12)), Sample::MAX);
+		assert_eq!(Sample::from_volts(Volts::from_int(-12)), Sample::MIN);
+
+		// One LSB is 305 uV
+		assert_eq!(Sample::from_bits(1).to_volts().to_microvolts(), 305);
+		assert_eq!(Sample::from_volts(Volts::from_microvolts(140)), Sample::ZERO);
+		assert_eq!(Sample::from_volts(Volts::from_microvolts(160)), Sample::from_bits(1));
+		assert_eq!(Sample::from_volts(Volts::from_microvolts(-160)), Sample::from_bits(-1));
+	}
+
+	#[test]
+	fn codes_and_duties() {
+		assert_eq!(Sample::from_code(0), Sample::MIN);
+		assert_eq!(Sample::from_code(0x800), Sample::ZERO);
+		assert_eq!(Sample::from_code(0xFFF), Sample::from_bits(0x7FF0));
+		assert_eq!(Sample::from_code(0xFFFF), Sample::from_bits(0x7FF0));
+
+		assert_eq!(Sample::from_fine_code(0), Sample::MIN);
+		assert_eq!(Sample::from_fine_code(0xFFFF), Sample::MAX);
+		assert_eq!(Sample::from_bits(-2).to_fine_code(), 0x7FFE);
+
+		assert_eq!(Sample::MIN.to_duty(9000), 0);
+		assert_eq!(Sample::ZERO.to_duty(9000), 4500);
+		assert_eq!(Sample::MAX.to_duty(9000), 9000);
+	}
+
+	#[test]
+	fn saturating_samples() {
+		let half = Sample::from_bits(0x4000);
+		assert_eq!(half + half, Sample::MAX);
+		assert_eq!(-half - half, Sample::from_bits(-0x8000));
+		assert_eq!(-half - half - half, Sample::MIN);
+		assert_eq!(-Sample::MIN, Sample::MAX);
+
+		assert_eq!(half * half, Sample::from_bits(0x2000));
+		assert_eq!(half * -half, Sample::from_bits(-0x2000));
+		assert_eq!(Sample::MIN * Sample::MIN, Sample::MAX);
+		// Rounded, not truncated
+		assert_eq!(Sample::from_bits(3) * half, Sample::from_bits(2));
+	}
+
+	#[test]
+	fn volts() {
+		let v = Volts::from_millivolts(1500);
+		assert_eq!(v.to_bits(), 0x1_8000);
+		assert_eq!((v.floor(), v.fract()), (1, Volts::from_bits(0x8000)));
+		assert_eq!((-v).floor(), -2);
+		assert_eq!(v.to_millivolts(), 1500);
+		assert_eq!(Volts::from_microvolts(-1_000_000).to_microvolts(), -1_000_000);
+		// A cent at 1V/oct is 55 LSB
+		assert_eq!(Volts::from_microvolts(-833).to_bits(), -55);
+
+		assert_eq!(v + Volts::ONE, Volts::from_millivolts(2500));
+		assert_eq!(v * v, Volts::from_millivolts(2250));
+		assert_eq!(Volts::from_int(-4).scale(Sample::from_bits(0x4000)), Volts::from_int(-2));
+		// A gain of exactly -1
+		assert_eq!(Volts::MIN.scale(Sample::MIN), Volts::MAX);
+
+		assert_eq!(Volts::MAX + Volts::ONE, Volts::MAX);
+		assert_eq!(Volts::MIN - Volts::ONE, Volts::MIN);
+		assert_eq!(-Volts::MIN, Volts::MAX);
+		assert_eq!(Volts::from_int(300) * Volts::from_int(300), Volts::MAX);
+		assert_eq!(Volts::from_int(1 << 20), Volts::from_bits(0x7FFF_0000));
+		assert_eq!(Volts::MAX.to_microvolts(), i32::MAX);
+		assert_eq!(Volts::from(Sample::MIN), Volts::from_int(-10));
+	}
+}
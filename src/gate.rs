@@ -0,0 +1,310 @@
+//! Gates and triggers on the inputs.
+//!
+//! An input in gate mode is compared against two thresholds around its
+//! `threshold`, `hysteresis` apart: the gate goes high when the input
+//! reaches the upper one and low again only below the lower one, so noise
+//! on a slow edge does not chatter. A change must also hold for
+//! `min_width` samples before it counts, shorter pulses and spikes are
+//! dropped. Accepted changes are reported as `Event`s stamped with the
+//! sample clock tick at which the input first crossed, not the one at
+//! which the change was confirmed.
+//!
+//! Events go into a fixed size `Events` queue filled from the DMA interrupt
+//! and drained by whatever consumes them.
+
+use crate::adc::{Frames, MAX_CHANNELS};
+use crate::calibration::InputCalibrations;
+use crate::fixed::{Sample, Volts};
+
+/// Events held until drained
+pub const CAPACITY: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GateConfig {
+	pub threshold: Volts,
+	// Distance between the rising and the falling threshold
+	pub hysteresis: Volts,
+	// Samples a change has to hold, 0 and 1 take every change at once
+	pub min_width: u32,
+}
+
+impl Default for GateConfig {
+	/// Between the 0 V and 5 V of a usual gate, 0.5 V of hysteresis
+	fn default() -> GateConfig {
+		GateConfig { threshold: Volts::from_millivolts(1500), hysteresis: Volts::from_millivolts(500), min_width: 1 }
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edge {
+	Rising,
+	Falling,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event {
+	pub input: usize,
+	pub edge: Edge,
+	// Sample clock tick of the first sample past the threshold
+	pub at: u64,
+}
+
+/// Gate state of one input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GateDetector {
+	rising: Volts,
+	falling: Volts,
+	min_width: u32,
+	high: bool,
+	// First tick past the threshold and the samples since, of a change
+	// not yet accepted
+	pending: Option<(u64, u32)>,
+}
+
+impl GateDetector {
+	/// Starts low
+	pub fn new(config: GateConfig) -> GateDetector {
+		let half = Volts::from_bits(config.hysteresis.to_bits().abs() / 2);
+		GateDetector {
+			rising: config.threshold + half,
+			falling: config.threshold - half,
+			min_width: config.min_width.max(1),
+			high: false,
+			pending: None,
+		}
+	}
+
+	pub fn is_high(&self) -> bool {
+		self.high
+	}
+
+	/// Next sample of the input taken at tick `at`. If the gate changed,
+	/// the edge and the tick at which the input first crossed.
+	pub fn process(&mut self, volts: Volts, at: u64) -> Option<(Edge, u64)> {
+		let crossed = if self.high { volts <= self.falling } else { volts >= self.rising };
+		// Back past the other threshold cancels a pending change, in
+		// between keeps it going
+		let returned = if self.high { volts >= self.rising } else { volts <= self.falling };
+
+		let (since, count) = match self.pending {
+			Some((since, count)) if !returned => (since, count + 1),
+			_ if crossed => (at, 1),
+			_ => {
+				self.pending = None;
+				return None;
+			}
+		};
+		if count < self.min_width {
+			self.pending = Some((since, count));
+			return None;
+		}
+
+		self.pending = None;
+		self.high = !self.high;
+		Some((if self.high { Edge::Rising } else { Edge::Falling }, since))
+	}
+}
+
+/// Queue of events from the interrupt to the main loop.
+pub struct Events {
+	events: [Event; CAPACITY],
+	head: usize,
+	len: usize,
+	dropped: u32,
+}
+
+impl Events {
+	pub fn new() -> Events {
+		Events {
+			events: [Event { input: 0, edge: Edge::Rising, at: 0 }; CAPACITY],
+			head: 0,
+			len: 0,
+			dropped: 0,
+		}
+	}
+
+	/// Add an event, dropped and counted when full
+	pub fn push(&mut self, event: Event) -> bool {
+		if self.len == CAPACITY {
+			self.dropped += 1;
+			return false;
+		}
+		self.events[(self.head + self.len) % CAPACITY] = event;
+		self.len += 1;
+		true
+	}
+
+	/// Oldest event
+	pub fn pop(&mut self) -> Option<Event> {
+		if self.len == 0 {
+			return None;
+		}
+		let event = self.events[self.head];
+		self.head = (self.head + 1) % CAPACITY;
+		self.len -= 1;
+		Some(event)
+	}
+
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	/// Events lost to a full queue
+	pub fn dropped(&self) -> u32 {
+		self.dropped
+	}
+}
+
+impl Default for Events {
+	fn default() -> Events {
+		Events::new()
+	}
+}
+
+/// Gate detection on the inputs in gate mode.
+pub struct GateBank {
+	detectors: [Option<GateDetector>; MAX_CHANNELS],
+	calibrations: InputCalibrations,
+	// Sample clock tick of the next frame
+	tick: u64,
+}
+
+impl GateBank {
+	/// Every input continuous CV
+	pub fn new(calibrations: InputCalibrations) -> GateBank {
+		GateBank { detectors: [None; MAX_CHANNELS], calibrations, tick: 0 }
+	}
+
+	/// Gate mode for an input, or back to CV with `None`
+	pub fn set(&mut self, input: usize, config: Option<GateConfig>) {
+		self.detectors[input] = config.map(GateDetector::new);
+	}
+
+	pub fn detector(&self, input: usize) -> Option<&GateDetector> {
+		self.detectors[input].as_ref()
+	}
+
+	/// Thresholds are compared with calibrated voltages
+	pub fn set_calibrations(&mut self, calibrations: InputCalibrations) {
+		self.calibrations = calibrations;
+	}
+
+	/// Tick of the next frame processed
+	pub fn tick(&self) -> u64 {
+		self.tick
+	}
+
+	/// Detect the edges in a completed buffer of decimated frames, one
+	/// sample per input.
+	pub fn process(&mut self, frames: &Frames<Sample>, events: &mut Events) {
+		for frame in frames.iter() {
+			for (input, &sample) in frame.iter().enumerate() {
+				let detector = match self.detectors[input].as_mut() {
+					Some(detector) => detector,
+					None => continue,
+				};
+				if let Some((edge, at)) = detector.process(self.calibrations.volts(input, sample), self.tick) {
+					events.push(Event { input, edge, at });
+				}
+			}
+			self.tick += 1;
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::calibration::InputCalibration;
+
+	fn volts(millivolts: &[i32]) -> Vec<Volts> {
+		millivolts.iter().map(|&mv| Volts::from_millivolts(mv)).collect()
+	}
+
+	fn edges(config: GateConfig, samples: &[Volts]) -> Vec<(usize, Edge)> {
+		let mut detector = GateDetector::new(config);
+		samples
+			.iter()
+			.enumerate()
+			.filter_map(|(i, &v)| detector.process(v, i as u64).map(|(edge, _)| (i, edge)))
+			.collect()
+	}
+
+	#[test]
+	fn hysteresis_keeps_slow_edges_clean() {
+		let config = GateConfig::default();
+		// Noisy ramp up through 1.5 V and back down
+		let samples = volts(&[0, 1000, 1600, 1400, 1760, 1700, 1800, 5000, 1700, 1240, 1300, 1000, 0]);
+		assert_eq!(edges(config, &samples), vec![(4, Edge::Rising), (9, Edge::Falling)]);
+
+		// Without hysteresis every wiggle counts
+		let config = GateConfig { hysteresis: Volts::ZERO, ..config };
+		assert_eq!(
+			edges(config, &samples),
+			vec![(2, Edge::Rising), (3, Edge::Falling), (4, Edge::Rising), (9, Edge::Falling)]
+		);
+	}
+
+	#[test]
+	fn short_pulses_are_dropped() {
+		let config = GateConfig { min_width: 3, ..GateConfig::default() };
+		// A two sample spike, then a gate of four samples with a dip
+		// inside the hysteresis
+		let samples = volts(&[0, 5000, 5000, 0, 0, 5000, 5000, 1400, 5000, 0, 0, 0]);
+
+		let mut detector = GateDetector::new(config);
+		let events: Vec<_> =
+			samples.iter().enumerate().filter_map(|(i, &v)| detector.process(v, i as u64)).collect();
+		// Stamped where the input crossed
+		assert_eq!(events, vec![(Edge::Rising, 5), (Edge::Falling, 9)]);
+		assert!(!detector.is_high());
+	}
+
+	#[test]
+	fn events_queue_up() {
+		let mut events = Events::new();
+		assert_eq!(events.pop(), None);
+		for at in 0..CAPACITY as u64 + 2 {
+			events.push(Event { input: 1, edge: Edge::Rising, at });
+		}
+		assert_eq!((events.len(), events.dropped()), (CAPACITY, 2));
+		assert_eq!(events.pop().map(|e| e.at), Some(0));
+		assert!(events.push(Event { input: 2, edge: Edge::Falling, at: 99 }));
+		let rest: Vec<_> = core::iter::from_fn(|| events.pop()).collect();
+		assert_eq!(rest.len(), CAPACITY);
+		assert_eq!(rest.last(), Some(&Event { input: 2, edge: Edge::Falling, at: 99 }));
+		assert!(events.is_empty());
+	}
+
+	#[test]
+	fn gates_from_the_inputs_in_gate_mode() {
+		// Inverting front end, code 0 is +10 V
+		let calibrations = InputCalibrations::new(InputCalibration::nominal(10_000, -10_000));
+		let samples = |codes: &[u16]| -> Vec<Sample> { codes.iter().map(|&code| Sample::from_code(code)).collect() };
+
+		let mut bank = GateBank::new(calibrations);
+		bank.set(1, Some(GateConfig { min_width: 2, ..GateConfig::default() }));
+		assert!(bank.detector(0).is_none());
+
+		// Two inputs, 0 V is code 2048, 5 V code 1024; input 0 stays CV
+		let mut events = Events::new();
+		bank.process(&Frames::new(&samples(&[0, 2048, 0, 1024, 4095, 1024]), 2), &mut events);
+		bank.process(&Frames::new(&samples(&[0, 2048, 0, 2048, 0, 2048]), 2), &mut events);
+		assert_eq!(bank.tick(), 6);
+		assert_eq!(events.pop(), Some(Event { input: 1, edge: Edge::Rising, at: 1 }));
+		assert_eq!(events.pop(), Some(Event { input: 1, edge: Edge::Falling, at: 3 }));
+		assert_eq!(events.pop(), None);
+		assert!(!bank.detector(1).unwrap().is_high());
+
+		// A single input, a frame per tick
+		let mut bank = GateBank::new(calibrations);
+		bank.set(0, Some(GateConfig::default()));
+		bank.process(&Frames::new(&samples(&[2048, 1024]), 1), &mut events);
+		assert_eq!(events.pop(), Some(Event { input: 0, edge: Edge::Rising, at: 1 }));
+		assert_eq!(bank.tick(), 2);
+	}
+}
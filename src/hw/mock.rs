@@ -0,0 +1,598 @@
+//! Pure Rust simulation of the sampling peripherals.
+//!
+//! A `Board` holds the shared peripheral state. The handles returned by
+//! `timer()`, `adc()`, `dma()` and `output()` implement the hardware traits
+//! and can be handed to the code under test, while the test itself drives
+//! the simulation with `tick()` and sets the analog inputs.
+//!
+//! `burst_dma()` adds a stream reading compare values for an output timer,
+//! one DMA burst of `BURST_LEN` half words per tick.
+//!
+//! `MockFlash` stands on its own: NOR flash in RAM which can lose power
+//! after a given number of programmed bytes or erases, leaving the byte
+//! or sector it was working on half done. Clones share the same memory, so
+//! a test can cut the power, `power_on` and mount the flash again.
+
+use std::cell::{RefCell, RefMut};
+use std::collections::VecDeque;
+use std::ptr;
+use std::rc::Rc;
+use std::string::String;
+use std::vec::Vec;
+
+use super::{
+	AdcSequencer, BurstRequest, DataWidth, DoubleBufferDma, Flash, FlashError, OutputDriver, SampleTimer, Serial, Target,
+};
+use crate::adc::{Allocation, ChannelMap, Mode};
+use crate::pwm::burst::BURST_LEN;
+use crate::pwm::{layout, Carrier};
+
+// Addresses reported as the ADC1 and the common data register
+pub const ADC_DR: u32 = 0x4001_204c;
+pub const ADC_CDR: u32 = 0x4001_2308;
+
+// Outputs run the firmware's 20 kHz carrier from 180 MHz (TIM1, TIM8) and
+// 90 MHz (TIM2-TIM4) timer clocks, 9000 and 4500 compare counts
+pub const CARRIER: u32 = 20_000;
+const TIMCLK1: u32 = 90_000_000;
+const TIMCLK2: u32 = 180_000_000;
+
+/// Carrier of the timer driving `output`, as on the board
+pub fn carrier(output: usize) -> Carrier {
+	let timer = layout::OUTPUTS[output].timer;
+	let clock = if timer.advanced() { TIMCLK2 } else { TIMCLK1 };
+	Carrier::with_max_reload(CARRIER, clock, timer.max_reload()).unwrap()
+}
+
+#[derive(Default)]
+struct Timer {
+	running: bool,
+	prescaler: u16,
+	reload: u32,
+	compare: u32,
+	interrupt: bool,
+}
+
+#[derive(Default)]
+struct Adc {
+	enabled: bool,
+	sequence: Vec<u8>,
+	address: u32,
+	inputs: [u16; 19],
+	overrun: bool,
+	overrun_interrupt: bool,
+}
+
+struct Dma {
+	enabled: bool,
+	peripheral: u32,
+	buffers: [*mut u16; 2],
+	len: u16,
+	target: Target,
+	remaining: u16,
+	complete: bool,
+	error: bool,
+}
+
+impl Dma {
+	fn new() -> Dma {
+		Dma {
+			enabled: false,
+			peripheral: 0,
+			buffers: [ptr::null_mut(); 2],
+			len: 0,
+			target: Target::M0,
+			remaining: 0,
+			complete: false,
+			error: false,
+		}
+	}
+
+	// Position of the next transfer
+	fn next(&self) -> *mut u16 {
+		let base = match self.target {
+			Target::M0 => self.buffers[0],
+			Target::M1 => self.buffers[1],
+		};
+		unsafe { base.add((self.len - self.remaining) as usize) }
+	}
+
+	fn advance(&mut self) {
+		self.remaining -= 1;
+		if self.remaining == 0 {
+			self.complete = true;
+			self.target = self.target.other();
+			self.remaining = self.len;
+		}
+	}
+}
+
+// An output timer loaded by DMA bursts
+struct Burst {
+	dma: Dma,
+	compare: [u16; BURST_LEN],
+	// TDE set
+	request: bool,
+	// A failed burst left the request pending, nothing moves until TDE is
+	// cleared
+	hung: bool,
+}
+
+struct State {
+	timer: Timer,
+	adc: Adc,
+	dma: Dma,
+	bursts: Vec<Burst>,
+	// Compare value of every output
+	outputs: Vec<u32>,
+	// Serial console, bytes typed and bytes written
+	received: VecDeque<u8>,
+	sent: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+enum Stream {
+	Adc,
+	Burst(usize),
+}
+
+#[derive(Clone)]
+pub struct Board {
+	state: Rc<RefCell<State>>,
+}
+
+impl Board {
+	pub fn new(outputs: usize) -> Board {
+		Board {
+			state: Rc::new(RefCell::new(State {
+				timer: Timer::default(),
+				adc: Adc::default(),
+				dma: Dma::new(),
+				bursts: Vec::new(),
+				outputs: vec![0; outputs],
+				received: VecDeque::new(),
+				sent: Vec::new(),
+			})),
+		}
+	}
+
+	pub fn timer(&self) -> MockTimer {
+		MockTimer(self.clone())
+	}
+
+	pub fn adc(&self) -> MockAdc {
+		MockAdc(self.clone(), Mode::Independent)
+	}
+
+	// ADC1-ADC3 in one of the multi ADC modes
+	pub fn multi_adc(&self, mode: Mode) -> MockAdc {
+		MockAdc(self.clone(), mode)
+	}
+
+	pub fn dma(&self) -> MockDma {
+		MockDma(self.clone(), Stream::Adc)
+	}
+
+	// A new DMA stream feeding the compare registers of one output timer
+	pub fn burst_dma(&self) -> MockDma {
+		let mut s = self.state.borrow_mut();
+		s.bursts.push(Burst { dma: Dma::new(), compare: [0; BURST_LEN], request: false, hung: false });
+		MockDma(self.clone(), Stream::Burst(s.bursts.len() - 1))
+	}
+
+	// The DMA request of the timer `dma` serves
+	pub fn burst_request(&self, dma: &MockDma) -> MockRequest {
+		match dma.1 {
+			Stream::Burst(i) => MockRequest(self.clone(), i),
+			Stream::Adc => panic!("not a burst stream"),
+		}
+	}
+
+	// Compare registers last loaded by a burst stream
+	pub fn compare(&self, dma: &MockDma) -> [u16; BURST_LEN] {
+		match dma.1 {
+			Stream::Burst(i) => self.state.borrow().bursts[i].compare,
+			Stream::Adc => panic!("not a burst stream"),
+		}
+	}
+
+	pub fn output(&self) -> MockOutput {
+		MockOutput(self.clone())
+	}
+
+	pub fn serial(&self) -> MockSerial {
+		MockSerial(self.clone())
+	}
+
+	// Bytes arriving on the serial port
+	pub fn type_text(&self, text: &str) {
+		self.state.borrow_mut().received.extend(text.bytes());
+	}
+
+	// Everything written to the serial port since the last call
+	pub fn take_serial_output(&self) -> String {
+		let sent = std::mem::take(&mut self.state.borrow_mut().sent);
+		String::from_utf8(sent).unwrap()
+	}
+
+	// Set the raw 12 bit value the ADCs read on a channel
+	pub fn set_input(&self, channel: u8, value: u16) {
+		self.state.borrow_mut().adc.inputs[channel as usize] = value & 0x0FFF;
+	}
+
+	pub fn duty(&self, channel: usize) -> u32 {
+		self.state.borrow().outputs[channel]
+	}
+
+	pub fn timer_settings(&self) -> (u16, u32, u32) {
+		let s = self.state.borrow();
+		(s.timer.prescaler, s.timer.reload, s.timer.compare)
+	}
+
+	pub fn timer_interrupt(&self) -> bool {
+		self.state.borrow().timer.interrupt
+	}
+
+	// ADC interrupt line, raised by an overrun with OVRIE set
+	pub fn adc_interrupt(&self) -> bool {
+		let s = self.state.borrow();
+		s.adc.overrun && s.adc.overrun_interrupt
+	}
+
+	// Channels converted per trigger, in the order the results reach the DMA
+	pub fn sequence(&self) -> Vec<u8> {
+		self.state.borrow().adc.sequence.clone()
+	}
+
+	// Force a DMA transfer error, as a bus fault on the memory port would
+	pub fn fail_dma(&self) {
+		let mut s = self.state.borrow_mut();
+		s.dma.error = true;
+		s.dma.enabled = false;
+	}
+
+	// Fail a burst stream part way through a burst, its timer's request
+	// left pending
+	pub fn fail_burst(&self, dma: &MockDma) {
+		let mut s = self.state.borrow_mut();
+		let burst = match dma.1 {
+			Stream::Burst(i) => &mut s.bursts[i],
+			Stream::Adc => panic!("not a burst stream"),
+		};
+		burst.dma.advance();
+		burst.dma.error = true;
+		burst.dma.enabled = false;
+		burst.hung = true;
+	}
+
+	/// One sample clock compare event.
+	///
+	/// Every enabled burst stream whose timer requests it loads one burst
+	/// into its compare registers.
+	/// Then the whole regular sequence is converted and every result moved
+	/// through the DMA, swapping buffers and flagging transfer complete when
+	/// one fills up. Data converted while the DMA is not serving the ADC sets
+	/// the overrun flag, after which the ADC ignores triggers until cleared.
+	pub fn tick(&self) {
+		let mut s = self.state.borrow_mut();
+		let s = &mut *s;
+
+		if !s.timer.running {
+			return;
+		}
+		s.timer.interrupt = true;
+
+		for burst in s.bursts.iter_mut().filter(|burst| burst.dma.enabled && burst.request && !burst.hung) {
+			for compare in burst.compare.iter_mut() {
+				*compare = unsafe { ptr::read_volatile(burst.dma.next()) };
+				burst.dma.advance();
+			}
+		}
+
+		if !s.adc.enabled || s.adc.overrun {
+			return;
+		}
+
+		for i in 0..s.adc.sequence.len() {
+			let value = s.adc.inputs[s.adc.sequence[i] as usize];
+			let dma = &mut s.dma;
+
+			if !dma.enabled || dma.peripheral != s.adc.address {
+				s.adc.overrun = true;
+				return;
+			}
+
+			unsafe { ptr::write_volatile(dma.next(), value) };
+			dma.advance();
+		}
+	}
+}
+
+pub struct MockTimer(Board);
+
+impl SampleTimer for MockTimer {
+	fn configure(&mut self, prescaler: u16, reload: u32, compare: u32) {
+		let mut s = self.0.state.borrow_mut();
+		s.timer.prescaler = prescaler;
+		s.timer.reload = reload;
+		s.timer.compare = compare;
+	}
+
+	fn start(&mut self) {
+		self.0.state.borrow_mut().timer.running = true;
+	}
+
+	fn stop(&mut self) {
+		self.0.state.borrow_mut().timer.running = false;
+	}
+
+	fn clear_interrupts(&mut self) {
+		self.0.state.borrow_mut().timer.interrupt = false;
+	}
+}
+
+pub struct MockAdc(Board, Mode);
+
+impl AdcSequencer for MockAdc {
+	fn configure(&mut self, map: &ChannelMap) {
+		let allocation = Allocation::new(map, self.1).unwrap();
+		let mut s = self.0.state.borrow_mut();
+		s.adc.sequence = allocation.frame_channels().collect();
+		s.adc.address = self.data_address();
+	}
+
+	fn enable(&mut self) {
+		self.0.state.borrow_mut().adc.enabled = true;
+	}
+
+	fn disable(&mut self) {
+		self.0.state.borrow_mut().adc.enabled = false;
+	}
+
+	fn frame_width(&self) -> usize {
+		self.0.state.borrow().adc.sequence.len()
+	}
+
+	fn data_address(&self) -> u32 {
+		match self.1 {
+			Mode::Independent => ADC_DR,
+			_ => ADC_CDR,
+		}
+	}
+
+	fn data_width(&self) -> DataWidth {
+		match self.1 {
+			Mode::Independent => DataWidth::HalfWord,
+			_ => DataWidth::Word,
+		}
+	}
+
+	fn enable_overrun_interrupt(&mut self) {
+		self.0.state.borrow_mut().adc.overrun_interrupt = true;
+	}
+
+	fn overrun(&self) -> bool {
+		self.0.state.borrow().adc.overrun
+	}
+
+	fn clear_overrun(&mut self) {
+		self.0.state.borrow_mut().adc.overrun = false;
+	}
+}
+
+pub struct MockDma(Board, Stream);
+
+impl MockDma {
+	fn stream(&self) -> RefMut<'_, Dma> {
+		let stream = self.1;
+		RefMut::map(self.0.state.borrow_mut(), |s| match stream {
+			Stream::Adc => &mut s.dma,
+			Stream::Burst(i) => &mut s.bursts[i].dma,
+		})
+	}
+}
+
+impl DoubleBufferDma for MockDma {
+	unsafe fn configure(&mut self, peripheral: u32, width: DataWidth, m0: *mut u16, m1: *mut u16, len: u16) {
+		let mut dma = self.stream();
+		assert!(!dma.enabled, "stream must be disabled while configured");
+		if width == DataWidth::Word {
+			assert!(len.is_multiple_of(2) && (m0 as usize).is_multiple_of(4) && (m1 as usize).is_multiple_of(4));
+		}
+		dma.peripheral = peripheral;
+		dma.buffers = [m0, m1];
+		dma.len = len;
+		dma.remaining = len;
+		dma.target = Target::M0;
+	}
+
+	fn enable(&mut self) {
+		self.stream().enabled = true;
+	}
+
+	fn disable(&mut self) {
+		self.stream().enabled = false;
+	}
+
+	fn is_enabled(&self) -> bool {
+		self.stream().enabled
+	}
+
+	fn current_target(&self) -> Target {
+		self.stream().target
+	}
+
+	fn remaining(&self) -> u16 {
+		self.stream().remaining
+	}
+
+	fn transfer_complete(&self) -> bool {
+		self.stream().complete
+	}
+
+	fn transfer_error(&self) -> bool {
+		self.stream().error
+	}
+
+	fn clear_interrupts(&mut self) {
+		let mut dma = self.stream();
+		dma.complete = false;
+		dma.error = false;
+	}
+}
+
+pub struct MockRequest(Board, usize);
+
+impl BurstRequest for MockRequest {
+	fn disable(&mut self) {
+		let mut s = self.0.state.borrow_mut();
+		let burst = &mut s.bursts[self.1];
+		burst.request = false;
+		burst.hung = false;
+	}
+
+	fn enable(&mut self) {
+		self.0.state.borrow_mut().bursts[self.1].request = true;
+	}
+}
+
+pub struct MockOutput(Board);
+
+impl OutputDriver for MockOutput {
+	fn channels(&self) -> usize {
+		self.0.state.borrow().outputs.len()
+	}
+
+	fn steps(&self, channel: usize) -> u32 {
+		carrier(channel).steps()
+	}
+
+	fn set(&mut self, channel: usize, compare: u32) {
+		self.0.state.borrow_mut().outputs[channel] = compare.min(self.steps(channel));
+	}
+}
+
+pub struct MockSerial(Board);
+
+impl Serial for MockSerial {
+	fn read(&mut self) -> Option<u8> {
+		self.0.state.borrow_mut().received.pop_front()
+	}
+
+	fn write(&mut self, byte: u8) -> bool {
+		self.0.state.borrow_mut().sent.push(byte);
+		true
+	}
+}
+
+struct FlashState {
+	sectors: Vec<Vec<u8>>,
+	erases: Vec<u32>,
+	// Byte writes and erases left before the power fails
+	budget: Option<usize>,
+	powered: bool,
+}
+
+#[derive(Clone)]
+pub struct MockFlash(Rc<RefCell<FlashState>>);
+
+impl MockFlash {
+	/// Erased flash of `sectors` sectors
+	pub fn new(sectors: usize, sector_size: usize) -> MockFlash {
+		MockFlash(Rc::new(RefCell::new(FlashState {
+			sectors: vec![vec![0xFF; sector_size]; sectors],
+			erases: vec![0; sectors],
+			budget: None,
+			powered: true,
+		})))
+	}
+
+	/// Fail the power after `steps` more programmed bytes or erases
+	pub fn cut_power_after(&self, steps: usize) {
+		self.0.borrow_mut().budget = Some(steps);
+	}
+
+	pub fn power_on(&self) {
+		let mut state = self.0.borrow_mut();
+		state.budget = None;
+		state.powered = true;
+	}
+
+	pub fn erases(&self, sector: usize) -> u32 {
+		self.0.borrow().erases[sector]
+	}
+
+	/// Flip bits behind the flash's back
+	pub fn corrupt(&self, sector: usize, offset: usize, mask: u8) {
+		self.0.borrow_mut().sectors[sector][offset] ^= mask;
+	}
+
+	pub fn contents(&self, sector: usize) -> Vec<u8> {
+		self.0.borrow().sectors[sector].clone()
+	}
+
+	// One more step, false once the power has failed
+	fn step(state: &mut FlashState) -> bool {
+		match state.budget {
+			Some(0) => {
+				state.powered = false;
+				false
+			}
+			Some(ref mut left) => {
+				*left -= 1;
+				true
+			}
+			None => true,
+		}
+	}
+}
+
+impl Flash for MockFlash {
+	fn sectors(&self) -> usize {
+		self.0.borrow().sectors.len()
+	}
+
+	fn sector_size(&self) -> usize {
+		self.0.borrow().sectors[0].len()
+	}
+
+	fn read(&self, sector: usize, offset: usize, buffer: &mut [u8]) {
+		buffer.copy_from_slice(&self.0.borrow().sectors[sector][offset..offset + buffer.len()]);
+	}
+
+	fn write(&mut self, sector: usize, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+		let state = &mut *self.0.borrow_mut();
+		if !state.powered {
+			return Err(FlashError::PowerLoss);
+		}
+		for (i, &byte) in data.iter().enumerate() {
+			let powered = MockFlash::step(state);
+			let cell = &mut state.sectors[sector][offset + i];
+			if !powered {
+				// Half the bits made it
+				*cell &= byte | 0x0F;
+				return Err(FlashError::PowerLoss);
+			}
+			*cell &= byte;
+		}
+		Ok(())
+	}
+
+	fn erase(&mut self, sector: usize) -> Result<(), FlashError> {
+		let state = &mut *self.0.borrow_mut();
+		if !state.powered {
+			return Err(FlashError::PowerLoss);
+		}
+		let powered = MockFlash::step(state);
+		let contents = &mut state.sectors[sector];
+		if !powered {
+			// Cut short half way through
+			let half = contents.len() / 2;
+			contents[..half].iter_mut().for_each(|byte| *byte = 0xFF);
+			return Err(FlashError::PowerLoss);
+		}
+		contents.iter_mut().for_each(|byte| *byte = 0xFF);
+		state.erases[sector] += 1;
+		Ok(())
+	}
+}
@@ -0,0 +1,162 @@
+//! Hardware abstraction between the application and the peripheral registers.
+//!
+//! Everything above this layer talks to the traits below. `stm32f446` drives
+//! the real registers, `mock` simulates the same peripherals in plain Rust so
+//! the signal path can be exercised by `cargo test` on the host.
+
+#[cfg(any(test, feature = "std"))]
+pub mod mock;
+#[cfg(feature = "stm32f446")]
+pub mod stm32f446;
+
+use crate::adc::ChannelMap;
+
+// ADC sample time in ADC clock cycles (SMPx field encoding)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleTime {
+	Cycles3 = 0b000,
+	Cycles15 = 0b001,
+	Cycles28 = 0b010,
+	Cycles56 = 0b011,
+	Cycles84 = 0b100,
+	Cycles112 = 0b101,
+	Cycles144 = 0b110,
+	Cycles480 = 0b111,
+}
+
+impl SampleTime {
+	pub fn cycles(self) -> u32 {
+		match self {
+			SampleTime::Cycles3 => 3,
+			SampleTime::Cycles15 => 15,
+			SampleTime::Cycles28 => 28,
+			SampleTime::Cycles56 => 56,
+			SampleTime::Cycles84 => 84,
+			SampleTime::Cycles112 => 112,
+			SampleTime::Cycles144 => 144,
+			SampleTime::Cycles480 => 480,
+		}
+	}
+}
+
+// The two memory targets of a double buffered DMA stream (CT bit)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+	M0,
+	M1,
+}
+
+impl Target {
+	pub fn other(self) -> Target {
+		match self {
+			Target::M0 => Target::M1,
+			Target::M1 => Target::M0,
+		}
+	}
+}
+
+// Size of one DMA transfer from the peripheral
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataWidth {
+	HalfWord,
+	// Two results packed in one word, low half word first (multi ADC mode 2)
+	Word,
+}
+
+/// Timer producing the sample clock which triggers the ADC conversions.
+pub trait SampleTimer {
+	/// Set prescaler, auto reload and compare values (in timer ticks)
+	fn configure(&mut self, prescaler: u16, reload: u32, compare: u32);
+	fn start(&mut self);
+	fn stop(&mut self);
+	fn clear_interrupts(&mut self);
+}
+
+/// ADC converting a regular sequence of channels on every sample clock event.
+pub trait AdcSequencer {
+	/// Program the regular sequence
+	fn configure(&mut self, map: &ChannelMap);
+	fn enable(&mut self);
+	fn disable(&mut self);
+	/// Half words produced per trigger by the configured sequence
+	fn frame_width(&self) -> usize;
+	/// Address of the data register, used as the DMA peripheral address
+	fn data_address(&self) -> u32;
+	fn data_width(&self) -> DataWidth;
+	/// Raise the ADC interrupt when a result is lost (OVRIE)
+	fn enable_overrun_interrupt(&mut self);
+	fn overrun(&self) -> bool;
+	/// Clear OVR and re-arm the DMA requests, conversions resume on the
+	/// next trigger once enabled again
+	fn clear_overrun(&mut self);
+}
+
+/// DMA stream moving half words from a peripheral into two alternating buffers.
+pub trait DoubleBufferDma {
+	/// Point the stream at the peripheral and the two memory buffers.
+	///
+	/// `len` is the buffer length in half words. The stream starts over
+	/// at the beginning of `m0`.
+	///
+	/// # Safety
+	/// The DMA keeps writing `len` half words into both buffers for as long
+	/// as the stream is enabled, they must stay valid until it is disabled.
+	/// Word transfers need both buffers 4 byte aligned.
+	unsafe fn configure(&mut self, peripheral: u32, width: DataWidth, m0: *mut u16, m1: *mut u16, len: u16);
+	fn enable(&mut self);
+	fn disable(&mut self);
+	fn is_enabled(&self) -> bool;
+	/// The buffer the DMA is currently writing to
+	fn current_target(&self) -> Target;
+	/// Half words left before the current buffer is full
+	fn remaining(&self) -> u16;
+	fn transfer_complete(&self) -> bool;
+	fn transfer_error(&self) -> bool;
+	fn clear_interrupts(&mut self);
+}
+
+/// A timer's DMA request for compare register bursts (TDE and DCR).
+pub trait BurstRequest {
+	/// Stop requesting, a request left pending by a failed burst is dropped
+	fn disable(&mut self);
+	/// Start the next burst at its first register, requested on the next
+	/// trigger
+	fn enable(&mut self);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlashError {
+	// The flash controller reported an error (protection, sequence)
+	Program,
+	Erase,
+	// The supply failed part way, simulated flash only
+	PowerLoss,
+}
+
+/// Flash sectors set aside for storage.
+///
+/// Erased bytes read 0xFF, programming can only clear bits and only an
+/// erase of the whole sector sets them again.
+pub trait Flash {
+	fn sectors(&self) -> usize;
+	fn sector_size(&self) -> usize;
+	fn read(&self, sector: usize, offset: usize, buffer: &mut [u8]);
+	fn write(&mut self, sector: usize, offset: usize, data: &[u8]) -> Result<(), FlashError>;
+	fn erase(&mut self, sector: usize) -> Result<(), FlashError>;
+}
+
+/// Byte oriented serial port, both directions non blocking.
+pub trait Serial {
+	fn read(&mut self) -> Option<u8>;
+	/// Queue a byte, false if the transmitter is still busy
+	fn write(&mut self, byte: u8) -> bool;
+}
+
+/// Drives the CV outputs.
+pub trait OutputDriver {
+	fn channels(&self) -> usize;
+	/// Compare counts per carrier period of the channel's timer
+	fn steps(&self, channel: usize) -> u32;
+	/// Compare value within `0..=steps`, written as is
+	fn set(&mut self, channel: usize, compare: u32);
+}
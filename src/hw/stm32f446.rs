@@ -0,0 +1,960 @@
+//! STM32F446 implementation of the hardware traits.
+//!
+//! Sample clock on TIM5 channel 1, ADC1 (or ADC1-ADC3 in triple mode)
+//! triggered by TIM5_CH1 and DMA2 stream 0 (channel 0) moving the results
+//! in double buffer mode. The CV outputs are PWM channels of TIM1, TIM2,
+//! TIM3, TIM4 and TIM8, TIM1 and TIM8 loaded by DMA2 streams 4 and 7 on
+//! every TIM5 tick. The serial console is USART3 on PC10 (TX) and PC11 (RX).
+//! Sectors 6 and 7 of the internal flash are kept for storage.
+
+use core::ptr;
+
+use cortex_m::asm;
+use stm32f4::stm32f446 as pac;
+
+use super::{
+	AdcSequencer, BurstRequest, DataWidth, DoubleBufferDma, Flash, FlashError, OutputDriver, SampleTimer, Serial, Target,
+};
+use crate::adc::{Allocation, ChannelMap, Mode, Port};
+use crate::clocks::{Clocks, Mcu, Setup, Source};
+use crate::pwm::{self, burst, layout, Carrier, Output, Timer, MAX_OUTPUTS};
+
+// Give a peripheral reset time to propagate
+fn reset_delay() {
+	for _ in 0..100 {
+		asm::nop();
+	}
+}
+
+/// Switch the system clock over to `setup`.
+///
+/// Returns the clocks as decoded from the RCC registers after the switch,
+/// which match `setup.clocks` unless something refused to lock.
+pub fn configure_clocks(rcc: &pac::RCC, flash: &pac::FLASH, pwr: &pac::PWR, setup: &Setup) -> Clocks {
+	assert!(setup.mcu == Mcu::Stm32f446);
+
+	if let Source::Hse(_) = setup.source {
+		rcc.cr.modify(|_, w| w.hseon().bit(true));
+		while !rcc.cr.read().hserdy().bit() {}
+	}
+
+	// Voltage scale 1, needed above 144 MHz
+	rcc.apb1enr.modify(|_, w| w.pwren().bit(true));
+	pwr.cr.modify(|_, w| unsafe { w.vos().bits(0b11) });
+
+	if setup.pll.is_some() {
+		rcc.pllcfgr.write(|w| unsafe { w.bits(setup.pllcfgr()) });
+		rcc.cr.modify(|_, w| w.pllon().bit(true));
+		while !rcc.cr.read().pllrdy().bit() {}
+
+		if setup.overdrive {
+			pwr.cr.modify(|_, w| w.oden().bit(true));
+			while !pwr.csr.read().odrdy().bit() {}
+			pwr.cr.modify(|_, w| w.odswen().bit(true));
+			while !pwr.csr.read().odswrdy().bit() {}
+		}
+	}
+
+	// More wait states before speeding up
+	flash.acr.modify(
+		|_, w| unsafe {
+			w
+			.latency().bits(setup.flash_latency)
+			.prften().bit(true) // Prefetch
+			.icen().bit(true) // Instruction cache
+			.dcen().bit(true) // Data cache
+		}
+	);
+	while flash.acr.read().latency().bits() != setup.flash_latency {}
+
+	let cfgr = setup.cfgr();
+	rcc.cfgr.write(|w| unsafe { w.bits(cfgr) });
+	while (rcc.cfgr.read().bits() >> 2) & 0b11 != cfgr & 0b11 {}
+
+	Clocks::decode(setup.hse(), rcc.pllcfgr.read().bits(), rcc.cfgr.read().bits())
+}
+
+// Put the pins used by the channel map in analog mode
+pub fn configure_analog(gpioa: &pac::GPIOA, gpiob: &pac::GPIOB, gpioc: &pac::GPIOC, map: &ChannelMap) {
+	gpioa.moder.modify(|r, w| unsafe { w.bits(r.bits() | map.analog_moder(Port::A)) });
+	gpiob.moder.modify(|r, w| unsafe { w.bits(r.bits() | map.analog_moder(Port::B)) });
+	gpioc.moder.modify(|r, w| unsafe { w.bits(r.bits() | map.analog_moder(Port::C)) });
+}
+
+// Clock and reset GPIOA, GPIOB and GPIOC
+pub fn enable_gpio(rcc: &pac::RCC) {
+	rcc.ahb1enr.modify(|_, w| w.gpioaen().bit(true).gpioben().bit(true).gpiocen().bit(true));
+	rcc.ahb1rstr.modify(|_, w| w.gpioarst().bit(true).gpiobrst().bit(true).gpiocrst().bit(true));
+	reset_delay();
+	rcc.ahb1rstr.modify(|_, w| w.gpioarst().bit(false).gpiobrst().bit(false).gpiocrst().bit(false));
+}
+
+
+pub struct Tim5 {
+	tim: pac::TIM5,
+}
+
+impl Tim5 {
+	pub fn new(tim: pac::TIM5, rcc: &pac::RCC) -> Tim5 {
+		rcc.apb1enr.modify(|_, w| w.tim5en().bit(true));
+		rcc.apb1rstr.modify(|_, w| w.tim5rst().bit(true));
+		reset_delay();
+		rcc.apb1rstr.modify(|_, w| w.tim5rst().bit(false));
+
+		Tim5 { tim }
+	}
+}
+
+impl SampleTimer for Tim5 {
+	fn configure(&mut self, prescaler: u16, reload: u32, compare: u32) {
+		self.tim.cr1.write(
+			|w| unsafe {
+				w
+				.ckd().bits(0b00) // 00: Dont divide clock
+				.arpe().bit(true) // auto reload
+				.dir().bit(false) // count up
+				.cms().bits(0b00) // Edge aligned
+				.urs().bit(true) // Only under/over flow trigger update interrupt
+				.udis().bit(false) // DO generate update events
+			}
+		);
+
+		// PWM mode 1
+		// Channel 1 is high from the update event until COUNT matches CCR1,
+		// giving one rising edge per period. ADC1 is triggered on TIM5_CH1
+		// rising edges, toggle mode would only trigger every other match.
+		self.tim.ccmr1_output.write(
+			|w| unsafe {
+				w
+				.cc1s().bits(0b00) // Channel 1 is an output
+				.oc1m().bits(0b110) // 011: Toggle, 110: PWM mode 1
+				.oc1pe().bit(true) // Preload CCR1
+			}
+		);
+
+		self.tim.ccer.write(
+			|w|
+				w
+				.cc1e().bit(true) // Enable channel 1
+				.cc1p().bit(false) // Channel 1 is "active high"
+		);
+
+		// OC1REF as TRGO, the rising edge triggers the output timers
+		self.tim.cr2.write(|w| unsafe { w.mms().bits(0b100) });
+
+		// Timer interrups
+		self.tim.dier.write(|w| w
+			.uie().bit(false)
+			.cc1ie().bit(true)
+		);
+
+		self.tim.arr.write(|w| unsafe { w.bits(reload) });
+		self.tim.cnt.write(|w| unsafe { w.bits(0) });
+		self.tim.ccr1.write(|w| unsafe { w.bits(compare) });
+		self.tim.psc.write(|w| unsafe { w.bits(prescaler as u32) });
+	}
+
+	fn start(&mut self) {
+		// Enable counter
+		self.tim.cr1.modify(|_, w| w.cen().bit(true));
+		// Reinit the counter and fire update event
+		self.tim.egr.write(|w| w.ug().bit(true));
+	}
+
+	fn stop(&mut self) {
+		self.tim.cr1.modify(|_, w| w.cen().bit(false));
+	}
+
+	fn clear_interrupts(&mut self) {
+		self.tim.sr.modify(|_, w| w
+			.uif().bit(false)
+			.cc1if().bit(false)
+		);
+	}
+}
+
+
+// Program resolution, sequence and sample times of one ADC.
+// Only the master (ADC1) listens to the sample timer.
+fn program_adc(adc: &pac::adc1::RegisterBlock, map: &ChannelMap, master: bool) {
+	// Right alignment
+	adc.cr2.modify(|_, w| w.align().bit(false));
+
+	adc.cr1.modify(
+		|_, w| {
+			w
+			.res().bits(0b00) // 12 bit resolution
+			.scan().bit(true)
+			.eocie().bit(false) // no EOC interrupt
+		}
+	);
+
+	adc.cr2.modify(
+		|_, w| unsafe {
+			if master {
+				w
+				.exten().bits(0b01) // External trigger. 01: Rising Edge
+				.extsel().bits(0b1010) // TIM5_CH1 event
+			} else {
+				w.exten().bits(0b00) // No ext trigger, started by the master
+			}
+		}
+	);
+
+	// Sequence, length and sample times
+	let regs = map.registers();
+	adc.sqr1.write(|w| unsafe { w.bits(regs.sqr1) });
+	adc.sqr2.write(|w| unsafe { w.bits(regs.sqr2) });
+	adc.sqr3.write(|w| unsafe { w.bits(regs.sqr3) });
+	adc.smpr1.write(|w| unsafe { w.bits(regs.smpr1) });
+	adc.smpr2.write(|w| unsafe { w.bits(regs.smpr2) });
+}
+
+// Clock and reset all three ADCs (ADCRST is shared)
+fn enable_adcs(rcc: &pac::RCC, adcs: usize) {
+	rcc.apb2enr.modify(|_, w| w
+		.adc1en().bit(true)
+		.adc2en().bit(adcs > 1)
+		.adc3en().bit(adcs > 1)
+	);
+	rcc.apb2rstr.modify(|_, w| w.adcrst().bit(true));
+	reset_delay();
+	rcc.apb2rstr.modify(|_, w| w.adcrst().bit(false));
+}
+
+
+pub struct Adc1 {
+	adc: pac::ADC1,
+	width: usize,
+}
+
+impl Adc1 {
+	pub fn new(adc: pac::ADC1, common: &pac::ADC_COMMON, rcc: &pac::RCC, clocks: &Clocks) -> Adc1 {
+		enable_adcs(rcc, 1);
+
+		// Fastest ADC clock within limits, 00: PCLK2/2 .. 11: PCLK2/8
+		let (adcpre, _) = clocks.adc_prescaler(Mcu::Stm32f446);
+
+		common.ccr.modify(
+			|_, w| unsafe {
+				w
+				.adcpre().bits(adcpre)
+				.multi().bits(Mode::Independent.multi())
+				.delay().bits(0b0000) // 5 * adc_clk delay
+			}
+		);
+
+		Adc1 { adc, width: 0 }
+	}
+}
+
+impl AdcSequencer for Adc1 {
+	fn configure(&mut self, map: &ChannelMap) {
+		program_adc(&self.adc, map, true);
+		self.width = map.len();
+
+		// Enable DMA on ADC
+		self.adc.cr2.modify(
+			|_, w|
+				w
+				.dma().bit(true) // Enable DMA
+				.dds().bit(true) // DMA requests are issued as long as data are converted and DMA=1
+		);
+	}
+
+	fn enable(&mut self) {
+		self.adc.cr2.modify(
+			|_, w|
+				w.adon().bit(true)
+				.swstart().bit(true)
+		);
+	}
+
+	fn disable(&mut self) {
+		self.adc.cr2.modify(|_, w| w.adon().bit(false));
+	}
+
+	fn frame_width(&self) -> usize {
+		self.width
+	}
+
+	fn data_address(&self) -> u32 {
+		unsafe { &(*pac::ADC1::ptr()).dr as *const _ as u32 }
+	}
+
+	fn data_width(&self) -> DataWidth {
+		DataWidth::HalfWord
+	}
+
+	fn enable_overrun_interrupt(&mut self) {
+		self.adc.cr1.modify(|_, w| w.ovrie().bit(true));
+	}
+
+	fn overrun(&self) -> bool {
+		self.adc.sr.read().ovr().bit()
+	}
+
+	fn clear_overrun(&mut self) {
+		self.adc.sr.modify(|_, w| w.ovr().bit(false));
+		// DMA requests stay blocked after an overrun until DMA is toggled
+		self.adc.cr2.modify(|_, w| w.dma().bit(false));
+		self.adc.cr2.modify(|_, w| w.dma().bit(true));
+	}
+}
+
+
+/// ADC1-ADC3 in triple simultaneous or interleaved mode.
+///
+/// ADC1 is the master triggered by TIM5, the results are read from the
+/// common data register in DMA mode 2.
+pub struct TripleAdc {
+	adc1: pac::ADC1,
+	adc2: pac::ADC2,
+	adc3: pac::ADC3,
+	common: pac::ADC_COMMON,
+	mode: Mode,
+	width: usize,
+}
+
+impl TripleAdc {
+	pub fn new(
+		adc1: pac::ADC1,
+		adc2: pac::ADC2,
+		adc3: pac::ADC3,
+		common: pac::ADC_COMMON,
+		rcc: &pac::RCC,
+		clocks: &Clocks,
+		mode: Mode,
+	) -> TripleAdc {
+		assert!(mode != Mode::Independent);
+		enable_adcs(rcc, 3);
+
+		let (adcpre, _) = clocks.adc_prescaler(Mcu::Stm32f446);
+		common.ccr.modify(
+			|_, w| unsafe {
+				w
+				.adcpre().bits(adcpre)
+				.multi().bits(mode.multi())
+				.dma().bits(0b10) // DMA mode 2, two results per word
+				.dds().bit(true) // Keep issuing DMA requests
+			}
+		);
+
+		TripleAdc { adc1, adc2, adc3, common, mode, width: 0 }
+	}
+}
+
+impl AdcSequencer for TripleAdc {
+	fn configure(&mut self, map: &ChannelMap) {
+		let allocation = Allocation::new(map, self.mode).unwrap();
+
+		program_adc(&self.adc1, allocation.sequence(0), true);
+		program_adc(&self.adc2, allocation.sequence(1), false);
+		program_adc(&self.adc3, allocation.sequence(2), false);
+
+		// Interleaved: stagger the three ADCs evenly over one conversion,
+		// DELAY is 5 to 20 cycles
+		let delay = (allocation.sequence_cycles() / 3).clamp(5, 20) - 5;
+		self.common.ccr.modify(|_, w| w.delay().bits(delay as u8));
+
+		self.width = allocation.frame_width();
+	}
+
+	fn enable(&mut self) {
+		self.adc2.cr2.modify(|_, w| w.adon().bit(true));
+		self.adc3.cr2.modify(|_, w| w.adon().bit(true));
+		self.adc1.cr2.modify(
+			|_, w|
+				w.adon().bit(true)
+				.swstart().bit(true)
+		);
+	}
+
+	fn disable(&mut self) {
+		self.adc1.cr2.modify(|_, w| w.adon().bit(false));
+		self.adc2.cr2.modify(|_, w| w.adon().bit(false));
+		self.adc3.cr2.modify(|_, w| w.adon().bit(false));
+	}
+
+	fn frame_width(&self) -> usize {
+		self.width
+	}
+
+	fn data_address(&self) -> u32 {
+		unsafe { &(*pac::ADC_COMMON::ptr()).cdr as *const _ as u32 }
+	}
+
+	fn data_width(&self) -> DataWidth {
+		DataWidth::Word
+	}
+
+	fn enable_overrun_interrupt(&mut self) {
+		self.adc1.cr1.modify(|_, w| w.ovrie().bit(true));
+		self.adc2.cr1.modify(|_, w| w.ovrie().bit(true));
+		self.adc3.cr1.modify(|_, w| w.ovrie().bit(true));
+	}
+
+	fn overrun(&self) -> bool {
+		let csr = self.common.csr.read();
+		csr.ovr1().bit() || csr.ovr2().bit() || csr.ovr3().bit()
+	}
+
+	fn clear_overrun(&mut self) {
+		self.adc1.sr.modify(|_, w| w.ovr().bit(false));
+		self.adc2.sr.modify(|_, w| w.ovr().bit(false));
+		self.adc3.sr.modify(|_, w| w.ovr().bit(false));
+		// Multi mode DMA requests resume once the DMA mode is written again
+		self.common.ccr.modify(|_, w| w.dma().bits(0b00));
+		self.common.ccr.modify(|_, w| w.dma().bits(0b10));
+	}
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Direction {
+	PeripheralToMemory = 0b00,
+	MemoryToPeripheral = 0b01,
+}
+
+/// One stream of DMA2 in double buffer mode.
+///
+/// Streams only touch their own registers and their own bits of the shared
+/// interrupt status and clear registers.
+pub struct Dma2Stream {
+	stream: usize,
+	channel: u8,
+	direction: Direction,
+}
+
+/// The DMA2 streams in use.
+pub struct Dma2Streams {
+	/// Stream 0 channel 0, ADC1 (or the ADC common data register)
+	pub adc: Dma2Stream,
+	/// Stream 4 channel 6, TIM1_TRIG bursts into TIM1 DMAR
+	pub tim1: Dma2Stream,
+	/// Stream 7 channel 7, TIM8_TRIG bursts into TIM8 DMAR
+	pub tim8: Dma2Stream,
+}
+
+impl Dma2Streams {
+	pub fn new(_dma: pac::DMA2, rcc: &pac::RCC) -> Dma2Streams {
+		rcc.ahb1enr.modify(|_, w| w.dma2en().bit(true));
+		rcc.ahb1rstr.modify(|_, w| w.dma2rst().bit(true));
+		reset_delay();
+		rcc.ahb1rstr.modify(|_, w| w.dma2rst().bit(false));
+
+		Dma2Streams {
+			adc: Dma2Stream { stream: 0, channel: 0, direction: Direction::PeripheralToMemory },
+			tim1: Dma2Stream { stream: 4, channel: 6, direction: Direction::MemoryToPeripheral },
+			tim8: Dma2Stream { stream: 7, channel: 7, direction: Direction::MemoryToPeripheral },
+		}
+	}
+}
+
+impl Dma2Stream {
+	fn dma(&self) -> &pac::dma2::RegisterBlock {
+		unsafe { &*pac::DMA2::ptr() }
+	}
+
+	fn st(&self) -> &pac::dma2::ST {
+		&self.dma().st[self.stream]
+	}
+
+	// Position of the stream's flags in LISR/HISR and LIFCR/HIFCR
+	fn flag_shift(&self) -> u32 {
+		[0, 6, 16, 22][self.stream % 4]
+	}
+
+	fn status(&self) -> u32 {
+		let isr = if self.stream < 4 { self.dma().lisr.read().bits() } else { self.dma().hisr.read().bits() };
+		isr >> self.flag_shift()
+	}
+}
+
+// Stream flags relative to `flag_shift`
+const TEIF: u32 = 1 << 3;
+const HTIF: u32 = 1 << 4;
+const TCIF: u32 = 1 << 5;
+
+impl DoubleBufferDma for Dma2Stream {
+	unsafe fn configure(&mut self, peripheral: u32, width: DataWidth, m0: *mut u16, m1: *mut u16, len: u16) {
+		let st = self.st();
+
+		// NDTR counts peripheral sized transfers
+		let (size, transfers) = match width {
+			DataWidth::HalfWord => (0b01, len as u32),
+			DataWidth::Word => (0b10, len as u32 / 2),
+		};
+
+		st.par.write(|w| w.bits(peripheral));
+		st.m0ar.write(|w| w.bits(m0 as u32));
+		st.m1ar.write(|w| w.bits(m1 as u32));
+		st.ndtr.write(|w| w.bits(transfers));
+
+		// Very high priority
+		st.cr.modify(|_, w| w.chsel().bits(self.channel).pl().bits(0b11));
+
+		st.fcr.modify(
+			|_, w|
+				w
+				.fth().bits(0b01) // FIFO threshold, 01: Half Full
+				.dmdis().bit(self.direction == Direction::PeripheralToMemory) // Use the FIFO for the ADC, direct mode for bursts
+		);
+
+		st.cr.modify(
+			|_, w|
+			w
+				.msize().bits(size) // 01: Half Word(16 bit), 10: Word(32 bit)
+				.psize().bits(size)
+				.minc().bit(true)
+				.pinc().bit(false)
+				.dbm().bit(true) // Double buffer mode
+				.ct().bit(false) // Start with M0
+				.circ().bit(true)
+				.dir().bits(self.direction as u8)
+				.mburst().bits(0b00) // Single transfer
+				.pburst().bits(0b00) // Single transer
+				.tcie().bit(true) // Enable transfer complete interrupt
+				.teie().bit(true) // Enable transfer error interrupt
+		);
+	}
+
+	fn enable(&mut self) {
+		self.st().cr.modify(|_, w| w.en().bit(true));
+	}
+
+	fn disable(&mut self) {
+		self.st().cr.modify(|_, w| w.en().bit(false));
+		// The stream is only stopped once EN reads back as 0
+		while self.st().cr.read().en().bit() {}
+	}
+
+	fn is_enabled(&self) -> bool {
+		self.st().cr.read().en().bit()
+	}
+
+	fn current_target(&self) -> Target {
+		if self.st().cr.read().ct().bit() { Target::M1 } else { Target::M0 }
+	}
+
+	fn remaining(&self) -> u16 {
+		let ndtr = self.st().ndtr.read().bits() as u16;
+		if self.st().cr.read().psize().bits() == 0b10 { ndtr * 2 } else { ndtr }
+	}
+
+	fn transfer_complete(&self) -> bool {
+		self.status() & TCIF != 0
+	}
+
+	fn transfer_error(&self) -> bool {
+		self.status() & TEIF != 0
+	}
+
+	fn clear_interrupts(&mut self) {
+		let flags = (TCIF | HTIF | TEIF) << self.flag_shift();
+		if self.stream < 4 {
+			self.dma().lifcr.write(|w| unsafe { w.bits(flags) });
+		} else {
+			self.dma().hifcr.write(|w| unsafe { w.bits(flags) });
+		}
+	}
+}
+
+
+// PWM mode 1 with preloaded compare on both channels of a CCMR register
+const PWM_MODE_1: u32 = 0b110 << 12 | 1 << 11 | 0b110 << 4 | 1 << 3;
+
+// Program an output timer for `carrier`, edge aligned and counting up
+macro_rules! configure_pwm_timer {
+	($tim:expr, $carrier:expr, $ccer:expr) => {{
+		let tim = &$tim;
+		let carrier: Carrier = $carrier;
+		tim.cr1.write(|w| w.arpe().bit(true));
+		tim.psc.write(|w| unsafe { w.bits(carrier.prescaler() as u32) });
+		tim.arr.write(|w| unsafe { w.bits(carrier.reload()) });
+		tim.ccmr1_output.write(|w| unsafe { w.bits(PWM_MODE_1) });
+		tim.ccmr2_output.write(|w| unsafe { w.bits(PWM_MODE_1) });
+		tim.ccer.write(|w| unsafe { w.bits($ccer) });
+		// Load PSC, ARR and the compare preloads
+		tim.egr.write(|w| w.ug().bit(true));
+	}};
+}
+
+macro_rules! set_compare {
+	($tim:expr, $channel:expr, $value:expr) => {{
+		let value: u32 = $value;
+		match $channel {
+			1 => $tim.ccr1.write(|w| unsafe { w.bits(value) }),
+			2 => $tim.ccr2.write(|w| unsafe { w.bits(value) }),
+			3 => $tim.ccr3.write(|w| unsafe { w.bits(value) }),
+			_ => $tim.ccr4.write(|w| unsafe { w.bits(value) }),
+		}
+	}};
+}
+
+/// The CV outputs as PWM channels, see `pwm::layout` for the pin out.
+///
+/// Every timer in use runs the same carrier frequency. Timers on APB2 get
+/// a longer period and so more than 12 bits, `set` takes compare values in
+/// each timer's own counts, see `steps`.
+pub struct PwmOutputs {
+	tim1: pac::TIM1,
+	tim2: pac::TIM2,
+	tim3: pac::TIM3,
+	tim4: pac::TIM4,
+	tim8: pac::TIM8,
+	outputs: &'static [Output],
+	// Indexed by `Timer::index`, None for unused timers
+	carriers: [Option<Carrier>; 5],
+}
+
+impl PwmOutputs {
+	/// Start the first `count` outputs at `carrier_hz`, all at 0.
+	///
+	/// GPIOA-GPIOC must already be clocked, see `enable_gpio`.
+	#[allow(clippy::too_many_arguments)]
+	pub fn new(
+		tim1: pac::TIM1,
+		tim2: pac::TIM2,
+		tim3: pac::TIM3,
+		tim4: pac::TIM4,
+		tim8: pac::TIM8,
+		rcc: &pac::RCC,
+		gpioa: &pac::GPIOA,
+		gpiob: &pac::GPIOB,
+		gpioc: &pac::GPIOC,
+		clocks: &Clocks,
+		carrier_hz: u32,
+		count: usize,
+	) -> Result<PwmOutputs, pwm::Error> {
+		if count > MAX_OUTPUTS {
+			return Err(pwm::Error::TooManyOutputs);
+		}
+		let outputs = layout::outputs(count);
+
+		let mut carriers = [None; 5];
+		for &timer in Timer::ALL.iter().filter(|&&timer| layout::uses(outputs, timer)) {
+			let carrier = Carrier::with_max_reload(carrier_hz, timer.clock(clocks), timer.max_reload())?;
+			carriers[timer.index()] = Some(carrier);
+		}
+
+		rcc.apb1enr.modify(|_, w| w.tim2en().bit(true).tim3en().bit(true).tim4en().bit(true));
+		rcc.apb2enr.modify(|_, w| w.tim1en().bit(true).tim8en().bit(true));
+		rcc.apb1rstr.modify(|_, w| w.tim2rst().bit(true).tim3rst().bit(true).tim4rst().bit(true));
+		rcc.apb2rstr.modify(|_, w| w.tim1rst().bit(true).tim8rst().bit(true));
+		reset_delay();
+		rcc.apb1rstr.modify(|_, w| w.tim2rst().bit(false).tim3rst().bit(false).tim4rst().bit(false));
+		rcc.apb2rstr.modify(|_, w| w.tim1rst().bit(false).tim8rst().bit(false));
+
+		let pwm = PwmOutputs { tim1, tim2, tim3, tim4, tim8, outputs, carriers };
+
+		if let Some(carrier) = pwm.carriers[Timer::Tim1.index()] {
+			configure_pwm_timer!(pwm.tim1, carrier, layout::ccer(outputs, Timer::Tim1));
+			pwm.tim1.bdtr.modify(|_, w| w.moe().bit(true));
+		}
+		if let Some(carrier) = pwm.carriers[Timer::Tim2.index()] {
+			configure_pwm_timer!(pwm.tim2, carrier, layout::ccer(outputs, Timer::Tim2));
+		}
+		if let Some(carrier) = pwm.carriers[Timer::Tim3.index()] {
+			configure_pwm_timer!(pwm.tim3, carrier, layout::ccer(outputs, Timer::Tim3));
+		}
+		if let Some(carrier) = pwm.carriers[Timer::Tim4.index()] {
+			configure_pwm_timer!(pwm.tim4, carrier, layout::ccer(outputs, Timer::Tim4));
+		}
+		if let Some(carrier) = pwm.carriers[Timer::Tim8.index()] {
+			configure_pwm_timer!(pwm.tim8, carrier, layout::ccer(outputs, Timer::Tim8));
+			pwm.tim8.bdtr.modify(|_, w| w.moe().bit(true));
+		}
+
+		gpioa.moder.modify(|r, w| unsafe { w.bits(r.bits() | layout::alternate_moder(outputs, Port::A)) });
+		gpiob.moder.modify(|r, w| unsafe { w.bits(r.bits() | layout::alternate_moder(outputs, Port::B)) });
+		gpioc.moder.modify(|r, w| unsafe { w.bits(r.bits() | layout::alternate_moder(outputs, Port::C)) });
+		let (afrl, afrh) = layout::alternate_functions(outputs, Port::A);
+		gpioa.afrl.modify(|r, w| unsafe { w.bits(r.bits() | afrl) });
+		gpioa.afrh.modify(|r, w| unsafe { w.bits(r.bits() | afrh) });
+		let (afrl, afrh) = layout::alternate_functions(outputs, Port::B);
+		gpiob.afrl.modify(|r, w| unsafe { w.bits(r.bits() | afrl) });
+		gpiob.afrh.modify(|r, w| unsafe { w.bits(r.bits() | afrh) });
+		let (afrl, afrh) = layout::alternate_functions(outputs, Port::C);
+		gpioc.afrl.modify(|r, w| unsafe { w.bits(r.bits() | afrl) });
+		gpioc.afrh.modify(|r, w| unsafe { w.bits(r.bits() | afrh) });
+
+		// Start the counters back to back
+		if pwm.carriers[Timer::Tim1.index()].is_some() {
+			pwm.tim1.cr1.modify(|_, w| w.cen().bit(true));
+		}
+		if pwm.carriers[Timer::Tim2.index()].is_some() {
+			pwm.tim2.cr1.modify(|_, w| w.cen().bit(true));
+		}
+		if pwm.carriers[Timer::Tim3.index()].is_some() {
+			pwm.tim3.cr1.modify(|_, w| w.cen().bit(true));
+		}
+		if pwm.carriers[Timer::Tim4.index()].is_some() {
+			pwm.tim4.cr1.modify(|_, w| w.cen().bit(true));
+		}
+		if pwm.carriers[Timer::Tim8.index()].is_some() {
+			pwm.tim8.cr1.modify(|_, w| w.cen().bit(true));
+		}
+
+		Ok(pwm)
+	}
+
+	pub fn carrier(&self, timer: Timer) -> Option<Carrier> {
+		self.carriers[timer.index()]
+	}
+
+	/// Hand the compare registers of TIM1 and TIM8 over to DMA bursts.
+	///
+	/// Both timers stop and restart together on the next TIM5 tick (trigger
+	/// mode on ITR0 and ITR3). Once their `BurstTimer` is enabled every tick
+	/// requests one burst of CCR1-CCR4 through DMAR.
+	pub fn sync_to_sample_clock(&mut self) {
+		if self.carriers[Timer::Tim1.index()].is_some() {
+			let tim = &self.tim1;
+			tim.cr1.modify(|_, w| w.cen().bit(false));
+			tim.cnt.write(|w| unsafe { w.bits(0) });
+			tim.smcr.modify(|_, w| unsafe { w.ts().bits(0b000).sms().bits(0b110) });
+		}
+		if self.carriers[Timer::Tim8.index()].is_some() {
+			let tim = &self.tim8;
+			tim.cr1.modify(|_, w| w.cen().bit(false));
+			tim.cnt.write(|w| unsafe { w.bits(0) });
+			tim.smcr.modify(|_, w| unsafe { w.ts().bits(0b011).sms().bits(0b110) });
+		}
+	}
+
+	/// The trigger DMA request of TIM1 or TIM8
+	pub fn burst_timer(&self, timer: Timer) -> BurstTimer {
+		match timer {
+			Timer::Tim1 => BurstTimer { tim: pac::TIM1::ptr() },
+			Timer::Tim8 => BurstTimer { tim: pac::TIM8::ptr() },
+			_ => panic!("only TIM1 and TIM8 are served by DMA2"),
+		}
+	}
+
+	/// DMA burst address (DMAR) of TIM1 or TIM8
+	pub fn dmar(&self, timer: Timer) -> u32 {
+		match timer {
+			Timer::Tim1 => unsafe { &(*pac::TIM1::ptr()).dmar as *const _ as u32 },
+			Timer::Tim8 => unsafe { &(*pac::TIM8::ptr()).dmar as *const _ as u32 },
+			_ => panic!("only TIM1 and TIM8 are served by DMA2"),
+		}
+	}
+}
+
+/// DMA request of TIM1 or TIM8 for compare register bursts.
+pub struct BurstTimer {
+	tim: *const pac::tim1::RegisterBlock,
+}
+
+impl BurstTimer {
+	fn tim(&self) -> &pac::tim1::RegisterBlock {
+		unsafe { &*self.tim }
+	}
+}
+
+impl BurstRequest for BurstTimer {
+	fn disable(&mut self) {
+		self.tim().dier.modify(|_, w| w.tde().bit(false));
+	}
+
+	fn enable(&mut self) {
+		// Writing DCR starts the next burst over at CCR1
+		self.tim().dcr.write(|w| unsafe { w.bits(burst::DCR) });
+		self.tim().dier.modify(|_, w| w.tde().bit(true));
+	}
+}
+
+impl OutputDriver for PwmOutputs {
+	fn channels(&self) -> usize {
+		self.outputs.len()
+	}
+
+	fn steps(&self, channel: usize) -> u32 {
+		self.carriers[self.outputs[channel].timer.index()].map_or(0, |carrier| carrier.steps())
+	}
+
+	fn set(&mut self, channel: usize, compare: u32) {
+		let output = self.outputs[channel];
+
+		match output.timer {
+			Timer::Tim1 => set_compare!(self.tim1, output.channel, compare),
+			Timer::Tim2 => set_compare!(self.tim2, output.channel, compare),
+			Timer::Tim3 => set_compare!(self.tim3, output.channel, compare),
+			Timer::Tim4 => set_compare!(self.tim4, output.channel, compare),
+			Timer::Tim8 => set_compare!(self.tim8, output.channel, compare),
+		}
+	}
+}
+
+
+/// USART3 on PC10/PC11, 8N1, polled.
+pub struct Usart3 {
+	usart: pac::USART3,
+}
+
+impl Usart3 {
+	/// GPIOC must already be clocked, see `enable_gpio`.
+	pub fn new(usart: pac::USART3, rcc: &pac::RCC, gpioc: &pac::GPIOC, clocks: &Clocks, baud: u32) -> Usart3 {
+		rcc.apb1enr.modify(|_, w| w.usart3en().bit(true));
+		rcc.apb1rstr.modify(|_, w| w.uart3rst().bit(true));
+		reset_delay();
+		rcc.apb1rstr.modify(|_, w| w.uart3rst().bit(false));
+
+		// PC10 and PC11 alternate function 7
+		gpioc.moder.modify(|r, w| unsafe { w.bits(r.bits() & !(0b1111 << 20) | 0b1010 << 20) });
+		gpioc.afrh.modify(|r, w| unsafe { w.bits(r.bits() & !(0xFF << 8) | 0x77 << 8) });
+
+		// 16x oversampling, BRR is PCLK1 / baud rounded
+		let brr = (clocks.pclk1 + baud / 2) / baud;
+		usart.brr.write(|w| unsafe { w.bits(brr) });
+		usart.cr1.write(
+			|w|
+				w
+				.ue().bit(true) // Enable
+				.te().bit(true) // Transmitter
+				.re().bit(true) // Receiver
+		);
+
+		Usart3 { usart }
+	}
+}
+
+impl Serial for Usart3 {
+	fn read(&mut self) -> Option<u8> {
+		let sr = self.usart.sr.read();
+		if sr.ore().bit() {
+			// Reading DR after SR clears the overrun, the byte is lost anyway
+			let _ = self.usart.dr.read();
+			return None;
+		}
+		if !sr.rxne().bit() {
+			return None;
+		}
+		Some(self.usart.dr.read().bits() as u8)
+	}
+
+	fn write(&mut self, byte: u8) -> bool {
+		if !self.usart.sr.read().txe().bit() {
+			return false;
+		}
+		self.usart.dr.write(|w| unsafe { w.bits(byte as u32) });
+		true
+	}
+}
+
+
+// Sectors 6 and 7, the STORAGE region in memory.x
+const STORAGE_SECTORS: [u8; 2] = [6, 7];
+const STORAGE_START: usize = 0x0804_0000;
+const STORAGE_SECTOR_SIZE: usize = 128 * 1024;
+
+// FLASH_KEYR unlock sequence
+const FLASH_KEY1: u32 = 0x4567_0123;
+const FLASH_KEY2: u32 = 0xCDEF_89AB;
+// FLASH_SR error flags: OPERR, WRPERR, PGAERR, PGPERR, PGSERR
+const FLASH_ERRORS: u32 = 0b1111_0010;
+
+/// The storage sectors of the internal flash, programmed a byte at a time.
+///
+/// The CPU stalls while it fetches from a flash being programmed or
+/// erased, for one to two seconds per 128K sector erase, so interrupts are
+/// late and the sampler will likely recover from an overrun.
+pub struct InternalFlash {
+	flash: pac::FLASH,
+}
+
+impl InternalFlash {
+	/// Call after `configure_clocks`, which sets the wait states.
+	pub fn new(flash: pac::FLASH) -> InternalFlash {
+		InternalFlash { flash }
+	}
+
+	fn address(sector: usize, offset: usize) -> usize {
+		STORAGE_START + sector * STORAGE_SECTOR_SIZE + offset
+	}
+
+	fn unlock(&mut self) {
+		if self.flash.cr.read().lock().bit() {
+			self.flash.keyr.write(|w| unsafe { w.bits(FLASH_KEY1) });
+			self.flash.keyr.write(|w| unsafe { w.bits(FLASH_KEY2) });
+		}
+	}
+
+	fn lock(&mut self) {
+		self.flash.cr.modify(|_, w| w.pg().bit(false).ser().bit(false).lock().bit(true));
+	}
+
+	// Wait for the operation to finish, clear and report errors
+	fn wait(&mut self) -> bool {
+		while self.flash.sr.read().bsy().bit() {}
+
+		let errors = self.flash.sr.read().bits() & FLASH_ERRORS;
+		self.flash.sr.write(|w| unsafe { w.bits(errors) });
+		errors == 0
+	}
+
+	// The data cache may still hold the old contents
+	fn flush_data_cache(&mut self) {
+		self.flash.acr.modify(|_, w| w.dcen().bit(false));
+		self.flash.acr.modify(|_, w| w.dcrst().bit(true));
+		self.flash.acr.modify(|_, w| w.dcrst().bit(false).dcen().bit(true));
+	}
+}
+
+impl Flash for InternalFlash {
+	fn sectors(&self) -> usize {
+		STORAGE_SECTORS.len()
+	}
+
+	fn sector_size(&self) -> usize {
+		STORAGE_SECTOR_SIZE
+	}
+
+	fn read(&self, sector: usize, offset: usize, buffer: &mut [u8]) {
+		assert!(offset + buffer.len() <= STORAGE_SECTOR_SIZE);
+
+		let start = InternalFlash::address(sector, offset) as *const u8;
+		for (i, byte) in buffer.iter_mut().enumerate() {
+			*byte = unsafe { ptr::read_volatile(start.add(i)) };
+		}
+	}
+
+	fn write(&mut self, sector: usize, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+		assert!(offset + data.len() <= STORAGE_SECTOR_SIZE);
+
+		self.unlock();
+		self.wait();
+		// Byte parallelism, fine at any supply voltage
+		self.flash.cr.modify(|_, w| unsafe { w.psize().bits(0b00).pg().bit(true) });
+
+		let start = InternalFlash::address(sector, offset) as *mut u8;
+		let mut ok = true;
+		for (i, &byte) in data.iter().enumerate() {
+			// Already erased, nothing to program
+			if byte == 0xFF {
+				continue;
+			}
+			unsafe { ptr::write_volatile(start.add(i), byte) };
+			if !self.wait() {
+				ok = false;
+				break;
+			}
+		}
+
+		self.lock();
+		self.flush_data_cache();
+		if ok { Ok(()) } else { Err(FlashError::Program) }
+	}
+
+	fn erase(&mut self, sector: usize) -> Result<(), FlashError> {
+		self.unlock();
+		self.wait();
+		self.flash.cr.modify(|_, w| unsafe { w.psize().bits(0b00).ser().bit(true).snb().bits(STORAGE_SECTORS[sector]) });
+		self.flash.cr.modify(|_, w| w.strt().bit(true));
+		let ok = self.wait();
+		self.lock();
+		self.flush_data_cache();
+		if ok { Ok(()) } else { Err(FlashError::Erase) }
+	}
+}
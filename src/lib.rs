@@ -0,0 +1,31 @@
+//! Eurorack CV-IO firmware library.
+//!
+//! Everything that does not need the board lives here so it can be built and
+//! tested on the host. The `cv_io` binary only brings up the board and wires
+//! the library to the interrupts.
+//!
+//! Features:
+//! - `stm32f446`: register level implementation of the `hw` traits
+//! - `std`: host build, enables the simulated board in `hw::mock`
+
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+pub mod adc;
+pub mod calibration;
+pub mod clock_follower;
+pub mod clock_out;
+pub mod clocks;
+pub mod config;
+pub mod console;
+pub mod double_buffer;
+pub mod filter;
+pub mod fixed;
+pub mod gate;
+pub mod hw;
+pub mod pwm;
+pub mod quantizer;
+pub mod sample_clock;
+pub mod sample_hold;
+pub mod sampler;
+pub mod storage;
+pub mod tuning;
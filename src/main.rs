@@ -1,370 +1,973 @@
 #![no_std]
 #![no_main]
-#![feature(const_raw_ptr_to_usize_cast)]
+// State shared with the interrupt handlers lives in `static mut`s, taken
+// inside `interrupt::free` or from the handler that owns it
+#![allow(static_mut_refs)]
 
 // 80041500/44100 = 1815
 // 
 // 44100*160	/44100 = 160	/48000 = 147
 // 44100*160*2	/44100 = 320	/48000 = 294	/96000 = 147
 
+// Board bring-up and interrupt wiring, everything else lives in the `cv_io` library
+
 // pick a panicking behavior
 // use panic_halt as _; // you can put a breakpoint on `rust_begin_unwind` to catch panics
 // use panic_abort as _; // requires nightly
 // use panic_itm as _; // logs messages over ITM; requires ITM support
 use panic_semihosting as _; // logs messages to the host stderr; requires a debugger
 
-use cortex_m::asm;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use cortex_m_rt::{entry};
 use cortex_m_semihosting::hprintln;
 
-use core::ptr;
+// Bring up messages over semihosting, dropped when no debugger listens
+macro_rules! log {
+	($($arg:tt)*) => {
+		let _ = hprintln!($($arg)*);
+	};
+}
 
 use stm32f4::stm32f446 as pac;
 use pac::{interrupt, NVIC};
 
+use cv_io::calibration::{
+	Action, Calibration, InputCalibration, InputCalibrations, Limits, OutputCalibration, OutputCalibrations, Plan,
+	Session,
+};
+use cv_io::clock_follower::{ClockFollower, State};
+use cv_io::clock_out::{self, ClockOut, ClockOutConfig, ClockOuts};
+use cv_io::clocks::{ClockConfig, Mcu};
+use cv_io::config::{
+	ClockInput, Config, ConfigStore, InputSettings, Modes, OutputMode, QuantizerConfig, ScaleSource, MAX_OVERSAMPLING,
+};
+use cv_io::console::Console;
+use cv_io::double_buffer::DoubleBuffer;
+use cv_io::filter::{self, Decimator, FilterBank, Kind};
+use cv_io::fixed::{Sample, Volts};
+use cv_io::gate::{Edge, Events, GateBank, GateConfig};
+use cv_io::adc::{Allocation, ChannelMap, Mode, MAX_CHANNELS};
+use cv_io::hw::SampleTime;
+use cv_io::adc::Frames;
+use cv_io::hw::stm32f446::{
+	self as board, BurstTimer, Dma2Stream, Dma2Streams, InternalFlash, PwmOutputs, Tim5, TripleAdc, Usart3,
+};
+use cv_io::pwm::burst::{BurstLayout, BurstOutput, BURST_LEN};
+use cv_io::pwm::{layout, OutputEngine, Shaping, Timer, MAX_OUTPUTS};
+use cv_io::quantizer::{Quantizer, Quantizers, Scale};
+use cv_io::sample_clock::SampleClock;
+use cv_io::sample_hold::{HoldConfig, Mode as HoldMode, SampleHolds, Source};
+use cv_io::sampler::{Diagnostics, Sampler};
+use cv_io::storage::Storage;
+use cv_io::tuning::{self, Tuning, Upload};
+
+static mut SAMPLER: Option<Sampler<Tim5, TripleAdc, Dma2Stream>> = None;
+static mut BUFFERS: Option<DoubleBuffer> = None;
+// Input decimation and filters, and where each input sits in a frame
+static mut FILTERS: Option<(Decimator, FilterBank, Allocation)> = None;
+// Inputs in gate mode and their events for the main loop
+static mut GATES: Option<(GateBank, Events)> = None;
+// Frames per second of the gates, the configured sample rate
+static mut GATE_RATE: u32 = SAMPLE_RATE;
+// Follows the clock on one of the gate inputs
+static mut CLOCK: Option<ClockFollower> = None;
+// Outputs in clock mode, dividing or multiplying that clock
+static mut CLOCK_OUTS: Option<ClockOuts> = None;
+// Outputs quantizing an input, and their triggers
+static mut QUANTIZERS: Option<Quantizers> = None;
+// Outputs sampling or tracking an input or noise
+static mut HOLDS: Option<SampleHolds> = None;
+static mut OUTPUTS: Option<OutputEngine<PwmOutputs>> = None;
+// Outputs 0-7, loaded by DMA bursts on every sample tick
+static mut TIM8_OUT: Option<BurstOutput<Dma2Stream, BurstTimer>> = None;
+static mut TIM1_OUT: Option<BurstOutput<Dma2Stream, BurstTimer>> = None;
+static mut TIM8_BUFFERS: Option<DoubleBuffer> = None;
+static mut TIM1_BUFFERS: Option<DoubleBuffer> = None;
+
+// All 16 inputs, PA0-PA7, PB0-PB1, PC0-PC5
+const CHANNELS: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+const SAMPLE_RATE: u32 = 16_000;
+
+// Off, all 16 inputs at 56 cycles keep up with a ratio of 3 at most
+const OVERSAMPLING: usize = 1;
+
+// PWM DAC carrier, filtered down to the 16 kHz band on the board
+const CARRIER: u32 = 20_000;
+
+// ADC1-ADC3 converting in parallel, 6 ranks per ADC for 16 inputs
+const ADC_MODE: Mode = Mode::TripleSimultaneous;
+
+// Frames per DMA buffer after decimation, a triple mode frame is up to 3
+// results per input and oversampling takes that many per frame
+const FRAMES: usize = 6;
+const BUFFER_SIZE: usize = 3 * MAX_CHANNELS * FRAMES * MAX_OVERSAMPLING;
+
+// Word aligned for the 32 bit CDR transfers
+#[repr(align(4))]
+struct Buffer([u16; BUFFER_SIZE]);
+static mut BUFFER1: Buffer = Buffer([0; BUFFER_SIZE]);
+static mut BUFFER2: Buffer = Buffer([0; BUFFER_SIZE]);
+
+// Same number of ticks as the input side, so both complete on the same tick
+const BURST_SIZE: usize = BURST_LEN * FRAMES * MAX_OVERSAMPLING;
+static mut TIM8_BURSTS: [[u16; BURST_SIZE]; 2] = [[0; BURST_SIZE]; 2];
+static mut TIM1_BURSTS: [[u16; BURST_SIZE]; 2] = [[0; BURST_SIZE]; 2];
+
+// Decimated input frames of the last completed buffer, one sample per input
+static mut INPUT_FRAMES: [Sample; MAX_CHANNELS * FRAMES] = [Sample::ZERO; MAX_CHANNELS * FRAMES];
+
+// Next output frames, one fine code per output
+static mut OUTPUT_FRAMES: [u16; MAX_OUTPUTS * FRAMES] = [0; MAX_OUTPUTS * FRAMES];
+
+// The input being calibrated
+static mut CALIBRATING: Option<usize> = None;
+// Its unfiltered samples from the last completed buffer
+static mut CALIBRATION_SAMPLES: [Sample; FRAMES] = [Sample::ZERO; FRAMES];
+static CALIBRATION_READY: AtomicBool = AtomicBool::new(false);
+
+const CONSOLE_BAUD: u32 = 115_200;
+
+// Of the quantizers, 10 cents
+const HYSTERESIS_MICROVOLTS: i32 = 8333;
+
+// Inverting +-10 V inputs, +-5 V outputs
+const INPUT_RANGE: (i32, i32) = (10_000, -10_000);
+const OUTPUT_RANGE: (i32, i32) = (-5_000_000, 5_000_000);
+const INPUTS: [usize; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+const REFERENCES: [i32; 5] = [-8000, -4000, 0, 4000, 8000];
+const OUTPUT_CODES: [u16; 5] = [3277, 16384, 32768, 49151, 62258];
+
+// Every input in the channel map, every output
+fn calibration_plan(inputs: usize) -> Plan<'static> {
+	Plan {
+		inputs: &INPUTS[..inputs],
+		references: &REFERENCES,
+		// Half a second at 16 kHz
+		samples: 8000,
+		outputs: &INPUTS,
+		codes: &OUTPUT_CODES,
+		input_nominal: InputCalibration::nominal(INPUT_RANGE.0, INPUT_RANGE.1),
+		output_nominal: OUTPUT_RANGE,
+		// 300 mV, several percent of offset or gain
+		limits: Limits { input: 300_000, output: 300_000 },
+	}
+}
 
+// Used when nothing (readable) is stored
+fn default_config() -> Config {
+	Config {
+		sample_rate: SAMPLE_RATE,
+		adc_mode: ADC_MODE,
+		oversampling: OVERSAMPLING,
+		// 56 cycles for some margin, 6 conversions per ADC fit a 16 kHz period easily
+		channels: ChannelMap::new(&CHANNELS, SampleTime::Cycles56).unwrap(),
+		calibration: Calibration {
+			inputs: InputCalibrations::new(InputCalibration::nominal(INPUT_RANGE.0, INPUT_RANGE.1)),
+			outputs: OutputCalibrations::new(OutputCalibration::nominal(OUTPUT_RANGE.0, OUTPUT_RANGE.1)),
+		},
+		modes: Modes::default(),
+	}
+}
 
-static mut pacd: Option<pac::Peripherals> = None;
-
-
-fn wait_for<F>(f: F) 
-	where F: Fn() -> bool
-{
-	while !f() {}
+// Filter an input, the input keeps its filter if the cutoff does not fit
+unsafe fn apply_filter(input: usize, kind: Kind, cutoff: f32) -> Result<(), &'static str> {
+	cortex_m::interrupt::free(|_| FILTERS.as_mut().unwrap().1.set(input, kind, cutoff)).map_err(|error| match error {
+		filter::Error::CutoffTooHigh => "cutoff too high for the sample rate",
+		filter::Error::CutoffTooLow => "cutoff too low for the sample rate",
+		filter::Error::NoSuchInput => "no input",
+		filter::Error::Ratio => "oversampling ratio out of range",
+	})
 }
 
-const buffer_size: usize = 12;
-static mut buffer1: [u16; buffer_size] = [0; buffer_size];
-static mut buffer2: [u16; buffer_size] = [0; buffer_size];
+// `filter <input> <kind> <hz>`, the reply for the console
+unsafe fn set_filter(line: &str, modes: &mut Modes) -> Result<(), &'static str> {
+	let mut words = line.split_whitespace().skip(1);
+	let input: usize = words.next().and_then(|word| word.parse().ok()).filter(|&i| i < MAX_CHANNELS).ok_or("no input")?;
+	let kind = words.next().and_then(Kind::from_name).ok_or("off, onepole, butterworth, bessel or average")?;
+	let cutoff = match kind {
+		Kind::Off => 0.0,
+		_ => words.next().and_then(|word| word.parse().ok()).ok_or("no cutoff in Hz")?,
+	};
+	apply_filter(input, kind, cutoff)?;
+	modes.inputs[input] = InputSettings { filter: kind, cutoff, ..modes.inputs[input] };
+	Ok(())
+}
 
+unsafe fn apply_dither(output: usize, shaping: Shaping) -> Result<(), &'static str> {
+	cortex_m::interrupt::free(|_| {
+		// Outputs on TIM8 and TIM1 are quantised as their bursts are filled
+		let on_burst = [TIM8_OUT.as_mut(), TIM1_OUT.as_mut()]
+			.iter_mut()
+			.flatten()
+			.any(|burst| burst.layout_mut().set_shaping(output, shaping));
+		if on_burst {
+			return Ok(());
+		}
+		// The others are written once per buffer, shaping would put the
+		// noise in the audio band
+		if matches!(shaping, Shaping::FirstOrder | Shaping::SecondOrder) {
+			return Err("noise shaping only on outputs 0-7");
+		}
+		OUTPUTS.as_mut().unwrap().set_shaping(output, shaping);
+		Ok(())
+	})
+}
 
-#[entry]
-unsafe fn main() -> ! {
-	hprintln!("Entry");
+// `dither <output> <off|tpdf|first|second>`, the reply for the console
+unsafe fn set_dither(line: &str, modes: &mut Modes) -> Result<(), &'static str> {
+	let mut words = line.split_whitespace().skip(1);
+	let output: usize = words.next().and_then(|word| word.parse().ok()).filter(|&o| o < MAX_OUTPUTS).ok_or("no output")?;
+	let shaping = match words.next() {
+		Some("off") => Shaping::Off,
+		Some("tpdf") => Shaping::Tpdf,
+		Some("first") => Shaping::FirstOrder,
+		Some("second") => Shaping::SecondOrder,
+		_ => return Err("off, tpdf, first or second"),
+	};
+	apply_dither(output, shaping)?;
+	modes.outputs[output].dither = shaping;
+	Ok(())
+}
 
-	hprintln!("Disabling Interrupts");
-	cortex_m::interrupt::disable();
-	hprintln!("Interrupts Disabled");
+// `gate <input> off` or `gate <input> <threshold mV> [<hysteresis mV> [<min width>]]`
+unsafe fn set_gate(line: &str, modes: &mut Modes) -> Result<(), &'static str> {
+	let mut words = line.split_whitespace().skip(1);
+	let input: usize = words.next().and_then(|word| word.parse().ok()).filter(|&i| i < MAX_CHANNELS).ok_or("no input")?;
+	let config = match words.next() {
+		Some("off") => None,
+		Some(word) => {
+			let mut config = GateConfig::default();
+			let number = |word: &str| word.parse::<i32>().map_err(|_| "not a number");
+			config.threshold = Volts::from_millivolts(number(word)?);
+			if let Some(word) = words.next() {
+				config.hysteresis = Volts::from_millivolts(number(word)?);
+			}
+			if let Some(word) = words.next() {
+				config.min_width = number(word)?.max(0) as u32;
+			}
+			Some(config)
+		}
+		None => return Err("off or a threshold in mV"),
+	};
+	cortex_m::interrupt::free(|_| GATES.as_mut().unwrap().0.set(input, config));
+	modes.inputs[input].gate = config;
+	Ok(())
+}
 
-	hprintln!("Unmask TIM5 and DMA2_S0 interrupt in NVIC");
-	NVIC::unmask(pac::Interrupt::TIM5);
-	NVIC::unmask(pac::Interrupt::DMA2_STREAM0);
-	hprintln!("Done");
+// Follow the clock on an input, putting it in gate mode if it is not already
+unsafe fn apply_clock(clock: Option<ClockInput>) {
+	cortex_m::interrupt::free(|_| {
+		CLOCK = clock.map(|clock| {
+			let (gates, _) = GATES.as_mut().unwrap();
+			if gates.detector(clock.input).is_none() {
+				gates.set(clock.input, Some(GateConfig::default()));
+			}
+			ClockFollower::new(clock.input, GATE_RATE, clock.ppqn)
+		});
+	});
+}
 
-	while pacd.is_none() {
-		pacd = pac::Peripherals::take();
+// `clock <input> [<ppqn>]` or `clock off`, the input goes into gate mode
+unsafe fn set_clock(line: &str, modes: &mut Modes) -> Result<(), &'static str> {
+	let mut words = line.split_whitespace().skip(1);
+	let input: usize = match words.next() {
+		Some("off") => {
+			apply_clock(None);
+			modes.clock = None;
+			return Ok(());
+		}
+		Some(word) => word.parse().ok().filter(|&i| i < MAX_CHANNELS).ok_or("no input")?,
+		None => return Err("off or an input"),
+	};
+	let ppqn = match words.next() {
+		Some(word) => word.parse().map_err(|_| "not a number")?,
+		None => 1,
+	};
+	let clock = ClockInput { input, ppqn };
+	apply_clock(Some(clock));
+	if modes.inputs[input].gate.is_none() {
+		modes.inputs[input].gate = Some(GateConfig::default());
 	}
+	modes.clock = Some(clock);
+	Ok(())
+}
 
-	let device = pacd.as_ref().unwrap();
-
-	// Enable clock on DMA and GPIO_A
-	device.RCC.ahb1enr.modify(
-		|_, w| w
-				//.dma1en().bit(true)
-				.dma2en().bit(true)
-				.gpioaen().bit(true)
-	);
-
-	// Enable clock on ADC1 and TIM5
-	device.RCC.apb2enr.modify(|_, w| w.adc1en().bit(true));
-	device.RCC.apb1enr.modify(|_, w| w.tim5en().bit(true));
-
-	// Reset DMA1, DMA2, and GPIOA
-	device.RCC.ahb1rstr.write(
-		|w|
-			w.dma2rst().bit(true).dma1rst().bit(true).gpioarst().bit(true)
-	);
-
-	// Reset ADC and TIM5
-	device.RCC.apb1rstr.write(|w| w.tim5rst().bit(true));
-	device.RCC.apb2rstr.write(|w| w.adcrst().bit(true));
+// An output runs one mode at a time, setting one turns the others off
+unsafe fn release(output: usize) {
+	// Turning a clock output off can not fail
+	let _ = CLOCK_OUTS.as_mut().unwrap().set(output, None);
+	QUANTIZERS.as_mut().unwrap().release(output);
+	HOLDS.as_mut().unwrap().set(output, None);
+}
 
-	// Wait for reset
-	for _ in 0..100 {
-		asm::nop();
+fn scale(source: ScaleSource, tuning: &Tuning) -> Result<Scale, &'static str> {
+	match source {
+		ScaleSource::Mask(mask) => Scale::from_mask(mask).map_err(|_| "no notes in the mask"),
+		ScaleSource::Tuning => tuning.scale().map_err(|_| "the tuning has no scale"),
 	}
+}
 
-	// Stop reseting anything on the ahb1, apb1 and apb2 bus
-	device.RCC.ahb1rstr.write(|w|w.bits(0b0));
-	device.RCC.apb1rstr.write(|w|w.bits(0b0));
-	device.RCC.apb2rstr.write(|w|w.bits(0b0));
-
-	// Configure GPIO_A_0 as analog
-	device.GPIOA.moder.modify(
-		|_, w|
-			w
-			.moder0().bits(0b11) // Analog Mode
-			.moder1().bits(0b11) // Analog Mode
-	);
-
-
-	// Pointer to ADC data register
-	const ADC_DR: *const u32 = 0x4001204c as *const u32;
-
-
-	hprintln!("Setup dma...");
-
-	// ## SET UP DMA ## //
-	//	Set PAR
-	device.DMA2.st[0].par.write(|w| w.bits(ADC_DR as u32));
-	
-	// 	Set destination memory buffers
-	device.DMA2.st[0].m0ar.write(|w| w.bits((&buffer1 as *const u16) as u32));
-	device.DMA2.st[0].m1ar.write(|w| w.bits((&buffer2 as *const u16) as u32));
-
-	//	Set number data transer
-	device.DMA2.st[0].ndtr.write(|w| w.bits(buffer_size as u32));
-
-	// 	Set channel
-	device.DMA2.st[0].cr.modify(|_, w| w.chsel().bits(0b00));
-
-	// Set priority
-	device.DMA2.st[0].cr.modify(|_, w| w.pl().bits(0b11));
-
-	device.DMA2.st[0].fcr.modify(
-		|_, w|
-			w
-			.fth().bits(0b01) // FIFO threshold, 01: Half Full
-			.dmdis().bit(true) // Disable Direct Mode, Use FIFO
-	);
-
-	// Set rest of DMA config
-	device.DMA2.st[0].cr.modify(
-		|_, w|
-		w
-			.msize().bits(0b01) // Half Word(16 bit)
-			.psize().bits(0b01) // Half Word(16 bit)
-			.minc().bit(true)
-			.pinc().bit(false)
-			.dbm().bit(true) // Double buffer mode
-			.circ().bit(true)
-			.dir().bits(0b00) // Peripheral to memory
-			.mburst().bits(0b00) // Single transfer
-			.pburst().bits(0b00) // Single transer
-			.tcie().bit(true) // Enable transfer complete interrupt
-			.teie().bit(true) // Enable transfer error interrupt
-	);
-
-	// Enable DMA
-	device.DMA2.st[0].cr.modify(|_, w| w.en().bit(true));
-
-	hprintln!("Done");
-
-
-	hprintln!("Setup ADC Common...");
-
-	// ## ADC COMMON INIT ## //
-	device.ADC_COMMON.ccr.modify(
-		|_, w|
-			w
-			.adcpre().bits(0b00) // Prescalar (8) 11: PCLK2/8, 00: PCLK/2
-			.multi().bits(0b00000) // Indipendent ADC mode
-			.delay().bits(0b0000) // 00: 5 * adc_clk delay
-	);
-
-	hprintln!("Done");
-
-
-	hprintln!("Setup sample timer (Timer 3)...");
+// Put an output in `mode`, checked before the output's other modes are turned off
+unsafe fn apply_output(output: usize, mode: OutputMode, tuning: &Tuning) -> Result<(), &'static str> {
+	let quantizer = match mode {
+		OutputMode::Clock(config) => {
+			ClockOut::new(config).map_err(|error| match error {
+				clock_out::Error::Ratio => "ratio out of range",
+				clock_out::Error::Swing => "swing 50-75%",
+				clock_out::Error::Width => "width 1-99%",
+			})?;
+			None
+		}
+		OutputMode::Quantize(config) => {
+			let hysteresis = Volts::from_microvolts(HYSTERESIS_MICROVOLTS);
+			let mut quantizer = Quantizer::new(scale(config.scale, tuning)?, hysteresis);
+			quantizer.set_transpose(config.transpose);
+			Some((config.input, quantizer))
+		}
+		_ => None,
+	};
+	cortex_m::interrupt::free(|_| {
+		release(output);
+		match mode {
+			OutputMode::Off => {}
+			OutputMode::Clock(config) => CLOCK_OUTS.as_mut().unwrap().set(output, Some(config)).unwrap(),
+			OutputMode::Quantize(config) => {
+				if let Some(trigger) = config.trigger {
+					release(trigger);
+				}
+				let quantizers = QUANTIZERS.as_mut().unwrap();
+				quantizers.set(output, quantizer);
+				quantizers.set_trigger(output, config.trigger);
+			}
+			OutputMode::Hold(config) => HOLDS.as_mut().unwrap().set(output, Some(config)),
+		}
+	});
+	Ok(())
+}
 
-	// ## SETUP TIMER_3 CHANNEL 1 ## //
-	// Fire every 1/441000 seconds to signal the ADC to take a sample
-	device.TIM5.cr1.write(
-		|w|
-			w
-			.ckd().bits(0b00) // 00: Dont divide clock (≈80MHz ?)
-			.arpe().bit(true) // auto reload
-			.dir().bit(false) // count up
-			.cms().bits(0b00) // Count up and down, set output when counting up
-			.urs().bit(true) // Only under/over flow trigger update interrupt
-			.udis().bit(false) // DO generate update events
-	);
+// `clockout <output> off` or `clockout <output> <multiply>[/<divide>] [<swing %> [<width %>]]`
+unsafe fn set_clock_out(line: &str, modes: &mut Modes, tuning: &Tuning) -> Result<(), &'static str> {
+	let mut words = line.split_whitespace().skip(1);
+	let output: usize = words.next().and_then(|word| word.parse().ok()).filter(|&o| o < MAX_OUTPUTS).ok_or("no output")?;
+	let number = |word: &str| word.parse::<u32>().map_err(|_| "not a number");
+	let mode = match words.next() {
+		Some("off") => OutputMode::Off,
+		Some(word) => {
+			let mut config = ClockOutConfig::default();
+			let mut ratio = word.splitn(2, '/');
+			config.multiply = number(ratio.next().unwrap_or(""))?;
+			if let Some(divide) = ratio.next() {
+				config.divide = number(divide)?;
+			}
+			if let Some(word) = words.next() {
+				config.swing = number(word)?;
+			}
+			if let Some(word) = words.next() {
+				config.width = number(word)?;
+			}
+			OutputMode::Clock(config)
+		}
+		None => return Err("off or a ratio"),
+	};
+	apply_output(output, mode, tuning)?;
+	modes.set_output(output, mode);
+	Ok(())
+}
 
-	// Output compare mode
-	// Channel 1 is setup up to give a high pulse whenever
-	// the COUNT of Timer1 matches a spesified calue (CCRn)
-	// This channel is linked to ADC1(Through it register setup)
-	// Such that it is fire whenever there is a pulse on TIMER3_CHANNEL1
-	device.TIM5.ccmr1_output.write(
-		|w|
-			w
-			.cc1s().bits(0b00) // Channel 1 is an output
-			.oc1m().bits(0b011) // 001: Set on match, 011: Toggle
-	);
+// `quantize <output> off` or `quantize <output> <input> <scale> [<transpose> [<trigger output>]]`, the scale
+// `chromatic`, `major`, `minor`, `tuning` for the stored tuning's, or a 12 bit mask of semitones in hex
+unsafe fn set_quantizer(line: &str, modes: &mut Modes, tuning: &Tuning) -> Result<(), &'static str> {
+	let mut words = line.split_whitespace().skip(1);
+	let output = |word: Option<&str>| {
+		word.and_then(|word| word.parse().ok()).filter(|&o: &usize| o < MAX_OUTPUTS).ok_or("no output")
+	};
+	let quantized = output(words.next())?;
+	let input: usize = match words.next() {
+		Some("off") => {
+			if matches!(modes.outputs[quantized].mode, OutputMode::Quantize(_)) {
+				apply_output(quantized, OutputMode::Off, tuning)?;
+				modes.set_output(quantized, OutputMode::Off);
+			}
+			return Ok(());
+		}
+		Some(word) => word.parse().ok().filter(|&i| i < MAX_CHANNELS).ok_or("no input")?,
+		None => return Err("off or an input"),
+	};
+	let scale = match words.next() {
+		Some("chromatic") => ScaleSource::Mask(0xFFF),
+		Some("major") => ScaleSource::Mask(0xAB5),
+		Some("minor") => ScaleSource::Mask(0x5AD),
+		Some("tuning") => ScaleSource::Tuning,
+		Some(word) => u16::from_str_radix(word.trim_start_matches("0x"), 16)
+			.ok()
+			.filter(|&mask| mask <= 0xFFF)
+			.map(ScaleSource::Mask)
+			.ok_or("not a scale")?,
+		None => return Err("chromatic, major, minor, tuning or a mask"),
+	};
+	let transpose = match words.next() {
+		Some(word) => word.parse().map_err(|_| "not a number")?,
+		None => 0,
+	};
+	let trigger = match words.next() {
+		Some(word) => Some(output(Some(word))?),
+		None => None,
+	};
+	if trigger == Some(quantized) {
+		return Err("trigger on another output");
+	}
+	let mode = OutputMode::Quantize(QuantizerConfig { input, scale, transpose, trigger });
+	apply_output(quantized, mode, tuning)?;
+	modes.set_output(quantized, mode);
+	Ok(())
+}
 
-	device.TIM5.ccer.write(
-		|w|
-			w
-			.cc1e().bit(true) // Enable channel 1
-			.cc1p().bit(false) // Channel 1 is "active high"
-	);
+// `hold <output> off` or `hold <output> <input>|noise <trigger input> [track]`, sample & hold unless `track`
+unsafe fn set_hold(line: &str, modes: &mut Modes, tuning: &Tuning) -> Result<(), &'static str> {
+	let mut words = line.split_whitespace().skip(1);
+	let output: usize = words.next().and_then(|word| word.parse().ok()).filter(|&o| o < MAX_OUTPUTS).ok_or("no output")?;
+	let input = |word: Option<&str>| {
+		word.and_then(|word| word.parse().ok()).filter(|&i: &usize| i < MAX_CHANNELS).ok_or("no input")
+	};
+	let source = match words.next() {
+		Some("off") => {
+			if matches!(modes.outputs[output].mode, OutputMode::Hold(_)) {
+				apply_output(output, OutputMode::Off, tuning)?;
+				modes.set_output(output, OutputMode::Off);
+			}
+			return Ok(());
+		}
+		Some("noise") => Source::Noise,
+		word => Source::Input(input(word)?),
+	};
+	let trigger = input(words.next()).map_err(|_| "no trigger input")?;
+	let mode = match words.next() {
+		Some("track") => HoldMode::TrackHold,
+		Some(_) => return Err("track or nothing"),
+		None => HoldMode::SampleHold,
+	};
+	let mode = OutputMode::Hold(HoldConfig { source, trigger, mode, gate: GateConfig::default() });
+	apply_output(output, mode, tuning)?;
+	modes.set_output(output, mode);
+	Ok(())
+}
 
-	// Timer interrups 
-	device.TIM5.dier.write(|w|w
-		.uie().bit(false)
-		.cc1ie().bit(true)
-	);
-	
-	// Set auto reload register (1815 ≈ 44100hz)
-	// From where does the time count down from
-	// Reload Value
-	device.TIM5.arr.write(|w| w.bits(0xFFFFFF as u32));
+// `note <output> <note>`, holds an output at a note of the stored tuning
+unsafe fn set_note(
+	line: &str,
+	modes: &mut Modes,
+	tuning: &Tuning,
+	calibrations: &OutputCalibrations,
+) -> Result<(), &'static str> {
+	let mut words = line.split_whitespace().skip(1);
+	let output: usize = words.next().and_then(|word| word.parse().ok()).filter(|&o| o < MAX_OUTPUTS).ok_or("no output")?;
+	let note = words.next().and_then(|word| word.parse().ok()).ok_or("no note")?;
+	let code = tuning.code(note, calibrations.get(output)).ok_or("note not in the tuning")?;
+	cortex_m::interrupt::free(|_| {
+		release(output);
+		for frame in OUTPUT_FRAMES.chunks_exact_mut(MAX_OUTPUTS) {
+			frame[output] = code;
+		}
+		if let Some(outputs) = OUTPUTS.as_mut() {
+			outputs.write_fine(output, code);
+		}
+	});
+	modes.set_output(output, OutputMode::Off);
+	Ok(())
+}
 
-	// Start Count
-	device.TIM5.cnt.write(|w| w.bits(0xFFFFFF as u32));
+// What the console set before the last restart. Settings that no longer
+// apply, such as a cutoff above a new sample rate, are reported and skipped
+unsafe fn restore(modes: &Modes, tuning: &Tuning) {
+	for (input, settings) in modes.inputs.iter().enumerate() {
+		cortex_m::interrupt::free(|_| GATES.as_mut().unwrap().0.set(input, settings.gate));
+		if let Err(error) = apply_filter(input, settings.filter, settings.cutoff) {
+			log!("Input {} filter not restored, {}", input, error);
+		}
+	}
+	apply_clock(modes.clock);
+	// Trigger outputs are off, turning them off would take them from their quantizer
+	for (output, settings) in modes.outputs.iter().enumerate() {
+		if settings.mode == OutputMode::Off {
+			continue;
+		}
+		if let Err(error) = apply_output(output, settings.mode, tuning) {
+			log!("Output {} mode not restored, {}", output, error);
+		}
+	}
+	for (output, settings) in modes.outputs.iter().enumerate() {
+		if let Err(error) = apply_dither(output, settings.dither) {
+			log!("Output {} dither not restored, {}", output, error);
+		}
+	}
+}
 
-	// Capture/Compare value
-	device.TIM5.ccr1.write(|w| w.bits(0x0 as u32));
+// Keep the modes set on the console for the next start
+fn keep(store: &mut ConfigStore<InternalFlash>, modes: &Modes) -> Result<(), &'static str> {
+	store.save(Config { modes: *modes, ..*store.config() }).map_err(|_| "could not save the settings")
+}
 
-	// Prescaler
-	device.TIM5.psc.write(|w| w.bits(0x0 as u32));
+// `tuning <hex>` adds to an upload, `tuning save` stores it and `tuning clear` drops it. The new
+// tuning, if saved
+fn upload_tuning(
+	line: &str,
+	upload: &mut Upload,
+	store: &mut ConfigStore<InternalFlash>,
+) -> Result<Option<Tuning>, &'static str> {
+	match line.split_whitespace().nth(1) {
+		Some("save") => {
+			let tuning = upload.finish().map_err(|_| "not a tuning, upload it again")?;
+			tuning::save(store.storage_mut(), &tuning).map_err(|_| "could not save the tuning")?;
+			Ok(Some(tuning))
+		}
+		Some("clear") => {
+			upload.clear();
+			Ok(None)
+		}
+		Some(hex) => {
+			upload.push_hex(hex).map_err(|error| match error {
+				tuning::Error::BufferTooSmall => "longer than any tuning",
+				_ => "not hex",
+			})?;
+			Ok(None)
+		}
+		None => Err("hex, save or clear"),
+	}
+}
 
-	hprintln!("Done");
+// `oversample <ratio>`, saved for the next start if the ADCs keep up
+fn set_oversampling(
+	line: &str,
+	store: &mut ConfigStore<InternalFlash>,
+	runs: impl Fn(&Config) -> bool,
+) -> Result<(), &'static str> {
+	let oversampling = line
+		.split_whitespace()
+		.nth(1)
+		.and_then(|word| word.parse().ok())
+		.filter(|ratio| (1..=MAX_OVERSAMPLING).contains(ratio))
+		.ok_or("a ratio of 1-16")?;
+	let config = Config { oversampling, ..*store.config() };
+	if !runs(&config) {
+		return Err("the ADCs do not keep up at that ratio");
+	}
+	store.save(config).map_err(|_| "could not save the settings")
+}
 
+// "ok" or why a command was refused
+fn reply(console: &mut impl Write, result: Result<(), &str>) {
+	let _ = match result {
+		Ok(()) => writeln!(console, "ok"),
+		Err(error) => writeln!(console, "{}", error),
+	};
+}
 
-	hprintln!("Setup ADC1...");
+// Do what the calibration session asks for
+unsafe fn follow(action: Action) {
+	cortex_m::interrupt::free(|_| {
+		CALIBRATING = None;
+		match action {
+			Action::Sample { input } => {
+				CALIBRATION_READY.store(false, Ordering::Release);
+				CALIBRATING = Some(input);
+			}
+			Action::SetOutput { output, code } => {
+				// Outputs 0-7 from the bursts, the others directly
+				for frame in OUTPUT_FRAMES.chunks_exact_mut(MAX_OUTPUTS) {
+					frame[output] = code;
+				}
+				if let Some(outputs) = OUTPUTS.as_mut() {
+					outputs.write_fine(output, code);
+				}
+			}
+			_ => {}
+		}
+	});
+}
 
-	// ## ADC 1 INIT ## //
-	// 1: Right Alignment
-	device.ADC1.cr2.modify(|_, w| w.align().bit(false));
 
-	device.ADC1.cr1.modify(
-		|_, w|
-			w
-			.res().bits(0b00) // 12 bit resolution
-			.scan().bit(true)
-			.eocie().bit(false) // no EOC interrupt
-	);
+#[entry]
+unsafe fn main() -> ! {
+	log!("Entry");
 
-	// Set ADC sample time (Min for 12 bit resolution is 15 cycles)
-	// 000: 3 cycles
-	// 001: 15 cycles
-	// 010: 28 cycles
-	// 011: 56 cycles
-	// 100: 84 cycles
-	// 101: 112 cycles
-	// 110: 144 cycles
-	// 111: 480 cycles
-	device.ADC1.smpr2.modify(
-		|_, w|
-			w
-			.smp0().bits(0b011) // 56 cycles for some margin
-			.smp1().bits(0b011) // 56 cycles for some margin
-	);
+	log!("Disabling Interrupts");
+	cortex_m::interrupt::disable();
+	log!("Interrupts Disabled");
 
-	device.ADC1.cr2.modify(
-		|_, w|
-			w
-			//.cont().bit(true) // Continous Mode
-			.exten().bits(0b01) // External trigger. 01: Rising Edge, 00: No ext trigger
-			.extsel().bits(0b1010) // TIM5_CH1 event
+	log!("Unmask TIM5, DMA2_S0/S4/S7 and ADC interrupt in NVIC");
+	NVIC::unmask(pac::Interrupt::TIM5);
+	NVIC::unmask(pac::Interrupt::DMA2_STREAM0);
+	NVIC::unmask(pac::Interrupt::DMA2_STREAM4);
+	NVIC::unmask(pac::Interrupt::DMA2_STREAM7);
+	NVIC::unmask(pac::Interrupt::ADC);
+	log!("Done");
+
+	let device = pac::Peripherals::take().unwrap();
+
+	log!("Setup clocks...");
+	// 180 MHz from the HSI
+	let setup = ClockConfig::new(Mcu::Stm32f446).solve().unwrap();
+	let clocks = board::configure_clocks(&device.RCC, &device.FLASH, &device.PWR, &setup);
+	log!("SYSCLK {} HCLK {} PCLK1 {} PCLK2 {}", clocks.sysclk, clocks.hclk, clocks.pclk1, clocks.pclk2);
+	if clocks != setup.clocks {
+		log!("Clocks differ from the requested {:?}", setup.clocks);
+	}
+	log!("Done");
+
+	log!("Load settings...");
+	let storage = Storage::mount(InternalFlash::new(device.FLASH)).unwrap();
+	log!("Generation {}, {} bytes free", storage.generation(), storage.free());
+	let (mut store, loaded) = ConfigStore::open(storage, default_config());
+	if let Err(error) = loaded {
+		log!("Settings unreadable ({:?}), using defaults", error);
+	}
+	let mut config = *store.config();
+	let mut tuning = match tuning::load(store.storage()) {
+		Ok(Some(tuning)) => tuning,
+		Ok(None) => Tuning::equal(),
+		Err(error) => {
+			log!("Tuning unreadable ({:?}), using 12-TET", error);
+			Tuning::equal()
+		}
+	};
+
+	// The sample clock ticks `oversampling` times per input sample
+	let (_, adc_clock) = clocks.adc_prescaler(Mcu::Stm32f446);
+	let timclk = clocks.timclk1();
+	let runs = |config: &Config| {
+		let rate = config.sample_rate * config.oversampling as u32;
+		Allocation::new(&config.channels, config.adc_mode).is_ok_and(|allocation| allocation.fits(adc_clock, rate))
+			&& SampleClock::new(rate, timclk).is_ok()
+	};
+	if !runs(&config) {
+		log!("Stored channels do not fit the sample rate, using defaults");
+		config = default_config();
+	}
+	let allocation = Allocation::new(&config.channels, config.adc_mode).unwrap();
+	let map = config.channels;
+	log!("Done");
+
+	board::enable_gpio(&device.RCC);
+	board::configure_analog(&device.GPIOA, &device.GPIOB, &device.GPIOC, &map);
+	let serial = Usart3::new(device.USART3, &device.RCC, &device.GPIOC, &clocks, CONSOLE_BAUD);
+
+	log!("Setup outputs...");
+	let mut pwm = PwmOutputs::new(
+		device.TIM1,
+		device.TIM2,
+		device.TIM3,
+		device.TIM4,
+		device.TIM8,
+		&device.RCC,
+		&device.GPIOA,
+		&device.GPIOB,
+		&device.GPIOC,
+		&clocks,
+		CARRIER,
+		MAX_OUTPUTS,
+	).unwrap();
+	let streams = Dma2Streams::new(device.DMA2, &device.RCC);
+
+	// TIM8 and TIM1 follow the sample clock through DMA bursts
+	pwm.sync_to_sample_clock();
+	let outputs = layout::outputs(MAX_OUTPUTS);
+	let mut tim8 = BurstOutput::new(
+		streams.tim8,
+		pwm.burst_timer(Timer::Tim8),
+		BurstLayout::new(Timer::Tim8, pwm.carrier(Timer::Tim8).unwrap(), outputs),
 	);
-
-	// Define sequence (Single channel)
-	device.ADC1.sqr1.modify(
-		|_, w|
-			w.l().bits(0b0001) // Two conversions
+	let mut tim1 = BurstOutput::new(
+		streams.tim1,
+		pwm.burst_timer(Timer::Tim1),
+		BurstLayout::new(Timer::Tim1, pwm.carrier(Timer::Tim1).unwrap(), outputs),
 	);
-
-	// Set input sequence
-	device.ADC1.sqr3.modify(
-		|_, w|
-			w.sq1().bits(0b0000) // channel 0
-			.sq2().bits(0b0001) // channel 1
+	// A burst per sample clock tick
+	let bursts = BurstLayout::buffer_len(FRAMES) * config.oversampling;
+	let [m0, m1] = &mut TIM8_BURSTS;
+	TIM8_BUFFERS = Some(DoubleBuffer::new(&mut m0[..bursts], &mut m1[..bursts]));
+	tim8.start(pwm.dmar(Timer::Tim8), TIM8_BUFFERS.as_ref().unwrap());
+	let [m0, m1] = &mut TIM1_BURSTS;
+	TIM1_BUFFERS = Some(DoubleBuffer::new(&mut m0[..bursts], &mut m1[..bursts]));
+	tim1.start(pwm.dmar(Timer::Tim1), TIM1_BUFFERS.as_ref().unwrap());
+	TIM8_OUT = Some(tim8);
+	TIM1_OUT = Some(tim1);
+
+	OUTPUTS = Some(OutputEngine::new(pwm, config.calibration.outputs));
+	CLOCK_OUTS = Some(ClockOuts::new(config.calibration.outputs));
+	QUANTIZERS = Some(Quantizers::new(config.sample_rate, config.calibration.inputs, config.calibration.outputs));
+	HOLDS = Some(SampleHolds::new(config.calibration.inputs, config.calibration.outputs));
+	log!("Done");
+
+	let timer = Tim5::new(device.TIM5, &device.RCC);
+	let adc = TripleAdc::new(
+		device.ADC1,
+		device.ADC2,
+		device.ADC3,
+		device.ADC_COMMON,
+		&device.RCC,
+		&clocks,
+		config.adc_mode,
 	);
+	SAMPLER = Some(Sampler::new(timer, adc, streams.adc));
+	let sampler = SAMPLER.as_mut().unwrap();
+
+	log!("Setup sample timer (Timer 5)...");
+	let clock = SampleClock::new(config.sample_rate * config.oversampling as u32, timclk).unwrap();
+	log!("Sample clock {} Hz ({} ppm), {}x oversampling", clock.rate(), clock.error_ppm(), config.oversampling);
+	sampler.configure_timer(&clock);
+	log!("Done");
+
+	// Decimated to one sample per input at the sample rate, in every ADC mode
+	let decimator = Decimator::new(config.oversampling, config.adc_mode).unwrap();
+	FILTERS = Some((decimator, FilterBank::new(config.sample_rate), allocation));
+	GATES = Some((GateBank::new(config.calibration.inputs), Events::new()));
+	GATE_RATE = config.sample_rate;
+	restore(&config.modes, &tuning);
+	let mut modes = config.modes;
+
+	log!("Start sampling");
+	let len = allocation.frame_width() * FRAMES * config.oversampling;
+	BUFFERS = Some(DoubleBuffer::new(&mut BUFFER1.0[..len], &mut BUFFER2.0[..len]));
+	sampler.start(&map, BUFFERS.as_ref().unwrap());
+	log!("Done");
+
+
+	log!("Enabling interrupts");
+	cortex_m::interrupt::enable();
+	log!("Interrupts enabled");
 
-	// Enable DMA on ADC
-	device.ADC1.cr2.modify(
-		|_, w|
-			w
-			.dma().bit(true) // Enable DMA
-			.dds().bit(true) // DMA requests are issued as long as data are converted and DMA=
-	);
 
-	// Turn on the ADC
-	device.ADC1.cr2.modify(
-		|_, w|
-			w.adon().bit(true)
-			.swstart().bit(true)
+	let mut console = Console::new(serial);
+	let _ = writeln!(
+		console,
+		"cv-io, type cal to calibrate, filter <input> <kind> <hz>, dither <output> <shaping>, gate <input> <mV>, \
+		clock <input> [<ppqn>], clockout <output> <ratio>, \
+		quantize <output> <input> <scale>, hold <output> <input> <trigger>, note <output> <note>, \
+		tuning <hex>|save, oversample <ratio> or tempo"
 	);
+	let mut session: Option<Session> = None;
+	let mut upload = Upload::new();
+	// Recoveries already reported, the interrupts only count them
+	let mut reported = Diagnostics::default();
 
-	hprintln!("Done");
-
-
-	hprintln!("Start sample timer (TIMER 3)");
-	// Enable counter
-	device.TIM5.cr1.write(|w| w.cen().bit(true));
-	// Reinit the counter and fire update event
-	device.TIM5.egr.write(|w| w.ug().bit(true));
-	hprintln!("Done");
-
-
-	hprintln!("Enabling interrupts");
-	cortex_m::interrupt::enable();
-	hprintln!("Interrupts enabled");
-
-
-	// ## DO THINGS ## //
 	loop {
-		asm::nop();
-
-		// TODO: Enable ovr interrupt and move this code
-		if device.ADC1.sr.read().ovr().bit() {
-			device.DMA1.st[0].m0ar.write(|w| { w.bits((&buffer1 as *const u16) as u32) });
-			device.DMA1.st[0].m1ar.write(|w| { w.bits((&buffer2 as *const u16) as u32) });
+		let diagnostics = cortex_m::interrupt::free(|_| SAMPLER.as_ref().map(|sampler| sampler.diagnostics()));
+		if let Some(diagnostics) = diagnostics {
+			if diagnostics.adc_overruns != reported.adc_overruns || diagnostics.dma_errors != reported.dma_errors {
+				let _ = writeln!(console, "sampling restarted, {:?}", diagnostics);
+				reported = diagnostics;
+			}
+		}
 
-			//	Set number data transer
-			device.DMA1.st[0].ndtr.write(|w| w.bits(buffer_size as u32));
+		if let Some(line) = console.poll() {
+			match session.as_mut() {
+				Some(running) => {
+					let action = running.line(line, &mut store);
+					follow(action);
+					if action == Action::Done {
+						let calibration = store.config().calibration;
+						cortex_m::interrupt::free(|_| {
+							OUTPUTS.as_mut().unwrap().set_calibrations(calibration.outputs);
+							GATES.as_mut().unwrap().0.set_calibrations(calibration.inputs);
+							CLOCK_OUTS.as_mut().unwrap().set_calibrations(calibration.outputs);
+							QUANTIZERS.as_mut().unwrap().set_calibrations(calibration.inputs, calibration.outputs);
+							HOLDS.as_mut().unwrap().set_calibrations(calibration.inputs, calibration.outputs);
+						});
+					}
+				}
+				None if line.starts_with("filter") => {
+					let result = set_filter(line, &mut modes);
+					reply(&mut console, result.and_then(|()| keep(&mut store, &modes)));
+				}
+				None if line.starts_with("dither") => {
+					let result = set_dither(line, &mut modes);
+					reply(&mut console, result.and_then(|()| keep(&mut store, &modes)));
+				}
+				None if line.starts_with("gate") => {
+					let result = set_gate(line, &mut modes);
+					reply(&mut console, result.and_then(|()| keep(&mut store, &modes)));
+				}
+				None if line.starts_with("quantize") => {
+					let result = set_quantizer(line, &mut modes, &tuning);
+					reply(&mut console, result.and_then(|()| keep(&mut store, &modes)));
+				}
+				None if line.starts_with("hold") => {
+					let result = set_hold(line, &mut modes, &tuning);
+					reply(&mut console, result.and_then(|()| keep(&mut store, &modes)));
+				}
+				None if line.starts_with("note") => {
+					let calibrations = store.config().calibration.outputs;
+					let result = set_note(line, &mut modes, &tuning, &calibrations);
+					reply(&mut console, result.and_then(|()| keep(&mut store, &modes)));
+				}
+				None if line.starts_with("clockout") => {
+					let result = set_clock_out(line, &mut modes, &tuning);
+					reply(&mut console, result.and_then(|()| keep(&mut store, &modes)));
+				}
+				None if line.starts_with("clock") => {
+					let result = set_clock(line, &mut modes);
+					reply(&mut console, result.and_then(|()| keep(&mut store, &modes)));
+				}
+				None if line.starts_with("tuning") => match upload_tuning(line, &mut upload, &mut store) {
+					Ok(Some(saved)) => {
+						tuning = saved;
+						let _ = writeln!(console, "saved");
+					}
+					Ok(None) => {
+						let _ = writeln!(console, "{} bytes", upload.received());
+					}
+					Err(error) => reply(&mut console, Err(error)),
+				},
+				None if line.starts_with("oversample") => match set_oversampling(line, &mut store, runs) {
+					Ok(()) => {
+						let _ = writeln!(console, "saved, restart to apply");
+					}
+					Err(error) => reply(&mut console, Err(error)),
+				},
+				None if line == "tempo" => {
+					let (now, clock) = cortex_m::interrupt::free(|_| (GATES.as_ref().unwrap().0.tick(), CLOCK));
+					match clock {
+						Some(clock) => match (clock.state(now), clock.bpm()) {
+							(State::Running, Some(bpm)) => {
+								let _ = writeln!(console, "{:.1} bpm", bpm);
+							}
+							(state, _) => {
+								let _ = writeln!(console, "{:?}", state);
+							}
+						},
+						None => {
+							let _ = writeln!(console, "no clock");
+						}
+					}
+				}
+				None if line == "cal" => {
+					let plan = calibration_plan(map.len());
+					let started = session.insert(Session::new(plan, store.config().calibration));
+					follow(started.action());
+				}
+				None => {}
+			}
+			if let Some(running) = session.as_ref() {
+				let _ = writeln!(console, "{}", running.prompt());
+			}
+		}
 
-			device.ADC1.sr.modify(|_, w| w.ovr().bit(false));
+		if CALIBRATION_READY.swap(false, Ordering::Acquire) {
+			if let Some(running) = session.as_mut() {
+				let samples = cortex_m::interrupt::free(|_| CALIBRATION_SAMPLES);
+				let action = running.samples(&samples);
+				follow(action);
+				if !matches!(action, Action::Sample { .. }) {
+					let _ = writeln!(console, "{}", running.prompt());
+				}
+			}
+		}
 
-			device.ADC1.cr2.modify(
-				|_, w|
-					w.adon().bit(true)
-			);
+		// Gate events to the clock, shown while not calibrating
+		while let Some(event) = cortex_m::interrupt::free(|_| GATES.as_mut().unwrap().1.pop()) {
+			cortex_m::interrupt::free(|_| {
+				if let Some(clock) = CLOCK.as_mut() {
+					clock.on_event(&event);
+				}
+			});
+			if session.is_none() {
+				let edge = if event.edge == Edge::Rising { "high" } else { "low" };
+				let _ = writeln!(console, "input {} {} at {}", event.input, edge, event.at);
+			}
+		}
 
-			device.ADC1.cr2.modify(
-				|_, w|
-					w.swstart().bit(true)
-			);
+		// Finished, back to normal operation
+		if matches!(session.as_ref().map(Session::action), Some(Action::Done) | Some(Action::Aborted)) {
+			session = None;
 		}
 	}
 }
 
 #[interrupt]
 unsafe fn TIM5() {
-	hprintln!("Sample Tick");
-	
-	while pacd.is_none() {
-		pacd = pac::Peripherals::take();
+	if let Some(sampler) = SAMPLER.as_mut() {
+		sampler.on_tick();
 	}
+}
 
-	let device = pacd.as_ref().unwrap();
-
-	hprintln!("Remove interrupt flag");
-	hprintln!(
-		"Filling buffer {}. I:{}",
-		if device.DMA2.st[0].cr.read().ct().bit() { 1 } else { 2 },
-		device.DMA2.st[0].ndtr.read().bits()
-	);
+#[interrupt]
+unsafe fn DMA2_STREAM0() {
+	if let Some(sampler) = SAMPLER.as_mut() {
+		if sampler.on_transfer_complete().is_some() {
+			if let Some(half) = sampler.buffers().and_then(|buffers| buffers.take()) {
+				if let Some((decimator, filters, allocation)) = FILTERS.as_mut() {
+					let inputs = allocation.inputs();
+					let len = decimator.process(&half, allocation, &mut INPUT_FRAMES) * inputs;
+					let samples = &mut INPUT_FRAMES[..len];
+
+					// Calibration measures the unfiltered samples
+					if let Some(input) = CALIBRATING {
+						let frames = Frames::new(samples, inputs);
+						for (calibration, sample) in CALIBRATION_SAMPLES.iter_mut().zip(frames.channel(input)) {
+							*calibration = sample;
+						}
+						CALIBRATION_READY.store(true, Ordering::Release);
+					}
+
+					filters.process(samples, inputs);
+					let frames = Frames::new(samples, inputs);
+					if let Some((gates, events)) = GATES.as_mut() {
+						gates.process(&frames, events);
+						// The next output frames, from the tick after this buffer
+						if let Some(outs) = CLOCK_OUTS.as_mut() {
+							outs.render(CLOCK.as_ref(), gates.tick(), &mut OUTPUT_FRAMES);
+						}
+					}
+					if let Some(quantizers) = QUANTIZERS.as_mut() {
+						quantizers.process(&frames, &mut OUTPUT_FRAMES);
+					}
+					if let Some(holds) = HOLDS.as_mut() {
+						holds.process(&frames, &mut OUTPUT_FRAMES);
+					}
+					// Directly for the outputs not on TIM8 and TIM1, the bursts
+					// overwrite the others
+					if let (Some(outputs), Some(outs), Some(quantizers), Some(holds)) =
+						(OUTPUTS.as_mut(), CLOCK_OUTS.as_ref(), QUANTIZERS.as_ref(), HOLDS.as_ref())
+					{
+						for (output, &code) in OUTPUT_FRAMES[..MAX_OUTPUTS].iter().enumerate() {
+							if outs.get(output).is_some() || quantizers.drives(output) || holds.get(output).is_some() {
+								outputs.write_fine(output, code);
+							}
+						}
+					}
+				}
+			}
+		}
+	}
+}
 
-	// clear update interrupt flag if set
-	device.TIM5.sr.modify(|_, w| w.uif().bit(false));
+// Shared by ADC1-ADC3, only the overrun interrupt is enabled. Recoveries are
+// reported from the main loop
+#[interrupt]
+unsafe fn ADC() {
+	if let Some(sampler) = SAMPLER.as_mut() {
+		sampler.on_adc_interrupt();
+	}
+}
 
-	// Clear CC interrupt flag
-	device.TIM5.sr.modify(|_, w| w.cc1if().bit(false));
+// Refill the half of the output bursts the DMA just finished
+unsafe fn refill(output: &mut Option<BurstOutput<Dma2Stream, BurstTimer>>) {
+	if let Some(output) = output.as_mut() {
+		if output.on_transfer_complete().is_some() {
+			output.refill(&Frames::new(&OUTPUT_FRAMES, MAX_OUTPUTS));
+		}
+	}
 }
 
 #[interrupt]
-unsafe fn DMA2_STREAM0() {
-	while pacd.is_none() {
-		pacd = pac::Peripherals::take();
-	}
+unsafe fn DMA2_STREAM4() {
+	refill(&mut TIM1_OUT);
+}
 
-	let device = pacd.as_ref().unwrap();
-	hprintln!("DMA Stream Full");
-	device.DMA2.lifcr.write(|w| w
-		.ctcif0().bit(true)
-		.chtif0().bit(true)
-	);
+#[interrupt]
+unsafe fn DMA2_STREAM7() {
+	refill(&mut TIM8_OUT);
 }
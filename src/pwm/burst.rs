@@ -0,0 +1,408 @@
+//! Compare register updates by timer DMA burst.
+//!
+//! TIM1 and TIM8 run in trigger mode slaved to the sample timer (TIM5 TRGO
+//! on ITR0 and ITR3) with the trigger DMA request enabled. Every sample
+//! clock tick then makes the timer request one burst through its DMAR
+//! register, which DCR points at CCR1-CCR4:
+//!
+//! ```text
+//! TIM5 tick -> TIM1_TRIG -> DMA2 stream 4 -> TIM1 DMAR -> CCR1..CCR4
+//!           -> TIM8_TRIG -> DMA2 stream 7 -> TIM8 DMAR -> CCR1..CCR4
+//! ```
+//!
+//! The streams run in double buffer mode like the ADC stream, one burst per
+//! sample clock tick. With the same number of ticks per buffer on both
+//! sides the input and output transfers complete on the same tick, and the
+//! half the output DMA just finished is refilled while it reads the other
+//! one. With oversampling the clock ticks several times per output frame,
+//! each frame then fills as many bursts.
+//! The compare values are preloaded, so they take effect at the start of
+//! the next carrier period.
+//!
+//! Only TIM1 and TIM8 requests reach DMA2. Outputs on TIM2-TIM4 are still
+//! written by the CPU through `OutputEngine`.
+
+use crate::adc::Frames;
+use crate::double_buffer::DoubleBuffer;
+use crate::hw::{BurstRequest, DataWidth, DoubleBufferDma, Target};
+
+use super::{Carrier, Dither, Output, Shaping, Timer};
+
+// Transfers per burst, CCR1-CCR4
+pub const BURST_LEN: usize = 4;
+
+// CCR1 in 32 bit words from the start of the timer (0x34)
+const CCR1_WORD: u32 = 0x34 / 4;
+
+/// DCR for a burst of `BURST_LEN` transfers starting at CCR1 (DBL, DBA)
+pub const DCR: u32 = (BURST_LEN as u32 - 1) << 8 | CCR1_WORD;
+
+/// Whether the timer's DMA requests are served by DMA2
+pub fn on_dma2(timer: Timer) -> bool {
+	timer == Timer::Tim1 || timer == Timer::Tim8
+}
+
+/// Where each output of one timer sits in its burst.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BurstLayout {
+	timer: Timer,
+	carrier: Carrier,
+	// Output index per timer channel
+	outputs: [Option<usize>; BURST_LEN],
+	dithers: [Dither; BURST_LEN],
+}
+
+impl BurstLayout {
+	/// Layout for the outputs of `timer` among `outputs`.
+	pub fn new(timer: Timer, carrier: Carrier, outputs: &[Output]) -> BurstLayout {
+		assert!(on_dma2(timer), "only TIM1 and TIM8 are served by DMA2");
+
+		let mut layout = BurstLayout {
+			timer,
+			carrier,
+			outputs: [None; BURST_LEN],
+			dithers: [Dither::new(Shaping::Off, 0); BURST_LEN],
+		};
+		for (i, output) in outputs.iter().enumerate().filter(|(_, output)| output.timer == timer) {
+			layout.outputs[(output.channel - 1) as usize] = Some(i);
+			layout.dithers[(output.channel - 1) as usize] = Dither::new(Shaping::Off, i as u32);
+		}
+		layout
+	}
+
+	/// Dither and noise shaping of output `output`, false if it is not on
+	/// this timer
+	pub fn set_shaping(&mut self, output: usize, shaping: Shaping) -> bool {
+		match self.outputs.iter().position(|&o| o == Some(output)) {
+			Some(channel) => {
+				self.dithers[channel].set_shaping(shaping);
+				true
+			}
+			None => false,
+		}
+	}
+
+	pub fn timer(&self) -> Timer {
+		self.timer
+	}
+
+	// Half words per buffer half for `frames` output frames
+	pub fn buffer_len(frames: usize) -> usize {
+		frames * BURST_LEN
+	}
+
+	/// Write the bursts for frames of output codes into `half`.
+	///
+	/// Every frame holds one 16 bit fine code per output, as produced by the
+	/// output calibration, and fills an equal share of `half`: one burst, or
+	/// one per tick of an oversampling sample clock, each dithered anew.
+	/// Channels of this timer without an output are kept at 0.
+	pub fn fill(&mut self, frames: &Frames, half: &mut [u16]) {
+		let len = BurstLayout::buffer_len(frames.len());
+		assert!(len > 0 && half.len().is_multiple_of(len), "a whole number of bursts per frame");
+
+		let steps = self.carrier.steps();
+		let ticks = half.len() / len;
+		for (frame, bursts) in frames.iter().zip(half.chunks_exact_mut(BURST_LEN * ticks)) {
+			for burst in bursts.chunks_exact_mut(BURST_LEN) {
+				let channels = burst.iter_mut().zip(self.outputs.iter()).zip(self.dithers.iter_mut());
+				for ((compare, output), dither) in channels {
+					*compare = match output {
+						Some(i) => dither.quantize(frame[*i], steps) as u16,
+						None => 0,
+					};
+				}
+			}
+		}
+	}
+}
+
+/// One output timer fed by a double buffered DMA stream.
+pub struct BurstOutput<D, R> {
+	dma: D,
+	request: R,
+	layout: BurstLayout,
+	buffers: Option<&'static DoubleBuffer>,
+	// Timer DMAR the stream writes to
+	dmar: u32,
+	errors: u32,
+}
+
+impl<D: DoubleBufferDma, R: BurstRequest> BurstOutput<D, R> {
+	pub fn new(dma: D, request: R, layout: BurstLayout) -> Self {
+		BurstOutput { dma, request, layout, buffers: None, dmar: 0, errors: 0 }
+	}
+
+	pub fn layout(&self) -> &BurstLayout {
+		&self.layout
+	}
+
+	pub fn layout_mut(&mut self) -> &mut BurstLayout {
+		&mut self.layout
+	}
+
+	/// Start feeding the timer's DMAR at `dmar` from both halves of `buffers`.
+	///
+	/// Both halves should already hold bursts. Start before the sample timer
+	/// so the first tick already loads M0.
+	pub fn start(&mut self, dmar: u32, buffers: &'static DoubleBuffer) {
+		assert!(buffers.len().is_multiple_of(BURST_LEN), "buffer must hold whole bursts");
+		assert!(buffers.len() <= u16::MAX as usize);
+
+		self.dmar = dmar;
+		self.buffers = Some(buffers);
+		self.configure_dma(buffers);
+		self.dma.enable();
+		self.request.enable();
+	}
+
+	pub fn stop(&mut self) {
+		self.request.disable();
+		self.dma.disable();
+	}
+
+	fn configure_dma(&mut self, buffers: &'static DoubleBuffer) {
+		// The DMA only reads, the halves are written through `refill`
+		unsafe {
+			self.dma.configure(self.dmar, DataWidth::HalfWord, buffers.m0(), buffers.m1(), buffers.len() as u16);
+		}
+	}
+
+	/// Restart the stream and the timer's request after a transfer error.
+	///
+	/// As `Sampler::recover` does for the ADC:
+	/// 1. Drop the timer's DMA request so no burst starts half way
+	/// 2. Disable the stream and clear its flags
+	/// 3. Re-initialise the stream, back at the start of M0. Both halves
+	///    keep their bursts, the outputs replay them until refilled
+	/// 4. Enable the stream, then the request. The next tick loads a whole
+	///    burst starting at CCR1
+	pub fn recover(&mut self) {
+		self.request.disable();
+
+		self.dma.disable();
+		self.dma.clear_interrupts();
+
+		if let Some(buffers) = self.buffers {
+			self.configure_dma(buffers);
+			buffers.restart();
+		}
+
+		self.dma.enable();
+		self.request.enable();
+	}
+
+	/// DMA interrupt, returns the half that was just read.
+	///
+	/// A transfer error is counted and recovered from instead.
+	pub fn on_transfer_complete(&mut self) -> Option<Target> {
+		if self.dma.transfer_error() {
+			self.errors += 1;
+			self.recover();
+			return None;
+		}
+
+		if !self.dma.transfer_complete() {
+			return None;
+		}
+
+		self.dma.clear_interrupts();
+		let current = self.dma.current_target();
+		if let Some(buffers) = self.buffers {
+			buffers.swap(current);
+		}
+		Some(current.other())
+	}
+
+	/// Write the next output frames into the half the DMA just finished.
+	///
+	/// False if no half is free, the DMA then plays the other half again.
+	pub fn refill(&mut self, frames: &Frames) -> bool {
+		let mut half = match self.buffers.and_then(|buffers| buffers.take_mut()) {
+			Some(half) => half,
+			None => return false,
+		};
+
+		self.layout.fill(frames, &mut half);
+		true
+	}
+
+	/// The DMA started reading a half that was still being refilled
+	pub fn underruns(&self) -> u32 {
+		self.buffers.map_or(0, |buffers| buffers.overruns())
+	}
+
+	pub fn transfer_errors(&self) -> u32 {
+		self.errors
+	}
+
+	pub fn dma(&mut self) -> &mut D {
+		&mut self.dma
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::adc::ChannelMap;
+	use crate::hw::mock::Board;
+	use crate::hw::SampleTime;
+	use crate::pwm::layout;
+	use crate::sampler::Sampler;
+
+	const OUTPUTS: usize = 8;
+
+	fn layout(timer: Timer) -> BurstLayout {
+		BurstLayout::new(timer, Carrier::new(20_000, 180_000_000).unwrap(), layout::outputs(OUTPUTS))
+	}
+
+	fn buffers(len: usize) -> &'static DoubleBuffer {
+		Box::leak(Box::new(DoubleBuffer::new(
+			Box::leak(vec![0u16; len].into_boxed_slice()),
+			Box::leak(vec![0u16; len].into_boxed_slice()),
+		)))
+	}
+
+	#[test]
+	fn bursts_cover_the_timer_channels() {
+		let mut tim8 = layout(Timer::Tim8);
+		let mut tim1 = layout(Timer::Tim1);
+		let codes: Vec<u16> = (0..OUTPUTS as u16).map(|i| i * 8192).collect();
+		let frames = Frames::new(&codes, OUTPUTS);
+
+		let mut burst = [0u16; BURST_LEN];
+		tim8.fill(&frames, &mut burst);
+		assert_eq!(burst, [0, 1125, 2250, 3375]);
+		tim1.fill(&frames, &mut burst);
+		assert_eq!(burst, [4500, 5625, 6750, 7875]);
+
+		// TIM1 has no outputs among the first four
+		let mut tim1 = BurstLayout::new(Timer::Tim1, Carrier::new(20_000, 180_000_000).unwrap(), layout::outputs(4));
+		tim1.fill(&Frames::new(&codes[..4], 4), &mut burst);
+		assert_eq!(burst, [0; BURST_LEN]);
+
+		// Oversampling ticks three times a frame, every frame fills three bursts
+		let two: Vec<u16> = codes.iter().chain(&[0; OUTPUTS]).copied().collect();
+		let mut bursts = [7u16; 2 * 3 * BURST_LEN];
+		tim8.fill(&Frames::new(&two, OUTPUTS), &mut bursts);
+		assert!(bursts[..3 * BURST_LEN].chunks_exact(BURST_LEN).all(|burst| burst == [0, 1125, 2250, 3375]));
+		assert_eq!(bursts[3 * BURST_LEN..], [0; 3 * BURST_LEN]);
+
+		assert_eq!(DCR, 3 << 8 | 13);
+	}
+
+	#[test]
+	fn shaping_per_output() {
+		let mut tim8 = layout(Timer::Tim8);
+		assert!(tim8.set_shaping(2, Shaping::SecondOrder));
+		assert!(!tim8.set_shaping(4, Shaping::SecondOrder));
+
+		// 1000.41 counts on every output
+		let codes = [7285u16; OUTPUTS * 64];
+		let mut bursts = [0u16; BURST_LEN * 64];
+		tim8.fill(&Frames::new(&codes, OUTPUTS), &mut bursts);
+
+		let sum = |channel: usize| bursts.iter().skip(channel).step_by(BURST_LEN).map(|&c| c as u32).sum::<u32>();
+		assert!(bursts.iter().step_by(BURST_LEN).all(|&c| c == 1000));
+		assert_eq!((sum(0), sum(1)), (64_000, 64_000));
+		// Only the second order output moves, averaging to the fraction
+		assert!((sum(2) as f64 / 64.0 - 1000.41).abs() < 0.1);
+		assert!(bursts.iter().skip(2).step_by(BURST_LEN).any(|&c| c != 1000));
+	}
+
+	#[test]
+	fn one_burst_per_tick() {
+		let board = Board::new(0);
+		let mut sampler = Sampler::new(board.timer(), board.adc(), board.dma());
+		let dma = board.burst_dma();
+		let request = board.burst_request(&dma);
+		let mut tim8 = BurstOutput::new(dma, request, layout(Timer::Tim8));
+
+		tim8.start(0x4001_044c, buffers(BurstLayout::buffer_len(2)));
+		assert!(!tim8.refill(&Frames::new(&[0; OUTPUTS], OUTPUTS)));
+
+		// Nothing moves before the sample clock runs
+		board.tick();
+		assert_eq!(board.compare(tim8.dma()), [0; BURST_LEN]);
+
+		sampler.start(&ChannelMap::new(&[0], SampleTime::Cycles56).unwrap(), buffers(2));
+
+		// Both sides complete on the same tick
+		board.tick();
+		assert_eq!(tim8.on_transfer_complete(), None);
+		assert_eq!(sampler.on_transfer_complete(), None);
+		board.tick();
+		assert_eq!(tim8.on_transfer_complete(), Some(Target::M0));
+		assert_eq!(sampler.on_transfer_complete(), Some(Target::M0));
+
+		let mut codes = [0u16; 2 * OUTPUTS];
+		codes[0] = 0xFFF0;
+		codes[3] = 0x8000;
+		codes[OUTPUTS + 1] = 0x4000;
+		assert!(tim8.refill(&Frames::new(&codes, OUTPUTS)));
+		assert!(!tim8.refill(&Frames::new(&codes, OUTPUTS)));
+
+		// M1 is played next, then the refilled M0
+		board.tick();
+		board.tick();
+		assert_eq!(tim8.on_transfer_complete(), Some(Target::M1));
+		board.tick();
+		assert_eq!(board.compare(tim8.dma()), [8998, 0, 0, 4500]);
+		board.tick();
+		assert_eq!(board.compare(tim8.dma()), [0, 2250, 0, 0]);
+		assert_eq!(tim8.underruns(), 0);
+	}
+
+	#[test]
+	fn recovers_from_transfer_error() {
+		let board = Board::new(0);
+		let mut sampler = Sampler::new(board.timer(), board.adc(), board.dma());
+		let dma = board.burst_dma();
+		let request = board.burst_request(&dma);
+		let mut tim8 = BurstOutput::new(dma, request, layout(Timer::Tim8));
+		tim8.start(0x4001_044c, buffers(BurstLayout::buffer_len(2)));
+		sampler.start(&ChannelMap::new(&[0], SampleTime::Cycles56).unwrap(), buffers(2));
+
+		// 0 V on output 0 from M0, output 1 at a quarter from M1
+		let mut codes = [0u16; 2 * OUTPUTS];
+		codes[0] = 0x8000;
+		codes[OUTPUTS] = 0x8000;
+		board.tick();
+		board.tick();
+		assert_eq!(tim8.on_transfer_complete(), Some(Target::M0));
+		assert!(tim8.refill(&Frames::new(&codes, OUTPUTS)));
+		let mut codes = [0u16; 2 * OUTPUTS];
+		codes[1] = 0x4000;
+		codes[OUTPUTS + 1] = 0x4000;
+		board.tick();
+		board.tick();
+		assert_eq!(tim8.on_transfer_complete(), Some(Target::M1));
+		assert!(tim8.refill(&Frames::new(&codes, OUTPUTS)));
+		board.tick();
+		assert_eq!(board.compare(tim8.dma()), [4500, 0, 0, 0]);
+
+		// The error stops the stream part way through the next burst, the
+		// timer's request left pending holds it even with the stream back on
+		board.fail_burst(tim8.dma());
+		tim8.dma().enable();
+		board.tick();
+		tim8.dma().disable();
+		assert_eq!(board.compare(tim8.dma()), [4500, 0, 0, 0]);
+
+		assert_eq!(tim8.on_transfer_complete(), None);
+		assert_eq!(tim8.transfer_errors(), 1);
+		assert!(tim8.dma().is_enabled());
+		assert!(!tim8.dma().transfer_error());
+		assert_eq!(tim8.dma().current_target(), Target::M0);
+		assert_eq!(tim8.dma().remaining(), BurstLayout::buffer_len(2) as u16);
+
+		// Whole bursts again, M0 from the start, then M1
+		board.tick();
+		assert_eq!(board.compare(tim8.dma()), [4500, 0, 0, 0]);
+		board.tick();
+		board.tick();
+		assert_eq!(board.compare(tim8.dma()), [0, 2250, 0, 0]);
+		assert_eq!(tim8.on_transfer_complete(), Some(Target::M0));
+		assert_eq!(tim8.underruns(), 0);
+	}
+}
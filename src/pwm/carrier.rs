@@ -0,0 +1,181 @@
+//! PWM carrier timing.
+//!
+//! An output timer counts `(PSC + 1) * (ARR + 1)` timer clock ticks per
+//! carrier period. A 12 bit DAC needs at least 4096 counts per period, so
+//! the carrier runs at most at `timer_clock / 4096`. Any extra counts are
+//! kept as headroom and codes are scaled onto the full `ARR + 1` range.
+
+use crate::sample_clock::MAX_RELOAD_16;
+
+use super::Error;
+
+// Output resolution in bits and the matching code range
+pub const RESOLUTION: u32 = 12;
+pub const MAX_CODE: u16 = (1 << RESOLUTION) - 1;
+
+// Fine codes span the same range in 16 bits, for calibrated outputs that
+// need what the timer resolves beyond 12 bits
+pub const FINE_RESOLUTION: u32 = 16;
+pub const MAX_FINE_CODE: u16 = 0xFFFF;
+
+const STEPS: u64 = 1 << RESOLUTION;
+const MAX_PRESCALER: u64 = 0xFFFF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Carrier {
+	timer_clock: u32,
+	target: u32,
+	prescaler: u16,
+	reload: u32,
+}
+
+impl Carrier {
+	/// Carrier for a 16 bit output timer (TIM1, TIM3, TIM4, TIM8).
+	pub fn new(frequency_hz: u32, timer_clock_hz: u32) -> Result<Carrier, Error> {
+		Carrier::with_max_reload(frequency_hz, timer_clock_hz, MAX_RELOAD_16)
+	}
+
+	/// Carrier for a timer whose auto reload register holds at most `max_reload`.
+	pub fn with_max_reload(frequency_hz: u32, timer_clock_hz: u32, max_reload: u32) -> Result<Carrier, Error> {
+		if frequency_hz == 0 || timer_clock_hz == 0 {
+			return Err(Error::ZeroFrequency);
+		}
+
+		let clock = timer_clock_hz as u64;
+		let frequency = frequency_hz as u64;
+
+		let ticks = (clock + frequency / 2) / frequency;
+		if ticks < STEPS {
+			return Err(Error::FrequencyTooHigh);
+		}
+
+		// Prescale only as far as needed to fit the reload register, every
+		// count kept adds resolution
+		let period_max = max_reload as u64 + 1;
+		let divider = ticks.div_ceil(period_max);
+		// At most 2^32 ticks over a 16 bit reload, the prescaler always fits
+		debug_assert!(divider - 1 <= MAX_PRESCALER);
+
+		let period = ((clock + frequency * divider / 2) / (frequency * divider)).min(period_max);
+		if period < STEPS {
+			return Err(Error::FrequencyTooHigh);
+		}
+
+		Ok(Carrier {
+			timer_clock: timer_clock_hz,
+			target: frequency_hz,
+			prescaler: (divider - 1) as u16,
+			reload: (period - 1) as u32,
+		})
+	}
+
+	pub fn prescaler(&self) -> u16 {
+		self.prescaler
+	}
+
+	pub fn reload(&self) -> u32 {
+		self.reload
+	}
+
+	pub fn target(&self) -> u32 {
+		self.target
+	}
+
+	/// The carrier frequency actually produced, in Hz.
+	pub fn frequency(&self) -> f64 {
+		self.timer_clock as f64 / ((self.prescaler as f64 + 1.0) * (self.reload as f64 + 1.0))
+	}
+
+	/// Compare value for a 12 bit output code, codes above `MAX_CODE` clip.
+	pub fn compare(&self, code: u16) -> u32 {
+		self.compare_fine(code.min(MAX_CODE) << (FINE_RESOLUTION - RESOLUTION))
+	}
+
+	/// Compare value for a 16 bit fine code, rounded to the nearest count.
+	pub fn compare_fine(&self, code: u16) -> u32 {
+		let scaled = code as u64 * (self.reload as u64 + 1);
+		((scaled + (1 << (FINE_RESOLUTION - 1))) >> FINE_RESOLUTION) as u32
+	}
+
+	/// Fine code landing exactly (to 1/16 count or better) on `compare`.
+	///
+	/// Calibration points taken at such codes measure the output stage
+	/// rather than the rounding of `compare_fine`.
+	pub fn fine_code(&self, compare: u32) -> u16 {
+		let steps = self.reload as u64 + 1;
+		let code = ((compare as u64) << FINE_RESOLUTION).div_ceil(steps);
+		code.min(MAX_FINE_CODE as u64) as u16
+	}
+
+	/// Compare counts per carrier period, the resolution actually available
+	pub fn steps(&self) -> u32 {
+		self.reload + 1
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::sample_clock::MAX_RELOAD_32;
+
+	#[test]
+	fn twenty_khz_at_180_mhz() {
+		// TIM1/TIM8 on APB2
+		let carrier = Carrier::new(20_000, 180_000_000).unwrap();
+		assert_eq!((carrier.prescaler(), carrier.reload()), (0, 8999));
+		assert_eq!(carrier.frequency(), 20_000.0);
+
+		// TIM2-TIM4 on APB1
+		let carrier = Carrier::new(20_000, 90_000_000).unwrap();
+		assert_eq!((carrier.prescaler(), carrier.reload()), (0, 4499));
+	}
+
+	#[test]
+	fn codes_span_the_period() {
+		let carrier = Carrier::new(20_000, 90_000_000).unwrap();
+		assert_eq!(carrier.compare(0), 0);
+		assert_eq!(carrier.compare(2048), 2250);
+		assert_eq!(carrier.compare(MAX_CODE), 4499);
+		assert_eq!(carrier.compare(0xFFFF), 4499);
+		assert_eq!(carrier.compare_fine(MAX_FINE_CODE), 4500);
+
+		assert_eq!(carrier.compare_fine(0x8000), 2250);
+		assert_eq!(carrier.compare_fine(0x8010), 2251);
+
+		for compare in 0..=4500 {
+			assert_eq!(carrier.compare_fine(carrier.fine_code(compare)), compare);
+		}
+
+		// Every code gets its own compare value
+		let mut last = None;
+		for code in 0..=MAX_CODE {
+			let compare = carrier.compare(code);
+			assert_ne!(Some(compare), last);
+			last = Some(compare);
+		}
+	}
+
+	#[test]
+	fn slow_carriers_are_prescaled() {
+		let carrier = Carrier::new(100, 180_000_000).unwrap();
+		assert_eq!(carrier.prescaler(), 27);
+		assert!(carrier.reload() <= MAX_RELOAD_16);
+		assert!((carrier.frequency() - 100.0).abs() < 0.01);
+
+		// A 32 bit timer needs no prescaler
+		let carrier = Carrier::with_max_reload(100, 90_000_000, MAX_RELOAD_32).unwrap();
+		assert_eq!((carrier.prescaler(), carrier.reload()), (0, 899_999));
+	}
+
+	#[test]
+	fn rejects_impossible_carriers() {
+		assert_eq!(Carrier::new(0, 90_000_000), Err(Error::ZeroFrequency));
+		// 90 MHz / 4096 = 21.97 kHz
+		assert_eq!(Carrier::new(22_000, 90_000_000), Err(Error::FrequencyTooHigh));
+		assert!(Carrier::new(21_970, 90_000_000).is_ok());
+
+		// Even 1 Hz fits a 16 bit timer
+		let carrier = Carrier::new(1, 180_000_000).unwrap();
+		assert_eq!(carrier.prescaler(), 2746);
+	}
+}
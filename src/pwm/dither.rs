@@ -0,0 +1,200 @@
+//! Dither and noise shaping of the compare values.
+//!
+//! A fine code asks for a fraction of a compare count that plain rounding
+//! throws away, a slow ramp then moves in visible steps of one count. With
+//! TPDF dither the count flickers between neighbours and averages to the
+//! fraction, the error becomes white noise independent of the signal.
+//! Error feedback then pushes that noise up towards half the rate the
+//! compare values are updated at, the sample clock tick (8 kHz at 16 kHz),
+//! away from the low frequencies a CV moves at:
+//!
+//! ```text
+//! v = x - e[n-1]                 first order,  NTF 1 - z^-1
+//! v = x - 2 e[n-1] + e[n-2]      second order, NTF (1 - z^-1)^2
+//! y = round(v + dither)
+//! e[n] = y - v
+//! ```
+//!
+//! Values are in compare counts with 16 fractional bits.
+
+// Compare counts with 16 fractional bits
+const FRACTION: u32 = 16;
+const HALF: i64 = 1 << (FRACTION - 1);
+
+// Feedback kept within two counts so clipping at the rails can not run away
+const MAX_ERROR: i64 = 2 << FRACTION;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shaping {
+	// Rounded to the nearest count
+	Off,
+	// Triangular dither of +-1 count, white error
+	Tpdf,
+	// Dither with first order error feedback
+	FirstOrder,
+	// Dither with second order error feedback
+	SecondOrder,
+}
+
+/// Quantiser state of one output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dither {
+	shaping: Shaping,
+	// Last two quantisation errors
+	errors: [i64; 2],
+	// xorshift32
+	random: u32,
+}
+
+impl Dither {
+	/// `seed` tells the outputs' dither apart, any value
+	pub fn new(shaping: Shaping, seed: u32) -> Dither {
+		// Spread small seeds, xorshift must not start at zero
+		let random = seed.wrapping_mul(0x9E37_79B9) | 1;
+		Dither { shaping, errors: [0; 2], random }
+	}
+
+	pub fn shaping(&self) -> Shaping {
+		self.shaping
+	}
+
+	/// Change the shaping, dropping the feedback state
+	pub fn set_shaping(&mut self, shaping: Shaping) {
+		self.shaping = shaping;
+		self.errors = [0; 2];
+	}
+
+	// Sum of two uniform values, +-1 count
+	fn tpdf(&mut self) -> i64 {
+		self.random ^= self.random << 13;
+		self.random ^= self.random >> 17;
+		self.random ^= self.random << 5;
+		(self.random & 0xFFFF) as i64 + (self.random >> 16) as i64 - (1 << FRACTION)
+	}
+
+	/// Compare value for a 16 bit fine code with `steps` counts per
+	/// period, within `0..=steps`
+	pub fn quantize(&mut self, code: u16, steps: u32) -> u32 {
+		let x = code as i64 * steps as i64;
+		let v = match self.shaping {
+			Shaping::Off => return ((x + HALF) >> FRACTION) as u32,
+			Shaping::Tpdf => x,
+			Shaping::FirstOrder => x - self.errors[0],
+			Shaping::SecondOrder => x - 2 * self.errors[0] + self.errors[1],
+		};
+
+		let y = ((v + self.tpdf() + HALF) >> FRACTION).clamp(0, steps as i64);
+		let error = ((y << FRACTION) - v).clamp(-MAX_ERROR, MAX_ERROR);
+		self.errors = [error, self.errors[0]];
+		y as u32
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::f64::consts::PI;
+
+	// TIM1/TIM8 at 20 kHz
+	const STEPS: u32 = 9000;
+	const LEN: usize = 8192;
+
+	// RMS of the error below `band` (relative to the update rate), Hann
+	// windowed so the noise pushed to high frequencies stays there
+	fn in_band_error(errors: &[f64], band: f64) -> f64 {
+		let n = errors.len();
+		let window: Vec<f64> = (0..n).map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / n as f64).cos()).collect();
+		let power: f64 = window.iter().map(|w| w * w).sum();
+
+		let bins = (band * n as f64) as usize;
+		let mut total = 0.0;
+		for k in 0..=bins {
+			let (mut re, mut im) = (0.0, 0.0);
+			for (i, (e, w)) in errors.iter().zip(&window).enumerate() {
+				let phase = 2.0 * PI * (k * i) as f64 / n as f64;
+				re += e * w * phase.cos();
+				im -= e * w * phase.sin();
+			}
+			// Both sides of the spectrum, DC once
+			let scale = if k == 0 { 1.0 } else { 2.0 };
+			total += scale * (re * re + im * im) / (power * n as f64);
+		}
+		total.sqrt()
+	}
+
+	// In-band error in counts of `shaping` following `codes`
+	fn noise_floor(shaping: Shaping, codes: impl Fn(usize) -> u16) -> f64 {
+		let mut dither = Dither::new(shaping, 1);
+		let errors: Vec<f64> = (0..LEN)
+			.map(|i| {
+				let code = codes(i);
+				let y = dither.quantize(code, STEPS) as f64;
+				y - code as f64 * STEPS as f64 / 65536.0
+			})
+			.collect();
+		// Below 1/64 of the update rate, 250 Hz at a 16 kHz sample clock
+		in_band_error(&errors, 1.0 / 64.0)
+	}
+
+	#[test]
+	fn rounds_without_shaping() {
+		let mut dither = Dither::new(Shaping::Off, 0);
+		assert_eq!(dither.quantize(0x8000, STEPS), 4500);
+		assert_eq!(dither.quantize(0xFFFF, STEPS), 9000);
+		assert_eq!(dither.quantize(7, STEPS), 1);
+		assert_eq!(dither.quantize(3, STEPS), 0);
+	}
+
+	#[test]
+	fn averages_to_the_fraction() {
+		for &shaping in &[Shaping::Tpdf, Shaping::FirstOrder, Shaping::SecondOrder] {
+			let mut dither = Dither::new(shaping, 7);
+			// 1000.41 counts
+			let code = 7285;
+			let sum: u32 = (0..10_000).map(|_| dither.quantize(code, STEPS)).sum();
+			let mean = sum as f64 / 10_000.0;
+			assert!((mean - 7285.0 * STEPS as f64 / 65536.0).abs() < 0.02, "{:?} {}", shaping, mean);
+
+			// Never further than the dither and feedback reach
+			let spread = (0..1000).map(|_| dither.quantize(code, STEPS)).fold((u32::MAX, 0), |(low, high), y| {
+				(low.min(y), high.max(y))
+			});
+			assert!(spread.0 >= 994 && spread.1 <= 1007, "{:?} {:?}", shaping, spread);
+		}
+	}
+
+	#[test]
+	fn stays_within_the_period() {
+		for &shaping in &[Shaping::Tpdf, Shaping::FirstOrder, Shaping::SecondOrder] {
+			let mut dither = Dither::new(shaping, 3);
+			// Clipping at the rails is fed back, the shaped error is kept near
+			assert!((0..1000).all(|_| dither.quantize(0, STEPS) <= 4));
+			assert!((0..1000).all(|_| (STEPS - 4..=STEPS).contains(&dither.quantize(0xFFFF, STEPS))));
+			// And recovers from the clipping
+			let sum: u32 = (0..10_000).map(|_| dither.quantize(0x8000, STEPS)).sum();
+			assert!((sum as f64 / 10_000.0 - 4500.0).abs() < 0.02);
+		}
+	}
+
+	#[test]
+	fn lowers_the_in_band_noise_floor() {
+		// A level between two counts, and a ramp over a few counts
+		let level = |_: usize| -> u16 { 7285 };
+		let ramp = |i: usize| 7200 + (i / 64) as u16;
+
+		for signal in &[&level as &dyn Fn(usize) -> u16, &ramp] {
+			let off = noise_floor(Shaping::Off, signal);
+			let tpdf = noise_floor(Shaping::Tpdf, signal);
+			let first = noise_floor(Shaping::FirstOrder, signal);
+			let second = noise_floor(Shaping::SecondOrder, signal);
+
+			// The rounding error sits in band, TPDF spreads it over the whole
+			// spectrum up to half the update rate, shaping moves it out of the
+			// band towards there
+			assert!(off > 0.15, "off {}", off);
+			assert!(tpdf < 0.12, "TPDF {}", tpdf);
+			assert!(first < tpdf / 5.0, "first order {}", first);
+			assert!(second < first / 3.0, "second order {}", second);
+		}
+	}
+}
@@ -0,0 +1,193 @@
+//! Which timer channel and pin drives each output.
+//!
+//! The 64 pin packages leave 16 timer channels free next to the ADC inputs
+//! (PA0-PA7, PB0-PB1, PC0-PC5) and the SWD pins:
+//!
+//! ```text
+//! outputs  0-3   TIM8 CH1-CH4  PC6-PC9   AF3
+//! outputs  4-7   TIM1 CH1-CH4  PA8-PA11  AF1
+//! outputs  8-11  TIM4 CH1-CH4  PB6-PB9   AF2
+//! outputs 12-14  TIM2 CH1-CH3  PA15 PB3 PB10  AF1
+//! output  15     TIM3 CH1      PB4       AF2
+//! ```
+//!
+//! Outputs are used in this order. TIM8 and TIM1 come first as only they
+//! can have their compare registers loaded by DMA2, see `pwm::burst`.
+
+use crate::adc::{Pin, Port};
+use crate::clocks::Clocks;
+use crate::sample_clock::{MAX_RELOAD_16, MAX_RELOAD_32};
+
+pub const MAX_OUTPUTS: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timer {
+	Tim1,
+	Tim2,
+	Tim3,
+	Tim4,
+	Tim8,
+}
+
+impl Timer {
+	pub const ALL: [Timer; 5] = [Timer::Tim1, Timer::Tim2, Timer::Tim3, Timer::Tim4, Timer::Tim8];
+
+	/// Position in `ALL`
+	pub fn index(self) -> usize {
+		match self {
+			Timer::Tim1 => 0,
+			Timer::Tim2 => 1,
+			Timer::Tim3 => 2,
+			Timer::Tim4 => 3,
+			Timer::Tim8 => 4,
+		}
+	}
+
+	/// Kernel clock, TIM1 and TIM8 sit on APB2, the rest on APB1
+	pub fn clock(self, clocks: &Clocks) -> u32 {
+		match self {
+			Timer::Tim1 | Timer::Tim8 => clocks.timclk2(),
+			_ => clocks.timclk1(),
+		}
+	}
+
+	pub fn max_reload(self) -> u32 {
+		match self {
+			Timer::Tim2 => MAX_RELOAD_32,
+			_ => MAX_RELOAD_16,
+		}
+	}
+
+	// Advanced control timers need MOE set before anything reaches the pins
+	pub fn advanced(self) -> bool {
+		self == Timer::Tim1 || self == Timer::Tim8
+	}
+
+	// GPIO alternate function of the timer channels
+	pub fn alternate_function(self) -> u8 {
+		match self {
+			Timer::Tim1 | Timer::Tim2 => 1,
+			Timer::Tim3 | Timer::Tim4 => 2,
+			Timer::Tim8 => 3,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Output {
+	pub timer: Timer,
+	// Timer channel, 1-4
+	pub channel: u8,
+	pub pin: Pin,
+}
+
+const fn output(timer: Timer, channel: u8, port: Port, pin: u8) -> Output {
+	Output { timer, channel, pin: Pin { port, pin } }
+}
+
+pub const OUTPUTS: [Output; MAX_OUTPUTS] = [
+	output(Timer::Tim8, 1, Port::C, 6),
+	output(Timer::Tim8, 2, Port::C, 7),
+	output(Timer::Tim8, 3, Port::C, 8),
+	output(Timer::Tim8, 4, Port::C, 9),
+	output(Timer::Tim1, 1, Port::A, 8),
+	output(Timer::Tim1, 2, Port::A, 9),
+	output(Timer::Tim1, 3, Port::A, 10),
+	output(Timer::Tim1, 4, Port::A, 11),
+	output(Timer::Tim4, 1, Port::B, 6),
+	output(Timer::Tim4, 2, Port::B, 7),
+	output(Timer::Tim4, 3, Port::B, 8),
+	output(Timer::Tim4, 4, Port::B, 9),
+	output(Timer::Tim2, 1, Port::A, 15),
+	output(Timer::Tim2, 2, Port::B, 3),
+	output(Timer::Tim2, 3, Port::B, 10),
+	output(Timer::Tim3, 1, Port::B, 4),
+];
+
+/// The first `count` outputs.
+pub fn outputs(count: usize) -> &'static [Output] {
+	&OUTPUTS[..count.min(MAX_OUTPUTS)]
+}
+
+/// Whether any of `outputs` is on `timer`
+pub fn uses(outputs: &[Output], timer: Timer) -> bool {
+	outputs.iter().any(|output| output.timer == timer)
+}
+
+/// CCER with the compare outputs (CCxE) of `timer` used by `outputs` enabled
+pub fn ccer(outputs: &[Output], timer: Timer) -> u32 {
+	outputs
+		.iter()
+		.filter(|output| output.timer == timer)
+		.fold(0, |ccer, output| ccer | 1 << ((output.channel - 1) * 4))
+}
+
+/// MODER bits putting the pins of `outputs` on `port` in alternate function mode
+pub fn alternate_moder(outputs: &[Output], port: Port) -> u32 {
+	outputs
+		.iter()
+		.filter(|output| output.pin.port == port)
+		.fold(0, |moder, output| moder | 0b10 << (output.pin.pin * 2))
+}
+
+/// AFRL and AFRH bits for the pins of `outputs` on `port`
+pub fn alternate_functions(outputs: &[Output], port: Port) -> (u32, u32) {
+	outputs
+		.iter()
+		.filter(|output| output.pin.port == port)
+		.fold((0, 0), |(afrl, afrh), output| {
+			let af = output.timer.alternate_function() as u32;
+			let pin = output.pin.pin as u32;
+			if pin < 8 {
+				(afrl | af << (pin * 4), afrh)
+			} else {
+				(afrl, afrh | af << ((pin - 8) * 4))
+			}
+		})
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::adc::{pin, MAX_CHANNELS};
+
+	#[test]
+	fn outputs_do_not_collide() {
+		for (i, a) in OUTPUTS.iter().enumerate() {
+			assert!((1..=4).contains(&a.channel));
+			for b in &OUTPUTS[i + 1..] {
+				assert_ne!(a.pin, b.pin);
+				assert!(a.timer != b.timer || a.channel != b.channel);
+			}
+
+			for channel in 0..MAX_CHANNELS as u8 {
+				assert_ne!(Some(a.pin), pin(channel));
+			}
+
+			// SWD
+			assert!(a.pin.port != Port::A || ![13, 14].contains(&a.pin.pin));
+		}
+	}
+
+	#[test]
+	fn eight_outputs_use_two_timers() {
+		let used: Vec<Timer> = Timer::ALL.iter().copied().filter(|&t| uses(outputs(8), t)).collect();
+		assert_eq!(used, vec![Timer::Tim1, Timer::Tim8]);
+		assert_eq!(outputs(20).len(), MAX_OUTPUTS);
+	}
+
+	#[test]
+	fn register_values() {
+		assert_eq!(ccer(&OUTPUTS, Timer::Tim1), 0x1111);
+		assert_eq!(ccer(&OUTPUTS, Timer::Tim2), 0x0111);
+		assert_eq!(ccer(&OUTPUTS, Timer::Tim3), 0x0001);
+
+		assert_eq!(alternate_moder(&OUTPUTS, Port::A), 0b10 << 30 | 0b10 << 22 | 0b10 << 20 | 0b10 << 18 | 0b10 << 16);
+		assert_eq!(alternate_functions(&OUTPUTS, Port::A), (0, 1 << 28 | 1 << 12 | 1 << 8 | 1 << 4 | 1));
+		assert_eq!(alternate_functions(&OUTPUTS, Port::C), (3 << 28 | 3 << 24, 3 << 4 | 3));
+
+		let (afrl, afrh) = alternate_functions(&OUTPUTS, Port::B);
+		assert_eq!(afrl, 2 << 28 | 2 << 24 | 2 << 16 | 1 << 12);
+		assert_eq!(afrh, 1 << 8 | 2 << 4 | 2);
+	}
+}
@@ -0,0 +1,225 @@
+//! PWM DAC outputs.
+//!
+//! Every CV output is a timer channel in PWM mode 1 followed by an analog
+//! low pass filter. `Carrier` works out the timer period for 12 bit
+//! resolution at the requested carrier frequency, `layout` assigns outputs
+//! to timer channels and pins, and `OutputEngine` moves samples from a
+//! stream per output into the compare registers through an `OutputDriver`.
+//! On TIM1 and TIM8 `burst` has the DMA load the compare registers instead,
+//! in step with the sample clock. Either way `dither` can quantise each
+//! output's fine codes with dither and noise shaping.
+
+pub mod burst;
+mod carrier;
+pub mod dither;
+pub mod layout;
+
+pub use self::carrier::{Carrier, FINE_RESOLUTION, MAX_CODE, MAX_FINE_CODE, RESOLUTION};
+pub use self::dither::{Dither, Shaping};
+pub use self::layout::{Output, Timer, MAX_OUTPUTS, OUTPUTS};
+
+use crate::calibration::OutputCalibrations;
+use crate::fixed::{Sample, Volts};
+use crate::hw::OutputDriver;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+	// The requested carrier or timer clock is zero
+	ZeroFrequency,
+	// Fewer than 4096 timer ticks per carrier period
+	FrequencyTooHigh,
+	TooManyOutputs,
+}
+
+/// Writes output codes through an `OutputDriver`.
+///
+/// Fine codes are quantised to the compare counts of each output's timer,
+/// the same way `BurstLayout::fill` does for TIM1 and TIM8.
+pub struct OutputEngine<O> {
+	driver: O,
+	calibrations: OutputCalibrations,
+	dithers: [Dither; MAX_OUTPUTS],
+}
+
+impl<O: OutputDriver> OutputEngine<O> {
+	/// `calibrations` as for `set_calibrations`, the nominal range of the
+	/// output stage until the outputs are calibrated
+	pub fn new(driver: O, calibrations: OutputCalibrations) -> Self {
+		let mut dithers = [Dither::new(Shaping::Off, 0); MAX_OUTPUTS];
+		for (i, dither) in dithers.iter_mut().enumerate() {
+			*dither = Dither::new(Shaping::Off, i as u32);
+		}
+		OutputEngine { driver, calibrations, dithers }
+	}
+
+	pub fn channels(&self) -> usize {
+		self.driver.channels()
+	}
+
+	/// Write a 12 bit code
+	pub fn write(&mut self, channel: usize, code: u16) {
+		self.write_fine(channel, code.min(MAX_CODE) << (FINE_RESOLUTION - RESOLUTION));
+	}
+
+	/// Write a 16 bit fine code
+	pub fn write_fine(&mut self, channel: usize, code: u16) {
+		let steps = self.driver.steps(channel);
+		let compare = self.dithers[channel].quantize(code, steps);
+		self.driver.set(channel, compare);
+	}
+
+	/// Dither and noise shaping of an output. The shaping only moves the
+	/// noise above the audio band when the output is written on every
+	/// sample tick, as the bursts are, not once per buffer.
+	pub fn set_shaping(&mut self, channel: usize, shaping: Shaping) {
+		self.dithers[channel].set_shaping(shaping);
+	}
+
+	pub fn shaping(&self, channel: usize) -> Shaping {
+		self.dithers[channel].shaping()
+	}
+
+	/// Calibrations used by `write_volts`
+	pub fn set_calibrations(&mut self, calibrations: OutputCalibrations) {
+		self.calibrations = calibrations;
+	}
+
+	/// Drive an output to a voltage through its calibration
+	pub fn write_volts(&mut self, channel: usize, volts: Volts) {
+		let code = self.calibrations.code(channel, volts);
+		self.write_fine(channel, code);
+	}
+
+	/// Write a sample of the +-10 V range, through the calibration
+	pub fn write_sample(&mut self, channel: usize, sample: Sample) {
+		self.write_volts(channel, sample.to_volts());
+	}
+
+	/// Write one code per output, `frame[i]` to output `i`.
+	pub fn write_frame(&mut self, frame: &[u16]) {
+		assert!(frame.len() <= self.channels());
+
+		for (channel, &code) in frame.iter().enumerate() {
+			self.write(channel, code);
+		}
+	}
+
+	/// Move the next sample of every stream to its output.
+	///
+	/// `streams[i]` feeds output `i`. An exhausted stream leaves its output
+	/// at the last value. Returns the number of outputs updated.
+	pub fn update<I>(&mut self, streams: &mut [I]) -> usize
+		where I: Iterator<Item = u16>
+	{
+		assert!(streams.len() <= self.channels());
+
+		let mut updated = 0;
+		for (channel, stream) in streams.iter_mut().enumerate() {
+			if let Some(code) = stream.next() {
+				self.write(channel, code);
+				updated += 1;
+			}
+		}
+		updated
+	}
+
+	pub fn driver(&mut self) -> &mut O {
+		&mut self.driver
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::calibration::OutputCalibration;
+	use crate::hw::mock::{self, Board};
+
+	// Uncalibrated +-5 V outputs
+	fn nominal() -> OutputCalibrations {
+		OutputCalibrations::new(OutputCalibration::nominal(-5_000_000, 5_000_000))
+	}
+
+	#[test]
+	fn streams_reach_the_outputs() {
+		let board = Board::new(3);
+		let mut engine = OutputEngine::new(board.output(), nominal());
+		let compare = |code: u16| mock::carrier(0).compare(code);
+
+		let a = [1u16, 2048, 3];
+		let b = [10u16];
+		let c = [0xFFFFu16, 7];
+		let mut streams = [a.iter().copied(), b.iter().copied(), c.iter().copied()];
+
+		assert_eq!(engine.update(&mut streams), 3);
+		assert_eq!((board.duty(0), board.duty(1), board.duty(2)), (compare(1), compare(10), compare(MAX_CODE)));
+
+		// The second stream ran out and holds
+		assert_eq!(engine.update(&mut streams), 2);
+		assert_eq!((board.duty(0), board.duty(1), board.duty(2)), (4500, compare(10), compare(7)));
+
+		assert_eq!(engine.update(&mut streams), 1);
+		assert_eq!(engine.update(&mut streams), 0);
+		assert_eq!((board.duty(0), board.duty(1), board.duty(2)), (compare(3), compare(10), compare(7)));
+	}
+
+	#[test]
+	fn voltages_through_calibration() {
+		let board = Board::new(9);
+		let mut engine = OutputEngine::new(board.output(), nominal());
+
+		// 6/10 of the nominal +-5 V range, in the counts of TIM8 and TIM4
+		engine.write_volts(0, Volts::from_int(1));
+		engine.write_volts(8, Volts::from_int(1));
+		assert_eq!((board.duty(0), board.duty(8)), (5400, 2700));
+
+		// Output 1 reads 2% low with a 10 mV offset
+		let mut calibrations = nominal();
+		calibrations.set(1, OutputCalibration::nominal(-4_890_000, 4_910_000));
+		engine.set_calibrations(calibrations);
+		engine.write_volts(1, Volts::ZERO);
+		let offset = board.duty(1);
+		assert_eq!(offset, mock::carrier(1).compare_fine(calibrations.code(1, Volts::ZERO)));
+		assert!(offset < 4500);
+
+		// Past the nominal +-5 V outputs
+		engine.write_sample(0, Sample::from_bits(0x5000));
+		assert_eq!(board.duty(0), 9000);
+		engine.write_sample(0, Sample::from_volts(Volts::from_int(1)));
+		assert_eq!(board.duty(0), 5400);
+	}
+
+	#[test]
+	fn dithered_outputs_average_to_the_fine_code() {
+		// Outputs 8 and 9 are on TIM4, 4500 counts, written by the CPU
+		let board = Board::new(10);
+		let mut engine = OutputEngine::new(board.output(), nominal());
+		engine.set_shaping(9, Shaping::FirstOrder);
+		assert_eq!((engine.shaping(8), engine.shaping(9)), (Shaping::Off, Shaping::FirstOrder));
+
+		// 2249.45 of the 4500 counts
+		let mut sum = 0;
+		for _ in 0..1000 {
+			engine.write_fine(8, 0x7FF8);
+			engine.write_fine(9, 0x7FF8);
+			assert_eq!(board.duty(8), 2249);
+			sum += board.duty(9);
+		}
+		assert_eq!((sum + 50) / 100, 22495);
+
+		// Never past the top
+		for _ in 0..100 {
+			engine.write_fine(9, 0xFFFF);
+			assert!(board.duty(9) <= 4500);
+		}
+	}
+
+	#[test]
+	fn frames_write_every_output() {
+		let board = Board::new(4);
+		let mut engine = OutputEngine::new(board.output(), nominal());
+
+		engine.write_frame(&[4, 3, 2]);
+		let compare = |code: u16| mock::carrier(0).compare(code);
+		assert_eq!((0..4).map(|i| board.duty(i)).collect::<Vec<_>>(), vec![compare(4), compare(3), compare(2), 0]);
+	}
+}
@@ -0,0 +1,444 @@
+//! 1V/oct pitch quantizer.
+//!
+//! A `Scale` is a set of pitches within one period, repeated every period
+//! up and down: twelve tone scales from a mask of semitones with a 1 V
+//! octave, or any table of pitches with any period for microtonal tunings.
+//! Notes are numbered through the octaves, note `len` is the first degree
+//! one period up.
+//!
+//! A `Quantizer` moves its input to the nearest note. Near the middle
+//! between two notes it would flip back and forth on every bit of noise,
+//! so it only leaves the current note once the input is `hysteresis`
+//! closer to another one. The output is transposed in scale degrees after
+//! quantizing, a transpose of `len` is one period.
+
+use crate::adc::{Frames, MAX_CHANNELS};
+use crate::calibration::{InputCalibrations, OutputCalibrations};
+use crate::fixed::{Sample, Volts};
+use crate::pwm::MAX_OUTPUTS;
+
+/// Most pitches in a scale
+pub const MAX_DEGREES: usize = 128;
+
+// Trigger on a note change
+const TRIGGER_MS: u32 = 10;
+const TRIGGER: Volts = Volts::from_bits(5 << 16);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+	// No pitches at all
+	Empty,
+	// More than MAX_DEGREES pitches
+	TooManyDegrees,
+	// Pitches not rising, or outside the period
+	Unsorted,
+	// Zero or negative period
+	Period,
+}
+
+/// Pitches repeating every period.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Scale {
+	degrees: [Volts; MAX_DEGREES],
+	len: usize,
+	period: Volts,
+}
+
+impl Scale {
+	/// Rising pitches within `[0, period)`
+	pub fn new(degrees: &[Volts], period: Volts) -> Result<Scale, Error> {
+		if period <= Volts::ZERO {
+			return Err(Error::Period);
+		}
+		if degrees.is_empty() {
+			return Err(Error::Empty);
+		}
+		if degrees.len() > MAX_DEGREES {
+			return Err(Error::TooManyDegrees);
+		}
+		let rising = degrees.windows(2).all(|pair| pair[0] < pair[1]);
+		if !rising || degrees[0] < Volts::ZERO || degrees[degrees.len() - 1] >= period {
+			return Err(Error::Unsorted);
+		}
+
+		let mut scale = Scale { degrees: [Volts::ZERO; MAX_DEGREES], len: degrees.len(), period };
+		scale.degrees[..degrees.len()].copy_from_slice(degrees);
+		Ok(scale)
+	}
+
+	/// Twelve tone equal temperament, bit `i` of `mask` for `i` semitones
+	/// above the root
+	pub fn from_mask(mask: u16) -> Result<Scale, Error> {
+		let mut degrees = [Volts::ZERO; 12];
+		let mut len = 0;
+		for semitone in (0..12).filter(|i| mask & 1 << i != 0) {
+			degrees[len] = semitone_volts(semitone);
+			len += 1;
+		}
+		Scale::new(&degrees[..len], Volts::ONE)
+	}
+
+	pub fn chromatic() -> Scale {
+		Scale::from_mask(0xFFF).unwrap()
+	}
+
+	pub fn major() -> Scale {
+		Scale::from_mask(0xAB5).unwrap()
+	}
+
+	/// Natural minor
+	pub fn minor() -> Scale {
+		Scale::from_mask(0x5AD).unwrap()
+	}
+
+	pub fn degrees(&self) -> &[Volts] {
+		&self.degrees[..self.len]
+	}
+
+	pub fn period(&self) -> Volts {
+		self.period
+	}
+
+	/// Pitch of note `note`, saturating
+	pub fn pitch(&self, note: i32) -> Volts {
+		let len = self.len as i32;
+		let period = note.div_euclid(len) as i64 * self.period.to_bits() as i64;
+		let bits = period + self.degrees[note.rem_euclid(len) as usize].to_bits() as i64;
+		Volts::from_bits(bits.clamp(i32::MIN as i64, i32::MAX as i64) as i32)
+	}
+
+	/// Note nearest to `volts`
+	pub fn nearest(&self, volts: Volts) -> i32 {
+		let period = self.period.to_bits();
+		let base = volts.to_bits().div_euclid(period) * self.len as i32;
+		let within = Volts::from_bits(volts.to_bits().rem_euclid(period));
+		// The first degree above, the one below may be in the period below
+		let above = base + self.degrees().partition_point(|&degree| degree <= within) as i32;
+		let below = above - 1;
+		if distance(volts, self.pitch(above)) < distance(volts, self.pitch(below)) {
+			above
+		} else {
+			below
+		}
+	}
+}
+
+// Twelve tone equal temperament, rounded to the nearest bit
+fn semitone_volts(semitone: i32) -> Volts {
+	Volts::from_bits((semitone * Volts::ONE.to_bits() + 6) / 12)
+}
+
+fn distance(a: Volts, b: Volts) -> u32 {
+	a.to_bits().abs_diff(b.to_bits())
+}
+
+/// Quantizer output for one input sample.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Note {
+	// Note number before the transpose
+	pub note: i32,
+	pub volts: Volts,
+	// A different note than the sample before
+	pub changed: bool,
+}
+
+/// Quantizes one channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quantizer {
+	scale: Scale,
+	hysteresis: Volts,
+	transpose: i32,
+	note: Option<i32>,
+}
+
+impl Quantizer {
+	pub fn new(scale: Scale, hysteresis: Volts) -> Quantizer {
+		Quantizer { scale, hysteresis, transpose: 0, note: None }
+	}
+
+	pub fn scale(&self) -> &Scale {
+		&self.scale
+	}
+
+	/// Change the scale, the next sample picks a note afresh
+	pub fn set_scale(&mut self, scale: Scale) {
+		self.scale = scale;
+		self.note = None;
+	}
+
+	pub fn set_hysteresis(&mut self, hysteresis: Volts) {
+		self.hysteresis = hysteresis;
+	}
+
+	/// Transpose in scale degrees
+	pub fn set_transpose(&mut self, degrees: i32) {
+		self.transpose = degrees;
+	}
+
+	pub fn transpose(&self) -> i32 {
+		self.transpose
+	}
+
+	/// Next input sample
+	pub fn process(&mut self, volts: Volts) -> Note {
+		let nearest = self.scale.nearest(volts);
+		let note = match self.note {
+			Some(current) if current != nearest => {
+				let from = distance(volts, self.scale.pitch(current)) as i64;
+				let to = distance(volts, self.scale.pitch(nearest)) as i64;
+				if from - to >= self.hysteresis.to_bits() as i64 { nearest } else { current }
+			}
+			_ => nearest,
+		};
+		let changed = self.note.is_some_and(|current| current != note);
+		self.note = Some(note);
+		Note { note, volts: self.scale.pitch(note.saturating_add(self.transpose)), changed }
+	}
+}
+
+// A quantizer from an input to an output, with an optional trigger output
+#[derive(Clone, Copy, Debug)]
+struct Channel {
+	input: usize,
+	quantizer: Quantizer,
+	trigger: Option<usize>,
+	// Ticks the trigger stays high
+	remaining: u32,
+}
+
+/// Quantizers from the inputs to the outputs.
+pub struct Quantizers {
+	channels: [Option<Channel>; MAX_OUTPUTS],
+	inputs: InputCalibrations,
+	outputs: OutputCalibrations,
+	trigger_ticks: u32,
+}
+
+impl Quantizers {
+	/// No quantized outputs
+	pub fn new(sample_rate: u32, inputs: InputCalibrations, outputs: OutputCalibrations) -> Quantizers {
+		Quantizers { channels: [None; MAX_OUTPUTS], inputs, outputs, trigger_ticks: sample_rate * TRIGGER_MS / 1000 }
+	}
+
+	/// Quantize `input` to `output`, or stop with `None`
+	pub fn set(&mut self, output: usize, route: Option<(usize, Quantizer)>) {
+		assert!(route.is_none_or(|(input, _)| input < MAX_CHANNELS));
+		self.channels[output] =
+			route.map(|(input, quantizer)| Channel { input, quantizer, trigger: None, remaining: 0 });
+	}
+
+	pub fn get(&self, output: usize) -> Option<&Quantizer> {
+		self.channels[output].as_ref().map(|channel| &channel.quantizer)
+	}
+
+	pub fn get_mut(&mut self, output: usize) -> Option<&mut Quantizer> {
+		self.channels[output].as_mut().map(|channel| &mut channel.quantizer)
+	}
+
+	/// Output pulsing on every note change of `output`'s quantizer,
+	/// false if `output` does not quantize
+	pub fn set_trigger(&mut self, output: usize, trigger: Option<usize>) -> bool {
+		match self.channels[output].as_mut() {
+			Some(channel) => {
+				channel.trigger = trigger;
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Stop driving `output`, as a quantized output or as a trigger
+	pub fn release(&mut self, output: usize) {
+		self.channels[output] = None;
+		for channel in self.channels.iter_mut().flatten() {
+			if channel.trigger == Some(output) {
+				channel.trigger = None;
+			}
+		}
+	}
+
+	/// Whether `output` is quantized or the trigger of a quantizer
+	pub fn drives(&self, output: usize) -> bool {
+		self.channels.iter().enumerate().any(|(quantized, channel)| {
+			channel.as_ref().is_some_and(|channel| quantized == output || channel.trigger == Some(output))
+		})
+	}
+
+	pub fn set_calibrations(&mut self, inputs: InputCalibrations, outputs: OutputCalibrations) {
+		self.inputs = inputs;
+		self.outputs = outputs;
+	}
+
+	/// Quantize a completed buffer of decimated input frames into output
+	/// frames of one fine code per output, frame by frame. Outputs not
+	/// quantizing or triggering are left alone.
+	pub fn process(&mut self, input_frames: &Frames<Sample>, frames: &mut [u16]) {
+		let inputs = input_frames.width();
+		for (input_frame, frame) in input_frames.iter().zip(frames.chunks_exact_mut(MAX_OUTPUTS)) {
+			for (output, channel) in self.channels.iter_mut().enumerate() {
+				let channel = match channel {
+					Some(channel) if channel.input < inputs => channel,
+					_ => continue,
+				};
+				let sample = input_frame[channel.input];
+				let note = channel.quantizer.process(self.inputs.volts(channel.input, sample));
+				frame[output] = self.outputs.code(output, note.volts);
+
+				if let Some(trigger) = channel.trigger {
+					if note.changed {
+						channel.remaining = self.trigger_ticks;
+					}
+					let level = if channel.remaining > 0 { TRIGGER } else { Volts::ZERO };
+					channel.remaining = channel.remaining.saturating_sub(1);
+					frame[trigger] = self.outputs.code(trigger, level);
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::calibration::{InputCalibration, OutputCalibration};
+
+	fn mv(millivolts: i32) -> Volts {
+		Volts::from_millivolts(millivolts)
+	}
+
+	// Semitones, rounded
+	fn semitones(volts: Volts) -> i32 {
+		(volts.to_bits() as f64 * 12.0 / 65536.0).round() as i32
+	}
+
+	#[test]
+	fn twelve_tone_scales() {
+		let chromatic = Scale::chromatic();
+		assert_eq!(chromatic.degrees().len(), 12);
+		assert_eq!(chromatic.nearest(mv(1040)), 12);
+		assert_eq!(chromatic.nearest(mv(1045)), 13);
+		assert_eq!(chromatic.nearest(mv(-40)), 0);
+		assert_eq!(chromatic.nearest(mv(-45)), -1);
+		assert_eq!(chromatic.pitch(-1), Volts::from_bits(-5461));
+		assert_eq!(chromatic.pitch(12), Volts::ONE);
+
+		// C D E F G A B, two octaves down, a little above each semitone
+		let major = Scale::major();
+		let notes: Vec<i32> =
+			(0..12).map(|s| major.nearest(Volts::from_int(-2) + chromatic.pitch(s) + mv(10))).collect();
+		assert_eq!(notes, [-14, -13, -13, -12, -12, -11, -10, -10, -9, -9, -8, -8]);
+		assert_eq!(semitones(major.pitch(-9)), -15);
+
+		// A flat and B flat in C minor
+		let minor = Scale::minor();
+		assert_eq!(semitones(minor.pitch(minor.nearest(chromatic.pitch(9) - mv(10)))), 8);
+		assert_eq!(semitones(minor.pitch(minor.nearest(chromatic.pitch(9) + mv(10)))), 10);
+
+		// Masks without the root wrap around the octave
+		let fifths = Scale::from_mask(0x080).unwrap();
+		assert_eq!((fifths.nearest(mv(100)), fifths.nearest(mv(-100))), (0, -1));
+		assert_eq!(semitones(fifths.pitch(-1)), -5);
+		assert_eq!(Scale::from_mask(0xF000), Err(Error::Empty));
+	}
+
+	#[test]
+	fn microtonal_tables() {
+		// Bohlen-Pierce, 13 equal steps of a 3:1 tritave
+		let tritave = Volts::from_bits((3f64.log2() * 65536.0).round() as i32);
+		let steps: Vec<Volts> =
+			(0..13).map(|i| Volts::from_bits((tritave.to_bits() as i64 * i / 13) as i32)).collect();
+		let bp = Scale::new(&steps, tritave).unwrap();
+		assert_eq!(bp.nearest(tritave + steps[5]), 18);
+		assert_eq!(bp.pitch(-13), -tritave);
+		assert_eq!(bp.nearest(mv(-60)), 0);
+		assert_eq!(bp.nearest(mv(-70)), -1);
+
+		assert_eq!(Scale::new(&[], Volts::ONE), Err(Error::Empty));
+		assert_eq!(Scale::new(&[mv(0)], Volts::ZERO), Err(Error::Period));
+		assert_eq!(Scale::new(&[mv(0), mv(500), mv(400)], Volts::ONE), Err(Error::Unsorted));
+		assert_eq!(Scale::new(&[mv(0), mv(1000)], Volts::ONE), Err(Error::Unsorted));
+		assert_eq!(Scale::new(&[mv(0); MAX_DEGREES + 1], Volts::ONE), Err(Error::TooManyDegrees));
+	}
+
+	#[test]
+	fn hysteresis_stops_chatter() {
+		// 10 cents
+		let mut quantizer = Quantizer::new(Scale::chromatic(), Volts::from_microvolts(8333));
+		assert_eq!(quantizer.process(mv(40)), Note { note: 0, volts: Volts::ZERO, changed: false });
+
+		// Noise around the middle between C and C sharp
+		let noisy = [41, 43, 40, 44, 42, 45, 41, 43];
+		assert!(noisy.iter().all(|&v| quantizer.process(mv(v)).note == 0));
+		let up = quantizer.process(mv(47));
+		assert_eq!((up.note, up.changed), (1, true));
+		assert!(noisy.iter().all(|&v| quantizer.process(mv(v)).note == 1));
+		assert!(!quantizer.process(mv(42)).changed);
+		assert_eq!(quantizer.process(mv(36)).note, 0);
+
+		// Without hysteresis every crossing counts
+		let mut quantizer = Quantizer::new(Scale::chromatic(), Volts::ZERO);
+		let changes = noisy.iter().filter(|&&v| quantizer.process(mv(v)).changed).count();
+		assert_eq!(changes, 5);
+	}
+
+	#[test]
+	fn transposes_in_scale_degrees() {
+		let mut quantizer = Quantizer::new(Scale::major(), Volts::ZERO);
+		quantizer.set_transpose(2);
+		assert_eq!(quantizer.transpose(), 2);
+		// D up a third in C major is F
+		let note = quantizer.process(Volts::ONE + Scale::chromatic().pitch(2));
+		assert_eq!(note.note, 8);
+		assert_eq!(semitones(note.volts), 17);
+
+		// Down an octave
+		quantizer.set_transpose(-7);
+		assert_eq!(semitones(quantizer.process(Volts::ONE).volts), 0);
+
+		quantizer.set_scale(Scale::chromatic());
+		assert_eq!(quantizer.scale(), &Scale::chromatic());
+		assert!(!quantizer.process(Volts::ZERO).changed);
+	}
+
+	#[test]
+	fn quantizes_inputs_to_outputs() {
+		// 1 mV per code around 0 V, outputs +-5 V
+		let inputs = InputCalibrations::new(InputCalibration::nominal(-2048, 2047));
+		let outputs = OutputCalibrations::new(OutputCalibration::nominal(-5_000_000, 5_000_000));
+
+		// 1 kHz, triggers of 10 ticks
+		let mut quantizers = Quantizers::new(1000, inputs, outputs);
+		assert!(!quantizers.set_trigger(2, Some(3)));
+		quantizers.set(2, Some((1, Quantizer::new(Scale::chromatic(), Volts::ZERO))));
+		assert!(quantizers.set_trigger(2, Some(3)));
+		assert!(quantizers.drives(2) && quantizers.drives(3) && !quantizers.drives(1));
+		quantizers.get_mut(2).unwrap().set_transpose(12);
+		assert_eq!(quantizers.get(2).map(Quantizer::transpose), Some(12));
+
+		// Two inputs, input 1 at 0 V, then 250 mV: C, then E flat, an octave up
+		let mut buffer = vec![Sample::from_code(0); 2 * 12];
+		for (i, frame) in buffer.chunks_exact_mut(2).enumerate() {
+			frame[1] = Sample::from_code(if i < 2 { 2048 } else { 2298 });
+		}
+		let mut frames = [7; MAX_OUTPUTS * 12];
+		quantizers.process(&Frames::new(&buffer, 2), &mut frames);
+
+		let code = |millivolts| outputs.code(0, mv(millivolts));
+		let column = |output: usize| -> Vec<u16> { frames.chunks_exact(MAX_OUTPUTS).map(|f| f[output]).collect() };
+		assert_eq!(column(2)[..3], [code(1000), code(1000), code(1250)]);
+		let trigger = column(3);
+		assert_eq!(trigger[..2], [code(0), code(0)]);
+		assert!(trigger[2..].iter().all(|&c| c == code(5000)));
+		assert!(column(0).iter().all(|&c| c == 7));
+
+		let more = vec![Sample::from_code(2298); 2 * 2];
+		quantizers.process(&Frames::new(&more, 2), &mut frames[..2 * MAX_OUTPUTS]);
+		assert_eq!(frames[3], code(0));
+
+		// Taking the trigger away leaves the quantizer running
+		quantizers.release(3);
+		assert!(quantizers.drives(2) && !quantizers.drives(3));
+		quantizers.release(2);
+		assert!(!quantizers.drives(2));
+	}
+}
@@ -0,0 +1,170 @@
+//! Sample clock timing.
+//!
+//! The sample timer runs in PWM mode 1, one rising edge on channel 1 per
+//! counter period, and every rising edge triggers one ADC sequence. The
+//! sample rate is therefore
+//!
+//! ```text
+//! timer_clock / ((PSC + 1) * (ARR + 1))
+//! ```
+//!
+//! and the compare value only sets the duty of the trigger pulse.
+
+// TIM2 and TIM5 are 32 bit timers
+pub const MAX_RELOAD_32: u32 = 0xFFFF_FFFF;
+// Every other general purpose timer is 16 bit
+pub const MAX_RELOAD_16: u32 = 0xFFFF;
+
+const MAX_PRESCALER: u64 = 0xFFFF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+	// The requested rate or timer clock is zero
+	ZeroRate,
+	// Less than two timer ticks per sample, no trigger edge can be generated
+	RateTooHigh,
+	// The period does not fit prescaler and reload register
+	RateTooLow,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SampleClock {
+	timer_clock: u32,
+	target: u32,
+	prescaler: u16,
+	reload: u32,
+}
+
+impl SampleClock {
+	/// Timing for a 32 bit sample timer (TIM5).
+	pub fn new(rate_hz: u32, timer_clock_hz: u32) -> Result<SampleClock, Error> {
+		SampleClock::with_max_reload(rate_hz, timer_clock_hz, MAX_RELOAD_32)
+	}
+
+	/// Timing for a timer whose auto reload register holds at most `max_reload`.
+	pub fn with_max_reload(rate_hz: u32, timer_clock_hz: u32, max_reload: u32) -> Result<SampleClock, Error> {
+		if rate_hz == 0 || timer_clock_hz == 0 {
+			return Err(Error::ZeroRate);
+		}
+
+		let clock = timer_clock_hz as u64;
+		let rate = rate_hz as u64;
+
+		// Total timer ticks per sample, rounded to nearest
+		let ticks = (clock + rate / 2) / rate;
+		if ticks < 2 {
+			return Err(Error::RateTooHigh);
+		}
+
+		// Smallest prescaler that makes the period fit the reload register,
+		// any larger prescaler only gives a coarser period.
+		let period_max = max_reload as u64 + 1;
+		let divider = ticks.div_ceil(period_max);
+		if divider - 1 > MAX_PRESCALER {
+			return Err(Error::RateTooLow);
+		}
+
+		let period = (clock + rate * divider / 2) / (rate * divider);
+		if period < 2 {
+			return Err(Error::RateTooHigh);
+		}
+
+		Ok(SampleClock {
+			timer_clock: timer_clock_hz,
+			target: rate_hz,
+			prescaler: (divider - 1) as u16,
+			reload: (period.min(period_max) - 1) as u32,
+		})
+	}
+
+	pub fn prescaler(&self) -> u16 {
+		self.prescaler
+	}
+
+	pub fn reload(&self) -> u32 {
+		self.reload
+	}
+
+	// Compare value giving a 50% trigger pulse
+	pub fn compare(&self) -> u32 {
+		(self.reload as u64).div_ceil(2) as u32
+	}
+
+	// Timer ticks per sample
+	pub fn period(&self) -> u64 {
+		(self.prescaler as u64 + 1) * (self.reload as u64 + 1)
+	}
+
+	pub fn target(&self) -> u32 {
+		self.target
+	}
+
+	pub fn timer_clock(&self) -> u32 {
+		self.timer_clock
+	}
+
+	/// The sample rate actually produced, in Hz.
+	pub fn rate(&self) -> f64 {
+		self.timer_clock as f64 / self.period() as f64
+	}
+
+	/// Deviation of the produced rate from the requested one, in ppm.
+	pub fn error_ppm(&self) -> f64 {
+		(self.rate() / self.target as f64 - 1.0) * 1_000_000.0
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn exact_rates() {
+		// 90 MHz timer clock (APB1 x2 at 180 MHz sysclk)
+		let clock = SampleClock::new(48_000, 90_000_000).unwrap();
+		assert_eq!(clock.prescaler(), 0);
+		assert_eq!(clock.reload(), 1874);
+		assert_eq!(clock.compare(), 937);
+		assert_eq!(clock.error_ppm(), 0.0);
+
+		let clock = SampleClock::new(16_000, 16_000_000).unwrap();
+		assert_eq!((clock.prescaler(), clock.reload()), (0, 999));
+		assert_eq!(clock.rate(), 16_000.0);
+
+		let clock = SampleClock::new(32_000, 96_000_000).unwrap();
+		assert_eq!((clock.prescaler(), clock.reload()), (0, 2999));
+	}
+
+	#[test]
+	fn inexact_rate_reports_error() {
+		// 90e6 / 44100 = 2040.8
+		let clock = SampleClock::new(44_100, 90_000_000).unwrap();
+		assert_eq!(clock.reload(), 2040);
+		assert!((clock.rate() - 44_096.03).abs() < 0.01);
+		assert!((clock.error_ppm() + 90.0).abs() < 0.1);
+
+		// 16e6 / 44100 = 362.8
+		let clock = SampleClock::new(44_100, 16_000_000).unwrap();
+		assert_eq!(clock.reload(), 362);
+		assert!(clock.error_ppm() < 0.0);
+		assert!((clock.error_ppm() + 518.5).abs() < 0.1);
+	}
+
+	#[test]
+	fn prescaler_used_for_16_bit_timers() {
+		// 90e6 / 1000 = 90000 ticks, does not fit 16 bits
+		let clock = SampleClock::with_max_reload(1_000, 90_000_000, MAX_RELOAD_16).unwrap();
+		assert_eq!(clock.prescaler(), 1);
+		assert_eq!(clock.reload(), 44_999);
+		assert_eq!(clock.error_ppm(), 0.0);
+	}
+
+	#[test]
+	fn rejects_impossible_rates() {
+		assert_eq!(SampleClock::new(0, 90_000_000), Err(Error::ZeroRate));
+		assert_eq!(SampleClock::new(48_000, 0), Err(Error::ZeroRate));
+		assert_eq!(SampleClock::new(100_000_000, 90_000_000), Err(Error::RateTooHigh));
+		assert_eq!(SampleClock::with_max_reload(1, 90_000_000, 0xFF), Err(Error::RateTooLow));
+		assert!(SampleClock::new(45_000_000, 90_000_000).is_ok());
+	}
+}
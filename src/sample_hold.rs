@@ -0,0 +1,264 @@
+//! Sample & hold and track & hold outputs.
+//!
+//! An output in hold mode follows a source, an input or internal noise,
+//! clocked by a trigger input. Sample & hold takes the source on every
+//! rising edge of the trigger and holds it until the next. Track & hold
+//! follows the source while the trigger is high and holds it from the
+//! falling edge on. Each output has its own gate detector on its trigger
+//! input, so the trigger input need not be in gate mode and several outputs
+//! can share one trigger.
+//!
+//! Noise is uniform over the +-5 V of the outputs, a new value every sample,
+//! so sampling it gives random voltages.
+
+use crate::adc::{Frames, MAX_CHANNELS};
+use crate::calibration::{InputCalibrations, OutputCalibrations};
+use crate::fixed::{Sample, Volts};
+use crate::gate::{Edge, GateConfig, GateDetector};
+use crate::pwm::MAX_OUTPUTS;
+
+// Noise spans +-5 V
+const NOISE_MICROVOLTS: i64 = 5_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+	Input(usize),
+	Noise,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+	// Takes the source on the rising edge
+	SampleHold,
+	// Follows the source while the trigger is high
+	TrackHold,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HoldConfig {
+	pub source: Source,
+	pub trigger: usize,
+	pub mode: Mode,
+	pub gate: GateConfig,
+}
+
+/// One output in hold mode.
+#[derive(Clone, Copy, Debug)]
+pub struct Hold {
+	config: HoldConfig,
+	detector: GateDetector,
+	held: Volts,
+	// xorshift32
+	random: u32,
+}
+
+impl Hold {
+	/// Holds 0 V until the first trigger, `seed` tells the noise of the
+	/// outputs apart
+	pub fn new(config: HoldConfig, seed: u32) -> Hold {
+		// Spread small seeds, xorshift must not start at zero
+		let random = seed.wrapping_mul(0x9E37_79B9) | 1;
+		Hold { config, detector: GateDetector::new(config.gate), held: Volts::ZERO, random }
+	}
+
+	pub fn config(&self) -> HoldConfig {
+		self.config
+	}
+
+	pub fn held(&self) -> Volts {
+		self.held
+	}
+
+	fn noise(&mut self) -> Volts {
+		self.random ^= self.random << 13;
+		self.random ^= self.random >> 17;
+		self.random ^= self.random << 5;
+		let microvolts = ((self.random as i64 * 2 * NOISE_MICROVOLTS) >> 32) - NOISE_MICROVOLTS;
+		Volts::from_microvolts(microvolts as i32)
+	}
+
+	/// Next sample of the trigger and, for an input source, of the source
+	pub fn process(&mut self, trigger: Volts, source: Volts, at: u64) -> Volts {
+		let source = match self.config.source {
+			Source::Input(_) => source,
+			Source::Noise => self.noise(),
+		};
+		let edge = self.detector.process(trigger, at).map(|(edge, _)| edge);
+		match self.config.mode {
+			Mode::SampleHold if edge == Some(Edge::Rising) => self.held = source,
+			// Tracking stops on the falling edge, with the sample before
+			Mode::TrackHold if self.detector.is_high() => self.held = source,
+			_ => {}
+		}
+		self.held
+	}
+}
+
+/// The outputs in hold mode.
+pub struct SampleHolds {
+	holds: [Option<Hold>; MAX_OUTPUTS],
+	inputs: InputCalibrations,
+	outputs: OutputCalibrations,
+	// Sample clock tick of the next frame
+	tick: u64,
+}
+
+impl SampleHolds {
+	/// No outputs in hold mode
+	pub fn new(inputs: InputCalibrations, outputs: OutputCalibrations) -> SampleHolds {
+		SampleHolds { holds: [None; MAX_OUTPUTS], inputs, outputs, tick: 0 }
+	}
+
+	/// Hold mode for an output, or back to CV with `None`
+	pub fn set(&mut self, output: usize, config: Option<HoldConfig>) {
+		assert!(config.is_none_or(|config| {
+			let source = match config.source {
+				Source::Input(input) => input,
+				Source::Noise => 0,
+			};
+			config.trigger < MAX_CHANNELS && source < MAX_CHANNELS
+		}));
+		self.holds[output] = config.map(|config| Hold::new(config, output as u32 + 1));
+	}
+
+	pub fn get(&self, output: usize) -> Option<&Hold> {
+		self.holds[output].as_ref()
+	}
+
+	pub fn set_calibrations(&mut self, inputs: InputCalibrations, outputs: OutputCalibrations) {
+		self.inputs = inputs;
+		self.outputs = outputs;
+	}
+
+	/// Run a completed buffer of decimated input frames into output frames
+	/// of one fine code per output, frame by frame. Outputs not in hold mode
+	/// are left alone, as are those whose inputs are not in the channel map.
+	pub fn process(&mut self, input_frames: &Frames<Sample>, frames: &mut [u16]) {
+		let inputs = &self.inputs;
+		for (input_frame, frame) in input_frames.iter().zip(frames.chunks_exact_mut(MAX_OUTPUTS)) {
+			for (output, hold) in self.holds.iter_mut().enumerate() {
+				let hold = match hold {
+					Some(hold) => hold,
+					None => continue,
+				};
+				let config = hold.config;
+				let volts = |input: usize| input_frame.get(input).map(|&sample| inputs.volts(input, sample));
+				let source = match config.source {
+					Source::Input(input) => volts(input),
+					Source::Noise => Some(Volts::ZERO),
+				};
+				if let (Some(trigger), Some(source)) = (volts(config.trigger), source) {
+					let held = hold.process(trigger, source, self.tick);
+					frame[output] = self.outputs.code(output, held);
+				}
+			}
+			self.tick += 1;
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::calibration::{InputCalibration, OutputCalibration};
+
+	fn config(source: Source, mode: Mode) -> HoldConfig {
+		HoldConfig { source, trigger: 0, mode, gate: GateConfig::default() }
+	}
+
+	fn mv(millivolts: i32) -> Volts {
+		Volts::from_millivolts(millivolts)
+	}
+
+	// Held voltages in mV following the trigger and source in mV
+	fn run(hold: &mut Hold, trigger: &[i32], source: &[i32]) -> Vec<i32> {
+		trigger
+			.iter()
+			.zip(source)
+			.enumerate()
+			.map(|(at, (&t, &s))| hold.process(mv(t), mv(s), at as u64).to_millivolts())
+			.collect()
+	}
+
+	#[test]
+	fn samples_on_the_rising_edge() {
+		let mut hold = Hold::new(config(Source::Input(1), Mode::SampleHold), 1);
+		let trigger = [0, 5000, 5000, 0, 0, 5000, 0, 0];
+		let source = [100, 200, 300, 400, 500, 600, 700, 800];
+		assert_eq!(run(&mut hold, &trigger, &source), [0, 200, 200, 200, 200, 600, 600, 600]);
+		assert_eq!(hold.held(), mv(600));
+	}
+
+	#[test]
+	fn tracks_while_high() {
+		let mut hold = Hold::new(config(Source::Input(1), Mode::TrackHold), 1);
+		let trigger = [0, 5000, 5000, 5000, 0, 0, 5000, 0];
+		let source = [100, 200, 300, 400, 500, 600, 700, 800];
+		assert_eq!(run(&mut hold, &trigger, &source), [0, 200, 300, 400, 400, 400, 700, 700]);
+
+		// The gate's minimum width delays both edges and drops the short pulse
+		let gate = GateConfig { min_width: 2, ..GateConfig::default() };
+		let mut hold = Hold::new(HoldConfig { gate, ..config(Source::Input(1), Mode::TrackHold) }, 1);
+		assert_eq!(run(&mut hold, &trigger, &source), [0, 0, 300, 400, 500, 500, 500, 500]);
+	}
+
+	#[test]
+	fn samples_noise() {
+		let mut hold = Hold::new(config(Source::Noise, Mode::SampleHold), 1);
+		// Triggers every other sample, the source is ignored
+		let trigger: Vec<i32> = (0..2000).map(|i| if i % 2 == 1 { 5000 } else { 0 }).collect();
+		let held = run(&mut hold, &trigger, &[0; 2000]);
+		let samples: Vec<i32> = held.iter().skip(1).step_by(2).copied().collect();
+
+		assert!(samples.iter().all(|&v| (-5000..5000).contains(&v)));
+		let mean = samples.iter().sum::<i32>() as f64 / samples.len() as f64;
+		assert!(mean.abs() < 250.0, "mean {}", mean);
+		// Spread over the whole range
+		assert!(samples.iter().any(|&v| v < -4500) && samples.iter().any(|&v| v > 4500));
+		let changes = samples.windows(2).filter(|pair| pair[0] != pair[1]).count();
+		assert!(changes > 990);
+
+		// Another seed, other values
+		let mut other = Hold::new(config(Source::Noise, Mode::SampleHold), 2);
+		assert_ne!(run(&mut other, &trigger, &[0; 2000]), held);
+	}
+
+	#[test]
+	fn holds_inputs_on_outputs() {
+		// 1 mV per code around 0 V, outputs +-5 V
+		let inputs = InputCalibrations::new(InputCalibration::nominal(-2048, 2047));
+		let outputs = OutputCalibrations::new(OutputCalibration::nominal(-5_000_000, 5_000_000));
+
+		let mut holds = SampleHolds::new(inputs, outputs);
+		// Input 1 sampled on input 2, two outputs on the same trigger
+		let config = HoldConfig { trigger: 2, ..config(Source::Input(1), Mode::SampleHold) };
+		holds.set(5, Some(config));
+		holds.set(6, Some(HoldConfig { mode: Mode::TrackHold, ..config }));
+		assert_eq!(holds.get(5).map(Hold::config), Some(config));
+		assert!(holds.get(0).is_none());
+
+		// Three inputs, the trigger at 0 V, 2 V, 2 V, 0 V; the source rising
+		// 100 mV a frame
+		let trigger = [2048, 4048, 4048, 2048];
+		let buffer: Vec<Sample> = (0..4usize)
+			.flat_map(|i| [0, 2148 + 100 * i as u16, trigger[i]])
+			.map(Sample::from_code)
+			.collect();
+		let mut frames = [7; 4 * MAX_OUTPUTS];
+		holds.process(&Frames::new(&buffer, 3), &mut frames);
+
+		let code = |millivolts: i32| outputs.code(0, mv(millivolts));
+		let column = |output: usize| -> Vec<u16> {
+			frames.chunks_exact(MAX_OUTPUTS).map(|frame| frame[output]).collect()
+		};
+		assert_eq!(column(5), [code(0), code(200), code(200), code(200)]);
+		assert_eq!(column(6), [code(0), code(200), code(300), code(300)]);
+		assert!(column(0).iter().all(|&c| c == 7));
+
+		// An input beyond the channel map leaves the output alone
+		holds.set(5, Some(HoldConfig { trigger: 3, ..config }));
+		let mut frames = [7; MAX_OUTPUTS];
+		holds.process(&Frames::new(&buffer[..3], 3), &mut frames);
+		assert_eq!(frames[5], 7);
+	}
+}
@@ -0,0 +1,384 @@
+//! The input signal path.
+//!
+//! The sample timer triggers a conversion of the ADC regular sequence and the
+//! DMA moves every result into one of two buffers, swapping buffer whenever
+//! one fills up. Completed buffers are lent out through the `DoubleBuffer`.
+//!
+//! An ADC overrun (a result not picked up by the DMA in time) or a DMA
+//! transfer error stops the chain. Both raise an interrupt and the sampler
+//! restarts the ADC and the DMA from a clean state, see `recover`.
+
+use crate::adc::ChannelMap;
+use crate::double_buffer::DoubleBuffer;
+use crate::hw::{AdcSequencer, DoubleBufferDma, SampleTimer, Target};
+use crate::sample_clock::SampleClock;
+
+/// Error counters since the sampler was created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Diagnostics {
+	/// ADC results lost, recovered through the ADC interrupt
+	pub adc_overruns: u32,
+	/// DMA transfer errors, recovered through the DMA interrupt
+	pub dma_errors: u32,
+	/// DMA switched into a buffer the application still held
+	pub buffer_overruns: u32,
+}
+
+pub struct Sampler<T, A, D> {
+	timer: T,
+	adc: A,
+	dma: D,
+	buffers: Option<&'static DoubleBuffer>,
+	adc_overruns: u32,
+	dma_errors: u32,
+}
+
+impl<T, A, D> Sampler<T, A, D>
+	where T: SampleTimer, A: AdcSequencer, D: DoubleBufferDma
+{
+	pub fn new(timer: T, adc: A, dma: D) -> Self {
+		Sampler {
+			timer,
+			adc,
+			dma,
+			buffers: None,
+			adc_overruns: 0,
+			dma_errors: 0,
+		}
+	}
+
+	pub fn configure_timer(&mut self, clock: &SampleClock) {
+		self.timer.configure(clock.prescaler(), clock.reload(), clock.compare());
+	}
+
+	/// Start sampling the channels of `map` into both halves of `buffers`.
+	pub fn start(&mut self, map: &ChannelMap, buffers: &'static DoubleBuffer) {
+		self.adc.configure(map);
+		self.adc.enable_overrun_interrupt();
+		assert!(buffers.len().is_multiple_of(self.adc.frame_width()), "buffer must hold whole frames");
+		assert!(buffers.len() <= u16::MAX as usize);
+
+		self.buffers = Some(buffers);
+		self.configure_dma(buffers);
+		self.dma.enable();
+
+		self.adc.enable();
+
+		self.timer.start();
+	}
+
+	pub fn stop(&mut self) {
+		self.timer.stop();
+		self.adc.disable();
+		self.dma.disable();
+	}
+
+	fn configure_dma(&mut self, buffers: &'static DoubleBuffer) {
+		// The buffers are 'static and never handed out mutably, the DMA is
+		// their only writer
+		unsafe {
+			self.dma.configure(
+				self.adc.data_address(),
+				self.adc.data_width(),
+				buffers.m0(),
+				buffers.m1(),
+				buffers.len() as u16,
+			);
+		}
+	}
+
+	/// Restart the ADC and the DMA after an overrun or a transfer error.
+	///
+	/// Following the reference manual (RM0390 13.8.1):
+	/// 1. Stop the ADC so no new results arrive
+	/// 2. Disable the stream and wait for EN to clear, clear its flags
+	/// 3. Re-initialise the stream: both buffer addresses, a full NDTR and
+	///    CT back on M0. The partly filled half is dropped
+	/// 4. Clear OVR, which also re-arms the ADC DMA requests
+	/// 5. Enable the stream, then the ADC. The sample timer kept running,
+	///    the next trigger converts a new frame into the start of M0
+	pub fn recover(&mut self) {
+		self.adc.disable();
+
+		self.dma.disable();
+		self.dma.clear_interrupts();
+
+		if let Some(buffers) = self.buffers {
+			self.configure_dma(buffers);
+			buffers.restart();
+		}
+
+		self.adc.clear_overrun();
+
+		self.dma.enable();
+		self.adc.enable();
+	}
+
+	/// ADC interrupt, recovers from an overrun. True if there was one.
+	pub fn on_adc_interrupt(&mut self) -> bool {
+		if !self.adc.overrun() {
+			return false;
+		}
+
+		self.adc_overruns += 1;
+		self.recover();
+		true
+	}
+
+	pub fn diagnostics(&self) -> Diagnostics {
+		Diagnostics {
+			adc_overruns: self.adc_overruns,
+			dma_errors: self.dma_errors,
+			buffer_overruns: self.buffers.map_or(0, |b| b.overruns()),
+		}
+	}
+
+	// Sample timer interrupt
+	pub fn on_tick(&mut self) {
+		self.timer.clear_interrupts();
+	}
+
+	/// DMA interrupt, returns the buffer that was just filled.
+	///
+	/// The filled half is then ready to `take` from the `DoubleBuffer`.
+	/// A transfer error is counted and recovered from instead.
+	pub fn on_transfer_complete(&mut self) -> Option<Target> {
+		if self.dma.transfer_error() {
+			self.dma_errors += 1;
+			self.recover();
+			return None;
+		}
+
+		if !self.dma.transfer_complete() {
+			return None;
+		}
+
+		self.dma.clear_interrupts();
+		let current = self.dma.current_target();
+		if let Some(buffers) = self.buffers {
+			buffers.swap(current);
+		}
+		Some(current.other())
+	}
+
+	pub fn buffers(&self) -> Option<&'static DoubleBuffer> {
+		self.buffers
+	}
+
+	pub fn adc(&mut self) -> &mut A {
+		&mut self.adc
+	}
+
+	pub fn dma(&mut self) -> &mut D {
+		&mut self.dma
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::hw::mock::Board;
+	use crate::adc::{Allocation, Mode};
+	use crate::hw::SampleTime;
+
+	fn map(channels: &[u8]) -> ChannelMap {
+		ChannelMap::new(channels, SampleTime::Cycles56).unwrap()
+	}
+
+	// Word aligned so the same buffers serve the 32 bit multi mode transfers
+	fn half(len: usize) -> &'static mut [u16] {
+		let words = Box::leak(vec![0u32; len.div_ceil(2)].into_boxed_slice());
+		unsafe { core::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u16, len) }
+	}
+
+	fn buffers(len: usize) -> &'static DoubleBuffer {
+		Box::leak(Box::new(DoubleBuffer::new(half(len), half(len))))
+	}
+
+	#[test]
+	fn fills_buffers_in_sequence_order() {
+		let board = Board::new(0);
+		let mut sampler = Sampler::new(board.timer(), board.adc(), board.dma());
+		let buffers = buffers(4);
+
+		sampler.start(&map(&[1, 0]), buffers);
+		assert_eq!(board.sequence(), vec![1, 0]);
+
+		board.set_input(0, 100);
+		board.set_input(1, 200);
+		board.tick();
+		assert_eq!(sampler.on_transfer_complete(), None);
+		assert!(buffers.take().is_none());
+
+		board.set_input(0, 101);
+		board.tick();
+		assert_eq!(sampler.on_transfer_complete(), Some(Target::M0));
+		{
+			let half = buffers.take().unwrap();
+			assert_eq!(half.target(), Target::M0);
+			assert_eq!(&*half, &[200, 100, 200, 101]);
+		}
+
+		board.tick();
+		board.tick();
+		assert_eq!(sampler.on_transfer_complete(), Some(Target::M1));
+		assert_eq!(&*buffers.take().unwrap(), &[200, 101, 200, 101]);
+		assert_eq!(buffers.overruns(), 0);
+	}
+
+	#[test]
+	fn consumer_holding_a_buffer_is_an_overrun() {
+		let board = Board::new(0);
+		let mut sampler = Sampler::new(board.timer(), board.adc(), board.dma());
+		let buffers = buffers(1);
+		sampler.start(&map(&[0]), buffers);
+
+		board.set_input(0, 1);
+		board.tick();
+		sampler.on_transfer_complete();
+		let held = buffers.take().unwrap();
+		assert_eq!(&*held, &[1]);
+
+		// M1 fills and the DMA moves back into M0, which is still held
+		board.tick();
+		sampler.on_transfer_complete();
+		assert_eq!(buffers.overruns(), 1);
+		assert!(!held.intact());
+
+		board.set_input(0, 2);
+		board.tick();
+		assert_eq!(&*held, &[2]);
+		drop(held);
+
+		sampler.on_transfer_complete();
+		assert_eq!(buffers.overruns(), 1);
+	}
+
+	#[test]
+	fn recovers_from_adc_overrun() {
+		let board = Board::new(0);
+		let mut sampler = Sampler::new(board.timer(), board.adc(), board.dma());
+		let buffers = buffers(2);
+		sampler.start(&map(&[0]), buffers);
+
+		board.set_input(0, 1);
+		board.tick();
+
+		// The DMA stops serving the ADC, the next result is lost
+		sampler.dma().disable();
+		board.tick();
+		assert!(board.adc_interrupt());
+
+		// Further triggers are ignored until recovered
+		board.tick();
+		assert!(sampler.on_adc_interrupt());
+		assert!(!board.adc_interrupt());
+		assert!(!sampler.on_adc_interrupt());
+		assert!(sampler.dma().is_enabled());
+		assert_eq!(sampler.dma().current_target(), Target::M0);
+		assert_eq!(sampler.dma().remaining(), 2);
+
+		// The half filled M0 starts over with whole frames
+		board.set_input(0, 2);
+		board.tick();
+		board.set_input(0, 3);
+		board.tick();
+		assert_eq!(sampler.on_transfer_complete(), Some(Target::M0));
+		assert_eq!(&*buffers.take().unwrap(), &[2, 3]);
+
+		assert_eq!(sampler.diagnostics(), Diagnostics { adc_overruns: 1, dma_errors: 0, buffer_overruns: 0 });
+	}
+
+	#[test]
+	fn recovers_from_transfer_error() {
+		let board = Board::new(0);
+		let mut sampler = Sampler::new(board.timer(), board.adc(), board.dma());
+		let buffers = buffers(2);
+		sampler.start(&map(&[0]), buffers);
+
+		board.tick();
+		board.tick();
+		board.tick();
+		assert_eq!(sampler.on_transfer_complete(), Some(Target::M0));
+		let held = buffers.take().unwrap();
+
+		// The stream is disabled by the error, the ADC overruns behind it
+		board.fail_dma();
+		board.tick();
+		assert!(board.adc_interrupt());
+
+		assert_eq!(sampler.on_transfer_complete(), None);
+		assert!(!sampler.dma().transfer_error());
+		assert!(!board.adc_interrupt());
+		assert!(!sampler.on_adc_interrupt());
+
+		// The restart writes into the M0 still held
+		assert!(!held.intact());
+		drop(held);
+
+		board.set_input(0, 5);
+		board.tick();
+		board.tick();
+		assert_eq!(sampler.on_transfer_complete(), Some(Target::M0));
+		assert_eq!(&*buffers.take().unwrap(), &[5, 5]);
+
+		assert_eq!(sampler.diagnostics(), Diagnostics { adc_overruns: 0, dma_errors: 1, buffer_overruns: 1 });
+	}
+
+	#[test]
+	fn triple_mode_frames() {
+		let board = Board::new(0);
+		let mut sampler = Sampler::new(board.timer(), board.multi_adc(Mode::TripleSimultaneous), board.dma());
+
+		let inputs = map(&[4, 5, 0, 6]);
+		let allocation = Allocation::new(&inputs, Mode::TripleSimultaneous).unwrap();
+		assert_eq!(allocation.frame_width(), 6);
+
+		let buffers = buffers(6);
+		sampler.start(&inputs, buffers);
+
+		for &channel in [4, 5, 0, 6].iter() {
+			board.set_input(channel, channel as u16 * 10);
+		}
+		board.tick();
+		assert_eq!(sampler.on_transfer_complete(), Some(Target::M0));
+
+		let mut streams = [[0u16; 1]; 4];
+		{
+			let mut out: Vec<&mut [u16]> = streams.iter_mut().map(|s| &mut s[..]).collect();
+			allocation.deinterleave(&buffers.take().unwrap(), &mut out);
+		}
+		assert_eq!(streams, [[40], [50], [0], [60]]);
+	}
+
+	#[test]
+	fn tick_interrupt_is_cleared() {
+		let board = Board::new(0);
+		let mut sampler = Sampler::new(board.timer(), board.adc(), board.dma());
+		sampler.configure_timer(&SampleClock::new(48_000, 90_000_000).unwrap());
+		assert_eq!(board.timer_settings(), (0, 1874, 937));
+
+		sampler.start(&map(&[0]), buffers(2));
+
+		board.tick();
+		assert!(board.timer_interrupt());
+		sampler.on_tick();
+		assert!(!board.timer_interrupt());
+	}
+
+	#[test]
+	fn nothing_is_sampled_once_stopped() {
+		let board = Board::new(0);
+		let mut sampler = Sampler::new(board.timer(), board.adc(), board.dma());
+		let buffers = buffers(1);
+
+		sampler.start(&map(&[0]), buffers);
+		sampler.stop();
+
+		board.set_input(0, 7);
+		board.tick();
+		assert_eq!(sampler.on_transfer_complete(), None);
+		assert!(buffers.take().is_none());
+		assert_eq!(buffers.swaps(), 0);
+	}
+}
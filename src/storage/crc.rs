@@ -0,0 +1,73 @@
+//! CRC-32 (IEEE 802.3, reflected, as zlib) with a 16 entry table.
+
+const POLYNOMIAL: u32 = 0xEDB8_8320;
+
+// CRC of each nibble value
+const TABLE: [u32; 16] = table();
+
+const fn table() -> [u32; 16] {
+	let mut table = [0; 16];
+	let mut i = 0;
+	while i < 16 {
+		let mut crc = i as u32;
+		let mut bit = 0;
+		while bit < 4 {
+			crc = if crc & 1 != 0 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 };
+			bit += 1;
+		}
+		table[i] = crc;
+		i += 1;
+	}
+	table
+}
+
+/// Running CRC over data fed in pieces.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+	pub fn new() -> Crc32 {
+		Crc32(0xFFFF_FFFF)
+	}
+
+	pub fn update(&mut self, data: &[u8]) {
+		for &byte in data {
+			let mut crc = self.0 ^ byte as u32;
+			crc = (crc >> 4) ^ TABLE[(crc & 0x0F) as usize];
+			crc = (crc >> 4) ^ TABLE[(crc & 0x0F) as usize];
+			self.0 = crc;
+		}
+	}
+
+	pub fn finish(self) -> u32 {
+		!self.0
+	}
+}
+
+impl Default for Crc32 {
+	fn default() -> Crc32 {
+		Crc32::new()
+	}
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+	let mut crc = Crc32::new();
+	crc.update(data);
+	crc.finish()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn check_value() {
+		assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+		assert_eq!(crc32(b""), 0);
+
+		let mut crc = Crc32::new();
+		crc.update(b"1234");
+		crc.update(b"56789");
+		assert_eq!(crc.finish(), 0xCBF4_3926);
+	}
+}
@@ -0,0 +1,534 @@
+//! Key/value store in two sectors of internal flash.
+//!
+//! Values are appended to a log in the active sector, each record a key,
+//! a length and a CRC over both and the value, followed by the value padded
+//! to a word. A later record for the same key replaces an earlier one, a
+//! record without a value (`TOMBSTONE` length) removes the key. Nothing is
+//! ever rewritten in place.
+//!
+//! When the active sector is full the latest record of every key is copied
+//! into the other sector, which then becomes active. Its header (magic,
+//! generation, CRC) is written last, so a copy cut short by a power loss is
+//! never mounted and the old sector, left as it was, still holds every
+//! value. On mount the valid header with the highest generation wins. Each
+//! collection erases the sector it moves into, so both sectors wear at the
+//! same rate, once per sector full of writes.
+//!
+//! A record cut short by a power loss fails its CRC. The log ends before
+//! it and the next write first collects into the other sector, as the
+//! bytes after the end are no longer erased.
+
+pub mod crc;
+
+use core::iter;
+
+use crate::hw::{Flash, FlashError};
+
+use self::crc::{crc32, Crc32};
+
+// "CVKV"
+const MAGIC: u32 = 0x564B_5643;
+// Magic, generation, CRC of both
+const SECTOR_HEADER: usize = 12;
+// Key, length, CRC
+const RECORD_HEADER: usize = 8;
+// Length of a record removing its key
+const TOMBSTONE: u16 = 0xFFFE;
+// Read from erased flash, not a valid key
+const ERASED_KEY: u16 = 0xFFFF;
+// Bytes moved at a time while collecting
+const CHUNK: usize = 32;
+
+// Longest value
+pub const MAX_VALUE: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+	Flash(FlashError),
+	// No room for the record, even after collecting the garbage
+	Full,
+	// 0xFFFF is reserved
+	InvalidKey,
+	TooLong,
+	// The value is longer than the buffer given to `read`
+	BufferTooSmall(usize),
+}
+
+impl From<FlashError> for Error {
+	fn from(error: FlashError) -> Error {
+		Error::Flash(error)
+	}
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Record {
+	offset: usize,
+	key: u16,
+	len: u16,
+	crc: u32,
+}
+
+impl Record {
+	fn value_len(&self) -> usize {
+		if self.len == TOMBSTONE { 0 } else { self.len as usize }
+	}
+
+	// Bytes taken in the sector
+	fn size(&self) -> usize {
+		RECORD_HEADER + padded(self.value_len())
+	}
+}
+
+fn padded(len: usize) -> usize {
+	(len + 3) & !3
+}
+
+fn sector_header(generation: u32) -> [u8; SECTOR_HEADER] {
+	let mut header = [0; SECTOR_HEADER];
+	header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+	header[4..8].copy_from_slice(&generation.to_le_bytes());
+	let crc = crc32(&header[..8]);
+	header[8..].copy_from_slice(&crc.to_le_bytes());
+	header
+}
+
+// Generation of a sector with a valid header
+fn generation<F: Flash>(flash: &F, sector: usize) -> Option<u32> {
+	let mut header = [0; SECTOR_HEADER];
+	flash.read(sector, 0, &mut header);
+	let word = |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+
+	if word(0) != MAGIC || word(8) != crc32(&header[..8]) {
+		return None;
+	}
+	Some(word(4))
+}
+
+pub struct Storage<F> {
+	flash: F,
+	// Sector holding the log
+	active: usize,
+	generation: u32,
+	// Offset of the next record
+	end: usize,
+	// Bytes after `end` are not erased, collect before appending
+	dirty: bool,
+}
+
+impl<F: Flash> Storage<F> {
+	/// Open the store, formatting the flash if neither sector is valid.
+	pub fn mount(flash: F) -> Result<Storage<F>, Error> {
+		assert!(flash.sectors() == 2, "the store takes exactly two sectors");
+
+		let mut newest: Option<(usize, u32)> = None;
+		for sector in 0..2 {
+			if let Some(generation) = generation(&flash, sector) {
+				if newest.is_none_or(|(_, newest)| generation > newest) {
+					newest = Some((sector, generation));
+				}
+			}
+		}
+
+		let mut storage = Storage { flash, active: 0, generation: 0, end: SECTOR_HEADER, dirty: false };
+		match newest {
+			Some((sector, generation)) => {
+				storage.active = sector;
+				storage.generation = generation;
+				storage.scan();
+			}
+			None => {
+				storage.flash.erase(0)?;
+				storage.flash.write(0, 0, &sector_header(1))?;
+				storage.generation = 1;
+			}
+		}
+		Ok(storage)
+	}
+
+	// Find the end of the log
+	fn scan(&mut self) {
+		let size = self.flash.sector_size();
+		let mut offset = SECTOR_HEADER;
+		while let Some(record) = self.header(offset).filter(|record| self.intact(record)) {
+			offset += record.size();
+		}
+		self.end = offset;
+
+		let mut chunk = [0; CHUNK];
+		while offset < size {
+			let len = (size - offset).min(CHUNK);
+			self.flash.read(self.active, offset, &mut chunk[..len]);
+			if chunk[..len].iter().any(|&byte| byte != 0xFF) {
+				self.dirty = true;
+				return;
+			}
+			offset += len;
+		}
+	}
+
+	// Record header at `offset`, none where the flash is erased or the
+	// header makes no sense
+	fn header(&self, offset: usize) -> Option<Record> {
+		let size = self.flash.sector_size();
+		if offset + RECORD_HEADER > size {
+			return None;
+		}
+
+		let mut header = [0; RECORD_HEADER];
+		self.flash.read(self.active, offset, &mut header);
+		let record = Record {
+			offset,
+			key: u16::from_le_bytes([header[0], header[1]]),
+			len: u16::from_le_bytes([header[2], header[3]]),
+			crc: u32::from_le_bytes([header[4], header[5], header[6], header[7]]),
+		};
+
+		if record.key == ERASED_KEY || (record.len != TOMBSTONE && record.len as usize > MAX_VALUE) {
+			return None;
+		}
+		if offset + record.size() > size {
+			return None;
+		}
+		Some(record)
+	}
+
+	fn intact(&self, record: &Record) -> bool {
+		let mut crc = Crc32::new();
+		crc.update(&record.key.to_le_bytes());
+		crc.update(&record.len.to_le_bytes());
+
+		let mut chunk = [0; CHUNK];
+		let mut done = 0;
+		while done < record.value_len() {
+			let len = (record.value_len() - done).min(CHUNK);
+			self.flash.read(self.active, record.offset + RECORD_HEADER + done, &mut chunk[..len]);
+			crc.update(&chunk[..len]);
+			done += len;
+		}
+		crc.finish() == record.crc
+	}
+
+	// Every record in the log, oldest first
+	fn records(&self, from: usize) -> impl Iterator<Item = Record> + '_ {
+		let mut offset = from;
+		iter::from_fn(move || {
+			if offset >= self.end {
+				return None;
+			}
+			// Checked by `scan` or written by `append`
+			let record = self.header(offset).unwrap();
+			offset += record.size();
+			Some(record)
+		})
+	}
+
+	// Latest record of `key`
+	fn find(&self, key: u16) -> Option<Record> {
+		self.records(SECTOR_HEADER)
+			.filter(|record| record.key == key)
+			.last()
+			.filter(|record| record.len != TOMBSTONE)
+	}
+
+	/// Copy the value of `key` into `buffer`, returning its length.
+	pub fn read(&self, key: u16, buffer: &mut [u8]) -> Result<Option<usize>, Error> {
+		if key == ERASED_KEY {
+			return Err(Error::InvalidKey);
+		}
+
+		match self.find(key) {
+			Some(record) => {
+				let len = record.value_len();
+				if len > buffer.len() {
+					return Err(Error::BufferTooSmall(len));
+				}
+				self.flash.read(self.active, record.offset + RECORD_HEADER, &mut buffer[..len]);
+				Ok(Some(len))
+			}
+			None => Ok(None),
+		}
+	}
+
+	pub fn contains(&self, key: u16) -> bool {
+		self.find(key).is_some()
+	}
+
+	pub fn write(&mut self, key: u16, value: &[u8]) -> Result<(), Error> {
+		if key == ERASED_KEY {
+			return Err(Error::InvalidKey);
+		}
+		if value.len() > MAX_VALUE {
+			return Err(Error::TooLong);
+		}
+		self.append(key, value.len() as u16, value)
+	}
+
+	pub fn remove(&mut self, key: u16) -> Result<(), Error> {
+		if key == ERASED_KEY {
+			return Err(Error::InvalidKey);
+		}
+		if !self.contains(key) {
+			return Ok(());
+		}
+		self.append(key, TOMBSTONE, &[])
+	}
+
+	fn append(&mut self, key: u16, len: u16, value: &[u8]) -> Result<(), Error> {
+		let size = self.flash.sector_size();
+		let needed = RECORD_HEADER + padded(value.len());
+		if needed > size - SECTOR_HEADER {
+			return Err(Error::Full);
+		}
+		if self.dirty || self.end + needed > size {
+			self.collect()?;
+			if self.end + needed > size {
+				return Err(Error::Full);
+			}
+		}
+
+		let mut crc = Crc32::new();
+		crc.update(&key.to_le_bytes());
+		crc.update(&len.to_le_bytes());
+		crc.update(value);
+		let mut header = [0; RECORD_HEADER];
+		header[..2].copy_from_slice(&key.to_le_bytes());
+		header[2..4].copy_from_slice(&len.to_le_bytes());
+		header[4..].copy_from_slice(&crc.finish().to_le_bytes());
+
+		// Until both writes went through the space is neither erased nor a record
+		self.dirty = true;
+		self.flash.write(self.active, self.end, &header)?;
+		self.flash.write(self.active, self.end + RECORD_HEADER, value)?;
+		self.end += needed;
+		self.dirty = false;
+		Ok(())
+	}
+
+	/// Move the latest value of every key into the other sector.
+	pub fn collect(&mut self) -> Result<(), Error> {
+		let target = 1 - self.active;
+		self.flash.erase(target)?;
+
+		let mut offset = SECTOR_HEADER;
+		let mut from = SECTOR_HEADER;
+		while from < self.end {
+			let record = self.header(from).unwrap();
+			from += record.size();
+
+			let superseded = self.records(from).any(|later| later.key == record.key);
+			if superseded || record.len == TOMBSTONE {
+				continue;
+			}
+
+			let len = RECORD_HEADER + record.value_len();
+			let mut chunk = [0; CHUNK];
+			let mut done = 0;
+			while done < len {
+				let n = (len - done).min(CHUNK);
+				self.flash.read(self.active, record.offset + done, &mut chunk[..n]);
+				self.flash.write(target, offset + done, &chunk[..n])?;
+				done += n;
+			}
+			offset += record.size();
+		}
+
+		// Only now the copy is complete
+		self.flash.write(target, 0, &sector_header(self.generation + 1))?;
+		self.active = target;
+		self.generation += 1;
+		self.end = offset;
+		self.dirty = false;
+		Ok(())
+	}
+
+	/// Number of times the log moved sectors
+	pub fn generation(&self) -> u32 {
+		self.generation
+	}
+
+	/// Bytes left for records before the next collection
+	pub fn free(&self) -> usize {
+		if self.dirty {
+			return 0;
+		}
+		self.flash.sector_size() - self.end
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::hw::mock::MockFlash;
+
+	fn value(storage: &Storage<MockFlash>, key: u16) -> Option<Vec<u8>> {
+		let mut buffer = [0; MAX_VALUE];
+		storage.read(key, &mut buffer).unwrap().map(|len| buffer[..len].to_vec())
+	}
+
+	#[test]
+	fn values_round_trip() {
+		let flash = MockFlash::new(2, 1024);
+		let mut storage = Storage::mount(flash.clone()).unwrap();
+		assert_eq!(storage.generation(), 1);
+		assert_eq!(value(&storage, 1), None);
+
+		storage.write(1, b"hello").unwrap();
+		storage.write(2, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+		storage.write(3, b"").unwrap();
+		storage.write(1, b"bye").unwrap();
+		assert_eq!(value(&storage, 1).unwrap(), b"bye");
+		assert_eq!(value(&storage, 2).unwrap(), [1, 2, 3, 4, 5, 6, 7, 8]);
+		assert_eq!(value(&storage, 3).unwrap(), b"");
+
+		storage.remove(2).unwrap();
+		storage.remove(4).unwrap();
+		assert_eq!(value(&storage, 2), None);
+		assert!(!storage.contains(4));
+
+		// Records of 16, 16, 8 and 12 bytes and an 8 byte tombstone
+		assert_eq!(storage.free(), 1024 - SECTOR_HEADER - 60);
+
+		let storage = Storage::mount(flash).unwrap();
+		assert_eq!(value(&storage, 1).unwrap(), b"bye");
+		assert_eq!(value(&storage, 2), None);
+		assert_eq!(value(&storage, 3).unwrap(), b"");
+		assert_eq!(storage.free(), 1024 - SECTOR_HEADER - 60);
+	}
+
+	#[test]
+	fn rejects_bad_requests() {
+		let mut storage = Storage::mount(MockFlash::new(2, 256)).unwrap();
+		storage.write(1, &[0; 20]).unwrap();
+
+		assert_eq!(storage.read(1, &mut [0; 8]), Err(Error::BufferTooSmall(20)));
+		assert_eq!(storage.write(ERASED_KEY, b"x"), Err(Error::InvalidKey));
+		assert_eq!(storage.write(2, &[0; MAX_VALUE + 1]), Err(Error::TooLong));
+		// Larger than an empty sector
+		assert_eq!(storage.write(2, &[0; 240]), Err(Error::Full));
+
+		// Live values fill the sector
+		storage.write(2, &[0; 200]).unwrap();
+		assert_eq!(storage.write(3, &[0; 20]), Err(Error::Full));
+		assert_eq!(value(&storage, 2).unwrap(), [0; 200]);
+	}
+
+	#[test]
+	fn garbage_is_collected() {
+		let flash = MockFlash::new(2, 256);
+		let mut storage = Storage::mount(flash.clone()).unwrap();
+		storage.write(7, b"kept").unwrap();
+
+		for i in 0..100u8 {
+			storage.write(1, &[i; 30]).unwrap();
+			if i == 50 {
+				storage.remove(7).unwrap();
+			}
+		}
+		assert_eq!(value(&storage, 1).unwrap(), [99; 30]);
+		assert_eq!(value(&storage, 7), None);
+		assert!(storage.generation() > 10);
+
+		// Both sectors take their turn
+		let erases = (flash.erases(0), flash.erases(1));
+		assert!((erases.0 as i32 - erases.1 as i32).abs() <= 1, "{:?}", erases);
+
+		let storage = Storage::mount(flash).unwrap();
+		assert_eq!(value(&storage, 1).unwrap(), [99; 30]);
+	}
+
+	#[test]
+	fn corrupt_records_end_the_log() {
+		let flash = MockFlash::new(2, 256);
+		let mut storage = Storage::mount(flash.clone()).unwrap();
+		storage.write(1, b"one").unwrap();
+		storage.write(2, b"two").unwrap();
+		let generation = storage.generation();
+
+		// A bit of the second value flips
+		flash.corrupt(0, SECTOR_HEADER + 8 + RECORD_HEADER, 0x04);
+		let mut storage = Storage::mount(flash.clone()).unwrap();
+		assert_eq!(value(&storage, 1).unwrap(), b"one");
+		assert_eq!(value(&storage, 2), None);
+		assert_eq!(storage.free(), 0);
+
+		// The next write moves away from the damage
+		storage.write(2, b"again").unwrap();
+		assert_eq!(storage.generation(), generation + 1);
+		let storage = Storage::mount(flash).unwrap();
+		assert_eq!(value(&storage, 1).unwrap(), b"one");
+		assert_eq!(value(&storage, 2).unwrap(), b"again");
+	}
+
+	// Cut the power at every step of a write prepared by `prepare`, the
+	// store must come back with either the old or the new value and keep
+	// working
+	fn survives_power_loss(prepare: impl Fn(&mut Storage<MockFlash>)) {
+		for steps in 0.. {
+			let flash = MockFlash::new(2, 256);
+			let mut storage = Storage::mount(flash.clone()).unwrap();
+			prepare(&mut storage);
+			let old = value(&storage, 1);
+			let others: Vec<_> = (2..6).map(|key| value(&storage, key)).collect();
+
+			flash.cut_power_after(steps);
+			let result = storage.write(1, b"new value");
+			flash.power_on();
+
+			let mut storage = Storage::mount(flash.clone()).unwrap();
+			match result {
+				Ok(()) => assert_eq!(value(&storage, 1).unwrap(), b"new value"),
+				Err(error) => {
+					assert_eq!(error, Error::Flash(FlashError::PowerLoss));
+					assert_eq!(value(&storage, 1), old, "cut after {} steps", steps);
+				}
+			}
+			let after: Vec<_> = (2..6).map(|key| value(&storage, key)).collect();
+			assert_eq!(after, others, "cut after {} steps", steps);
+
+			storage.write(1, b"after").unwrap();
+			assert_eq!(value(&storage, 1).unwrap(), b"after");
+			let storage = Storage::mount(flash).unwrap();
+			assert_eq!(value(&storage, 1).unwrap(), b"after");
+
+			if result.is_ok() {
+				break;
+			}
+		}
+	}
+
+	#[test]
+	fn power_loss_while_appending() {
+		survives_power_loss(|storage| {
+			storage.write(1, b"old").unwrap();
+			storage.write(2, b"other").unwrap();
+		});
+	}
+
+	#[test]
+	fn power_loss_while_collecting() {
+		survives_power_loss(|storage| {
+			storage.write(2, b"two").unwrap();
+			storage.write(3, &[3; 40]).unwrap();
+			// Too full for the new value
+			let mut i = 0;
+			while storage.free() >= 20 {
+				storage.write(1, &[i; 12]).unwrap();
+				i += 1;
+			}
+		});
+	}
+
+	#[test]
+	fn power_loss_while_formatting() {
+		for steps in 0..SECTOR_HEADER + 1 {
+			let flash = MockFlash::new(2, 256);
+			flash.cut_power_after(steps);
+			assert!(Storage::mount(flash.clone()).is_err());
+			flash.power_on();
+
+			let mut storage = Storage::mount(flash.clone()).unwrap();
+			storage.write(1, b"x").unwrap();
+			assert_eq!(value(&Storage::mount(flash).unwrap(), 1).unwrap(), b"x");
+		}
+	}
+}
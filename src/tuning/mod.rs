@@ -0,0 +1,474 @@
+//! Tunings, note numbers to pitch voltages.
+//!
+//! A `Tuning` is the compact form of a Scala scale and keyboard mapping:
+//! the scale's pitches as 1V/oct `Volts` and the mapping of note numbers
+//! onto scale degrees, with the voltage of the middle note. Everything is
+//! worked out on the host (see `scala`), the firmware only adds up periods
+//! and degrees. The encoded tuning reaches the firmware in hex over the
+//! console, see `Upload`.
+//!
+//! Degree 0 is the scale's 1/1 at 0 V, degrees `1..len` are the pitches
+//! of the scale file but its last, which is the period. Degree `d` beyond
+//! that is `d mod len` moved by whole periods. Without a mapping note
+//! `middle + d` plays degree `d`. With one, the mapping repeats every
+//! `map.len()` notes, each repeat `octave` degrees higher, and notes it
+//! leaves out are not played.
+//!
+//! The binary form, version 1, little endian:
+//!
+//! ```text
+//! u8 version, u8 degrees, i32 period, i32 pitch of degrees 1.., u8 first
+//! note, u8 last note, u8 middle note, u8 octave degree, i32 middle note
+//! volts, u8 map size, u8 degree per mapped note, 0xFF unmapped
+//! ```
+
+#[cfg(any(test, feature = "std"))]
+pub mod scala;
+
+use core::convert::TryInto;
+
+use crate::calibration::OutputCalibration;
+use crate::fixed::Volts;
+use crate::hw::Flash;
+use crate::quantizer::{self, Scale, MAX_DEGREES};
+use crate::storage::{self, Storage};
+
+pub const VERSION: u8 = 1;
+
+// Storage key of the tuning
+pub const KEY: u16 = 2;
+
+/// Longest keyboard mapping
+pub const MAX_MAP: usize = 128;
+
+/// Longest encoding
+pub const MAX_SIZE: usize = 2 + 4 * MAX_DEGREES + 4 + 4 + 1 + MAX_MAP;
+
+// Map entry of a note left out
+const UNMAPPED: u8 = 0xFF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+	// No degrees, or more than MAX_DEGREES
+	Degrees,
+	// Zero or negative period
+	Period,
+	// Longer than MAX_MAP, or first note above last
+	Keyboard,
+	Truncated,
+	TrailingBytes,
+	UnknownVersion(u8),
+	BufferTooSmall,
+	// An odd number of digits or something other than a hex digit
+	NotHex,
+	Storage(storage::Error),
+}
+
+impl From<storage::Error> for Error {
+	fn from(error: storage::Error) -> Error {
+		Error::Storage(error)
+	}
+}
+
+/// Which notes play which scale degrees.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keyboard {
+	pub first: u8,
+	pub last: u8,
+	// Plays degree 0
+	pub middle: u8,
+	pub middle_volts: Volts,
+	// Degree a repeat of the mapping moves up
+	pub octave: u8,
+	map: [u8; MAX_MAP],
+	map_len: usize,
+}
+
+impl Keyboard {
+	/// Every note a degree further, note `middle` at `middle_volts`
+	pub fn linear(middle: u8, middle_volts: Volts) -> Keyboard {
+		Keyboard { first: 0, last: 127, middle, middle_volts, octave: 0, map: [UNMAPPED; MAX_MAP], map_len: 0 }
+	}
+
+	/// Notes from `middle` on play `map`'s degrees, `None` leaves one out
+	pub fn mapped(
+		first: u8,
+		last: u8,
+		middle: u8,
+		middle_volts: Volts,
+		octave: u8,
+		map: &[Option<u8>],
+	) -> Result<Keyboard, Error> {
+		if map.len() > MAX_MAP || first > last || map.contains(&Some(UNMAPPED)) {
+			return Err(Error::Keyboard);
+		}
+		let mut keyboard =
+			Keyboard { first, last, octave, map_len: map.len(), ..Keyboard::linear(middle, middle_volts) };
+		for (entry, degree) in keyboard.map.iter_mut().zip(map) {
+			*entry = degree.unwrap_or(UNMAPPED);
+		}
+		Ok(keyboard)
+	}
+
+	/// Degrees of one repeat of the mapping, empty when linear
+	pub fn map(&self) -> impl Iterator<Item = Option<u8>> + '_ {
+		self.map[..self.map_len].iter().map(|&degree| if degree == UNMAPPED { None } else { Some(degree) })
+	}
+}
+
+/// A scale and its keyboard mapping.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tuning {
+	degrees: [Volts; MAX_DEGREES],
+	len: usize,
+	period: Volts,
+	keyboard: Keyboard,
+}
+
+impl Tuning {
+	/// `degrees` from the 1/1 on, which must be 0 V, any order
+	pub fn new(degrees: &[Volts], period: Volts, keyboard: Keyboard) -> Result<Tuning, Error> {
+		if degrees.is_empty() || degrees.len() > MAX_DEGREES || degrees[0] != Volts::ZERO {
+			return Err(Error::Degrees);
+		}
+		if period <= Volts::ZERO {
+			return Err(Error::Period);
+		}
+		let mut tuning = Tuning { degrees: [Volts::ZERO; MAX_DEGREES], len: degrees.len(), period, keyboard };
+		tuning.degrees[..degrees.len()].copy_from_slice(degrees);
+		Ok(tuning)
+	}
+
+	/// Twelve tone equal temperament, note 60 at 0 V
+	pub fn equal() -> Tuning {
+		let degrees = Scale::chromatic();
+		Tuning::new(degrees.degrees(), Volts::ONE, Keyboard::linear(60, Volts::ZERO)).unwrap()
+	}
+
+	pub fn degrees(&self) -> &[Volts] {
+		&self.degrees[..self.len]
+	}
+
+	pub fn period(&self) -> Volts {
+		self.period
+	}
+
+	pub fn keyboard(&self) -> &Keyboard {
+		&self.keyboard
+	}
+
+	// Pitch of degree `degree` above the 1/1
+	fn pitch(&self, degree: i32) -> i64 {
+		let len = self.len as i32;
+		degree.div_euclid(len) as i64 * self.period.to_bits() as i64
+			+ self.degrees[degree.rem_euclid(len) as usize].to_bits() as i64
+	}
+
+	/// Voltage of note `note`, `None` for notes not played
+	pub fn volts(&self, note: u8) -> Option<Volts> {
+		let keyboard = &self.keyboard;
+		if note < keyboard.first || note > keyboard.last {
+			return None;
+		}
+		let index = note as i32 - keyboard.middle as i32;
+		let pitch = if keyboard.map_len == 0 {
+			self.pitch(index)
+		} else {
+			let len = keyboard.map_len as i32;
+			let degree = keyboard.map[index.rem_euclid(len) as usize];
+			if degree == UNMAPPED {
+				return None;
+			}
+			index.div_euclid(len) as i64 * self.pitch(keyboard.octave as i32) + self.pitch(degree as i32)
+		};
+		let bits = keyboard.middle_volts.to_bits() as i64 + pitch;
+		Some(Volts::from_bits(bits.clamp(i32::MIN as i64, i32::MAX as i64) as i32))
+	}
+
+	/// Fine code playing note `note` on a calibrated output
+	pub fn code(&self, note: u8, calibration: &OutputCalibration) -> Option<u16> {
+		self.volts(note).map(|volts| calibration.code(volts))
+	}
+
+	/// The scale for the quantizer, degrees within one period and sorted,
+	/// relative to the middle note's voltage
+	pub fn scale(&self) -> Result<Scale, quantizer::Error> {
+		let period = self.period.to_bits();
+		let offset = self.keyboard.middle_volts.to_bits().rem_euclid(period);
+		let mut degrees = [Volts::ZERO; MAX_DEGREES];
+		for (reduced, degree) in degrees.iter_mut().zip(self.degrees()) {
+			*reduced = Volts::from_bits((degree.to_bits() as i64 + offset as i64).rem_euclid(period as i64) as i32);
+		}
+		let degrees = &mut degrees[..self.len];
+		degrees.sort_unstable();
+		// Degrees a whole period apart are the same note
+		let mut len = 0;
+		for i in 0..degrees.len() {
+			if i == 0 || degrees[i] != degrees[len - 1] {
+				degrees[len] = degrees[i];
+				len += 1;
+			}
+		}
+		Scale::new(&degrees[..len], self.period)
+	}
+}
+
+/// Write `tuning` in the current format, returning the length.
+pub fn encode(tuning: &Tuning, buffer: &mut [u8]) -> Result<usize, Error> {
+	let keyboard = &tuning.keyboard;
+	let len = 2 + 4 * tuning.len + 4 + 4 + 1 + keyboard.map_len;
+	let buffer = buffer.get_mut(..len).ok_or(Error::BufferTooSmall)?;
+
+	buffer[0] = VERSION;
+	buffer[1] = tuning.len as u8;
+	let mut at = 2;
+	let mut put = |bytes: &[u8]| {
+		buffer[at..at + bytes.len()].copy_from_slice(bytes);
+		at += bytes.len();
+	};
+	put(&tuning.period.to_bits().to_le_bytes());
+	for degree in &tuning.degrees()[1..] {
+		put(&degree.to_bits().to_le_bytes());
+	}
+	put(&[keyboard.first, keyboard.last, keyboard.middle, keyboard.octave]);
+	put(&keyboard.middle_volts.to_bits().to_le_bytes());
+	put(&[keyboard.map_len as u8]);
+	put(&keyboard.map[..keyboard.map_len]);
+	Ok(len)
+}
+
+/// Read a tuning written by `encode`.
+pub fn decode(bytes: &[u8]) -> Result<Tuning, Error> {
+	let mut data = bytes;
+	let mut take = |n: usize| -> Result<&[u8], Error> {
+		if data.len() < n {
+			return Err(Error::Truncated);
+		}
+		let (taken, rest) = data.split_at(n);
+		data = rest;
+		Ok(taken)
+	};
+	let volts = |bytes: &[u8]| Volts::from_bits(i32::from_le_bytes(bytes.try_into().unwrap()));
+
+	let version = take(1)?[0];
+	if version != VERSION {
+		return Err(Error::UnknownVersion(version));
+	}
+	let len = take(1)?[0] as usize;
+	if len == 0 || len > MAX_DEGREES {
+		return Err(Error::Degrees);
+	}
+	let period = volts(take(4)?);
+	let mut degrees = [Volts::ZERO; MAX_DEGREES];
+	for degree in &mut degrees[1..len] {
+		*degree = volts(take(4)?);
+	}
+	let [first, last, middle, octave]: [u8; 4] = take(4)?.try_into().unwrap();
+	let middle_volts = volts(take(4)?);
+	let map_len = take(1)?[0] as usize;
+	if map_len > MAX_MAP {
+		return Err(Error::Keyboard);
+	}
+	let mut keyboard = Keyboard::linear(middle, middle_volts);
+	keyboard.map[..map_len].copy_from_slice(take(map_len)?);
+	keyboard.map_len = map_len;
+	if first > last {
+		return Err(Error::Keyboard);
+	}
+	keyboard.first = first;
+	keyboard.last = last;
+	keyboard.octave = octave;
+	if !data.is_empty() {
+		return Err(Error::TrailingBytes);
+	}
+	Tuning::new(&degrees[..len], period, keyboard)
+}
+
+/// The stored tuning, `None` if there is none.
+pub fn load<F: Flash>(storage: &Storage<F>) -> Result<Option<Tuning>, Error> {
+	let mut buffer = [0; MAX_SIZE];
+	match storage.read(KEY, &mut buffer)? {
+		Some(len) => decode(&buffer[..len]).map(Some),
+		None => Ok(None),
+	}
+}
+
+pub fn save<F: Flash>(storage: &mut Storage<F>, tuning: &Tuning) -> Result<(), Error> {
+	let mut buffer = [0; MAX_SIZE];
+	let len = encode(tuning, &mut buffer)?;
+	storage.write(KEY, &buffer[..len])?;
+	Ok(())
+}
+
+/// An encoded tuning arriving in hex over several console lines.
+pub struct Upload {
+	bytes: [u8; MAX_SIZE],
+	len: usize,
+}
+
+impl Default for Upload {
+	fn default() -> Upload {
+		Upload::new()
+	}
+}
+
+impl Upload {
+	pub fn new() -> Upload {
+		Upload { bytes: [0; MAX_SIZE], len: 0 }
+	}
+
+	/// Bytes received so far
+	pub fn received(&self) -> usize {
+		self.len
+	}
+
+	/// Append pairs of hex digits, nothing is appended on errors
+	pub fn push_hex(&mut self, hex: &str) -> Result<(), Error> {
+		let hex = hex.as_bytes();
+		if !hex.len().is_multiple_of(2) {
+			return Err(Error::NotHex);
+		}
+		let end = self.len + hex.len() / 2;
+		if end > MAX_SIZE {
+			return Err(Error::BufferTooSmall);
+		}
+
+		let digit = |c: u8| (c as char).to_digit(16).ok_or(Error::NotHex);
+		let mut bytes = [0; MAX_SIZE];
+		for (byte, pair) in bytes.iter_mut().zip(hex.chunks_exact(2)) {
+			*byte = (digit(pair[0])? << 4 | digit(pair[1])?) as u8;
+		}
+		self.bytes[self.len..end].copy_from_slice(&bytes[..end - self.len]);
+		self.len = end;
+		Ok(())
+	}
+
+	/// The tuning received, the upload starts over either way
+	pub fn finish(&mut self) -> Result<Tuning, Error> {
+		let len = core::mem::replace(&mut self.len, 0);
+		decode(&self.bytes[..len])
+	}
+
+	pub fn clear(&mut self) {
+		self.len = 0;
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::hw::mock::MockFlash;
+
+	fn cents(cents: i32) -> Volts {
+		Volts::from_bits(((cents as i64 * 65536 + 600) / 1200) as i32)
+	}
+
+	// Pythagorean pentatonic, unsorted as a scale file may be, on a
+	// keyboard of white keys with every other one left out
+	fn tuning() -> Tuning {
+		let degrees = [cents(0), cents(204), cents(702), cents(408), cents(906)];
+		let map = [Some(0), None, Some(1), None, Some(3), Some(2), None, Some(4), None, None, None, None];
+		let keyboard = Keyboard::mapped(36, 96, 60, Volts::from_millivolts(-250), 5, &map).unwrap();
+		Tuning::new(&degrees, cents(1200), keyboard).unwrap()
+	}
+
+	#[test]
+	fn notes_to_volts() {
+		let equal = Tuning::equal();
+		assert_eq!(equal.volts(60), Some(Volts::ZERO));
+		assert_eq!(equal.volts(72), Some(Volts::ONE));
+		assert_eq!(equal.volts(0), Some(Volts::from_int(-5)));
+		assert_eq!(equal.volts(67), Some(Scale::chromatic().degrees()[7]));
+
+		let tuning = tuning();
+		let middle = Volts::from_millivolts(-250);
+		assert_eq!(tuning.volts(60), Some(middle));
+		assert_eq!(tuning.volts(61), None);
+		assert_eq!(tuning.volts(64), Some(middle + cents(408)));
+		assert_eq!(tuning.volts(65), Some(middle + cents(702)));
+		// A repeat of the mapping up and down
+		assert_eq!(tuning.volts(74), Some(middle + cents(1404)));
+		assert_eq!(tuning.volts(55), Some(middle - cents(1200) + cents(906)));
+		// Outside the keyboard
+		assert_eq!((tuning.volts(35), tuning.volts(97)), (None, None));
+
+		// Onto a calibrated output
+		let calibration = OutputCalibration::nominal(-5_000_000, 5_000_000);
+		assert_eq!(equal.code(72, &calibration), Some(calibration.code(Volts::ONE)));
+		assert_eq!(tuning.code(61, &calibration), None);
+	}
+
+	#[test]
+	fn scale_for_the_quantizer() {
+		let scale = tuning().scale().unwrap();
+		// Relative to the middle note at -250 mV, which is 9 semitones up
+		// in the octave
+		let expected: Vec<Volts> =
+			[0, 204, 408, 702, 906].iter().map(|&c| Volts::from_bits((cents(c).to_bits() + 49152) % 65536)).collect();
+		let mut expected = expected;
+		expected.sort();
+		assert_eq!(scale.degrees(), &expected[..]);
+		assert_eq!(scale.period(), Volts::ONE);
+
+		assert_eq!(Tuning::equal().scale(), Ok(Scale::chromatic()));
+	}
+
+	#[test]
+	fn round_trip() {
+		for tuning in &[Tuning::equal(), tuning()] {
+			let mut buffer = [0; MAX_SIZE];
+			let len = encode(tuning, &mut buffer).unwrap();
+			assert_eq!(decode(&buffer[..len]), Ok(*tuning));
+
+			assert_eq!(decode(&buffer[..len - 1]), Err(Error::Truncated));
+			assert_eq!(decode(&buffer[..len + 1]), Err(Error::TrailingBytes));
+			assert_eq!(encode(tuning, &mut buffer[..len - 1]), Err(Error::BufferTooSmall));
+		}
+		// 12 degrees and the linear keyboard in 59 bytes
+		let mut buffer = [0; MAX_SIZE];
+		assert_eq!(encode(&Tuning::equal(), &mut buffer), Ok(59));
+		assert_eq!(decode(&[2]), Err(Error::UnknownVersion(2)));
+	}
+
+	#[test]
+	fn uploads_in_hex() {
+		let mut buffer = [0; MAX_SIZE];
+		let len = encode(&tuning(), &mut buffer).unwrap();
+		let hex: String = buffer[..len].iter().map(|byte| format!("{:02X}", byte)).collect();
+
+		let mut upload = Upload::new();
+		for chunk in hex.as_bytes().chunks(10) {
+			upload.push_hex(core::str::from_utf8(chunk).unwrap()).unwrap();
+		}
+		assert_eq!(upload.received(), len);
+		assert_eq!(upload.finish(), Ok(tuning()));
+		assert_eq!(upload.received(), 0);
+
+		// Bad lines are dropped whole
+		upload.push_hex("0102").unwrap();
+		assert_eq!(upload.push_hex("0g"), Err(Error::NotHex));
+		assert_eq!(upload.push_hex("+f"), Err(Error::NotHex));
+		assert_eq!(upload.push_hex("030"), Err(Error::NotHex));
+		assert_eq!(upload.received(), 2);
+		assert_eq!(upload.push_hex(&"00".repeat(MAX_SIZE)), Err(Error::BufferTooSmall));
+		assert_eq!(upload.finish(), Err(Error::Truncated));
+	}
+
+	#[test]
+	fn stored_in_flash() {
+		let mut storage = Storage::mount(MockFlash::new(2, 16 * 1024)).unwrap();
+		assert_eq!(load(&storage), Ok(None));
+		save(&mut storage, &tuning()).unwrap();
+		assert_eq!(load(&storage), Ok(Some(tuning())));
+	}
+
+	#[test]
+	fn rejects_bad_tunings() {
+		let keyboard = Keyboard::linear(60, Volts::ZERO);
+		assert_eq!(Tuning::new(&[], Volts::ONE, keyboard), Err(Error::Degrees));
+		assert_eq!(Tuning::new(&[cents(100)], Volts::ONE, keyboard), Err(Error::Degrees));
+		assert_eq!(Tuning::new(&[Volts::ZERO], Volts::ZERO, keyboard), Err(Error::Period));
+		assert_eq!(Keyboard::mapped(61, 60, 60, Volts::ZERO, 12, &[]), Err(Error::Keyboard));
+		assert_eq!(Keyboard::mapped(0, 127, 60, Volts::ZERO, 12, &[Some(255)]), Err(Error::Keyboard));
+	}
+}
@@ -0,0 +1,332 @@
+//! Scala scale (`.scl`) and keyboard mapping (`.kbm`) files, host only.
+//!
+//! Lines starting with `!` are comments. A scale file has a description,
+//! the number of pitches and the pitches, each in cents when it has a
+//! period (`701.955`) and otherwise a ratio (`3/2`, or `2` for `2/1`).
+//! Anything after the value on a line is ignored. The 1/1 is implied, the
+//! last pitch is the period.
+//!
+//! A keyboard mapping has, one per line: the map size, the first and last
+//! note retuned, the middle note playing the 1/1, the reference note, its
+//! frequency, the degree of the formal octave, then one degree per note of
+//! the map, `x` for a note left out. A map size of 0 is a linear mapping.
+//!
+//! Frequencies become 1V/oct voltages with `ZERO_VOLT_HZ` at 0 V.
+
+use std::convert::TryFrom;
+
+use super::{Keyboard, Tuning, MAX_MAP, MAX_SIZE};
+use crate::console::LINE_LEN;
+use crate::fixed::Volts;
+
+/// Middle C at 0 V
+pub const ZERO_VOLT_HZ: f64 = 261.625_565_300_598_6;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+	// Line number of a missing or unreadable count or field
+	Count(usize),
+	Pitch(usize),
+	Field(usize),
+	Mapping(usize),
+	// Fewer pitches or map entries than the count says
+	Missing,
+	// The reference note is left out or outside the keyboard
+	Reference,
+	Tuning(super::Error),
+}
+
+/// A scale file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scl {
+	pub description: String,
+	// In cents, the last is the period
+	pub pitches: Vec<f64>,
+}
+
+/// A keyboard mapping file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Kbm {
+	pub first: u8,
+	pub last: u8,
+	pub middle: u8,
+	pub reference: u8,
+	pub frequency: f64,
+	pub octave: u32,
+	// Empty for a linear mapping
+	pub map: Vec<Option<u32>>,
+}
+
+impl Default for Kbm {
+	/// Scala's default, linear with A 440 Hz on note 69 and the 1/1 on 60
+	fn default() -> Kbm {
+		Kbm { first: 0, last: 127, middle: 60, reference: 69, frequency: 440.0, octave: 0, map: Vec::new() }
+	}
+}
+
+// Lines that are not comments, with their line numbers from 1
+fn lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+	text.lines().enumerate().map(|(i, line)| (i + 1, line.trim())).filter(|(_, line)| !line.starts_with('!'))
+}
+
+// First word of a line
+fn value(line: &str) -> &str {
+	line.split_whitespace().next().unwrap_or("")
+}
+
+fn cents(word: &str) -> Option<f64> {
+	if word.contains('.') {
+		return word.parse().ok();
+	}
+	let (numerator, denominator) = match word.split_once('/') {
+		Some((numerator, denominator)) => (numerator.parse::<u64>().ok()?, denominator.parse::<u64>().ok()?),
+		None => (word.parse::<u64>().ok()?, 1),
+	};
+	if numerator == 0 || denominator == 0 {
+		return None;
+	}
+	Some(1200.0 * (numerator as f64 / denominator as f64).log2())
+}
+
+pub fn parse_scl(text: &str) -> Result<Scl, Error> {
+	let mut lines = lines(text);
+	// The description may be empty
+	let description = lines.next().map(|(_, line)| String::from(line)).ok_or(Error::Count(1))?;
+	let (number, line) = lines.next().ok_or(Error::Missing)?;
+	let count: usize = value(line).parse().map_err(|_| Error::Count(number))?;
+	if count == 0 {
+		return Err(Error::Count(number));
+	}
+
+	let pitches = lines
+		.take(count)
+		.map(|(number, line)| cents(value(line)).ok_or(Error::Pitch(number)))
+		.collect::<Result<Vec<_>, _>>()?;
+	if pitches.len() < count {
+		return Err(Error::Missing);
+	}
+	Ok(Scl { description, pitches })
+}
+
+pub fn parse_kbm(text: &str) -> Result<Kbm, Error> {
+	let mut lines = lines(text).filter(|(_, line)| !line.is_empty());
+	let mut field = |max: u32| -> Result<u32, Error> {
+		let (number, line) = lines.next().ok_or(Error::Missing)?;
+		value(line).parse().ok().filter(|&value| value <= max).ok_or(Error::Field(number))
+	};
+	let size = field(MAX_MAP as u32)? as usize;
+	let first = field(127)? as u8;
+	let last = field(127)? as u8;
+	let middle = field(127)? as u8;
+	let reference = field(127)? as u8;
+
+	let (number, line) = lines.next().ok_or(Error::Missing)?;
+	let frequency = value(line).parse().ok().filter(|&hz: &f64| hz > 0.0).ok_or(Error::Field(number))?;
+	let (number, line) = lines.next().ok_or(Error::Missing)?;
+	let octave = value(line).parse().map_err(|_| Error::Field(number))?;
+
+	let map = lines
+		.take(size)
+		.map(|(number, line)| match value(line) {
+			"x" => Ok(None),
+			word => word.parse().map(Some).map_err(|_| Error::Mapping(number)),
+		})
+		.collect::<Result<Vec<_>, _>>()?;
+	if map.len() < size {
+		return Err(Error::Missing);
+	}
+	Ok(Kbm { first, last, middle, reference, frequency, octave, map })
+}
+
+// Cents to 1V/oct
+fn volts(cents: f64) -> Volts {
+	Volts::from_bits((cents / 1200.0 * 65536.0).round() as i32)
+}
+
+/// The compact tuning of a scale on a keyboard mapping
+pub fn tuning(scl: &Scl, kbm: &Kbm) -> Result<Tuning, Error> {
+	let (period, pitches) = scl.pitches.split_last().ok_or(Error::Missing)?;
+	let degrees: Vec<Volts> = core::iter::once(Volts::ZERO).chain(pitches.iter().map(|&c| volts(c))).collect();
+
+	// Degrees are bytes in the compact form
+	let byte = |degree: u32| u8::try_from(degree).map_err(|_| Error::Tuning(super::Error::Keyboard));
+	let map = kbm.map.iter().map(|&entry| entry.map(byte).transpose()).collect::<Result<Vec<_>, _>>()?;
+	// A formal octave of 0 is the period
+	let octave = byte(if kbm.octave == 0 { degrees.len() as u32 } else { kbm.octave })?;
+
+	let keyboard = if map.is_empty() {
+		Keyboard { first: kbm.first, last: kbm.last, ..Keyboard::linear(kbm.middle, Volts::ZERO) }
+	} else {
+		Keyboard::mapped(kbm.first, kbm.last, kbm.middle, Volts::ZERO, octave, &map).map_err(Error::Tuning)?
+	};
+	let unshifted = Tuning::new(&degrees, volts(*period), keyboard).map_err(Error::Tuning)?;
+
+	// Shift the whole keyboard so the reference note plays its frequency
+	let reference = unshifted.volts(kbm.reference).ok_or(Error::Reference)?;
+	let at = volts(1200.0 * (kbm.frequency / ZERO_VOLT_HZ).log2());
+	let keyboard = Keyboard { middle_volts: at - reference, ..keyboard };
+	Tuning::new(&degrees, volts(*period), keyboard).map_err(Error::Tuning)
+}
+
+/// Console lines storing `tuning` on the module, its encoding in hex as
+/// `tuning <hex>` lines the console takes whole, then `tuning save`
+pub fn console_lines(tuning: &Tuning) -> Vec<String> {
+	let mut buffer = [0; MAX_SIZE];
+	// Every tuning fits MAX_SIZE
+	let len = super::encode(tuning, &mut buffer).unwrap();
+	let per_line = (LINE_LEN - "tuning ".len()) / 2;
+
+	let mut lines: Vec<String> = buffer[..len]
+		.chunks(per_line)
+		.map(|bytes| bytes.iter().fold(String::from("tuning "), |line, byte| line + &format!("{:02x}", byte)))
+		.collect();
+	lines.push(String::from("tuning save"));
+	lines
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	const MEANTONE: &str = "! meanquar.scl
+!
+1/4-comma meantone scale. Pietro Aaron's temperament (1523)
+ 12
+!
+ 76.04900
+ 193.15686
+ 310.26471
+ 5/4
+ 503.42157
+ 579.47057
+ 696.57843
+ 25/16
+ 889.73529
+ 1006.84314
+ 1082.89214
+ 2/1
+";
+
+	const WHITE_KEYS: &str = "! The white keys only
+12
+0
+127
+60
+69
+440.0
+12
+! Mapping
+0
+x
+2
+x
+4
+5
+x
+7
+x
+9
+x
+11
+";
+
+	fn equal(steps: usize, period: &str) -> String {
+		let mut scl = format!("{} equal steps\n{}\n", steps, steps);
+		for step in 1..steps {
+			scl += &format!("{:.5}\n", step as f64 * cents(period).unwrap() / steps as f64);
+		}
+		scl + period + "\n"
+	}
+
+	#[test]
+	fn reads_scale_files() {
+		let scl = parse_scl(MEANTONE).unwrap();
+		assert_eq!(scl.description, "1/4-comma meantone scale. Pietro Aaron's temperament (1523)");
+		assert_eq!(scl.pitches.len(), 12);
+		assert!((scl.pitches[3] - 386.3137).abs() < 1e-3);
+		assert_eq!(scl.pitches[11], 1200.0);
+
+		// Integers are ratios, text after the value is ignored
+		let scl = parse_scl("\n2\n3 tritave fifth\n3\n").unwrap();
+		assert_eq!(scl.description, "");
+		assert!((scl.pitches[1] - 1901.955).abs() < 1e-3);
+		// Negative cents are fine
+		assert_eq!(parse_scl("a\n1\n-100.0\n").unwrap().pitches, [-100.0]);
+	}
+
+	#[test]
+	fn reports_the_line() {
+		assert_eq!(parse_scl("! empty\n"), Err(Error::Count(1)));
+		assert_eq!(parse_scl("x\n!\ntwelve\n"), Err(Error::Count(3)));
+		assert_eq!(parse_scl("x\n0\n"), Err(Error::Count(2)));
+		assert_eq!(parse_scl("x\n2\n3/0\n2/1\n"), Err(Error::Pitch(3)));
+		assert_eq!(parse_scl("x\n3\n100.0\n!\nabc\n2/1\n"), Err(Error::Pitch(5)));
+		assert_eq!(parse_scl("x\n3\n100.0\n"), Err(Error::Missing));
+
+		assert_eq!(parse_kbm("0\n0\n128\n"), Err(Error::Field(3)));
+		assert_eq!(parse_kbm("0\n0\n127\n60\n69\n-440\n0\n"), Err(Error::Field(6)));
+		assert_eq!(parse_kbm("2\n0\n127\n60\n69\n440\n12\n0\ny\n"), Err(Error::Mapping(9)));
+		assert_eq!(parse_kbm("2\n0\n127\n60\n69\n440\n12\n0\n"), Err(Error::Missing));
+	}
+
+	#[test]
+	fn twelve_equal_is_1v_per_octave() {
+		let tuning = tuning(&parse_scl(&equal(12, "2/1")).unwrap(), &Kbm::default()).unwrap();
+		// A 440 Hz is 9 semitones above middle C, which is at 0 V
+		assert!(tuning.volts(60).unwrap().to_microvolts().abs() <= 16);
+		assert!((tuning.volts(72).unwrap().to_microvolts() - 1_000_000).abs() <= 16);
+		assert!((tuning.volts(69).unwrap().to_microvolts() - 750_000).abs() <= 16);
+		assert!(tuning.scale().unwrap().degrees().len() == 12);
+	}
+
+	#[test]
+	fn maps_the_keyboard() {
+		let scl = parse_scl(MEANTONE).unwrap();
+		let kbm = parse_kbm(WHITE_KEYS).unwrap();
+		assert_eq!(kbm.map.len(), 12);
+		assert_eq!(kbm.map[..3], [Some(0), None, Some(2)]);
+		let tuning = tuning(&scl, &kbm).unwrap();
+
+		// A at 440 Hz, the meantone whole tone and octave
+		let a = tuning.volts(69).unwrap();
+		assert!((a.to_microvolts() - 750_000).abs() <= 16);
+		let step = |from: u8, to: u8| (tuning.volts(to).unwrap() - tuning.volts(from).unwrap()).to_microvolts();
+		assert!((step(60, 62) - 160_964).abs() <= 16);
+		assert!((step(60, 72) - 1_000_000).abs() <= 16);
+		assert!((step(57, 69) - 1_000_000).abs() <= 16);
+		assert_eq!(tuning.volts(61), None);
+		assert_eq!(tuning.keyboard().octave, 12);
+
+		// The reference note must play
+		let kbm = Kbm { reference: 61, ..kbm };
+		assert_eq!(super::tuning(&scl, &kbm), Err(Error::Reference));
+	}
+
+	#[test]
+	fn microtonal_periods() {
+		// Bohlen-Pierce, 13 steps to the 3:1
+		let bp = tuning(&parse_scl(&equal(13, "3/1")).unwrap(), &Kbm::default()).unwrap();
+		let tritave = (bp.volts(73).unwrap() - bp.volts(60).unwrap()).to_microvolts();
+		assert!((tritave - 1_584_963).abs() <= 16);
+		assert_eq!(bp.scale().unwrap().period(), bp.period());
+
+		// The compact form survives a round trip
+		let mut buffer = [0; super::super::MAX_SIZE];
+		let len = super::super::encode(&bp, &mut buffer).unwrap();
+		assert_eq!(super::super::decode(&buffer[..len]), Ok(bp));
+	}
+
+	#[test]
+	fn uploads_over_the_console() {
+		let tuning = tuning(&parse_scl(MEANTONE).unwrap(), &parse_kbm(WHITE_KEYS).unwrap()).unwrap();
+		let lines = console_lines(&tuning);
+		assert!(lines.iter().all(|line| line.len() <= LINE_LEN));
+		assert_eq!(lines.last().map(String::as_str), Some("tuning save"));
+
+		let mut upload = super::super::Upload::new();
+		for line in &lines[..lines.len() - 1] {
+			upload.push_hex(line.trim_start_matches("tuning ")).unwrap();
+		}
+		assert_eq!(upload.finish(), Ok(tuning));
+	}
+}